@@ -0,0 +1,127 @@
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use flashblocks_websocket_proxy::metrics::Metrics;
+use flashblocks_websocket_proxy::registry::{Delivery, Registry};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+use tokio::sync::Notify;
+
+const CLIENT_COUNTS: &[usize] = &[100, 1_000, 10_000];
+
+/// A flashblock diff with a realistic number of transactions, roughly the size seen on mainnet.
+fn flashblock_payload() -> Bytes {
+    let transactions: Vec<String> = (0..40)
+        .map(|i| format!("\"0x02f8b0{}\"", format!("{i:02x}").repeat(170)))
+        .collect();
+
+    Bytes::from(format!(
+        r#"{{"payload_id":"0x0316ecb1aa1671b5","index":3,"diff":{{"state_root":"0x{root}","receipts_root":"0x{root}","logs_bloom":"0x{bloom}","gas_used":"0x1c9c380","block_hash":"0x{root}","transactions":[{transactions}],"withdrawals":[]}},"metadata":{{"block_number":26712463}}}}"#,
+        root = "ab".repeat(32),
+        bloom = "00".repeat(256),
+        transactions = transactions.join(","),
+    ))
+}
+
+/// Subscribes `clients` simulated clients that drain their queues, counting deliveries. `notify`
+/// is signalled when every client has received the current message.
+fn spawn_clients(
+    runtime: &Runtime,
+    registry: &Registry,
+    clients: usize,
+    delivered: Arc<AtomicUsize>,
+    notify: Arc<Notify>,
+) {
+    for _ in 0..clients {
+        let mut subscription = registry.register();
+        let delivered = delivered.clone();
+        let notify = notify.clone();
+
+        runtime.spawn(async move {
+            let mut batch = Vec::new();
+            loop {
+                if let Delivery::Messages(_) = subscription.recv_many(&mut batch, 1).await {
+                    for msg in batch.drain(..) {
+                        subscription.record_delivered(&msg);
+                    }
+                    if delivered.fetch_add(1, Ordering::AcqRel) + 1 == clients {
+                        notify.notify_one();
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Time from publishing a message until every client has taken it off its queue.
+fn bench_fan_out(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let payload = flashblock_payload();
+    let mut group = c.benchmark_group("fan_out");
+
+    for &clients in CLIENT_COUNTS {
+        let registry = Registry::new(20, 1, Arc::new(Metrics::default()));
+        let delivered = Arc::new(AtomicUsize::new(0));
+        let notify = Arc::new(Notify::new());
+        spawn_clients(
+            &runtime,
+            &registry,
+            clients,
+            delivered.clone(),
+            notify.clone(),
+        );
+
+        group.throughput(Throughput::Elements(clients as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(clients), &clients, |b, _| {
+            b.to_async(&runtime).iter_custom(|iters| {
+                let registry = registry.clone();
+                let payload = payload.clone();
+                let delivered = delivered.clone();
+                let notify = notify.clone();
+
+                async move {
+                    let mut total = Duration::ZERO;
+                    for _ in 0..iters {
+                        delivered.store(0, Ordering::Release);
+                        let start = Instant::now();
+                        registry.publish(payload.clone());
+                        notify.notified().await;
+                        total += start.elapsed();
+                    }
+                    total
+                }
+            });
+        });
+    }
+
+    group.finish();
+}
+
+/// Cost of queueing a message for every client, which runs on the upstream subscriber's task.
+fn bench_publish(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let payload = flashblock_payload();
+    let mut group = c.benchmark_group("publish");
+
+    for &clients in CLIENT_COUNTS {
+        let registry = Registry::new(20, 1, Arc::new(Metrics::default()));
+        spawn_clients(
+            &runtime,
+            &registry,
+            clients,
+            Arc::new(AtomicUsize::new(0)),
+            Arc::new(Notify::new()),
+        );
+
+        group.throughput(Throughput::Elements(clients as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(clients), &clients, |b, _| {
+            b.iter(|| registry.publish(payload.clone()));
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_fan_out, bench_publish);
+criterion_main!(benches);
@@ -0,0 +1,10 @@
+fn main() {
+    // protoc is vendored so that building doesn't need it installed
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().unwrap());
+
+    tonic_prost_build::configure()
+        .build_transport(false)
+        .bytes(".flashblocks.v1.Flashblock.payload")
+        .compile_protos(&["proto/flashblocks.proto"], &["proto"])
+        .unwrap();
+}
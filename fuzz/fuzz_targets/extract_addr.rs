@@ -0,0 +1,23 @@
+#![no_main]
+
+use flashblocks_websocket_proxy::server::extract_addr;
+use http::HeaderValue;
+use libfuzzer_sys::fuzz_target;
+use std::net::{IpAddr, Ipv4Addr};
+
+const FALLBACK: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(header) = HeaderValue::from_bytes(data) else {
+        return;
+    };
+
+    // The address is the last in the list, or the fallback if that isn't an address
+    let addr = extract_addr(&header, FALLBACK);
+    let last = header
+        .to_str()
+        .ok()
+        .and_then(|value| value.split(',').next_back())
+        .and_then(|value| value.trim().parse::<IpAddr>().ok());
+    assert_eq!(addr, last.unwrap_or(FALLBACK));
+});
@@ -0,0 +1,15 @@
+#![no_main]
+
+use bytes::Bytes;
+use flashblocks_websocket_proxy::recording::next_entry;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // A recording either reads to the end or fails, without reading past it
+    let mut recording = Bytes::copy_from_slice(data);
+    let mut read = 0;
+    while let Ok(Some((_, payload))) = next_entry(&mut recording) {
+        read += 12 + payload.len();
+        assert!(read <= data.len());
+    }
+});
@@ -0,0 +1,18 @@
+#![no_main]
+
+use bytes::Bytes;
+use flashblocks_websocket_proxy::registry::BroadcastMessage;
+use flashblocks_websocket_proxy::relay::{envelope, open_envelope};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((sequence, payload)) = open_envelope(Bytes::copy_from_slice(data)) else {
+        assert!(data.len() < 8);
+        return;
+    };
+
+    // Any frame that opens is the envelope of its sequence number and payload
+    let mut msg = BroadcastMessage::new(payload);
+    msg.sequence = sequence;
+    assert_eq!(envelope(&msg).into_data(), data);
+});
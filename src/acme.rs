@@ -0,0 +1,246 @@
+//! TLS on the websocket listener with certificates provisioned and renewed from an ACME CA such as
+//! Let's Encrypt, for small standalone deployments that don't sit behind a load balancer
+//! terminating TLS. Only built with the `acme` feature.
+//!
+//! Domains are validated with TLS-ALPN-01, which the listener answers itself, so the proxy must be
+//! reachable on port 443 at each domain. Certificates and the ACME account key are kept in the
+//! cache directory, so that restarts reuse them rather than request new ones, and certificates
+//! are renewed in the background before they expire. Until the first certificate is issued, TLS
+//! handshakes fail.
+//!
+//! TLS runs on the connections [`HandshakeListener`] accepts, so the handshake timeout and limits
+//! cover the TLS handshake too. Connections refused on accept are answered in plaintext, which a
+//! TLS client sees as a failed handshake. HTTP/2 is offered with ALPN when enabled.
+
+use crate::handshake::{HandshakeListener, HandshakeStream};
+use axum::serve::Listener;
+use futures::StreamExt;
+use rustls_acme::caches::DirCache;
+use rustls_acme::is_tls_alpn_challenge;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::task::JoinSet;
+use tokio_rustls::rustls::server::Acceptor;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::LazyConfigAcceptor;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+/// Let's Encrypt's production directory.
+pub const LETS_ENCRYPT: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct AcmeArgs {
+    /// Serve TLS on the listener for this domain, with a certificate from the ACME CA (repeatable,
+    /// ',' separated in the environment)
+    #[arg(long = "acme-domain", env = "ACME_DOMAINS", value_delimiter = ',')]
+    pub acme_domains: Vec<String>,
+
+    /// Directory to keep the ACME account key and certificates in
+    #[arg(long, env)]
+    pub acme_cache_dir: Option<PathBuf>,
+
+    /// Contact for the ACME account, e.g. mailto:ops@example.com (repeatable, ',' separated in the
+    /// environment)
+    #[arg(long = "acme-contact", env = "ACME_CONTACTS", value_delimiter = ',')]
+    pub acme_contacts: Vec<String>,
+
+    /// ACME directory to request certificates from, e.g.
+    /// https://acme-staging-v02.api.letsencrypt.org/directory for Let's Encrypt's staging CA
+    #[arg(long, env, default_value = LETS_ENCRYPT)]
+    pub acme_directory: String,
+}
+
+impl AcmeArgs {
+    /// Problems with the flags, for validation.
+    pub fn problems(&self) -> Vec<String> {
+        match (self.acme_domains.is_empty(), &self.acme_cache_dir) {
+            (false, None) => vec!["--acme-domain requires --acme-cache-dir".to_string()],
+            (true, Some(_)) => vec!["--acme-cache-dir requires --acme-domain".to_string()],
+            _ if self.acme_domains.is_empty() && !self.acme_contacts.is_empty() => {
+                vec!["--acme-contact requires --acme-domain".to_string()]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// The configuration, or `None` if ACME isn't enabled.
+    pub fn config(&self) -> Option<AcmeConfig> {
+        Some(AcmeConfig {
+            domains: (!self.acme_domains.is_empty()).then(|| self.acme_domains.clone())?,
+            cache_dir: self.acme_cache_dir.clone()?,
+            contacts: self.acme_contacts.clone(),
+            directory: self.acme_directory.clone(),
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AcmeConfig {
+    pub domains: Vec<String>,
+    pub cache_dir: PathBuf,
+    pub contacts: Vec<String>,
+    pub directory: String,
+}
+
+impl AcmeConfig {
+    /// Starts provisioning the certificate, and renewing it until `token` is cancelled, returning
+    /// the TLS configuration to accept connections with. With `http2`, HTTP/2 is offered as well
+    /// as HTTP/1.1.
+    pub fn start(&self, http2: bool, token: CancellationToken) -> AcmeTls {
+        let mut state = rustls_acme::AcmeConfig::new(&self.domains)
+            .contact(&self.contacts)
+            .cache(DirCache::new(self.cache_dir.clone()))
+            .directory(&self.directory)
+            .state();
+
+        let mut tls = (*state.default_rustls_config()).clone();
+        tls.alpn_protocols = match http2 {
+            true => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+            false => vec![b"http/1.1".to_vec()],
+        };
+        let acme_tls = AcmeTls {
+            challenge: state.challenge_rustls_config(),
+            tls: Arc::new(tls),
+        };
+
+        info!(
+            message = "provisioning certificates with ACME",
+            domains = ?self.domains,
+            directory = self.directory
+        );
+        // The state requests, caches and renews the certificate as it's polled
+        tokio::spawn(async move {
+            loop {
+                let event = tokio::select! {
+                    _ = token.cancelled() => return,
+                    event = state.next() => event,
+                };
+                match event {
+                    Some(Ok(event)) => info!(message = "ACME event", event = ?event),
+                    Some(Err(e)) => error!(message = "ACME error", error = e.to_string()),
+                    None => return,
+                }
+            }
+        });
+
+        acme_tls
+    }
+}
+
+/// TLS configurations for connections, with the certificates provisioned by
+/// [`AcmeConfig::start`].
+#[derive(Clone)]
+pub struct AcmeTls {
+    /// Answers TLS-ALPN-01 validation requests from the CA.
+    challenge: Arc<ServerConfig>,
+    tls: Arc<ServerConfig>,
+}
+
+impl AcmeTls {
+    /// Completes the TLS handshake on `stream`, or returns `None` if the connection was the CA
+    /// validating a domain.
+    async fn accept(
+        &self,
+        stream: HandshakeStream,
+    ) -> io::Result<Option<TlsStream<HandshakeStream>>> {
+        let start = LazyConfigAcceptor::new(Acceptor::default(), stream).await?;
+        if is_tls_alpn_challenge(&start.client_hello()) {
+            info!(message = "answering TLS-ALPN-01 validation request");
+            let mut stream = start.into_stream(self.challenge.clone()).await?;
+            stream.shutdown().await?;
+            return Ok(None);
+        }
+
+        Ok(Some(start.into_stream(self.tls.clone()).await?))
+    }
+}
+
+/// A [`HandshakeListener`] that serves TLS with the certificates provisioned by ACME. TLS
+/// handshakes run in their own tasks, so a slow one doesn't hold up accepting other connections.
+pub struct AcmeListener {
+    listener: HandshakeListener,
+    tls: AcmeTls,
+    handshakes: JoinSet<Option<(TlsStream<HandshakeStream>, SocketAddr)>>,
+}
+
+impl AcmeListener {
+    pub fn new(listener: HandshakeListener, tls: AcmeTls) -> Self {
+        Self {
+            listener,
+            tls,
+            handshakes: JoinSet::new(),
+        }
+    }
+}
+
+impl Listener for AcmeListener {
+    type Io = TlsStream<HandshakeStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        loop {
+            tokio::select! {
+                (stream, addr) = self.listener.accept() => {
+                    let tls = self.tls.clone();
+                    // Failed handshakes are the client's problem, as with plaintext connections
+                    // that never send a request
+                    self.handshakes.spawn(async move {
+                        let stream = tls.accept(stream).await.ok()??;
+                        Some((stream, addr))
+                    });
+                }
+                Some(handshake) = self.handshakes.join_next() => {
+                    if let Ok(Some(accepted)) = handshake {
+                        return accepted;
+                    }
+                }
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.listener.local_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(domains: &[&str], cache_dir: Option<&str>, contacts: &[&str]) -> AcmeArgs {
+        AcmeArgs {
+            acme_domains: domains.iter().map(|domain| domain.to_string()).collect(),
+            acme_cache_dir: cache_dir.map(PathBuf::from),
+            acme_contacts: contacts.iter().map(|contact| contact.to_string()).collect(),
+            acme_directory: LETS_ENCRYPT.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_problems() {
+        assert!(args(&[], None, &[]).problems().is_empty());
+        assert!(args(&[], None, &[]).config().is_none());
+
+        let enabled = args(&["proxy.example.com"], Some("/var/cache/acme"), &[]);
+        assert!(enabled.problems().is_empty());
+        assert_eq!(enabled.config().unwrap().domains, ["proxy.example.com"]);
+
+        assert_eq!(
+            args(&["proxy.example.com"], None, &[]).problems(),
+            ["--acme-domain requires --acme-cache-dir"]
+        );
+        assert_eq!(
+            args(&[], Some("/var/cache/acme"), &[]).problems(),
+            ["--acme-cache-dir requires --acme-domain"]
+        );
+        assert_eq!(
+            args(&[], None, &["mailto:ops@example.com"]).problems(),
+            ["--acme-contact requires --acme-domain"]
+        );
+    }
+}
@@ -0,0 +1,443 @@
+//! An authenticated admin API on `/admin`, for operators to tune the proxy during an incident
+//! without restarting it and disconnecting every client.
+//!
+//! `GET /admin/settings` returns the current settings and `PATCH /admin/settings` changes any of
+//! them, e.g. `{"message_buffer_size": 200, "ping_interval_ms": 0}`. Durations are in
+//! milliseconds, and 0 turns the setting off as with the corresponding flags. Client settings
+//! apply to the default stream's clients that connect after the change.
+//!
+//! With automatic bans enabled, `GET /admin/bans` lists the addresses currently banned,
+//! `DELETE /admin/bans/{addr}` lifts one address's ban and `DELETE /admin/bans` lifts them all. See
+//! [`crate::ban`].
+
+use crate::auth::constant_time_eq;
+use crate::ban::BanList;
+use crate::client::HeartbeatConfig;
+use crate::load_shedding::{LoadShedConfig, LoadShedder};
+use crate::registry::{ClientSettings, Registry};
+use axum::body::Bytes;
+use axum::extract::{Path, State};
+use axum::http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get};
+use axum::{Json, Router};
+use serde_json::{json, Map, Value};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+/// Pong timeout used when pings are turned on without one.
+const DEFAULT_PONG_TIMEOUT: Duration = Duration::from_millis(5000);
+const DEFAULT_MAX_MISSED_PONGS: u32 = 2;
+
+#[derive(Clone)]
+struct AdminState {
+    token: Arc<String>,
+    registry: Registry,
+    load_shedder: Option<Arc<LoadShedder>>,
+    bans: Option<Arc<BanList>>,
+}
+
+/// The admin routes, authenticated with `token` as a bearer token, to be nested at `/admin`.
+pub fn router(
+    token: String,
+    registry: Registry,
+    load_shedder: Option<Arc<LoadShedder>>,
+    bans: Option<Arc<BanList>>,
+) -> Router {
+    let router = Router::new().route("/settings", get(get_settings).patch(patch_settings));
+    let router = match bans {
+        Some(_) => router
+            .route("/bans", get(get_bans).delete(clear_bans))
+            .route("/bans/{addr}", delete(unban)),
+        None => router,
+    };
+
+    router.with_state(AdminState {
+        token: Arc::new(token),
+        registry,
+        load_shedder,
+        bans,
+    })
+}
+
+fn authorized(state: &AdminState, headers: &HeaderMap) -> bool {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| constant_time_eq(token.as_bytes(), state.token.as_bytes()))
+}
+
+fn unauthorized() -> Response {
+    (StatusCode::UNAUTHORIZED, [(WWW_AUTHENTICATE, "Bearer")]).into_response()
+}
+
+fn bad_request(message: String) -> Response {
+    (StatusCode::BAD_REQUEST, Json(json!({"message": message}))).into_response()
+}
+
+async fn get_settings(State(state): State<AdminState>, headers: HeaderMap) -> Response {
+    if !authorized(&state, &headers) {
+        return unauthorized();
+    }
+    Json(settings_json(&state)).into_response()
+}
+
+async fn patch_settings(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    if !authorized(&state, &headers) {
+        return unauthorized();
+    }
+
+    let changes = match serde_json::from_slice::<Value>(&body) {
+        Ok(Value::Object(changes)) => changes,
+        Ok(_) => return bad_request("expected a JSON object".to_string()),
+        Err(e) => return bad_request(format!("invalid JSON: {e}")),
+    };
+
+    let mut settings = state.registry.settings();
+    let mut load_shed = state.load_shedder.as_ref().map(|shedder| shedder.config());
+    if let Err(message) = apply(&changes, &mut settings, load_shed.as_mut()) {
+        return bad_request(message);
+    }
+
+    state.registry.set_settings(settings);
+    if let (Some(shedder), Some(config)) = (&state.load_shedder, load_shed) {
+        shedder.set_config(config);
+    }
+
+    let changes = Value::Object(changes);
+    let settings = settings_json(&state);
+    warn!(
+        message = "settings changed through the admin API",
+        changes = %changes,
+        settings = %settings
+    );
+    Json(settings).into_response()
+}
+
+async fn get_bans(State(state): State<AdminState>, headers: HeaderMap) -> Response {
+    if !authorized(&state, &headers) {
+        return unauthorized();
+    }
+
+    let bans = state
+        .bans
+        .as_ref()
+        .map(|bans| bans.bans())
+        .unwrap_or_default();
+    let bans: Vec<Value> = bans
+        .iter()
+        .map(|ban| {
+            json!({
+                "addr": ban.addr.to_string(),
+                "remaining_secs": ban.remaining.as_secs(),
+                "bans": ban.bans,
+            })
+        })
+        .collect();
+    Json(json!({ "bans": bans })).into_response()
+}
+
+async fn clear_bans(State(state): State<AdminState>, headers: HeaderMap) -> Response {
+    if !authorized(&state, &headers) {
+        return unauthorized();
+    }
+
+    let cleared = state.bans.as_ref().map_or(0, |bans| bans.clear());
+    warn!(
+        message = "bans cleared through the admin API",
+        cleared = cleared
+    );
+    Json(json!({ "cleared": cleared })).into_response()
+}
+
+async fn unban(
+    State(state): State<AdminState>,
+    Path(addr): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if !authorized(&state, &headers) {
+        return unauthorized();
+    }
+
+    let addr = match addr.parse::<IpAddr>() {
+        Ok(addr) => addr,
+        Err(e) => return bad_request(format!("invalid address {addr}: {e}")),
+    };
+    let cleared = state.bans.as_ref().is_some_and(|bans| bans.unban(addr));
+    warn!(
+        message = "ban lifted through the admin API",
+        client = addr.to_string(),
+        was_banned = cleared
+    );
+    Json(json!({ "cleared": usize::from(cleared) })).into_response()
+}
+
+fn settings_json(state: &AdminState) -> Value {
+    let settings = state.registry.settings();
+    let mut json = json!({
+        "message_buffer_size": settings.buffer_size,
+        "message_ttl_ms": millis(settings.message_ttl),
+        "ping_interval_ms": millis(settings.heartbeat.map(|heartbeat| heartbeat.ping_interval)),
+        "pong_timeout_ms": settings.heartbeat.map(|heartbeat| heartbeat.pong_timeout.as_millis() as u64),
+        "max_missed_pongs": settings.heartbeat.map(|heartbeat| heartbeat.max_missed_pongs),
+    });
+
+    if let Some(shedder) = &state.load_shedder {
+        let config = shedder.config();
+        json["load_shed_max_lag_ms"] = json!(millis(config.max_lag));
+        json["load_shed_max_queue_fraction"] = json!(config.max_queue_fraction.unwrap_or(0.0));
+    }
+    json
+}
+
+fn millis(duration: Option<Duration>) -> u64 {
+    duration.map_or(0, |duration| duration.as_millis() as u64)
+}
+
+/// Applies `changes` to the settings, which are left partly changed on error, so callers should
+/// pass copies. The load shedding thresholds can only be changed when a load shedder is running.
+fn apply(
+    changes: &Map<String, Value>,
+    settings: &mut ClientSettings,
+    mut load_shed: Option<&mut LoadShedConfig>,
+) -> Result<(), String> {
+    let mut ping_interval = settings.heartbeat.map(|heartbeat| heartbeat.ping_interval);
+    let mut pong_timeout = settings
+        .heartbeat
+        .map_or(DEFAULT_PONG_TIMEOUT, |heartbeat| heartbeat.pong_timeout);
+    let mut max_missed_pongs = settings
+        .heartbeat
+        .map_or(DEFAULT_MAX_MISSED_PONGS, |heartbeat| {
+            heartbeat.max_missed_pongs
+        });
+
+    for (key, value) in changes {
+        let integer = || {
+            value
+                .as_u64()
+                .ok_or_else(|| format!("{key} must be a non-negative integer"))
+        };
+        let duration = || integer().map(|ms| (ms > 0).then(|| Duration::from_millis(ms)));
+
+        match key.as_str() {
+            "message_buffer_size" => match integer()? {
+                0 => return Err(format!("{key} must be at least 1")),
+                size => settings.buffer_size = size as usize,
+            },
+            "message_ttl_ms" => settings.message_ttl = duration()?,
+            "ping_interval_ms" => ping_interval = duration()?,
+            "pong_timeout_ms" => {
+                pong_timeout = duration()?.ok_or_else(|| format!("{key} must be at least 1"))?
+            }
+            "max_missed_pongs" => {
+                max_missed_pongs =
+                    u32::try_from(integer()?).map_err(|_| format!("{key} must fit in 32 bits"))?
+            }
+            "load_shed_max_lag_ms" | "load_shed_max_queue_fraction" => {
+                let Some(load_shed) = load_shed.as_deref_mut() else {
+                    return Err(format!(
+                        "{key} needs load shedding to be enabled at startup"
+                    ));
+                };
+                if key == "load_shed_max_lag_ms" {
+                    load_shed.max_lag = duration()?;
+                } else {
+                    let fraction = value
+                        .as_f64()
+                        .filter(|fraction| (0.0..=1.0).contains(fraction))
+                        .ok_or_else(|| format!("{key} must be between 0 and 1"))?;
+                    load_shed.max_queue_fraction = (fraction > 0.0).then_some(fraction);
+                }
+            }
+            key => return Err(format!("unknown setting {key}")),
+        }
+    }
+
+    settings.heartbeat = match ping_interval {
+        Some(ping_interval) if pong_timeout > ping_interval => {
+            return Err(format!(
+                "pong_timeout_ms {} must be at most ping_interval_ms {}",
+                pong_timeout.as_millis(),
+                ping_interval.as_millis()
+            ))
+        }
+        Some(ping_interval) => Some(HeartbeatConfig {
+            ping_interval,
+            pong_timeout,
+            max_missed_pongs,
+        }),
+        None => None,
+    };
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ban::BanConfig;
+    use crate::metrics::Metrics;
+    use reqwest::Method;
+
+    async fn serve(router: Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        format!("http://{addr}/settings")
+    }
+
+    async fn send(url: &str, method: Method, token: &str, body: &str) -> (StatusCode, Value) {
+        let response = reqwest::Client::new()
+            .request(method, url)
+            .bearer_auth(token)
+            .body(body.to_string())
+            .send()
+            .await
+            .unwrap();
+        let status = response.status();
+        (status, response.json().await.unwrap_or(Value::Null))
+    }
+
+    #[tokio::test]
+    async fn test_settings() {
+        let registry = Registry::new(10, 1, Arc::new(Metrics::default()));
+        let shedder = Arc::new(LoadShedder::new(LoadShedConfig {
+            max_lag: Some(Duration::from_millis(500)),
+            max_queue_fraction: None,
+            sustained_checks: 3,
+            lag_drop_fraction: 0.1,
+        }));
+        let url = serve(router(
+            "secret".to_string(),
+            registry.clone(),
+            Some(shedder.clone()),
+            None,
+        ))
+        .await;
+
+        let (status, _) = send(&url, Method::GET, "wrong", "").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, settings) = send(&url, Method::GET, "secret", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            settings,
+            json!({
+                "message_buffer_size": 10,
+                "message_ttl_ms": 0,
+                "ping_interval_ms": 0,
+                "pong_timeout_ms": null,
+                "max_missed_pongs": null,
+                "load_shed_max_lag_ms": 500,
+                "load_shed_max_queue_fraction": 0.0,
+            })
+        );
+
+        let (status, settings) = send(
+            &url,
+            Method::PATCH,
+            "secret",
+            r#"{"message_buffer_size": 50, "ping_interval_ms": 10000, "load_shed_max_lag_ms": 0}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(settings["message_buffer_size"], 50);
+        assert_eq!(settings["pong_timeout_ms"], 5000);
+        assert_eq!(
+            registry.settings().heartbeat.unwrap().ping_interval,
+            Duration::from_secs(10)
+        );
+        assert_eq!(shedder.config().max_lag, None);
+
+        // Clients that connect afterwards get the new buffer size
+        let _subscription = registry.register();
+        for i in 0..30 {
+            registry.publish(Bytes::from(format!("{i}")));
+        }
+        assert_eq!(registry.queue_occupancy().max_fraction, 0.6);
+
+        // Invalid changes are rejected without applying any of them
+        for invalid in [
+            r#"{"message_buffer_size": 0}"#,
+            r#"{"message_buffer_size": 20, "colour": "blue"}"#,
+            r#"{"pong_timeout_ms": 20000}"#,
+            r#"{"load_shed_max_queue_fraction": 1.5}"#,
+            r#"{"message_ttl_ms": -1}"#,
+            "[]",
+            "{",
+        ] {
+            let (status, response) = send(&url, Method::PATCH, "secret", invalid).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{invalid}");
+            assert!(response["message"].is_string(), "{invalid}");
+        }
+        assert_eq!(registry.buffer_size(), 50);
+
+        // The load shedding thresholds can't be changed without a load shedder
+        let url = serve(router("secret".to_string(), registry, None, None)).await;
+        let (status, settings) = send(&url, Method::GET, "secret", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(settings.get("load_shed_max_lag_ms").is_none());
+        let (status, _) = send(
+            &url,
+            Method::PATCH,
+            "secret",
+            r#"{"load_shed_max_lag_ms": 100}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_bans() {
+        let registry = Registry::new(10, 1, Arc::new(Metrics::default()));
+        let bans = Arc::new(BanList::new(BanConfig {
+            threshold: 1,
+            window: Duration::from_secs(60),
+            duration: Duration::from_secs(600),
+            max_duration: Duration::from_secs(600),
+        }));
+        let url = serve(router(
+            "secret".to_string(),
+            registry,
+            None,
+            Some(bans.clone()),
+        ))
+        .await
+        .replace("/settings", "/bans");
+
+        let addr: IpAddr = "10.0.0.1".parse().unwrap();
+        bans.record_refusal(addr);
+        bans.record_refusal("10.0.0.2".parse().unwrap());
+
+        let (status, _) = send(&url, Method::GET, "wrong", "").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, response) = send(&url, Method::GET, "secret", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response["bans"][0]["addr"], "10.0.0.1");
+        assert_eq!(response["bans"][0]["bans"], 1);
+        assert_eq!(response["bans"].as_array().unwrap().len(), 2);
+
+        let (status, response) =
+            send(&format!("{url}/10.0.0.1"), Method::DELETE, "secret", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response, json!({"cleared": 1}));
+        assert_eq!(bans.banned(addr), None);
+
+        let (status, _) = send(&format!("{url}/nowhere"), Method::DELETE, "secret", "").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, response) = send(&url, Method::DELETE, "secret", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response, json!({"cleared": 1}));
+        assert!(bans.bans().is_empty());
+    }
+}
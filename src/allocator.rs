@@ -0,0 +1,68 @@
+use crate::metrics::Metrics;
+
+// Payload-heavy fan-out fragments the system allocator, so a different global allocator can be
+// linked in with the `jemalloc` or `mimalloc` features. If both are enabled, jemalloc is used.
+
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+#[global_allocator]
+static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
+
+#[cfg(feature = "jemalloc")]
+pub const NAME: &str = "jemalloc";
+
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+pub const NAME: &str = "mimalloc";
+
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+pub const NAME: &str = "system";
+
+/// Exports the allocator's own accounting of the memory it manages. Does nothing with the system
+/// allocator.
+#[cfg(feature = "jemalloc")]
+pub fn report(metrics: &Metrics) {
+    use tikv_jemalloc_ctl::{epoch, stats};
+
+    // jemalloc caches its statistics until the epoch is advanced.
+    if epoch::advance().is_err() {
+        return;
+    }
+
+    if let Ok(allocated) = stats::allocated::read() {
+        metrics.allocator_allocated_bytes.set(allocated as f64);
+    }
+    if let Ok(resident) = stats::resident::read() {
+        metrics.allocator_resident_bytes.set(resident as f64);
+    }
+}
+
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+pub fn report(metrics: &Metrics) {
+    use std::ptr::null_mut;
+
+    let mut current_rss = 0;
+    let mut current_commit = 0;
+
+    // SAFETY: every out-param is either null or points to a valid usize.
+    unsafe {
+        libmimalloc_sys::mi_process_info(
+            null_mut(),
+            null_mut(),
+            null_mut(),
+            &mut current_rss,
+            null_mut(),
+            &mut current_commit,
+            null_mut(),
+            null_mut(),
+        );
+    }
+
+    metrics.allocator_allocated_bytes.set(current_commit as f64);
+    metrics.allocator_resident_bytes.set(current_rss as f64);
+}
+
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+pub fn report(_metrics: &Metrics) {}
@@ -0,0 +1,61 @@
+use crate::client::ClientConnection;
+use crate::metrics::DisconnectReason;
+use std::net::IpAddr;
+use tracing::info;
+
+/// Target used for connection audit events, so they can be routed separately from application logs.
+pub const AUDIT_TARGET: &str = "audit";
+
+pub fn client_connected(connection_id: u64, client: &ClientConnection) {
+    info!(
+        target: AUDIT_TARGET,
+        message = "client connected",
+        event = "connect",
+        connection_id = connection_id,
+        client = client.id(),
+        client_name = client.labels().name,
+        client_version = client.labels().version,
+        country = client.location().country,
+        asn = client.location().asn,
+        as_org = client.location().as_org,
+    );
+}
+
+pub fn client_disconnected(
+    connection_id: u64,
+    client: &ClientConnection,
+    reason: DisconnectReason,
+) {
+    let stats = client.stats();
+
+    info!(
+        target: AUDIT_TARGET,
+        message = "client disconnected",
+        event = "disconnect",
+        connection_id = connection_id,
+        client = client.id(),
+        client_name = client.labels().name,
+        client_version = client.labels().version,
+        country = client.location().country,
+        asn = client.location().asn,
+        as_org = client.location().as_org,
+        duration_ms = client.connected_for().as_millis() as u64,
+        messages_sent = stats.messages_sent,
+        messages_dropped = stats.messages_dropped,
+        bytes_sent = stats.bytes_sent,
+        reason = reason.as_str(),
+    );
+}
+
+/// A client refused because it failed authentication or authorization: `reason` is the check it
+/// failed, one of `origin`, `geo_policy`, `basic_auth`, `authorizer` or `relay_token`.
+pub fn auth_failed(client: IpAddr, stream: Option<&str>, reason: &'static str) {
+    info!(
+        target: AUDIT_TARGET,
+        message = "client refused",
+        event = "auth_failure",
+        client = client.to_string(),
+        stream = stream,
+        reason = reason,
+    );
+}
@@ -0,0 +1,106 @@
+//! HTTP Basic authentication of websocket clients, for tooling that can't do anything else on the
+//! handshake.
+
+use axum::http::header::AUTHORIZATION;
+use axum::http::HeaderMap;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+/// Username and password pairs that clients may authenticate with.
+#[derive(Clone, Debug, Default)]
+pub struct BasicAuth {
+    credentials: HashMap<String, String>,
+}
+
+impl BasicAuth {
+    /// Reads `username:password` pairs from `path`, one per line. Blank lines and lines starting
+    /// with `#` are ignored.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut credentials = HashMap::new();
+
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (username, password) = line
+                .split_once(':')
+                .filter(|(username, password)| !username.is_empty() && !password.is_empty())
+                .ok_or_else(|| format!("line {}: expected username:password", number + 1))?;
+            if credentials
+                .insert(username.to_string(), password.to_string())
+                .is_some()
+            {
+                return Err(format!("line {}: duplicate user {username}", number + 1));
+            }
+        }
+
+        if credentials.is_empty() {
+            return Err("no credentials".to_string());
+        }
+        Ok(Self { credentials })
+    }
+
+    /// The user the request authenticates as with `Authorization: Basic`, if any.
+    pub fn authenticate(&self, headers: &HeaderMap) -> Option<&str> {
+        let encoded = headers
+            .get(AUTHORIZATION)?
+            .to_str()
+            .ok()?
+            .strip_prefix("Basic ")?;
+        let decoded = String::from_utf8(BASE64_STANDARD.decode(encoded.trim()).ok()?).ok()?;
+        let (username, password) = decoded.split_once(':')?;
+
+        let (username, expected) = self.credentials.get_key_value(username)?;
+        constant_time_eq(password.as_bytes(), expected.as_bytes()).then_some(username.as_str())
+    }
+}
+
+/// Compares secrets without exiting early on the first difference, so that the time taken doesn't
+/// reveal how much of a guess was right.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_basic_auth() {
+        let auth = BasicAuth::parse("# legacy tooling\nops:hunter2\n\nci:s3cr:et\n").unwrap();
+        let headers = |credentials: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                AUTHORIZATION,
+                format!("Basic {}", BASE64_STANDARD.encode(credentials))
+                    .parse()
+                    .unwrap(),
+            );
+            headers
+        };
+
+        assert_eq!(auth.authenticate(&headers("ops:hunter2")), Some("ops"));
+        assert_eq!(auth.authenticate(&headers("ci:s3cr:et")), Some("ci"));
+        assert_eq!(auth.authenticate(&headers("ops:hunter3")), None);
+        assert_eq!(auth.authenticate(&headers("root:hunter2")), None);
+        assert_eq!(auth.authenticate(&HeaderMap::new()), None);
+
+        let mut bearer = HeaderMap::new();
+        bearer.insert(AUTHORIZATION, "Bearer hunter2".parse().unwrap());
+        assert_eq!(auth.authenticate(&bearer), None);
+
+        assert!(BasicAuth::parse("").is_err());
+        assert!(BasicAuth::parse("ops").is_err());
+        assert!(BasicAuth::parse("ops:").is_err());
+        assert!(BasicAuth::parse("ops:a\nops:b").is_err());
+    }
+}
@@ -0,0 +1,271 @@
+//! Authorization of websocket clients by an external service, so that entitlement logic can live
+//! outside the proxy. For each handshake the proxy POSTs a JSON description of the client:
+//!
+//! ```json
+//! {"ip": "203.0.113.7", "origin": "https://app.example.com", "stream": null, "headers": {"authorization": "Bearer ..."}}
+//! ```
+//!
+//! `stream` is the named stream requested, or null for `/ws`, and `headers` holds whichever of
+//! [`FORWARDED_HEADERS`] the client sent. The service answers `{"allow": true}` or
+//! `{"allow": false}`; any other response, including a non-2xx status, is an error. An allowed
+//! client can also be given priority with `{"allow": true, "priority": true}`, see
+//! [`crate::priority`]. Decisions are cached for identical requests.
+
+use crate::client::{CLIENT_NAME_HEADER, CLIENT_VERSION_HEADER};
+use axum::http::header::ORIGIN;
+use axum::http::HeaderMap;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Handshake headers passed on to the authorization service.
+pub const FORWARDED_HEADERS: [&str; 4] = [
+    "authorization",
+    "user-agent",
+    CLIENT_NAME_HEADER,
+    CLIENT_VERSION_HEADER,
+];
+
+/// Most decisions cached at once. Expired decisions are dropped when the cache fills, and if it
+/// is still full, all of them are.
+const MAX_CACHED_DECISIONS: usize = 10_000;
+
+#[derive(Clone, Debug)]
+pub struct AuthorizerConfig {
+    pub url: String,
+    /// How long to wait for the service before treating the request as failed.
+    pub timeout: Duration,
+    /// How long to reuse a decision for identical requests. Zero disables caching.
+    pub cache_ttl: Duration,
+    /// Admit clients when the service fails, instead of refusing them.
+    pub fail_open: bool,
+}
+
+/// The outcome of asking the authorization service about a client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Decision {
+    Allow,
+    /// Allowed, and admitted ahead of clients without priority. See [`crate::priority`].
+    Priority,
+    Deny,
+    /// The service couldn't be asked or gave an invalid answer, and the policy is to fail open.
+    FailedOpen,
+    /// The service couldn't be asked or gave an invalid answer, and the policy is to fail closed.
+    FailedClosed,
+}
+
+impl Decision {
+    pub fn allows(&self) -> bool {
+        matches!(
+            self,
+            Decision::Allow | Decision::Priority | Decision::FailedOpen
+        )
+    }
+}
+
+pub struct Authorizer {
+    config: AuthorizerConfig,
+    client: reqwest::Client,
+    cache: Mutex<HashMap<String, (Decision, Instant)>>,
+}
+
+impl Authorizer {
+    pub fn new(config: AuthorizerConfig) -> Self {
+        Self {
+            config,
+            client: reqwest::Client::new(),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Asks the service whether the client at `ip` may connect to `stream` (`None` for `/ws`)
+    /// with the handshake `headers`.
+    pub async fn authorize(
+        &self,
+        ip: IpAddr,
+        stream: Option<&str>,
+        headers: &HeaderMap,
+    ) -> Decision {
+        let forwarded: Map<_, _> = FORWARDED_HEADERS
+            .iter()
+            .filter_map(|&name| {
+                let value = headers.get(name)?.to_str().ok()?;
+                Some((name.to_string(), Value::String(value.to_string())))
+            })
+            .collect();
+        let origin = headers.get(ORIGIN).and_then(|value| value.to_str().ok());
+        let request = json!({
+            "ip": ip.to_string(),
+            "origin": origin,
+            "stream": stream,
+            "headers": forwarded,
+        })
+        .to_string();
+
+        if let Some(decision) = self.cached(&request) {
+            return decision;
+        }
+
+        match self.ask(request.clone()).await {
+            Ok(decision) => {
+                self.cache(request, decision);
+                decision
+            }
+            Err(e) => {
+                warn!(message = "authorization service failed", error = e);
+                if self.config.fail_open {
+                    Decision::FailedOpen
+                } else {
+                    Decision::FailedClosed
+                }
+            }
+        }
+    }
+
+    async fn ask(&self, request: String) -> Result<Decision, String> {
+        let response = self
+            .client
+            .post(&self.config.url)
+            .header("content-type", "application/json")
+            .body(request)
+            .timeout(self.config.timeout)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| e.to_string())?;
+        let body: Value = response.json().await.map_err(|e| e.to_string())?;
+
+        let allow = body["allow"]
+            .as_bool()
+            .ok_or_else(|| format!("expected {{\"allow\": bool}}, got {body}"))?;
+        Ok(match (allow, body["priority"].as_bool()) {
+            (false, _) => Decision::Deny,
+            (true, Some(true)) => Decision::Priority,
+            (true, _) => Decision::Allow,
+        })
+    }
+
+    fn cached(&self, request: &str) -> Option<Decision> {
+        let cache = self.cache.lock().unwrap();
+        let &(decision, cached_at) = cache.get(request)?;
+        (cached_at.elapsed() < self.config.cache_ttl).then_some(decision)
+    }
+
+    fn cache(&self, request: String, decision: Decision) {
+        if self.config.cache_ttl.is_zero() {
+            return;
+        }
+
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= MAX_CACHED_DECISIONS {
+            let ttl = self.config.cache_ttl;
+            cache.retain(|_, (_, cached_at)| cached_at.elapsed() < ttl);
+            if cache.len() >= MAX_CACHED_DECISIONS {
+                cache.clear();
+            }
+        }
+        cache.insert(request, (decision, Instant::now()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::header::AUTHORIZATION;
+    use axum::{Json, Router};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_authorize() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let requests_clone = requests.clone();
+        let router = Router::new().fallback(async move |Json(request): Json<Value>| {
+            requests_clone.fetch_add(1, Ordering::Relaxed);
+            let authorization = &request["headers"]["authorization"];
+            let allow = (authorization == "Bearer good" || authorization == "Bearer paid")
+                && request["stream"].is_null();
+            Json(json!({"allow": allow, "priority": authorization == "Bearer paid"}))
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let config = AuthorizerConfig {
+            url: format!("http://{addr}/authorize"),
+            timeout: Duration::from_secs(5),
+            cache_ttl: Duration::from_secs(60),
+            fail_open: false,
+        };
+        let authorizer = Authorizer::new(config.clone());
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        let headers = |authorization: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(AUTHORIZATION, authorization.parse().unwrap());
+            headers
+        };
+
+        assert_eq!(
+            authorizer
+                .authorize(ip, None, &headers("Bearer good"))
+                .await,
+            Decision::Allow
+        );
+        assert_eq!(
+            authorizer.authorize(ip, None, &headers("Bearer bad")).await,
+            Decision::Deny
+        );
+        assert_eq!(
+            authorizer
+                .authorize(ip, Some("raw"), &headers("Bearer good"))
+                .await,
+            Decision::Deny
+        );
+        assert_eq!(
+            authorizer
+                .authorize(ip, None, &headers("Bearer paid"))
+                .await,
+            Decision::Priority
+        );
+        assert_eq!(requests.load(Ordering::Relaxed), 4);
+
+        // Repeated requests are answered from the cache
+        assert_eq!(
+            authorizer
+                .authorize(ip, None, &headers("Bearer good"))
+                .await,
+            Decision::Allow
+        );
+        assert_eq!(
+            authorizer.authorize(ip, None, &headers("Bearer bad")).await,
+            Decision::Deny
+        );
+        assert_eq!(
+            authorizer
+                .authorize(ip, None, &headers("Bearer paid"))
+                .await,
+            Decision::Priority
+        );
+        assert_eq!(requests.load(Ordering::Relaxed), 4);
+
+        let unreachable = AuthorizerConfig {
+            url: "http://127.0.0.1:1/authorize".to_string(),
+            ..config
+        };
+        let authorizer = Authorizer::new(unreachable.clone());
+        let decision = authorizer.authorize(ip, None, &HeaderMap::new()).await;
+        assert_eq!(decision, Decision::FailedClosed);
+        assert!(!decision.allows());
+
+        let authorizer = Authorizer::new(AuthorizerConfig {
+            fail_open: true,
+            ..unreachable
+        });
+        let decision = authorizer.authorize(ip, None, &HeaderMap::new()).await;
+        assert_eq!(decision, Decision::FailedOpen);
+        assert!(decision.allows());
+    }
+}
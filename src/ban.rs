@@ -0,0 +1,262 @@
+//! Automatic bans for addresses that keep getting refused, so that a client retrying a rejected
+//! handshake in a tight loop is turned away before authentication and rate limiting instead of
+//! costing a check each time.
+//!
+//! Each `401` and `429` refusal of an address counts towards banning it. Once an address has been
+//! refused [`BanConfig::threshold`] times within [`BanConfig::window`] it's banned for
+//! [`BanConfig::duration`], doubling with each ban after that up to [`BanConfig::max_duration`].
+//! An address that hasn't been refused for `max_duration` since its last ban ended starts over.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::warn;
+
+/// Fewest tracked addresses before expired ones are pruned.
+const MIN_PRUNE_LEN: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BanConfig {
+    /// Refusals within `window` that get an address banned.
+    pub threshold: u32,
+    pub window: Duration,
+    /// How long the first ban lasts.
+    pub duration: Duration,
+    /// Longest a ban may last, however often the address has been banned.
+    pub max_duration: Duration,
+}
+
+/// An address currently banned.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Ban {
+    pub addr: IpAddr,
+    pub remaining: Duration,
+    /// How many times the address has been banned, including this ban.
+    pub bans: u32,
+}
+
+#[derive(Debug)]
+struct Offender {
+    window_start: Instant,
+    refusals: u32,
+    banned_until: Option<Instant>,
+    bans: u32,
+    last_refused: Instant,
+}
+
+impl Offender {
+    /// Whether there's nothing left to remember about the address at `now`.
+    fn forgotten(&self, now: Instant, config: &BanConfig) -> bool {
+        let last_seen = self
+            .banned_until
+            .map_or(self.last_refused, |until| until.max(self.last_refused));
+        now >= last_seen + config.window.max(config.max_duration)
+    }
+}
+
+#[derive(Debug)]
+struct Offenders {
+    by_addr: HashMap<IpAddr, Offender>,
+    prune_at: usize,
+}
+
+#[derive(Debug)]
+pub struct BanList {
+    config: BanConfig,
+    offenders: Mutex<Offenders>,
+}
+
+impl BanList {
+    pub fn new(config: BanConfig) -> Self {
+        Self {
+            config,
+            offenders: Mutex::new(Offenders {
+                by_addr: HashMap::new(),
+                prune_at: MIN_PRUNE_LEN,
+            }),
+        }
+    }
+
+    /// How much longer `addr` is banned for, if it is.
+    pub fn banned(&self, addr: IpAddr) -> Option<Duration> {
+        let now = Instant::now();
+        let offenders = self.offenders.lock().unwrap();
+        let until = offenders.by_addr.get(&addr)?.banned_until?;
+        (until > now).then(|| until - now)
+    }
+
+    /// Counts a refusal of `addr`, returning how long it's banned for if this refusal got it
+    /// banned.
+    pub fn record_refusal(&self, addr: IpAddr) -> Option<Duration> {
+        let now = Instant::now();
+        let mut offenders = self.offenders.lock().unwrap();
+
+        if offenders.by_addr.len() >= offenders.prune_at {
+            offenders
+                .by_addr
+                .retain(|_, offender| !offender.forgotten(now, &self.config));
+            offenders.prune_at = (offenders.by_addr.len() * 2).max(MIN_PRUNE_LEN);
+        }
+
+        let offender = offenders.by_addr.entry(addr).or_insert(Offender {
+            window_start: now,
+            refusals: 0,
+            banned_until: None,
+            bans: 0,
+            last_refused: now,
+        });
+        if offender.forgotten(now, &self.config) {
+            offender.bans = 0;
+        }
+        if now >= offender.window_start + self.config.window {
+            offender.window_start = now;
+            offender.refusals = 0;
+        }
+        offender.refusals += 1;
+        offender.last_refused = now;
+
+        if offender.refusals < self.config.threshold {
+            return None;
+        }
+
+        let duration = self
+            .config
+            .duration
+            .saturating_mul(2u32.saturating_pow(offender.bans))
+            .min(self.config.max_duration);
+        offender.bans += 1;
+        offender.refusals = 0;
+        offender.window_start = now;
+        offender.banned_until = Some(now + duration);
+
+        warn!(
+            message = "banning client after repeated refusals",
+            client = addr.to_string(),
+            duration_secs = duration.as_secs(),
+            bans = offender.bans
+        );
+        Some(duration)
+    }
+
+    /// Addresses currently banned.
+    pub fn bans(&self) -> Vec<Ban> {
+        let now = Instant::now();
+        let offenders = self.offenders.lock().unwrap();
+        let mut bans: Vec<Ban> = offenders
+            .by_addr
+            .iter()
+            .filter_map(|(addr, offender)| {
+                let until = offender.banned_until.filter(|until| *until > now)?;
+                Some(Ban {
+                    addr: *addr,
+                    remaining: until - now,
+                    bans: offender.bans,
+                })
+            })
+            .collect();
+        bans.sort_by_key(|ban| ban.addr);
+        bans
+    }
+
+    /// Lifts `addr`'s ban and forgets its refusals, returning whether it was banned.
+    pub fn unban(&self, addr: IpAddr) -> bool {
+        let now = Instant::now();
+        let removed = self.offenders.lock().unwrap().by_addr.remove(&addr);
+        removed
+            .and_then(|offender| offender.banned_until)
+            .is_some_and(|until| until > now)
+    }
+
+    /// Lifts every ban and forgets every refusal, returning how many addresses were banned.
+    pub fn clear(&self) -> usize {
+        let now = Instant::now();
+        let mut offenders = self.offenders.lock().unwrap();
+        let banned = offenders
+            .by_addr
+            .values()
+            .filter(|offender| offender.banned_until.is_some_and(|until| until > now))
+            .count();
+        offenders.by_addr.clear();
+        banned
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ban_list() -> BanList {
+        BanList::new(BanConfig {
+            threshold: 3,
+            window: Duration::from_secs(10),
+            duration: Duration::from_secs(60),
+            max_duration: Duration::from_secs(200),
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ban_escalation() {
+        let bans = ban_list();
+        let addr: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+
+        // Refusals spread over more than the window don't add up
+        bans.record_refusal(addr);
+        bans.record_refusal(addr);
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(bans.record_refusal(addr), None);
+        assert_eq!(bans.record_refusal(addr), None);
+        assert_eq!(bans.banned(addr), None);
+
+        assert_eq!(bans.record_refusal(addr), Some(Duration::from_secs(60)));
+        assert_eq!(bans.banned(addr), Some(Duration::from_secs(60)));
+        assert_eq!(bans.banned(other), None);
+        assert_eq!(
+            bans.bans(),
+            [Ban {
+                addr,
+                remaining: Duration::from_secs(60),
+                bans: 1,
+            }]
+        );
+
+        // Each ban lasts twice as long as the last, up to the maximum
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert_eq!(bans.banned(addr), None);
+        for _ in 0..3 {
+            bans.record_refusal(addr);
+        }
+        assert_eq!(bans.banned(addr), Some(Duration::from_secs(120)));
+        tokio::time::advance(Duration::from_secs(120)).await;
+        for _ in 0..3 {
+            bans.record_refusal(addr);
+        }
+        assert_eq!(bans.banned(addr), Some(Duration::from_secs(200)));
+
+        // Until the address has been quiet for the maximum after its ban ends
+        tokio::time::advance(Duration::from_secs(400)).await;
+        for _ in 0..3 {
+            bans.record_refusal(addr);
+        }
+        assert_eq!(bans.banned(addr), Some(Duration::from_secs(60)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_unban() {
+        let bans = ban_list();
+        let addr: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "::1".parse().unwrap();
+        for _ in 0..3 {
+            bans.record_refusal(addr);
+            bans.record_refusal(other);
+        }
+
+        assert!(bans.unban(addr));
+        assert!(!bans.unban(addr));
+        assert_eq!(bans.banned(addr), None);
+        assert_eq!(bans.clear(), 1);
+        assert!(bans.bans().is_empty());
+    }
+}
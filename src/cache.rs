@@ -0,0 +1,163 @@
+//! A cache of the flashblocks of the most recent blocks, for answering queries about them without
+//! holding a websocket, e.g. over [`crate::rpc`].
+
+use bytes::Bytes;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+pub struct FlashblockCache {
+    max_blocks: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Each block's flashblocks, ordered by index.
+    blocks: BTreeMap<u64, Vec<(u64, Bytes)>>,
+    latest: Option<Bytes>,
+    /// Payload bytes of the cached flashblocks.
+    bytes: usize,
+}
+
+impl FlashblockCache {
+    /// A cache of the flashblocks of the `max_blocks` highest block numbers seen.
+    pub fn new(max_blocks: usize) -> Self {
+        Self {
+            max_blocks: max_blocks.max(1),
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// Caches `payload` if it is a flashblock, i.e. JSON with an `index` and a
+    /// `metadata.block_number`, and ignores it otherwise. A flashblock with the same block number
+    /// and index as a cached one replaces it.
+    pub fn insert(&self, payload: &Bytes) {
+        let Ok(json) = serde_json::from_slice::<Value>(payload) else {
+            return;
+        };
+        let (Some(block_number), Some(index)) = (
+            json.pointer("/metadata/block_number")
+                .and_then(Value::as_u64),
+            json["index"].as_u64(),
+        ) else {
+            return;
+        };
+
+        let mut inner = self.inner.lock().unwrap();
+        if inner.blocks.len() >= self.max_blocks && !inner.blocks.contains_key(&block_number) {
+            if inner
+                .blocks
+                .first_key_value()
+                .is_some_and(|(&oldest, _)| block_number < oldest)
+            {
+                return;
+            }
+            inner.evict_oldest();
+        }
+
+        let flashblocks = inner.blocks.entry(block_number).or_default();
+        let replaced = match flashblocks.binary_search_by_key(&index, |&(index, _)| index) {
+            Ok(position) => std::mem::replace(&mut flashblocks[position].1, payload.clone()).len(),
+            Err(position) => {
+                flashblocks.insert(position, (index, payload.clone()));
+                0
+            }
+        };
+        inner.bytes = inner.bytes + payload.len() - replaced;
+        inner.latest = Some(payload.clone());
+    }
+
+    /// The most recently cached flashblock.
+    pub fn latest(&self) -> Option<Bytes> {
+        self.inner.lock().unwrap().latest.clone()
+    }
+
+    /// The highest block number cached.
+    pub fn latest_block_number(&self) -> Option<u64> {
+        let inner = self.inner.lock().unwrap();
+        inner.blocks.last_key_value().map(|(&number, _)| number)
+    }
+
+    /// Payload bytes of the cached flashblocks.
+    pub fn bytes(&self) -> usize {
+        self.inner.lock().unwrap().bytes
+    }
+
+    /// Evicts the lowest cached block, unless it is the only one, returning the payload bytes
+    /// freed.
+    pub fn evict_oldest(&self) -> Option<usize> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.blocks.len() < 2 {
+            return None;
+        }
+        Some(inner.evict_oldest())
+    }
+
+    /// The cached flashblocks of `block_number`, ordered by index.
+    pub fn block(&self, block_number: u64) -> Option<Vec<Bytes>> {
+        let inner = self.inner.lock().unwrap();
+        let flashblocks = inner.blocks.get(&block_number)?;
+        Some(
+            flashblocks
+                .iter()
+                .map(|(_, payload)| payload.clone())
+                .collect(),
+        )
+    }
+}
+
+impl Inner {
+    fn evict_oldest(&mut self) -> usize {
+        let freed = self.blocks.pop_first().map_or(0, |(_, flashblocks)| {
+            flashblocks.iter().map(|(_, payload)| payload.len()).sum()
+        });
+        self.bytes -= freed;
+        freed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flashblock(block_number: u64, index: u64) -> Bytes {
+        Bytes::from(format!(
+            r#"{{"index":{index},"metadata":{{"block_number":{block_number}}}}}"#
+        ))
+    }
+
+    #[test]
+    fn test_cache() {
+        let cache = FlashblockCache::new(2);
+        assert_eq!(cache.latest(), None);
+
+        cache.insert(&flashblock(10, 1));
+        cache.insert(&flashblock(10, 0));
+        cache.insert(&Bytes::from(r#"{"type":"proxy_status"}"#));
+        cache.insert(&Bytes::from("not json"));
+        assert_eq!(cache.latest(), Some(flashblock(10, 0)));
+        assert_eq!(
+            cache.block(10),
+            Some(vec![flashblock(10, 0), flashblock(10, 1)])
+        );
+
+        // Only the highest block numbers are kept
+        cache.insert(&flashblock(11, 0));
+        cache.insert(&flashblock(12, 0));
+        assert_eq!(cache.block(10), None);
+        assert_eq!(cache.block(11), Some(vec![flashblock(11, 0)]));
+        assert_eq!(cache.latest_block_number(), Some(12));
+
+        cache.insert(&flashblock(9, 0));
+        assert_eq!(cache.block(9), None);
+        assert_eq!(cache.latest(), Some(flashblock(12, 0)));
+
+        assert_eq!(cache.bytes(), 2 * flashblock(11, 0).len());
+        assert_eq!(cache.evict_oldest(), Some(flashblock(11, 0).len()));
+        assert_eq!(cache.block(11), None);
+        // The latest block is kept
+        assert_eq!(cache.evict_oldest(), None);
+        assert_eq!(cache.bytes(), flashblock(12, 0).len());
+    }
+}
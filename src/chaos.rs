@@ -0,0 +1,166 @@
+//! Fault injection on the upstream path, for exercising the proxy's and its clients' handling of
+//! a misbehaving upstream end-to-end. Only built with the `chaos` feature.
+
+use bytes::Bytes;
+use rand::Rng;
+use std::time::Duration;
+
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct ChaosArgs {
+    /// Probability of dropping the upstream connection after each message
+    #[arg(long, env, default_value = "0")]
+    pub chaos_disconnect_probability: f64,
+
+    /// Probability of delaying each upstream message by --chaos-latency-ms
+    #[arg(long, env, default_value = "0")]
+    pub chaos_latency_probability: f64,
+
+    /// Milliseconds to delay upstream messages chosen by --chaos-latency-probability
+    #[arg(long, env, default_value = "0")]
+    pub chaos_latency_ms: u64,
+
+    /// Probability of publishing each upstream message twice
+    #[arg(long, env, default_value = "0")]
+    pub chaos_duplicate_probability: f64,
+
+    /// Probability of flipping a byte in each upstream message
+    #[arg(long, env, default_value = "0")]
+    pub chaos_corrupt_probability: f64,
+}
+
+impl ChaosArgs {
+    pub fn enabled(&self) -> bool {
+        self.chaos_disconnect_probability > 0.0
+            || (self.chaos_latency_probability > 0.0 && self.chaos_latency_ms > 0)
+            || self.chaos_duplicate_probability > 0.0
+            || self.chaos_corrupt_probability > 0.0
+    }
+
+    /// The probability flags, for validation.
+    pub fn probabilities(&self) -> [(&'static str, f64); 4] {
+        [
+            (
+                "--chaos-disconnect-probability",
+                self.chaos_disconnect_probability,
+            ),
+            (
+                "--chaos-latency-probability",
+                self.chaos_latency_probability,
+            ),
+            (
+                "--chaos-duplicate-probability",
+                self.chaos_duplicate_probability,
+            ),
+            (
+                "--chaos-corrupt-probability",
+                self.chaos_corrupt_probability,
+            ),
+        ]
+    }
+}
+
+/// Messages to publish in place of one received from upstream.
+#[derive(Debug, PartialEq)]
+pub struct Injected {
+    pub messages: Vec<Bytes>,
+    /// Drop the upstream connection once the messages are published.
+    pub disconnect: bool,
+}
+
+/// Applies the configured faults to each message received from an upstream.
+#[derive(Clone, Debug)]
+pub struct Chaos {
+    args: ChaosArgs,
+}
+
+impl Chaos {
+    pub fn new(args: ChaosArgs) -> Self {
+        Self { args }
+    }
+
+    /// Applies faults to `payload`, after sleeping for any injected latency.
+    pub async fn inject(&self, payload: Bytes) -> Injected {
+        if chance(self.args.chaos_latency_probability) {
+            tokio::time::sleep(Duration::from_millis(self.args.chaos_latency_ms)).await;
+        }
+
+        let payload = if chance(self.args.chaos_corrupt_probability) {
+            corrupt(&payload)
+        } else {
+            payload
+        };
+
+        let messages = if chance(self.args.chaos_duplicate_probability) {
+            vec![payload.clone(), payload]
+        } else {
+            vec![payload]
+        };
+
+        Injected {
+            messages,
+            disconnect: chance(self.args.chaos_disconnect_probability),
+        }
+    }
+}
+
+fn chance(probability: f64) -> bool {
+    probability > 0.0 && rand::rng().random_bool(probability.min(1.0))
+}
+
+/// Copies `payload` with one byte inverted.
+fn corrupt(payload: &Bytes) -> Bytes {
+    if payload.is_empty() {
+        return payload.clone();
+    }
+
+    let mut corrupted = payload.to_vec();
+    let index = rand::rng().random_range(0..corrupted.len());
+    corrupted[index] = !corrupted[index];
+    Bytes::from(corrupted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_inject() {
+        let payload = Bytes::from_static(b"{\"index\":0}");
+
+        let chaos = Chaos::new(ChaosArgs::default());
+        assert_eq!(
+            chaos.inject(payload.clone()).await,
+            Injected {
+                messages: vec![payload.clone()],
+                disconnect: false,
+            }
+        );
+
+        let chaos = Chaos::new(ChaosArgs {
+            chaos_disconnect_probability: 1.0,
+            chaos_duplicate_probability: 1.0,
+            chaos_corrupt_probability: 1.0,
+            ..Default::default()
+        });
+        let injected = chaos.inject(payload.clone()).await;
+        assert!(injected.disconnect);
+        assert_eq!(injected.messages.len(), 2);
+        assert_eq!(injected.messages[0], injected.messages[1]);
+        assert_eq!(injected.messages[0].len(), payload.len());
+        assert_ne!(injected.messages[0], payload);
+    }
+
+    #[tokio::test]
+    async fn test_inject_latency() {
+        let chaos = Chaos::new(ChaosArgs {
+            chaos_latency_probability: 1.0,
+            chaos_latency_ms: 50,
+            ..Default::default()
+        });
+        assert!(chaos.args.enabled());
+
+        let started_at = tokio::time::Instant::now();
+        chaos.inject(Bytes::from_static(b"{}")).await;
+        assert!(started_at.elapsed() >= Duration::from_millis(50));
+    }
+}
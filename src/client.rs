@@ -1,11 +1,162 @@
+use crate::geoip::Location;
+use crate::hooks::ConnectionHooks;
+use crate::metrics::{DisconnectReason, Metrics};
 use crate::rate_limit::Ticket;
-use axum::extract::ws::WebSocket;
+use crate::registry::BroadcastMessage;
+use crate::sampling::{Sampler, Sampling};
+use crate::transform::{self, Transform};
+use crate::{envelope, relay};
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket};
+use axum::http::HeaderMap;
 use axum::Error;
+use futures::{SinkExt, StreamExt};
+use std::error::Error as _;
+use std::io::ErrorKind;
 use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_tungstenite::tungstenite;
+
+/// Totals for a single client connection, reported when the client disconnects.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ConnectionStats {
+    pub messages_sent: u64,
+    pub messages_dropped: u64,
+    pub bytes_sent: u64,
+}
+
+/// Header a client may send on the handshake to name the application or team it belongs to.
+pub const CLIENT_NAME_HEADER: &str = "x-client-name";
+
+/// Header a client may send on the handshake with its version, e.g. of the SDK it uses.
+pub const CLIENT_VERSION_HEADER: &str = "x-client-version";
+
+/// Longest client name or version kept; anything longer is truncated.
+const MAX_LABEL_LEN: usize = 64;
+
+/// What the client says it is, for attributing lagging connections to the consumer responsible.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub struct ClientLabels {
+    pub name: Option<String>,
+    pub version: Option<String>,
+}
+
+impl ClientLabels {
+    /// Reads the `X-Client-Name` and `X-Client-Version` headers, ignoring empty or non-ASCII
+    /// values.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let label = |header: &str| {
+            let value = headers.get(header)?.to_str().ok()?.trim();
+            (!value.is_empty()).then(|| value.chars().take(MAX_LABEL_LEN).collect())
+        };
+
+        Self {
+            name: label(CLIENT_NAME_HEADER),
+            version: label(CLIENT_VERSION_HEADER),
+        }
+    }
+}
+
+/// How often clients are pinged, and how many pongs they may miss before they are disconnected.
+#[derive(Clone, Copy, Debug)]
+pub struct HeartbeatConfig {
+    pub ping_interval: Duration,
+    /// How long after a ping its pong must arrive. At most `ping_interval`.
+    pub pong_timeout: Duration,
+    /// Consecutive pongs a client may miss before it is disconnected.
+    pub max_missed_pongs: u32,
+}
+
+/// What a client's heartbeat needs doing when [`Heartbeat::due`] is reached.
+#[derive(Debug, PartialEq, Eq)]
+pub enum HeartbeatAction {
+    /// Send the client a ping.
+    Ping,
+    /// The client has missed too many pongs and should be disconnected.
+    Evict,
+    Wait,
+}
+
+/// Tracks the pings sent to one client and the pongs received back.
+pub struct Heartbeat {
+    config: HeartbeatConfig,
+    next_ping: tokio::time::Instant,
+    ping_sent_at: Option<tokio::time::Instant>,
+    missed_pongs: u32,
+}
+
+impl Heartbeat {
+    pub fn new(config: HeartbeatConfig) -> Self {
+        Self {
+            config,
+            next_ping: tokio::time::Instant::now() + config.ping_interval,
+            ping_sent_at: None,
+            missed_pongs: 0,
+        }
+    }
+
+    /// When the heartbeat next needs attention: the pong deadline for an outstanding ping, or
+    /// else the next ping.
+    pub fn due(&self) -> tokio::time::Instant {
+        match self.ping_sent_at {
+            Some(sent_at) => sent_at + self.config.pong_timeout,
+            None => self.next_ping,
+        }
+    }
+
+    /// Called once [`Self::due`] is reached.
+    pub fn tick(&mut self) -> HeartbeatAction {
+        let now = tokio::time::Instant::now();
+
+        if let Some(sent_at) = self.ping_sent_at {
+            if now < sent_at + self.config.pong_timeout {
+                return HeartbeatAction::Wait;
+            }
+            self.ping_sent_at = None;
+            self.missed_pongs += 1;
+            if self.missed_pongs > self.config.max_missed_pongs {
+                return HeartbeatAction::Evict;
+            }
+        }
+
+        if now < self.next_ping {
+            return HeartbeatAction::Wait;
+        }
+        self.ping_sent_at = Some(now);
+        self.next_ping = now + self.config.ping_interval;
+        HeartbeatAction::Ping
+    }
+
+    pub fn record_pong(&mut self) {
+        self.ping_sent_at = None;
+        self.missed_pongs = 0;
+    }
+}
+
+/// How messages are framed for a client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Framing {
+    /// The payload as received from upstream.
+    Plain,
+    /// See [`crate::relay`].
+    Relay,
+    /// See [`crate::envelope`].
+    Envelope,
+}
 
 pub struct ClientConnection {
     client_addr: IpAddr,
     _ticket: Ticket,
+    connected_at: Instant,
+    stats: ConnectionStats,
+    framing: Framing,
+    labels: ClientLabels,
+    location: Location,
+    hooks: Option<Arc<dyn ConnectionHooks>>,
+    sampler: Option<Sampler>,
+    max_rate: Option<f64>,
+    transform: Option<Box<dyn Transform>>,
+    priority: bool,
     pub(crate) websocket: WebSocket,
 }
 
@@ -14,15 +165,291 @@ impl ClientConnection {
         Self {
             client_addr,
             _ticket: ticket,
+            connected_at: Instant::now(),
+            stats: ConnectionStats::default(),
+            framing: Framing::Plain,
+            labels: ClientLabels::default(),
+            location: Location::default(),
+            hooks: None,
+            sampler: None,
+            max_rate: None,
+            transform: None,
+            priority: false,
             websocket,
         }
     }
 
-    pub async fn send(&mut self, data: String) -> Result<(), Error> {
-        self.websocket.send(data.into_bytes().into()).await
+    pub fn with_labels(mut self, labels: ClientLabels) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    /// Where the client's address is, according to the GeoIP databases.
+    pub fn with_location(mut self, location: Location) -> Self {
+        self.location = location;
+        self
+    }
+
+    /// Report the client's messages and disconnection to `hooks`.
+    pub fn with_hooks(mut self, hooks: Arc<dyn ConnectionHooks>) -> Self {
+        self.hooks = Some(hooks);
+        self
+    }
+
+    /// Send messages in the relay envelope, for a downstream proxy.
+    pub fn with_relay_envelope(mut self) -> Self {
+        self.framing = Framing::Relay;
+        self
+    }
+
+    /// Send messages in the sequence-numbered envelope the client asked for.
+    pub fn with_sequence_envelope(mut self) -> Self {
+        self.framing = Framing::Envelope;
+        self
+    }
+
+    /// Send only the messages `sampling` selects. See [`crate::sampling`].
+    pub fn with_sampling(mut self, sampling: Sampling) -> Self {
+        self.sampler = Some(Sampler::new(sampling));
+        self
+    }
+
+    /// Send at most `max_rate` messages per second, coalescing those that arrive sooner. See
+    /// [`crate::sampling::RateCap`].
+    pub fn with_max_rate(mut self, max_rate: f64) -> Self {
+        self.max_rate = Some(max_rate);
+        self
+    }
+
+    pub fn max_rate(&self) -> Option<f64> {
+        self.max_rate
+    }
+
+    /// Run `transform` on the messages sent to the client. See [`crate::transform`].
+    pub fn with_transform(mut self, transform: Box<dyn Transform>) -> Self {
+        self.transform = Some(transform);
+        self
+    }
+
+    /// Never evict the client to make room for another. See [`crate::priority`].
+    pub fn with_priority(mut self) -> Self {
+        self.priority = true;
+        self
+    }
+
+    pub fn priority(&self) -> bool {
+        self.priority
+    }
+
+    /// Whether the client asked for only some messages to be sent, by sampling or with a
+    /// transform that may drop them.
+    pub fn sampled(&self) -> bool {
+        self.sampler.is_some() || self.transform.is_some()
+    }
+
+    /// Removes the messages the client asked not to be sent from `batch`, returning how many.
+    pub fn sample(&mut self, batch: &mut Vec<BroadcastMessage>) -> u64 {
+        match &mut self.sampler {
+            Some(sampler) => sampler.sample(batch),
+            None => 0,
+        }
+    }
+
+    /// Runs the client's transform on `batch`, removing the messages it drops and replacing those
+    /// it rewrites.
+    pub fn transform(&mut self, batch: &mut Vec<BroadcastMessage>, metrics: &Metrics) {
+        let Some(transform) = &mut self.transform else {
+            return;
+        };
+
+        batch.retain_mut(|message| {
+            let Message::Binary(payload) = &message.frame else {
+                return true;
+            };
+            match transform::apply(transform.as_mut(), payload.clone(), metrics) {
+                Some(payload) => {
+                    message.size = payload.len();
+                    message.frame = Message::Binary(payload);
+                    true
+                }
+                None => false,
+            }
+        });
+    }
+
+    /// Writes all of `messages` to the client with a single flush, so a client catching up on a
+    /// backlog is written to with as few syscalls as possible.
+    pub async fn send_batch(&mut self, messages: &[BroadcastMessage]) -> Result<(), Error> {
+        for message in messages {
+            let frame = match self.framing {
+                Framing::Plain => message.frame.clone(),
+                Framing::Relay => relay::envelope(message),
+                Framing::Envelope => envelope::envelope(message),
+            };
+            self.websocket.feed(frame).await?;
+        }
+        self.websocket.flush().await?;
+
+        for message in messages {
+            self.stats.messages_sent += 1;
+            self.stats.bytes_sent += message.size as u64;
+        }
+        Ok(())
+    }
+
+    pub async fn ping(&mut self) -> Result<(), Error> {
+        self.websocket.send(Message::Ping(Default::default())).await
+    }
+
+    /// Tells the client the proxy is going away (1001), so it can reconnect elsewhere.
+    pub async fn close(&mut self) -> Result<(), Error> {
+        let frame = CloseFrame {
+            code: close_code::AWAY,
+            reason: "shutting down".into(),
+        };
+        self.websocket.send(Message::Close(Some(frame))).await
+    }
+
+    /// The next message from the client, or `None` once it has closed the connection.
+    pub async fn recv(&mut self) -> Option<Result<Message, Error>> {
+        self.websocket.next().await
+    }
+
+    pub fn record_dropped(&mut self, count: u64) {
+        self.stats.messages_dropped += count;
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        self.stats
+    }
+
+    pub fn labels(&self) -> &ClientLabels {
+        &self.labels
+    }
+
+    pub fn location(&self) -> &Location {
+        &self.location
+    }
+
+    pub fn hooks(&self) -> Option<&Arc<dyn ConnectionHooks>> {
+        self.hooks.as_ref()
     }
 
     pub fn id(&self) -> String {
         self.client_addr.to_string()
     }
+
+    pub fn connected_for(&self) -> Duration {
+        self.connected_at.elapsed()
+    }
+}
+
+/// Classifies a failed write to a client as either the client going away or a server-side error.
+pub fn disconnect_reason(error: &Error) -> DisconnectReason {
+    let Some(error) = error
+        .source()
+        .and_then(|source| source.downcast_ref::<tungstenite::Error>())
+    else {
+        return DisconnectReason::Error;
+    };
+
+    match error {
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+            DisconnectReason::ClientInitiated
+        }
+        tungstenite::Error::Io(e)
+            if matches!(
+                e.kind(),
+                ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted
+            ) =>
+        {
+            DisconnectReason::ClientInitiated
+        }
+        _ => DisconnectReason::Error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn test_disconnect_reason() {
+        let reason = |e: tungstenite::Error| disconnect_reason(&Error::new(e));
+
+        assert_eq!(
+            reason(tungstenite::Error::ConnectionClosed),
+            DisconnectReason::ClientInitiated
+        );
+        assert_eq!(
+            reason(tungstenite::Error::Io(io::Error::from(
+                ErrorKind::BrokenPipe
+            ))),
+            DisconnectReason::ClientInitiated
+        );
+        assert_eq!(
+            reason(tungstenite::Error::Io(io::Error::from(
+                ErrorKind::OutOfMemory
+            ))),
+            DisconnectReason::Error
+        );
+        assert_eq!(reason(tungstenite::Error::Utf8), DisconnectReason::Error);
+        assert_eq!(
+            disconnect_reason(&Error::new(io::Error::other("not a websocket error"))),
+            DisconnectReason::Error
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_heartbeat() {
+        let mut heartbeat = Heartbeat::new(HeartbeatConfig {
+            ping_interval: Duration::from_secs(10),
+            pong_timeout: Duration::from_secs(5),
+            max_missed_pongs: 1,
+        });
+        async fn tick(heartbeat: &mut Heartbeat) -> HeartbeatAction {
+            tokio::time::sleep_until(heartbeat.due()).await;
+            heartbeat.tick()
+        }
+
+        // Answered pings reset the count of missed pongs
+        assert_eq!(tick(&mut heartbeat).await, HeartbeatAction::Ping);
+        assert_eq!(tick(&mut heartbeat).await, HeartbeatAction::Wait);
+        assert_eq!(tick(&mut heartbeat).await, HeartbeatAction::Ping);
+        heartbeat.record_pong();
+        assert_eq!(tick(&mut heartbeat).await, HeartbeatAction::Ping);
+        assert_eq!(tick(&mut heartbeat).await, HeartbeatAction::Wait);
+        assert_eq!(tick(&mut heartbeat).await, HeartbeatAction::Ping);
+        assert_eq!(tick(&mut heartbeat).await, HeartbeatAction::Evict);
+    }
+
+    #[test]
+    fn test_client_labels() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            ClientLabels::from_headers(&headers),
+            ClientLabels::default()
+        );
+
+        headers.insert(CLIENT_NAME_HEADER, " indexer ".parse().unwrap());
+        headers.insert(CLIENT_VERSION_HEADER, "x".repeat(100).parse().unwrap());
+        assert_eq!(
+            ClientLabels::from_headers(&headers),
+            ClientLabels {
+                name: Some("indexer".to_string()),
+                version: Some("x".repeat(MAX_LABEL_LEN)),
+            }
+        );
+
+        headers.insert(CLIENT_NAME_HEADER, "".parse().unwrap());
+        headers.insert(
+            CLIENT_VERSION_HEADER,
+            axum::http::HeaderValue::from_bytes(b"caf\xe9").unwrap(),
+        );
+        assert_eq!(
+            ClientLabels::from_headers(&headers),
+            ClientLabels::default()
+        );
+    }
 }
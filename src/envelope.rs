@@ -0,0 +1,96 @@
+//! Opt-in envelope for websocket clients, so that they can detect the messages they missed and
+//! measure delivery latency. Clients that offer the [`PROTOCOL`] subprotocol on the handshake
+//! receive each message as a binary frame of:
+//!
+//! - the message's sequence number, 8 bytes big-endian, increasing by one with each message
+//!   published to the stream
+//! - the time the proxy received the message, in milliseconds since the Unix epoch, 8 bytes
+//!   big-endian
+//! - the index of the upstream the message came from, 2 bytes big-endian, or `0xffff` if unknown
+//! - the payload, unchanged
+
+use crate::registry::BroadcastMessage;
+use axum::extract::ws::Message;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+/// The `Sec-WebSocket-Protocol` clients offer to receive messages in the envelope.
+pub const PROTOCOL: &str = "flashblocks-envelope-v1";
+
+const HEADER_LEN: usize = 18;
+const UNKNOWN_UPSTREAM: u16 = u16::MAX;
+
+/// A message as received in the envelope.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Envelope {
+    pub sequence: u64,
+    pub received_at_ms: u64,
+    pub upstream: Option<u16>,
+    pub payload: Bytes,
+}
+
+/// Builds the enveloped frame for `msg`.
+pub fn envelope(msg: &BroadcastMessage) -> Message {
+    let Message::Binary(payload) = &msg.frame else {
+        unreachable!("broadcast messages are binary frames");
+    };
+
+    let mut frame = BytesMut::with_capacity(HEADER_LEN + payload.len());
+    frame.put_u64(msg.sequence);
+    frame.put_u64(msg.received_at_ms);
+    frame.put_u16(msg.upstream.unwrap_or(UNKNOWN_UPSTREAM));
+    frame.put_slice(payload);
+    Message::Binary(frame.freeze())
+}
+
+/// Parses an enveloped frame, or returns `None` if it is too short to be one.
+pub fn open_envelope(mut frame: Bytes) -> Option<Envelope> {
+    if frame.len() < HEADER_LEN {
+        return None;
+    }
+
+    let sequence = frame.get_u64();
+    let received_at_ms = frame.get_u64();
+    let upstream = Some(frame.get_u16()).filter(|&upstream| upstream != UNKNOWN_UPSTREAM);
+    Some(Envelope {
+        sequence,
+        received_at_ms,
+        upstream,
+        payload: frame,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_envelope() {
+        let mut msg = BroadcastMessage::new(Bytes::from("payload"));
+        msg.sequence = 258;
+        msg.received_at_ms = 1_700_000_000_000;
+        msg.upstream = Some(1);
+
+        let Message::Binary(frame) = envelope(&msg) else {
+            panic!("expected a binary frame");
+        };
+        assert_eq!(&frame[..8], &[0, 0, 0, 0, 0, 0, 1, 2]);
+        assert_eq!(&frame[16..18], &[0, 1]);
+        assert_eq!(
+            open_envelope(frame),
+            Some(Envelope {
+                sequence: 258,
+                received_at_ms: 1_700_000_000_000,
+                upstream: Some(1),
+                payload: Bytes::from("payload"),
+            })
+        );
+
+        msg.upstream = None;
+        let Message::Binary(frame) = envelope(&msg) else {
+            panic!("expected a binary frame");
+        };
+        assert_eq!(open_envelope(frame).unwrap().upstream, None);
+
+        assert_eq!(open_envelope(Bytes::from("too short")), None);
+    }
+}
@@ -0,0 +1,95 @@
+use serde_json::{json, Value};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// Sends proxy-internal failures (panics, persistent upstream outages) to an external webhook so
+/// they can alert on-call, even when they don't surface as client-facing errors.
+struct ErrorReporter {
+    url: String,
+    upstream_failure_threshold: u32,
+}
+
+static REPORTER: OnceLock<ErrorReporter> = OnceLock::new();
+
+const REPORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Enables error reporting to the given webhook URL. `upstream_failure_threshold` is the number of
+/// consecutive failed upstream connection attempts after which an outage is reported.
+pub fn init(url: String, upstream_failure_threshold: u32) {
+    info!(message = "reporting errors to webhook", url = url);
+
+    let reporter = ErrorReporter {
+        url,
+        upstream_failure_threshold,
+    };
+
+    if REPORTER.set(reporter).is_err() {
+        warn!(message = "error reporting already initialized");
+    }
+}
+
+/// Reports a panic, with `details` of where it happened, blocking until the webhook has been
+/// called so the report isn't lost if the process is about to exit.
+pub fn report_panic(message: &str, details: Value) {
+    let Some(reporter) = REPORTER.get() else {
+        return;
+    };
+
+    let body = event("panic", message, details);
+    let url = reporter.url.clone();
+    let _ = std::thread::spawn(move || post(&url, &body)).join();
+}
+
+/// Called after every failed upstream connection attempt. Reports once per outage, when the number
+/// of consecutive failures reaches the configured threshold.
+pub fn upstream_failed(uri: &str, consecutive_failures: u32, error: &str) {
+    let Some(reporter) = REPORTER.get() else {
+        return;
+    };
+
+    if consecutive_failures != reporter.upstream_failure_threshold {
+        return;
+    }
+
+    let body = event(
+        "upstream_failures",
+        "upstream connection failing repeatedly",
+        json!({
+            "uri": uri,
+            "consecutive_failures": consecutive_failures,
+            "error": error,
+        }),
+    );
+    let url = reporter.url.clone();
+    std::thread::spawn(move || post(&url, &body));
+}
+
+fn event(kind: &str, message: &str, details: Value) -> Value {
+    json!({
+        "service": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+        "kind": kind,
+        "message": message,
+        "details": details,
+        "timestamp": SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+    })
+}
+
+fn post(url: &str, body: &Value) {
+    let result = reqwest::blocking::Client::builder()
+        .timeout(REPORT_TIMEOUT)
+        .build()
+        .and_then(|client| client.post(url).json(body).send())
+        .and_then(|response| response.error_for_status());
+
+    if let Err(e) = result {
+        warn!(
+            message = "failed to report error to webhook",
+            error = e.to_string()
+        );
+    }
+}
@@ -0,0 +1,131 @@
+//! Filters for upstream messages that aren't flashblocks, such as the keepalive and status frames
+//! some upstreams interleave with them, so that they aren't broadcast to clients.
+
+use serde_json::Value;
+use std::str::FromStr;
+
+/// A filter as configured with `--upstream-filter`. Messages matching any filter are dropped.
+///
+/// - `prefix:<text>` matches messages starting with `text`, e.g. `prefix:ping`
+/// - `pointer:<pointer>=<value>` matches JSON messages with `value` at the JSON pointer, e.g.
+///   `pointer:/type="keepalive"`. The value is JSON, or a string if it isn't valid JSON.
+/// - `pointer:<pointer>` matches JSON messages with anything at the pointer, e.g. `pointer:/status`
+#[derive(Clone, Debug, PartialEq)]
+pub enum MessageFilter {
+    Prefix(String),
+    Pointer {
+        pointer: String,
+        value: Option<Value>,
+    },
+}
+
+impl FromStr for MessageFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(prefix) = s.strip_prefix("prefix:") {
+            if prefix.is_empty() {
+                return Err("prefix filter matches every message".to_string());
+            }
+            return Ok(MessageFilter::Prefix(prefix.to_string()));
+        }
+
+        let Some(pointer) = s.strip_prefix("pointer:") else {
+            return Err(format!("filter {s} must start with prefix: or pointer:"));
+        };
+        let (pointer, value) = match pointer.split_once('=') {
+            Some((pointer, value)) => (
+                pointer,
+                Some(
+                    serde_json::from_str(value)
+                        .unwrap_or_else(|_| Value::String(value.to_string())),
+                ),
+            ),
+            None => (pointer, None),
+        };
+        if !pointer.starts_with('/') {
+            return Err(format!("JSON pointer {pointer} must start with '/'"));
+        }
+
+        Ok(MessageFilter::Pointer {
+            pointer: pointer.to_string(),
+            value,
+        })
+    }
+}
+
+/// Whether `payload` matches any of `filters`. Payloads are only parsed as JSON when a pointer
+/// filter needs it, and then only once.
+pub fn matches_any(filters: &[MessageFilter], payload: &[u8]) -> bool {
+    let mut json = None;
+
+    filters.iter().any(|filter| match filter {
+        MessageFilter::Prefix(prefix) => payload.starts_with(prefix.as_bytes()),
+        MessageFilter::Pointer { pointer, value } => {
+            let json = json.get_or_insert_with(|| serde_json::from_slice::<Value>(payload).ok());
+            match (json.as_ref().and_then(|json| json.pointer(pointer)), value) {
+                (Some(found), Some(value)) => found == value,
+                (found, None) => found.is_some(),
+                (None, Some(_)) => false,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_filter() {
+        assert_eq!(
+            "prefix:ping".parse(),
+            Ok(MessageFilter::Prefix("ping".to_string()))
+        );
+        assert_eq!(
+            r#"pointer:/type="keepalive""#.parse(),
+            Ok(MessageFilter::Pointer {
+                pointer: "/type".to_string(),
+                value: Some(Value::String("keepalive".to_string())),
+            })
+        );
+        assert_eq!(
+            "pointer:/type=keepalive".parse(),
+            Ok(MessageFilter::Pointer {
+                pointer: "/type".to_string(),
+                value: Some(Value::String("keepalive".to_string())),
+            })
+        );
+        assert_eq!(
+            "pointer:/status".parse(),
+            Ok(MessageFilter::Pointer {
+                pointer: "/status".to_string(),
+                value: None,
+            })
+        );
+
+        for invalid in ["prefix:", "ping", "pointer:type=keepalive"] {
+            assert!(invalid.parse::<MessageFilter>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_matches_any() {
+        let filters: Vec<MessageFilter> =
+            ["prefix:ping", "pointer:/type=keepalive", "pointer:/id=0"]
+                .iter()
+                .map(|filter| filter.parse().unwrap())
+                .collect();
+
+        assert!(matches_any(&filters, b"ping 1"));
+        assert!(matches_any(&filters, br#"{"type":"keepalive"}"#));
+        assert!(matches_any(&filters, br#"{"id":0,"type":"status"}"#));
+        assert!(!matches_any(&filters, br#"{"id":1,"type":"flashblock"}"#));
+        assert!(!matches_any(&filters, b"not json"));
+        assert!(!matches_any(&[], b"ping"));
+
+        let exists = ["pointer:/status".parse().unwrap()];
+        assert!(matches_any(&exists, br#"{"status":null}"#));
+        assert!(!matches_any(&exists, br#"{"index":0}"#));
+    }
+}
@@ -0,0 +1,341 @@
+//! Country and autonomous system lookups of client addresses in MaxMind databases (`.mmdb`), such
+//! as GeoLite2-Country and GeoLite2-ASN, to see where consumers connect from. Databases are read
+//! with the [`maxminddb`] crate.
+
+use maxminddb::geoip2::{Asn, Country};
+use maxminddb::Reader;
+use std::net::IpAddr;
+use std::path::Path;
+
+/// Where an address is, according to the databases that know.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Location {
+    /// ISO 3166-1 alpha-2 code of the country, e.g. `US`.
+    pub country: Option<String>,
+    /// Number of the autonomous system announcing the address.
+    pub asn: Option<u32>,
+    /// Organization the autonomous system belongs to.
+    pub as_org: Option<String>,
+}
+
+/// One or more databases, queried together, e.g. a country and an ASN database.
+pub struct GeoIp {
+    databases: Vec<Reader<Vec<u8>>>,
+}
+
+impl GeoIp {
+    pub fn open(paths: &[impl AsRef<Path>]) -> Result<Self, String> {
+        let databases = paths
+            .iter()
+            .map(|path| {
+                let path = path.as_ref();
+                Reader::open_readfile(path).map_err(|e| format!("{}: {e}", path.display()))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { databases })
+    }
+
+    /// Looks `addr` up in every database, taking each field from the first that has it.
+    /// Addresses a database can't hold, e.g. IPv6 addresses in an IPv4 database, aren't found in
+    /// it.
+    pub fn lookup(&self, addr: IpAddr) -> Location {
+        let addr = addr.to_canonical();
+        let mut location = Location::default();
+
+        for db in &self.databases {
+            if let Ok(Some(record)) = db.lookup::<Country>(addr) {
+                location.country = location.country.or_else(|| {
+                    [record.country, record.registered_country]
+                        .into_iter()
+                        .find_map(|country| country?.iso_code)
+                        .map(str::to_string)
+                });
+            }
+            if let Ok(Some(record)) = db.lookup::<Asn>(addr) {
+                location.asn = location.asn.or(record.autonomous_system_number);
+                location.as_org = location
+                    .as_org
+                    .or_else(|| record.autonomous_system_organization.map(str::to_string));
+            }
+        }
+
+        location
+    }
+}
+
+/// Which countries and autonomous systems clients may connect from. A client is refused if its
+/// country or ASN is denied, or if there's an allow list for either that it isn't on. Clients whose
+/// country or ASN isn't known aren't on any allow list.
+#[derive(Clone, Debug, Default)]
+pub struct AccessPolicy {
+    /// ISO 3166-1 alpha-2 codes, e.g. `US`.
+    pub allow_countries: Vec<String>,
+    pub deny_countries: Vec<String>,
+    pub allow_asns: Vec<u32>,
+    pub deny_asns: Vec<u32>,
+}
+
+impl AccessPolicy {
+    pub fn allows(&self, location: &Location) -> bool {
+        let country = |codes: &[String]| {
+            location
+                .country
+                .as_ref()
+                .is_some_and(|country| codes.iter().any(|code| code.eq_ignore_ascii_case(country)))
+        };
+        let asn = |asns: &[u32]| location.asn.is_some_and(|asn| asns.contains(&asn));
+
+        if country(&self.deny_countries) || asn(&self.deny_asns) {
+            return false;
+        }
+
+        (self.allow_countries.is_empty() || country(&self.allow_countries))
+            && (self.allow_asns.is_empty() || asn(&self.allow_asns))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The data section types that test databases are built from.
+    enum Data<'a> {
+        String(&'a str),
+        Uint32(u32),
+        Map(Vec<(&'a str, Data<'a>)>),
+        Array(Vec<Data<'a>>),
+    }
+
+    impl Data<'_> {
+        /// Encodes the value in the data section format, which the metadata shares.
+        fn encode(&self, out: &mut Vec<u8>) {
+            let header = |kind: u8, size: usize, out: &mut Vec<u8>| {
+                let (size_bits, extra) = match size {
+                    0..29 => (size, 0),
+                    29..285 => (29, 1),
+                    285..65821 => (30, 2),
+                    _ => (31, 3),
+                };
+                if kind < 8 {
+                    out.push((kind << 5) | size_bits as u8);
+                } else {
+                    out.push(size_bits as u8);
+                    out.push(kind - 7);
+                }
+                let extra_size = size - [0, 29, 285, 65821][extra];
+                out.extend_from_slice(&(extra_size as u32).to_be_bytes()[4 - extra..]);
+            };
+            match self {
+                Data::String(s) => {
+                    header(2, s.len(), out);
+                    out.extend_from_slice(s.as_bytes());
+                }
+                Data::Uint32(n) => {
+                    header(6, 4, out);
+                    out.extend_from_slice(&n.to_be_bytes());
+                }
+                Data::Map(entries) => {
+                    header(7, entries.len(), out);
+                    for (key, value) in entries {
+                        Data::String(key).encode(out);
+                        value.encode(out);
+                    }
+                }
+                Data::Array(values) => {
+                    header(11, values.len(), out);
+                    for value in values {
+                        value.encode(out);
+                    }
+                }
+            }
+        }
+    }
+
+    /// A record with the country with `iso_code` under `field`.
+    fn country<'a>(field: &'a str, iso_code: &'a str) -> Data<'a> {
+        Data::Map(vec![(
+            field,
+            Data::Map(vec![("iso_code", Data::String(iso_code))]),
+        )])
+    }
+
+    /// Builds a database with 24 bit records mapping each network to a record, and opens it.
+    fn build(ip_version: u32, networks: &[(&str, Data)]) -> Reader<Vec<u8>> {
+        #[derive(Clone, Copy)]
+        enum Record {
+            Empty,
+            Node(usize),
+            Data(usize),
+        }
+
+        let mut nodes = vec![[Record::Empty; 2]];
+        let mut data = Vec::new();
+        for (network, record) in networks {
+            let (addr, len) = network.split_once('/').unwrap();
+            let len: u32 = len.parse().unwrap();
+            let (bits, total) = match addr.parse::<IpAddr>().unwrap() {
+                IpAddr::V4(addr) if ip_version == 4 => (u32::from(addr) as u128, 32),
+                IpAddr::V4(addr) => (u32::from(addr) as u128, 128),
+                IpAddr::V6(addr) => (u128::from(addr), 128),
+            };
+            let len = len + total - if addr.contains(':') { 128 } else { 32 };
+
+            let offset = data.len();
+            record.encode(&mut data);
+
+            let mut node = 0;
+            for i in 0..len {
+                let bit = ((bits >> (total - 1 - i)) & 1) as usize;
+                if i == len - 1 {
+                    nodes[node][bit] = Record::Data(offset);
+                } else {
+                    node = match nodes[node][bit] {
+                        Record::Node(next) => next,
+                        // A less specific network keeps covering the rest of the subtree
+                        existing => {
+                            nodes.push([existing; 2]);
+                            nodes[node][bit] = Record::Node(nodes.len() - 1);
+                            nodes.len() - 1
+                        }
+                    };
+                }
+            }
+        }
+
+        let node_count = nodes.len();
+        let mut db = Vec::new();
+        for node in &nodes {
+            for record in node {
+                let value = match *record {
+                    Record::Empty => node_count,
+                    Record::Node(next) => next,
+                    Record::Data(offset) => node_count + 16 + offset,
+                };
+                db.extend_from_slice(&(value as u32).to_be_bytes()[1..]);
+            }
+        }
+        db.extend_from_slice(&[0; 16]);
+        db.extend_from_slice(&data);
+        db.extend_from_slice(b"\xab\xcd\xefMaxMind.com");
+        Data::Map(vec![
+            ("binary_format_major_version", Data::Uint32(2)),
+            ("binary_format_minor_version", Data::Uint32(0)),
+            ("build_epoch", Data::Uint32(0)),
+            ("database_type", Data::String("Test")),
+            ("description", Data::Map(vec![])),
+            ("ip_version", Data::Uint32(ip_version)),
+            ("languages", Data::Array(vec![Data::String("en")])),
+            ("node_count", Data::Uint32(node_count as u32)),
+            ("record_size", Data::Uint32(24)),
+        ])
+        .encode(&mut db);
+
+        Reader::from_source(db).unwrap()
+    }
+
+    #[test]
+    fn test_lookup() {
+        for ip_version in [4, 6] {
+            let db = build(
+                ip_version,
+                &[
+                    ("1.2.0.0/16", country("country", "AU")),
+                    ("1.2.3.0/24", country("registered_country", "NZ")),
+                ],
+            );
+            let geoip = GeoIp {
+                databases: vec![db],
+            };
+
+            let country = |addr: &str| geoip.lookup(addr.parse().unwrap()).country;
+            assert_eq!(country("1.2.200.1").as_deref(), Some("AU"), "{ip_version}");
+            assert_eq!(country("1.2.3.4").as_deref(), Some("NZ"), "{ip_version}");
+            assert_eq!(country("::ffff:1.2.3.4").as_deref(), Some("NZ"));
+            assert_eq!(country("9.9.9.9"), None);
+            assert_eq!(country("2001:db8::1"), None);
+        }
+    }
+
+    #[test]
+    fn test_lookup_merges_databases() {
+        let country = build(6, &[("2001:db8::/32", country("country", "DE"))]);
+        let asn = build(
+            6,
+            &[(
+                "2001:db8::/48",
+                Data::Map(vec![
+                    ("autonomous_system_number", Data::Uint32(64496)),
+                    ("autonomous_system_organization", Data::String("Example")),
+                ]),
+            )],
+        );
+        let geoip = GeoIp {
+            databases: vec![country, asn],
+        };
+
+        assert_eq!(
+            geoip.lookup("2001:db8::1".parse().unwrap()),
+            Location {
+                country: Some("DE".to_string()),
+                asn: Some(64496),
+                as_org: Some("Example".to_string()),
+            }
+        );
+        assert_eq!(geoip.lookup("2001:db8:1::1".parse().unwrap()).asn, None);
+    }
+
+    #[test]
+    fn test_open() {
+        let path = std::env::temp_dir().join(format!("geoip-{}.mmdb", std::process::id()));
+        std::fs::write(&path, b"not a database").unwrap();
+        let error = GeoIp::open(&[&path]).err().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(error.starts_with(&path.display().to_string()), "{error}");
+
+        assert!(GeoIp::open(&["/nonexistent.mmdb"]).is_err());
+    }
+
+    #[test]
+    fn test_access_policy() {
+        let location = |country: Option<&str>, asn: Option<u32>| Location {
+            country: country.map(str::to_string),
+            asn,
+            as_org: None,
+        };
+        let us = location(Some("US"), Some(16509));
+
+        assert!(AccessPolicy::default().allows(&us));
+        assert!(AccessPolicy::default().allows(&Location::default()));
+
+        let policy = AccessPolicy {
+            deny_countries: vec!["cu".to_string()],
+            deny_asns: vec![14061],
+            ..Default::default()
+        };
+        assert!(policy.allows(&us));
+        assert!(policy.allows(&Location::default()));
+        assert!(!policy.allows(&location(Some("CU"), None)));
+        assert!(!policy.allows(&location(Some("US"), Some(14061))));
+
+        let policy = AccessPolicy {
+            allow_countries: vec!["US".to_string(), "DE".to_string()],
+            deny_asns: vec![14061],
+            ..Default::default()
+        };
+        assert!(policy.allows(&us));
+        assert!(!policy.allows(&location(Some("FR"), Some(16509))));
+        assert!(!policy.allows(&location(Some("DE"), Some(14061))));
+        assert!(!policy.allows(&Location::default()));
+
+        // Both allow lists have to match
+        let policy = AccessPolicy {
+            allow_countries: vec!["US".to_string()],
+            allow_asns: vec![16509],
+            ..Default::default()
+        };
+        assert!(policy.allows(&us));
+        assert!(!policy.allows(&location(Some("US"), Some(14061))));
+        assert!(!policy.allows(&location(Some("US"), None)));
+    }
+}
@@ -0,0 +1,168 @@
+//! A GraphQL subscription facade over the streams, for frontends standardised on GraphQL. Only
+//! built with the `graphql` feature.
+//!
+//! ```graphql
+//! subscription {
+//!   flashblocks { blockNumber index payloadId diff metadata }
+//! }
+//! ```
+//!
+//! Subscriptions are served over websockets on `/graphql` for the default stream and
+//! `/graphql/{stream}` for a named one, with either the `graphql-transport-ws` or the older
+//! `graphql-ws` protocol. Each flashblock is parsed from the upstream's JSON and only the selected
+//! fields are sent, so leaving out `diff` spares a client the transactions. Messages that aren't
+//! JSON objects are skipped.
+
+use crate::metrics::{DropCause, Metrics};
+use crate::registry::{BroadcastMessage, Delivery, Registry, Subscription};
+use async_graphql::futures_util::Stream;
+use async_graphql::{Context, EmptyMutation, Json, Object, Schema};
+use axum::extract::ws::Message;
+use futures::StreamExt;
+use serde_json::{Map, Value};
+use std::sync::Arc;
+
+pub type FlashblocksSchema = Schema<Query, EmptyMutation, SubscriptionRoot>;
+
+/// The schema, resolving against the [`Registry`] in each connection's data.
+pub fn schema() -> FlashblocksSchema {
+    Schema::build(Query, EmptyMutation, SubscriptionRoot).finish()
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    /// Clients connected to the stream.
+    async fn clients(&self, ctx: &Context<'_>) -> usize {
+        ctx.data_unchecked::<Registry>().client_count()
+    }
+}
+
+pub struct SubscriptionRoot;
+
+#[async_graphql::Subscription]
+impl SubscriptionRoot {
+    /// Every flashblock published to the stream after the subscription starts.
+    async fn flashblocks(&self, ctx: &Context<'_>) -> impl Stream<Item = Flashblock> {
+        let registry = ctx.data_unchecked::<Registry>();
+        let metrics = registry.metrics().clone();
+        metrics.graphql_subscriptions.increment(1);
+
+        subscribe(registry.register(), metrics)
+    }
+}
+
+/// A flashblock, as parsed from the upstream's JSON.
+pub struct Flashblock(Map<String, Value>);
+
+impl Flashblock {
+    fn parse(msg: &BroadcastMessage) -> Option<Self> {
+        let Message::Binary(payload) = &msg.frame else {
+            unreachable!("broadcast messages are binary frames");
+        };
+        match serde_json::from_slice(payload) {
+            Ok(Value::Object(fields)) => Some(Self(fields)),
+            _ => None,
+        }
+    }
+}
+
+#[Object]
+impl Flashblock {
+    /// `metadata.block_number`.
+    async fn block_number(&self) -> Option<u64> {
+        self.0.get("metadata")?.get("block_number")?.as_u64()
+    }
+
+    /// Position of the flashblock within its block.
+    async fn index(&self) -> Option<u64> {
+        self.0.get("index")?.as_u64()
+    }
+
+    async fn payload_id(&self) -> Option<&str> {
+        self.0.get("payload_id")?.as_str()
+    }
+
+    /// Changes to the block since the previous flashblock, as JSON.
+    async fn diff(&self) -> Option<Json<&Value>> {
+        self.0.get("diff").map(Json)
+    }
+
+    async fn metadata(&self) -> Option<Json<&Value>> {
+        self.0.get("metadata").map(Json)
+    }
+}
+
+/// Streams `subscription`'s messages as flashblocks. Messages that were dropped because the
+/// client lagged are skipped, as for websockets.
+fn subscribe(
+    subscription: Subscription,
+    metrics: Arc<Metrics>,
+) -> impl Stream<Item = Flashblock> + Send {
+    let state = (subscription, metrics, Vec::new());
+    futures::stream::unfold(state, |mut state| async move {
+        let (subscription, metrics, batch) = &mut state;
+        loop {
+            batch.clear();
+            match subscription.recv_many(batch, 64).await {
+                Delivery::Messages(_) => break,
+                Delivery::Lagged(dropped) => {
+                    metrics.lag_events.increment(1);
+                    metrics
+                        .dropped_messages
+                        .increment(DropCause::Lagged, dropped);
+                }
+                Delivery::Overwritten(dropped) => metrics
+                    .dropped_messages
+                    .increment(DropCause::Overwritten, dropped),
+            }
+        }
+
+        let mut flashblocks = Vec::with_capacity(batch.len());
+        for msg in batch.iter() {
+            flashblocks.extend(Flashblock::parse(msg));
+            metrics.sent_message_size.record(msg.size as f64);
+            let elapsed = subscription.record_delivered(msg);
+            metrics.fan_out_latency.record(elapsed.as_secs_f64());
+        }
+        metrics.sent_messages.increment(flashblocks.len() as u64);
+
+        Some((futures::stream::iter(flashblocks), state))
+    })
+    .flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_graphql::Request;
+    use bytes::Bytes;
+
+    #[tokio::test]
+    async fn test_field_selection() {
+        let registry = Registry::new(4, 1, Arc::new(Metrics::default()));
+        let schema = schema();
+        let mut stream = schema.execute_stream(
+            Request::new("subscription { flashblocks { blockNumber index } }")
+                .data(registry.clone()),
+        );
+
+        // The subscription is registered once the stream is first polled
+        let next = tokio::spawn(async move { stream.next().await.unwrap() });
+        while registry.client_count() == 0 {
+            tokio::task::yield_now().await;
+        }
+        registry.publish(Bytes::from_static(b"not json"));
+        registry.publish(Bytes::from_static(
+            br#"{"index":2,"diff":{"transactions":["0x01"]},"metadata":{"block_number":7}}"#,
+        ));
+
+        let response = next.await.unwrap();
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+        assert_eq!(
+            response.data.into_json().unwrap(),
+            serde_json::json!({"flashblocks": {"blockNumber": 7, "index": 2}})
+        );
+    }
+}
@@ -0,0 +1,157 @@
+//! A gRPC server-streaming endpoint, for services built on gRPC to consume the stream without a
+//! websocket client. It implements `SubscribeFlashblocks` from `proto/flashblocks.proto`, with
+//! code generated by `tonic-prost-build`:
+//!
+//! ```proto
+//! service Flashblocks {
+//!   rpc SubscribeFlashblocks(SubscribeRequest) returns (stream Flashblock);
+//! }
+//!
+//! message SubscribeRequest {
+//!   // A named stream, or empty for the default one
+//!   string stream = 1;
+//! }
+//!
+//! message Flashblock {
+//!   bytes payload = 1;
+//!   uint64 sequence = 2;
+//!   uint64 received_at_ms = 3;
+//!   optional uint32 upstream = 4;
+//! }
+//! ```
+//!
+//! The fields of `Flashblock` are those of the websocket [`crate::envelope`]. The service is
+//! served by the same HTTP/2 server as the websockets, so it needs `--http2`. Subscriptions go
+//! through the same checks as websocket upgrades, and refusals are reported with the
+//! corresponding gRPC status.
+
+use crate::metrics::{DropCause, Metrics};
+use crate::rate_limit::Ticket;
+use crate::registry::{BroadcastMessage, Delivery, Subscription};
+use axum::extract::ws::Message;
+use axum::http::StatusCode;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use std::sync::Arc;
+use tonic::Status;
+
+tonic::include_proto!("flashblocks.v1");
+
+pub use flashblocks_client::FlashblocksClient;
+pub use flashblocks_server::{Flashblocks, FlashblocksServer, SERVICE_NAME};
+
+/// The messages streamed to a `SubscribeFlashblocks` call.
+pub type FlashblockStream = BoxStream<'static, Result<Flashblock, Status>>;
+
+/// The status to fail a call with, for an HTTP status with which a websocket upgrade was refused.
+pub fn refusal(status: StatusCode) -> Status {
+    let message = status.canonical_reason().unwrap_or("refused");
+    match status {
+        StatusCode::UNAUTHORIZED => Status::unauthenticated(message),
+        StatusCode::FORBIDDEN => Status::permission_denied(message),
+        StatusCode::NOT_FOUND => Status::not_found(message),
+        StatusCode::TOO_MANY_REQUESTS => Status::resource_exhausted(message),
+        _ => Status::unavailable(message),
+    }
+}
+
+impl From<&BroadcastMessage> for Flashblock {
+    fn from(msg: &BroadcastMessage) -> Self {
+        let Message::Binary(payload) = &msg.frame else {
+            unreachable!("broadcast messages are binary frames");
+        };
+
+        Self {
+            payload: payload.clone(),
+            sequence: msg.sequence,
+            received_at_ms: msg.received_at_ms,
+            upstream: msg.upstream.map(u32::from),
+        }
+    }
+}
+
+/// Streams `subscription`'s messages to the caller until it goes away, holding `ticket` for as
+/// long. Messages that were dropped because the caller lagged are skipped, as for websockets.
+pub fn subscribe(
+    subscription: Subscription,
+    ticket: Ticket,
+    metrics: Arc<Metrics>,
+) -> FlashblockStream {
+    metrics.grpc_subscriptions.increment(1);
+
+    let state = (subscription, ticket, metrics, Vec::new());
+    futures::stream::unfold(state, |mut state| async move {
+        let (subscription, _, metrics, batch) = &mut state;
+        loop {
+            batch.clear();
+            match subscription.recv_many(batch, 64).await {
+                Delivery::Messages(_) => break,
+                Delivery::Lagged(dropped) => {
+                    metrics.lag_events.increment(1);
+                    metrics
+                        .dropped_messages
+                        .increment(DropCause::Lagged, dropped);
+                }
+                Delivery::Overwritten(dropped) => metrics
+                    .dropped_messages
+                    .increment(DropCause::Overwritten, dropped),
+            }
+        }
+
+        let mut flashblocks = Vec::with_capacity(batch.len());
+        for msg in batch.iter() {
+            flashblocks.push(Ok(Flashblock::from(msg)));
+            metrics.sent_message_size.record(msg.size as f64);
+            let elapsed = subscription.record_delivered(msg);
+            metrics.fan_out_latency.record(elapsed.as_secs_f64());
+        }
+        metrics.sent_messages.increment(batch.len() as u64);
+
+        Some((futures::stream::iter(flashblocks), state))
+    })
+    .flatten()
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use prost::Message as _;
+
+    #[test]
+    fn test_encode_flashblock() {
+        let mut msg = BroadcastMessage::new(Bytes::from("payload"));
+        msg.sequence = 300;
+        msg.received_at_ms = 1_700_000_000_000;
+        msg.upstream = Some(0);
+
+        let flashblock = Flashblock::from(&msg);
+        let encoded = flashblock.encode_to_vec();
+        assert_eq!(&encoded[..9], b"\x0a\x07payload");
+        assert_eq!(&encoded[9..12], b"\x10\xac\x02");
+        // Set optional fields are sent even when zero
+        assert_eq!(&encoded[encoded.len() - 2..], b"\x20\x00");
+        assert_eq!(Flashblock::decode(&encoded[..]).unwrap(), flashblock);
+
+        msg.upstream = None;
+        let encoded = Flashblock::from(&msg).encode_to_vec();
+        assert_eq!(Flashblock::decode(&encoded[..]).unwrap().upstream, None);
+    }
+
+    #[test]
+    fn test_refusal() {
+        assert_eq!(
+            refusal(StatusCode::UNAUTHORIZED).code(),
+            tonic::Code::Unauthenticated
+        );
+        assert_eq!(
+            refusal(StatusCode::TOO_MANY_REQUESTS).code(),
+            tonic::Code::ResourceExhausted
+        );
+        assert_eq!(
+            refusal(StatusCode::SERVICE_UNAVAILABLE).code(),
+            tonic::Code::Unavailable
+        );
+    }
+}
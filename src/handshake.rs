@@ -0,0 +1,434 @@
+//! Limits on connections that haven't completed the websocket handshake, so that slowloris-style
+//! clients, which open connections and then send their handshake as slowly as possible, can't
+//! hold sockets and memory indefinitely. Connections that haven't upgraded within the handshake
+//! timeout are closed, as are plain HTTP connections, e.g. for health checks, that stay open as
+//! long. Once too many connections are waiting to upgrade, new ones are refused on accept with a
+//! `503`, written without reading their request or involving the HTTP server, so that a
+//! reconnect storm costs as little as possible. Likewise, a client address with too many
+//! connections waiting to upgrade has further connections refused with a `429`, so that a single
+//! client's burst of handshakes can't take all of them.
+//!
+//! Connections speak HTTP/1.1 unless HTTP/2 is enabled, in which case clients may also send the
+//! HTTP/2 connection preface and open websockets with extended CONNECT (RFC 8441), several to a
+//! connection. HTTP/2 is only offered in cleartext, by prior knowledge, as the proxy is expected
+//! to sit behind a load balancer that terminates TLS.
+
+use crate::metrics::Metrics;
+use axum::serve::Listener;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Sleep;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HandshakeConfig {
+    /// Longest a connection may take from being accepted to upgrading to a websocket.
+    pub timeout: Option<Duration>,
+    /// Most connections that may be waiting to upgrade at once.
+    pub max_pending: Option<usize>,
+    /// Most connections from one IP address that may be waiting to upgrade at once. The address
+    /// is the connection's peer, not one from a header.
+    pub max_pending_per_ip: Option<usize>,
+    /// Accept HTTP/2 connections as well as HTTP/1.1.
+    pub http2: bool,
+}
+
+/// How the HTTP/2 connection preface starts, which no HTTP/1.1 request can.
+const HTTP2_PREFACE: &[u8] = b"PRI ";
+
+/// Response to connections refused on accept.
+const REFUSAL: &[u8] = b"HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\nconnection: close\r\nretry-after: 1\r\n\r\n";
+
+/// Response to connections refused on accept because their address has too many pending.
+const IP_REFUSAL: &[u8] = b"HTTP/1.1 429 Too Many Requests\r\ncontent-length: 0\r\nconnection: close\r\nretry-after: 1\r\n\r\n";
+
+#[derive(Debug, Default)]
+struct Pending {
+    by_addr: HashMap<SocketAddr, Arc<AtomicBool>>,
+    by_ip: HashMap<IpAddr, usize>,
+}
+
+/// The connections that have been accepted but not yet upgraded, by client address.
+#[derive(Debug, Default)]
+pub struct Handshakes {
+    pending: Mutex<Pending>,
+}
+
+impl Handshakes {
+    pub fn pending(&self) -> usize {
+        self.pending.lock().unwrap().by_addr.len()
+    }
+
+    /// Connections from `ip` waiting to upgrade.
+    pub fn pending_from(&self, ip: IpAddr) -> usize {
+        self.pending
+            .lock()
+            .unwrap()
+            .by_ip
+            .get(&ip)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    fn begin(&self, addr: SocketAddr) -> Arc<AtomicBool> {
+        let upgraded = Arc::new(AtomicBool::new(false));
+        let mut pending = self.pending.lock().unwrap();
+        if pending.by_addr.insert(addr, upgraded.clone()).is_none() {
+            *pending.by_ip.entry(addr.ip()).or_default() += 1;
+        }
+        upgraded
+    }
+
+    /// Marks the connection from `addr` as upgraded, so that the handshake limits no longer
+    /// apply to it.
+    pub fn complete(&self, addr: SocketAddr) {
+        let mut pending = self.pending.lock().unwrap();
+        let Some(upgraded) = pending.by_addr.remove(&addr) else {
+            return;
+        };
+        upgraded.store(true, Ordering::Relaxed);
+
+        if let Some(count) = pending.by_ip.get_mut(&addr.ip()) {
+            *count -= 1;
+            if *count == 0 {
+                pending.by_ip.remove(&addr.ip());
+            }
+        }
+    }
+}
+
+/// A listener that applies [`HandshakeConfig`] to the connections it accepts. Connections are
+/// marked upgraded with [`Handshakes::complete`].
+pub struct HandshakeListener {
+    listener: TcpListener,
+    config: HandshakeConfig,
+    handshakes: Arc<Handshakes>,
+    metrics: Arc<Metrics>,
+}
+
+impl HandshakeListener {
+    pub fn new(
+        listener: TcpListener,
+        config: HandshakeConfig,
+        handshakes: Arc<Handshakes>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            listener,
+            config,
+            handshakes,
+            metrics,
+        }
+    }
+}
+
+impl Listener for HandshakeListener {
+    type Io = HandshakeStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        loop {
+            let (mut stream, addr) = Listener::accept(&mut self.listener).await;
+
+            let refusal = if self
+                .config
+                .max_pending
+                .is_some_and(|max| self.handshakes.pending() >= max)
+            {
+                self.metrics.rejected_handshakes.increment(1);
+                Some(REFUSAL)
+            } else if self
+                .config
+                .max_pending_per_ip
+                .is_some_and(|max| self.handshakes.pending_from(addr.ip()) >= max)
+            {
+                self.metrics.ip_rejected_handshakes.increment(1);
+                Some(IP_REFUSAL)
+            } else {
+                None
+            };
+            if let Some(refusal) = refusal {
+                // Best effort: if the response can't be written the client sees the connection
+                // close instead
+                tokio::spawn(async move {
+                    let _ = stream.write_all(refusal).await;
+                    let _ = stream.shutdown().await;
+                });
+                continue;
+            }
+
+            let upgraded = self.handshakes.begin(addr);
+            let stream = HandshakeStream {
+                stream,
+                addr,
+                upgraded,
+                deadline: self
+                    .config
+                    .timeout
+                    .map(|timeout| Box::pin(tokio::time::sleep(timeout))),
+                refuse_http2: !self.config.http2,
+                handshakes: self.handshakes.clone(),
+                metrics: self.metrics.clone(),
+            };
+            return (stream, addr);
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.listener.local_addr()
+    }
+}
+
+/// A connection accepted by [`HandshakeListener`], which fails once the handshake timeout
+/// passes unless it has been upgraded.
+pub struct HandshakeStream {
+    stream: TcpStream,
+    addr: SocketAddr,
+    upgraded: Arc<AtomicBool>,
+    deadline: Option<Pin<Box<Sleep>>>,
+    /// Whether to fail if the first bytes read are the HTTP/2 preface. Only the first read is
+    /// checked, which is enough for clients that aren't deliberately splitting their preface.
+    refuse_http2: bool,
+    handshakes: Arc<Handshakes>,
+    metrics: Arc<Metrics>,
+}
+
+impl HandshakeStream {
+    /// Fails once the deadline passes while the connection is still waiting to upgrade. Polling
+    /// the deadline also wakes the task when it passes, even if the client sends nothing.
+    fn poll_deadline(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        let Some(deadline) = &mut self.deadline else {
+            return Ok(());
+        };
+
+        if self.upgraded.load(Ordering::Relaxed) {
+            self.deadline = None;
+        } else if deadline.as_mut().poll(cx).is_ready() {
+            self.deadline = None;
+            self.upgraded.store(true, Ordering::Relaxed);
+            self.handshakes.complete(self.addr);
+            self.metrics.handshake_timeouts.increment(1);
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "websocket handshake timed out",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for HandshakeStream {
+    fn drop(&mut self) {
+        if !self.upgraded.load(Ordering::Relaxed) {
+            self.handshakes.complete(self.addr);
+        }
+    }
+}
+
+impl AsyncRead for HandshakeStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.poll_deadline(cx)?;
+        if !self.refuse_http2 {
+            return Pin::new(&mut self.stream).poll_read(cx, buf);
+        }
+
+        let start = buf.filled().len();
+        let result = ready!(Pin::new(&mut self.stream).poll_read(cx, buf));
+        let read = &buf.filled()[start..];
+        if result.is_ok() && !read.is_empty() {
+            self.refuse_http2 = false;
+            if read.starts_with(HTTP2_PREFACE) {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "HTTP/2 is not enabled",
+                )));
+            }
+        }
+        Poll::Ready(result)
+    }
+}
+
+impl AsyncWrite for HandshakeStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_deadline(cx)?;
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_deadline(cx)?;
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    async fn listener(config: HandshakeConfig) -> (HandshakeListener, Arc<Handshakes>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let handshakes = Arc::new(Handshakes::default());
+        let listener = HandshakeListener::new(
+            listener,
+            config,
+            handshakes.clone(),
+            Arc::new(Metrics::default()),
+        );
+        (listener, handshakes)
+    }
+
+    #[tokio::test]
+    async fn test_handshake_timeout() {
+        let (mut listener, handshakes) = listener(HandshakeConfig {
+            timeout: Some(Duration::from_millis(100)),
+            max_pending: None,
+            max_pending_per_ip: None,
+            http2: false,
+        })
+        .await;
+        let addr = listener.local_addr().unwrap();
+
+        // A client that never finishes its handshake is cut off
+        let _slow = TcpStream::connect(addr).await.unwrap();
+        let (mut stream, _) = listener.accept().await;
+        assert_eq!(handshakes.pending(), 1);
+        let error = stream.read(&mut [0; 16]).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(handshakes.pending(), 0);
+
+        // An upgraded connection is left alone
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (mut stream, client_addr) = listener.accept().await;
+        handshakes.complete(client_addr);
+        assert_eq!(handshakes.pending(), 0);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_max_pending_handshakes() {
+        let (mut listener, handshakes) = listener(HandshakeConfig {
+            timeout: None,
+            max_pending: Some(1),
+            max_pending_per_ip: None,
+            http2: false,
+        })
+        .await;
+        let addr = listener.local_addr().unwrap();
+
+        let _first = TcpStream::connect(addr).await.unwrap();
+        let (first, _) = listener.accept().await;
+
+        // The second client is refused on accept while the first is still pending
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        let accept = tokio::spawn(async move { listener.accept().await });
+        let mut response = String::new();
+        second.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 "));
+
+        drop(first);
+        assert_eq!(handshakes.pending(), 0);
+        let _third = TcpStream::connect(addr).await.unwrap();
+        let (_third, _) = accept.await.unwrap();
+        assert_eq!(handshakes.pending(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_max_pending_handshakes_per_ip() {
+        let (mut listener, handshakes) = listener(HandshakeConfig {
+            timeout: None,
+            max_pending: None,
+            max_pending_per_ip: Some(2),
+            http2: false,
+        })
+        .await;
+        let addr = listener.local_addr().unwrap();
+
+        let _first = TcpStream::connect(addr).await.unwrap();
+        let (_first, _) = listener.accept().await;
+        let _second = TcpStream::connect(addr).await.unwrap();
+        let (second, second_addr) = listener.accept().await;
+        assert_eq!(handshakes.pending_from(second_addr.ip()), 2);
+
+        // A third from the same address is refused until one of the others upgrades
+        let mut third = TcpStream::connect(addr).await.unwrap();
+        let accept = tokio::spawn(async move { listener.accept().await });
+        let mut response = String::new();
+        third.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 429 "));
+
+        handshakes.complete(second_addr);
+        drop(second);
+        assert_eq!(handshakes.pending_from(second_addr.ip()), 1);
+        let _fourth = TcpStream::connect(addr).await.unwrap();
+        let (_fourth, _) = accept.await.unwrap();
+        assert_eq!(handshakes.pending_from(second_addr.ip()), 2);
+    }
+
+    #[tokio::test]
+    async fn test_refuse_http2() {
+        for http2 in [false, true] {
+            let (mut listener, _) = listener(HandshakeConfig {
+                timeout: None,
+                max_pending: None,
+                max_pending_per_ip: None,
+                http2,
+            })
+            .await;
+            let addr = listener.local_addr().unwrap();
+
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client
+                .write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n")
+                .await
+                .unwrap();
+            let (mut stream, _) = listener.accept().await;
+            let mut buf = [0; 24];
+            let read = stream.read_exact(&mut buf).await;
+            assert_eq!(read.is_ok(), http2, "http2: {http2}");
+
+            // HTTP/1.1 is always accepted
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client.write_all(b"PUT / HTTP/1.1\r\n").await.unwrap();
+            let (mut stream, _) = listener.accept().await;
+            let mut buf = [0; 16];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"PUT / HTTP/1.1\r\n");
+        }
+    }
+}
@@ -0,0 +1,273 @@
+//! Test harness that runs the proxy's server with websocket clients attached, for conformance tests
+//! of forks of the proxy and of client SDKs. Only built with the `harness` feature.
+//!
+//! ```ignore
+//! let mut harness = TestHarness::new(TestHarness::alloc_port().await);
+//! harness.start_server().await;
+//!
+//! let client = harness.connect_client();
+//! harness.wait_for_clients(1).await;
+//! harness.send_messages(vec!["one", "two"]);
+//! harness.wait_for_messages_to_drain().await;
+//! assert_eq!(harness.messages_for_client(client), vec!["one", "two"]);
+//! ```
+//!
+//! Messages are published to the registry directly; to exercise the upstream path as well, point
+//! a [`crate::subscriber::WebsocketSubscriber`] at [`spawn_mock_upstream`].
+
+use crate::metrics::Metrics;
+use crate::mock_upstream::{self, MockUpstreamArgs};
+use crate::rate_limit::InMemoryRateLimit;
+use crate::registry::Registry;
+use crate::server::{ReadinessConfig, Server};
+use crate::subscriber::UpstreamStatus;
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::connect_async;
+use tokio_util::sync::CancellationToken;
+use tracing::error;
+
+/// A server with a buffer of 5 messages per client, a limit of 3 connections, and one upstream
+/// that never connects, plus the clients connected to it. Methods panic when the server doesn't
+/// behave as expected.
+pub struct TestHarness {
+    received_messages: Arc<Mutex<HashMap<usize, Vec<String>>>>,
+    clients_failed_to_connect: Arc<Mutex<HashMap<usize, bool>>>,
+    current_client_id: usize,
+    cancel_token: CancellationToken,
+    server: Server,
+    server_addr: SocketAddr,
+    client_id_to_handle: HashMap<usize, JoinHandle<()>>,
+    registry: Registry,
+}
+
+impl TestHarness {
+    /// An address on localhost that is free to listen on.
+    pub async fn alloc_port() -> SocketAddr {
+        let address = SocketAddr::from(([127, 0, 0, 1], 0));
+        let listener = TcpListener::bind(&address).await.unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    pub fn new(addr: SocketAddr) -> TestHarness {
+        Self::with_readiness(addr, ReadinessConfig::default())
+    }
+
+    pub fn with_readiness(addr: SocketAddr, readiness: ReadinessConfig) -> TestHarness {
+        let metrics = Arc::new(Metrics::default());
+        let registry = Registry::new(5, 2, metrics.clone());
+        let rate_limited = Arc::new(InMemoryRateLimit::new(3, 10));
+
+        Self {
+            received_messages: Arc::new(Mutex::new(HashMap::new())),
+            clients_failed_to_connect: Arc::new(Mutex::new(HashMap::new())),
+            current_client_id: 0,
+            cancel_token: CancellationToken::new(),
+            server: Server::new(
+                addr,
+                registry.clone(),
+                metrics,
+                rate_limited,
+                "header".to_string(),
+                vec![Arc::new(UpstreamStatus::new(
+                    "ws://upstream.invalid".parse().unwrap(),
+                ))],
+                readiness,
+            ),
+            server_addr: addr,
+            client_id_to_handle: HashMap::new(),
+            registry,
+        }
+    }
+
+    /// Reconfigures the server before it is started, e.g. with [`Server::with_relay_token`].
+    pub fn with_server(mut self, configure: impl FnOnce(Server) -> Server) -> Self {
+        self.server = configure(self.server.clone());
+        self
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.server_addr
+    }
+
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// Cancelled when the harness is dropped, stopping the server.
+    pub fn cancel_token(&self) -> CancellationToken {
+        self.cancel_token.clone()
+    }
+
+    pub async fn healthcheck(&self) -> Result<(), Box<dyn Error>> {
+        let url = format!("http://{}/healthz", self.server_addr);
+        let response = reqwest::get(url).await?;
+        match response.error_for_status() {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Starts the server and waits for it to pass its health check.
+    pub async fn start_server(&mut self) {
+        let cancel_token = self.cancel_token.clone();
+        let server = self.server.clone();
+
+        tokio::spawn(async move {
+            server.listen(cancel_token).await;
+        });
+
+        let mut healthy = false;
+        for _ in 0..20 {
+            if self.healthcheck().await.is_ok() {
+                healthy = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(25)).await;
+        }
+
+        assert!(healthy);
+    }
+
+    /// Connects a websocket client to `/ws` that records every message it receives, returning
+    /// its id.
+    pub fn connect_client(&mut self) -> usize {
+        let uri = format!("ws://{}/ws", self.server_addr);
+
+        let client_id = self.current_client_id;
+        self.current_client_id += 1;
+
+        let results = self.received_messages.clone();
+        let failed_conns = self.clients_failed_to_connect.clone();
+
+        let handle = tokio::spawn(async move {
+            let (ws_stream, _) = match connect_async(uri).await {
+                Ok(results) => results,
+                Err(_) => {
+                    failed_conns.lock().unwrap().insert(client_id, true);
+                    return;
+                }
+            };
+
+            let (_, mut read) = ws_stream.split();
+
+            while let Some(msg) = read.next().await {
+                match msg {
+                    Ok(msg) => {
+                        results
+                            .lock()
+                            .unwrap()
+                            .entry(client_id)
+                            .or_default()
+                            .push(msg.to_string());
+                    }
+                    Err(e) => {
+                        error!(message = "error receiving message", error = e.to_string());
+                    }
+                }
+            }
+        });
+
+        self.client_id_to_handle.insert(client_id, handle);
+        client_id
+    }
+
+    /// Whether the client was refused a connection.
+    pub fn client_failed_to_connect(&self, client_id: usize) -> bool {
+        self.clients_failed_to_connect
+            .lock()
+            .unwrap()
+            .get(&client_id)
+            .copied()
+            .unwrap_or(false)
+    }
+
+    /// Waits for `count` clients to be registered with the server.
+    pub async fn wait_for_clients(&self, count: usize) {
+        for _ in 0..40 {
+            if self.registry.client_count() == count {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(25)).await;
+        }
+        assert_eq!(self.registry.client_count(), count);
+    }
+
+    /// Publishes each message to the connected clients, as if received from upstream.
+    pub fn send_messages(&mut self, messages: Vec<&str>) {
+        let messages: Vec<String> = messages.into_iter().map(String::from).collect();
+
+        for message in messages.iter() {
+            assert!(self.registry.publish(message.clone().into()) > 0);
+        }
+    }
+
+    /// Waits for every client's queue to be written out.
+    pub async fn wait_for_messages_to_drain(&mut self) {
+        let mut drained = false;
+        for _ in 0..5 {
+            let len = self.registry.queued_messages();
+            if len > 0 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                continue;
+            } else {
+                drained = true;
+                break;
+            }
+        }
+        assert!(drained);
+    }
+
+    pub fn messages_for_client(&mut self, client_id: usize) -> Vec<String> {
+        match self.received_messages.lock().unwrap().get(&client_id) {
+            Some(messages) => messages.clone(),
+            None => vec![],
+        }
+    }
+
+    /// Drops the client's connection without closing it cleanly.
+    pub async fn stop_client(&mut self, client_id: usize) {
+        if let Some(handle) = self.client_id_to_handle.remove(&client_id) {
+            handle.abort();
+            _ = handle.await;
+        } else {
+            panic!("no client with id {client_id}")
+        }
+    }
+}
+
+impl Drop for TestHarness {
+    fn drop(&mut self) {
+        self.cancel_token.cancel();
+        for handle in self.client_id_to_handle.values() {
+            handle.abort();
+        }
+    }
+}
+
+/// Serves synthetic flashblocks every `interval_ms` on a free port until the runtime shuts down,
+/// returning its address.
+pub async fn spawn_mock_upstream(interval_ms: u64) -> SocketAddr {
+    let listen_addr = TestHarness::alloc_port().await;
+    tokio::spawn(mock_upstream::run(MockUpstreamArgs {
+        listen_addr,
+        interval_ms,
+        transactions: 1,
+        gap_every: 0,
+        disconnect_after: 0,
+    }));
+
+    for _ in 0..40 {
+        if tokio::net::TcpStream::connect(listen_addr).await.is_ok() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(25)).await;
+    }
+    listen_addr
+}
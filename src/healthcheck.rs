@@ -0,0 +1,92 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio_tungstenite::connect_async;
+
+#[derive(clap::Args, Debug)]
+pub struct HealthcheckArgs {
+    /// Address the proxy listens on; a wildcard address is probed on loopback
+    #[arg(long, env = "LISTEN_ADDR", default_value = "127.0.0.1:8545")]
+    pub addr: SocketAddr,
+
+    /// Probe readiness on /readyz instead of liveness on /livez
+    #[arg(long)]
+    pub ready: bool,
+
+    /// Also open and close a websocket connection to the proxy
+    #[arg(long)]
+    pub websocket: bool,
+
+    /// Seconds to wait for each probe
+    #[arg(long, default_value = "5")]
+    pub timeout: u64,
+}
+
+/// Checks that the proxy at `args.addr` reports itself live, or ready with `args.ready`, returning
+/// a description of the failure if not.
+pub async fn run(args: HealthcheckArgs) -> Result<(), String> {
+    let addr = local_addr(args.addr);
+    let timeout = Duration::from_secs(args.timeout);
+
+    let client = reqwest::Client::builder()
+        .timeout(timeout)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let endpoint = if args.ready { "readyz" } else { "livez" };
+    let response = client
+        .get(format!("http://{addr}/{endpoint}"))
+        .send()
+        .await
+        .map_err(|e| format!("{endpoint}: {e}"))?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        return Err(format!("{endpoint}: {status} {body}"));
+    }
+
+    if args.websocket {
+        match tokio::time::timeout(timeout, connect_async(format!("ws://{addr}/ws"))).await {
+            Ok(Ok((mut stream, _))) => {
+                let _ = stream.close(None).await;
+            }
+            Ok(Err(e)) => return Err(format!("websocket: {e}")),
+            Err(_) => return Err(format!("websocket: no handshake within {timeout:?}")),
+        }
+    }
+
+    Ok(())
+}
+
+/// Address to connect to in order to reach a proxy listening on `listen_addr`, replacing a
+/// wildcard address with loopback.
+pub(crate) fn local_addr(listen_addr: SocketAddr) -> SocketAddr {
+    match listen_addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_addr.port())
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            SocketAddr::new(Ipv6Addr::LOCALHOST.into(), listen_addr.port())
+        }
+        _ => listen_addr,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_addr() {
+        assert_eq!(
+            local_addr("0.0.0.0:8545".parse().unwrap()),
+            "127.0.0.1:8545".parse().unwrap()
+        );
+        assert_eq!(
+            local_addr("[::]:8545".parse().unwrap()),
+            "[::1]:8545".parse().unwrap()
+        );
+        assert_eq!(
+            local_addr("10.0.0.1:8545".parse().unwrap()),
+            "10.0.0.1:8545".parse().unwrap()
+        );
+    }
+}
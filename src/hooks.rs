@@ -0,0 +1,42 @@
+//! Callbacks into the lifecycle of websocket clients, for embedders that need their own
+//! accounting, billing or abuse detection. Register an implementation with
+//! [`crate::server::Server::with_hooks`] or [`crate::proxy::ProxyBuilder::hooks`].
+//!
+//! Hooks are called inline, on the request or on the client's task, so they must return quickly:
+//! anything slow should be handed off to a channel or spawned task. gRPC subscribers aren't
+//! reported.
+
+use crate::client::ClientConnection;
+use crate::metrics::DisconnectReason;
+use crate::registry::BroadcastMessage;
+use http::HeaderMap;
+use std::net::IpAddr;
+
+/// Every method does nothing by default, so implementations only override what they need.
+pub trait ConnectionHooks: Send + Sync {
+    /// A client asked to connect, before any of the checks that may refuse it. `stream` is the
+    /// named stream requested, if any.
+    fn on_connect(&self, _client: IpAddr, _stream: Option<&str>, _headers: &HeaderMap) {}
+
+    /// A client passed every check and is about to be upgraded to a websocket.
+    fn on_authenticated(&self, _client: IpAddr, _stream: Option<&str>, _headers: &HeaderMap) {}
+
+    /// `message` was written to the client. `connection_id` is the one in the client's audit
+    /// events.
+    fn on_message_sent(
+        &self,
+        _connection_id: u64,
+        _client: &ClientConnection,
+        _message: &BroadcastMessage,
+    ) {
+    }
+
+    /// The client disconnected. Its totals are in [`ClientConnection::stats`].
+    fn on_disconnect(
+        &self,
+        _connection_id: u64,
+        _client: &ClientConnection,
+        _reason: DisconnectReason,
+    ) {
+    }
+}
@@ -1,214 +1,1220 @@
 mod test {
+    use crate::auth::BasicAuth;
+    use crate::authorizer::{Authorizer, AuthorizerConfig};
+    use crate::ban::BanConfig;
+    use crate::client::{ClientConnection, HeartbeatConfig};
+    use crate::envelope;
+    use crate::grpc;
+    use crate::handshake::HandshakeConfig;
+    use crate::harness::{spawn_mock_upstream, TestHarness};
+    use crate::healthcheck::{self, HealthcheckArgs};
+    use crate::hooks::ConnectionHooks;
+    use crate::loadtest::{self, LoadTestArgs};
+    use crate::metrics::DisconnectReason;
     use crate::metrics::Metrics;
-    use crate::rate_limit::InMemoryRateLimit;
-    use crate::registry::Registry;
-    use crate::server::Server;
+    use crate::priority::PriorityConfig;
+    use crate::proxy::ProxyBuilder;
+    use crate::registry::{BroadcastMessage, Registry};
+    use crate::server::{self, ReadinessConfig};
+    use crate::streams::Stream;
+    use crate::subscriber::WebsocketSubscriber;
+    #[cfg(feature = "webtransport")]
+    use crate::webtransport::WebTransportConfig;
     use futures::StreamExt;
-    use std::collections::hash_map::Entry;
-    use std::collections::HashMap;
-    use std::error::Error;
-    use std::net::SocketAddr;
+    use http::HeaderMap;
+    use hyper::client::conn::http2;
+    use hyper::ext::Protocol;
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use std::net::{IpAddr, SocketAddr};
+    use std::os::fd::IntoRawFd;
     use std::sync::{Arc, Mutex};
     use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
     use tokio::net::TcpListener;
-    use tokio::sync::broadcast;
-    use tokio::sync::broadcast::Sender;
-    use tokio::task::JoinHandle;
     use tokio_tungstenite::connect_async;
+    use tokio_tungstenite::tungstenite;
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio_tungstenite::tungstenite::protocol::Role;
+    use tokio_tungstenite::WebSocketStream;
     use tokio_util::sync::CancellationToken;
-    use tracing::error;
-
-    struct TestHarness {
-        received_messages: Arc<Mutex<HashMap<usize, Vec<String>>>>,
-        clients_failed_to_connect: Arc<Mutex<HashMap<usize, bool>>>,
-        current_client_id: usize,
-        cancel_token: CancellationToken,
-        server: Server,
-        server_addr: SocketAddr,
-        client_id_to_handle: HashMap<usize, JoinHandle<()>>,
-        sender: Sender<String>,
-    }
-
-    impl TestHarness {
-        async fn alloc_port() -> SocketAddr {
-            let address = SocketAddr::from(([127, 0, 0, 1], 0));
-            let listener = TcpListener::bind(&address).await.unwrap();
-            listener.local_addr().unwrap()
-        }
-        fn new(addr: SocketAddr) -> TestHarness {
-            let (sender, _) = broadcast::channel(5);
-            let metrics = Arc::new(Metrics::default());
-            let registry = Registry::new(sender.clone(), metrics.clone());
-            let rate_limited = Arc::new(InMemoryRateLimit::new(3, 10));
-
-            Self {
-                received_messages: Arc::new(Mutex::new(HashMap::new())),
-                clients_failed_to_connect: Arc::new(Mutex::new(HashMap::new())),
-                current_client_id: 0,
-                cancel_token: CancellationToken::new(),
-                server: Server::new(
-                    addr.into(),
-                    registry,
-                    metrics,
-                    rate_limited,
-                    "header".to_string(),
-                ),
-                server_addr: addr,
-                client_id_to_handle: HashMap::new(),
-                sender,
+
+    #[tokio::test]
+    async fn test_healthcheck() {
+        let addr = TestHarness::alloc_port().await;
+        let mut harness = TestHarness::new(addr);
+        assert!(harness.healthcheck().await.is_err());
+        harness.start_server().await;
+        assert!(harness.healthcheck().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_readiness() {
+        let addr = TestHarness::alloc_port().await;
+        let mut harness = TestHarness::with_readiness(
+            addr,
+            ReadinessConfig {
+                max_upstream_age: None,
+                max_capacity: Some(0.5),
+            },
+        );
+        harness.start_server().await;
+
+        let readyz = format!("http://{}/readyz", addr);
+        let livez = format!("http://{}/livez", addr);
+        assert_eq!(reqwest::get(&readyz).await.unwrap().status(), 200);
+
+        // Two of the three connections allowed by the harness are over the capacity threshold
+        harness.connect_client();
+        harness.connect_client();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(reqwest::get(&readyz).await.unwrap().status(), 503);
+        assert_eq!(reqwest::get(&livez).await.unwrap().status(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_readiness_fails_on_shutdown_notice() {
+        let addr = TestHarness::alloc_port().await;
+        let notice = CancellationToken::new();
+        let mut harness = TestHarness::new(addr)
+            .with_server(|server| server.with_shutdown_notice(notice.clone()));
+        harness.start_server().await;
+
+        let readyz = format!("http://{}/readyz", addr);
+        assert_eq!(reqwest::get(&readyz).await.unwrap().status(), 200);
+
+        // Still serving, but no longer ready
+        notice.cancel();
+        let response = reqwest::get(&readyz).await.unwrap();
+        assert_eq!(response.status(), 503);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["reasons"][0], "shutting down");
+
+        let client = harness.connect_client();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        harness.send_messages(vec!["one"]);
+        harness.wait_for_messages_to_drain().await;
+        assert_eq!(vec!["one"], harness.messages_for_client(client));
+    }
+
+    #[tokio::test]
+    async fn test_healthcheck_subcommand() {
+        let addr = TestHarness::alloc_port().await;
+        let notice = CancellationToken::new();
+        let mut harness = TestHarness::new(addr)
+            .with_server(|server| server.with_shutdown_notice(notice.clone()));
+        harness.start_server().await;
+        notice.cancel();
+
+        // Liveness passes while the proxy isn't ready
+        let args = |ready| HealthcheckArgs {
+            addr,
+            ready,
+            websocket: false,
+            timeout: 5,
+        };
+        assert!(healthcheck::run(args(false)).await.is_ok());
+        assert!(healthcheck::run(args(true))
+            .await
+            .unwrap_err()
+            .starts_with("readyz: 503"));
+    }
+
+    #[tokio::test]
+    async fn test_readiness_requires_fresh_upstream() {
+        let addr = TestHarness::alloc_port().await;
+        let mut harness = TestHarness::with_readiness(
+            addr,
+            ReadinessConfig {
+                max_upstream_age: Some(Duration::from_secs(10)),
+                max_capacity: None,
+            },
+        );
+        harness.start_server().await;
+
+        let response = reqwest::get(format!("http://{}/readyz", addr))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 503);
+
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["ready"], false);
+        assert_eq!(
+            body["reasons"][0],
+            "no upstream has delivered a recent message"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_status() {
+        let addr = TestHarness::alloc_port().await;
+        let mut harness = TestHarness::new(addr);
+        harness.start_server().await;
+
+        harness.connect_client();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let status: serde_json::Value = reqwest::get(format!("http://{}/status", addr))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+
+        assert_eq!(status["build"]["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(status["clients"]["active"], 1);
+        assert_eq!(status["buffer"]["size"], 5);
+        assert_eq!(status["rate_limit"]["active_connections"], 1);
+        assert_eq!(status["rate_limit"]["global_limit"], 3);
+        assert_eq!(status["upstreams"][0]["uri"], "ws://upstream.invalid/");
+        assert_eq!(status["upstreams"][0]["connected"], false);
+        assert!(status["upstreams"][0]["last_message_age_ms"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_clients_receive_messages() {
+        let addr = TestHarness::alloc_port().await;
+
+        let mut harness = TestHarness::new(addr);
+        harness.start_server().await;
+
+        let client_one = harness.connect_client();
+        let client_two = harness.connect_client();
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        harness.send_messages(vec!["one", "two"]);
+        harness.wait_for_messages_to_drain().await;
+
+        assert_eq!(vec!["one", "two"], harness.messages_for_client(client_one));
+        assert_eq!(vec!["one", "two"], harness.messages_for_client(client_two));
+    }
+
+    #[tokio::test]
+    async fn test_named_streams() {
+        let addr = TestHarness::alloc_port().await;
+        let raw = Registry::new(5, 1, Arc::new(Metrics::default()));
+        let mut harness = TestHarness::new(addr).with_server(|server| {
+            server.with_stream("raw", Stream::new(raw.clone()).with_max_connections(1))
+        });
+        harness.start_server().await;
+
+        match connect_async(format!("ws://{addr}/ws/unknown")).await {
+            Err(tokio_tungstenite::tungstenite::Error::Http(response)) => {
+                assert_eq!(response.status(), 404)
             }
+            other => panic!("expected 404, got {other:?}"),
+        }
+
+        let (mut stream, _) = connect_async(format!("ws://{addr}/ws/raw")).await.unwrap();
+        let default = harness.connect_client();
+        harness.wait_for_clients(1).await;
+        while raw.client_count() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
         }
 
-        async fn healthcheck(&self) -> Result<(), Box<dyn Error>> {
-            let url = format!("http://{}/healthz", self.server_addr);
-            let response = reqwest::get(url).await?;
-            match response.error_for_status() {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e.into()),
+        // Each stream only sees its own messages
+        raw.publish("raw".into());
+        harness.send_messages(vec!["default"]);
+        harness.wait_for_messages_to_drain().await;
+
+        let msg = tokio::time::timeout(Duration::from_secs(5), stream.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(msg.into_data(), "raw");
+        assert_eq!(harness.messages_for_client(default), vec!["default"]);
+
+        // The stream's connection limit is separate from the global one
+        assert!(connect_async(format!("ws://{addr}/ws/raw")).await.is_err());
+
+        let status: serde_json::Value = reqwest::get(format!("http://{addr}/status"))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(status["streams"]["raw"]["clients"], 1);
+        assert_eq!(status["streams"]["raw"]["max_connections"], 1);
+    }
+
+    #[cfg(feature = "webtransport")]
+    #[tokio::test]
+    async fn test_webtransport() {
+        use wtransport::{ClientConfig, Endpoint, Identity};
+
+        let addr = TestHarness::alloc_port().await;
+        let webtransport_addr = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let identity = Identity::self_signed(["localhost", "127.0.0.1"]).unwrap();
+        let certificate_hash = identity.certificate_chain().as_slice()[0].hash();
+        let mut harness = TestHarness::new(addr).with_server(|server| {
+            server.with_webtransport(WebTransportConfig {
+                listen_addr: webtransport_addr,
+                identity: Arc::new(identity),
+            })
+        });
+        harness.start_server().await;
+
+        let client = Endpoint::client(
+            ClientConfig::builder()
+                .with_bind_default()
+                .with_server_certificate_hashes([certificate_hash])
+                .build(),
+        )
+        .unwrap();
+        let url = |path: &str| format!("https://127.0.0.1:{}{path}", webtransport_addr.port());
+
+        assert!(client.connect(url("/ws/unknown")).await.is_err());
+
+        let connection = client.connect(url("/ws")).await.unwrap();
+        harness.wait_for_clients(1).await;
+        harness.send_messages(vec!["one", "two"]);
+
+        // Each message arrives on its own stream
+        for expected in ["one", "two"] {
+            let mut stream = tokio::time::timeout(Duration::from_secs(5), connection.accept_uni())
+                .await
+                .unwrap()
+                .unwrap();
+            let mut received = Vec::new();
+            let mut buf = [0; 1024];
+            while let Some(read) = stream.read(&mut buf).await.unwrap() {
+                received.extend_from_slice(&buf[..read]);
             }
+            assert_eq!(received, expected.as_bytes());
         }
 
-        async fn start_server(&mut self) {
-            let cancel_token = self.cancel_token.clone();
-            let server = self.server.clone();
+        // Closing the session ends the subscription
+        connection.close(0u32.into(), b"");
+        harness.wait_for_clients(0).await;
+    }
+
+    #[cfg(feature = "acme")]
+    #[tokio::test]
+    async fn test_acme() {
+        use crate::acme::AcmeConfig;
+        use crate::rate_limit::InMemoryRateLimit;
+        use base64::prelude::*;
+        use tokio_rustls::rustls::pki_types::ServerName;
+        use tokio_rustls::rustls::{crypto, ClientConfig, RootCertStore};
+        use tokio_rustls::TlsConnector;
 
-            // todo!
-            let _server_handle = tokio::spawn(async move {
-                _ = server.listen(cancel_token).await;
-            });
+        // A certificate already in the cache is served without asking the CA, which isn't
+        // reachable here. The cache names the file from the domains and directory.
+        let directory = "https://127.0.0.1:1/directory";
+        let cache_dir = std::env::temp_dir().join(format!("acme-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&cache_dir).unwrap();
+        let mut digest = ring::digest::Context::new(&ring::digest::SHA256);
+        digest.update(b"localhost\0");
+        digest.update(directory.as_bytes());
+        let cert_file = format!(
+            "cached_cert_{}",
+            BASE64_URL_SAFE_NO_PAD.encode(digest.finish())
+        );
+        let certified = rcgen::generate_simple_self_signed(["localhost".to_string()]).unwrap();
+        std::fs::write(
+            cache_dir.join(cert_file),
+            format!(
+                "{}{}",
+                certified.key_pair.serialize_pem(),
+                certified.cert.pem()
+            ),
+        )
+        .unwrap();
+
+        let addr = TestHarness::alloc_port().await;
+        let metrics = Arc::new(Metrics::default());
+        let registry = Registry::new(5, 1, metrics.clone());
+        let server = server::Server::new(
+            addr,
+            registry.clone(),
+            metrics,
+            Arc::new(InMemoryRateLimit::new(3, 10)),
+            "header".to_string(),
+            Vec::new(),
+            ReadinessConfig::default(),
+        )
+        .with_acme(AcmeConfig {
+            domains: vec!["localhost".to_string()],
+            cache_dir: cache_dir.clone(),
+            contacts: Vec::new(),
+            directory: directory.to_string(),
+        });
+        let token = CancellationToken::new();
+        tokio::spawn({
+            let token = token.clone();
+            async move { server.listen(token).await }
+        });
+
+        let mut roots = RootCertStore::empty();
+        roots.add(certified.cert.der().clone()).unwrap();
+        let tls = ClientConfig::builder_with_provider(Arc::new(crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(tls));
+
+        // The cached certificate is loaded in the background, so early handshakes may fail
+        let mut websocket = None;
+        for _ in 0..50 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let Ok(tcp) = tokio::net::TcpStream::connect(addr).await else {
+                continue;
+            };
+            let server_name = ServerName::try_from("localhost").unwrap();
+            let Ok(stream) = connector.connect(server_name, tcp).await else {
+                continue;
+            };
+            let (stream, _) = tokio_tungstenite::client_async("wss://localhost/ws", stream)
+                .await
+                .unwrap();
+            websocket = Some(stream);
+            break;
+        }
+        let mut websocket = websocket.expect("TLS handshake never succeeded");
+
+        while registry.client_count() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        registry.publish("one".into());
+        let message = websocket.next().await.unwrap().unwrap();
+        assert_eq!(message.into_data(), "one".as_bytes());
+
+        token.cancel();
+        std::fs::remove_dir_all(cache_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stream_limit_concurrent_handshakes() {
+        let addr = TestHarness::alloc_port().await;
+        let raw = Registry::new(5, 1, Arc::new(Metrics::default()));
+        let mut harness = TestHarness::new(addr).with_server(|server| {
+            server.with_stream("raw", Stream::new(raw.clone()).with_max_connections(1))
+        });
+        harness.start_server().await;
 
-            let mut healthy = true;
-            for _ in 0..5 {
-                let resp = self.healthcheck().await;
-                match resp {
-                    Ok(_) => {
-                        healthy = true;
-                        break;
-                    }
-                    Err(_) => {
-                        tokio::time::sleep(Duration::from_millis(25)).await;
-                    }
+        // None of the handshakes has registered a client when the others are admitted
+        let results =
+            futures::future::join_all((0..3).map(|_| connect_async(format!("ws://{addr}/ws/raw"))))
+                .await;
+        let mut connected = Vec::new();
+        for result in results {
+            match result {
+                Ok((stream, _)) => connected.push(stream),
+                Err(tokio_tungstenite::tungstenite::Error::Http(response)) => {
+                    assert_eq!(response.status(), 429)
                 }
+                Err(e) => panic!("expected 429, got {e:?}"),
             }
+        }
+        assert_eq!(connected.len(), 1);
 
-            assert!(healthy);
-        }
-
-        fn connect_client(&mut self) -> usize {
-            let uri = format!("ws://{}/ws", self.server_addr);
-
-            let client_id = self.current_client_id;
-            self.current_client_id += 1;
-
-            let results = self.received_messages.clone();
-            let failed_conns = self.clients_failed_to_connect.clone();
-
-            let handle = tokio::spawn(async move {
-                let (ws_stream, _) = match connect_async(uri).await {
-                    Ok(results) => results,
-                    Err(_) => {
-                        failed_conns.lock().unwrap().insert(client_id, true);
-                        return;
-                    }
-                };
-
-                let (_, mut read) = ws_stream.split();
-
-                loop {
-                    match read.next().await {
-                        Some(Ok(msg)) => {
-                            match results.lock().unwrap().entry(client_id.clone()) {
-                                Entry::Occupied(o) => {
-                                    o.into_mut().push(msg.to_string());
-                                }
-                                Entry::Vacant(v) => {
-                                    v.insert(vec![msg.to_string()]);
-                                }
-                            };
-                        }
-                        Some(Err(e)) => {
-                            error!(message = "error receiving message", error = e.to_string());
-                        }
-                        None => {}
-                    }
-                }
+        // The slot is freed when the client disconnects
+        while raw.client_count() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        drop(connected);
+
+        // It takes a couple of messages for dead clients to disconnect
+        while raw.client_count() > 0 {
+            raw.publish("raw".into());
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(connect_async(format!("ws://{addr}/ws/raw")).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_pong_timeout() {
+        let addr = TestHarness::alloc_port().await;
+        let registry =
+            Registry::new(5, 1, Arc::new(Metrics::default())).with_heartbeat(HeartbeatConfig {
+                ping_interval: Duration::from_millis(100),
+                pong_timeout: Duration::from_millis(50),
+                max_missed_pongs: 1,
             });
+        let mut harness = TestHarness::new(addr)
+            .with_server(|server| server.with_stream("pinged", Stream::new(registry.clone())));
+        harness.start_server().await;
+
+        // Reading answers pings, so only the client that never reads is disconnected
+        let (answering, _) = connect_async(format!("ws://{addr}/ws/pinged"))
+            .await
+            .unwrap();
+        let (_silent, _) = connect_async(format!("ws://{addr}/ws/pinged"))
+            .await
+            .unwrap();
+        let reader = tokio::spawn(answering.for_each(|_| async {}));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(registry.client_count(), 2);
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(registry.client_count(), 1);
+        reader.abort();
+    }
+
+    #[tokio::test]
+    async fn test_basic_auth() {
+        let addr = TestHarness::alloc_port().await;
+        let auth = BasicAuth::parse("ops:hunter2").unwrap();
+        let mut harness = TestHarness::new(addr).with_server(|server| server.with_basic_auth(auth));
+        harness.start_server().await;
+
+        let client = harness.connect_client();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(harness.client_failed_to_connect(client));
+
+        let mut request = format!("ws://{addr}/ws").into_client_request().unwrap();
+        request
+            .headers_mut()
+            .insert("Authorization", "Basic b3BzOmh1bnRlcjI=".parse().unwrap());
+        let (_stream, _) = connect_async(request).await.unwrap();
+        harness.wait_for_clients(1).await;
+    }
 
-            self.client_id_to_handle.insert(client_id, handle);
-            client_id
+    #[tokio::test]
+    async fn test_auto_ban() {
+        let addr = TestHarness::alloc_port().await;
+        let auth = BasicAuth::parse("ops:hunter2").unwrap();
+        let mut harness = TestHarness::new(addr).with_server(|server| {
+            server.with_basic_auth(auth).with_auto_ban(BanConfig {
+                threshold: 2,
+                window: Duration::from_secs(60),
+                duration: Duration::from_secs(60),
+                max_duration: Duration::from_secs(60),
+            })
+        });
+        harness.start_server().await;
+
+        let status = |result: Result<_, tungstenite::Error>| match result {
+            Err(tungstenite::Error::Http(response)) => response.status().as_u16(),
+            _ => panic!("expected the handshake to be refused"),
+        };
+        for _ in 0..2 {
+            assert_eq!(status(connect_async(format!("ws://{addr}/ws")).await), 401);
         }
 
-        fn send_messages(&mut self, messages: Vec<&str>) {
-            let messages: Vec<String> = messages.into_iter().map(String::from).collect();
+        // Banned, even with the right credentials
+        let mut request = format!("ws://{addr}/ws").into_client_request().unwrap();
+        request
+            .headers_mut()
+            .insert("Authorization", "Basic b3BzOmh1bnRlcjI=".parse().unwrap());
+        assert_eq!(status(connect_async(request).await), 403);
+    }
 
-            for message in messages.iter() {
-                match self.sender.send(message.clone()) {
-                    Ok(_) => {}
-                    Err(_) => {
-                        assert!(false)
-                    }
-                }
-            }
+    #[tokio::test]
+    async fn test_auto_ban_stream_limit() {
+        let addr = TestHarness::alloc_port().await;
+        let raw = Registry::new(5, 1, Arc::new(Metrics::default()));
+        let mut harness = TestHarness::new(addr).with_server(|server| {
+            server
+                .with_stream("raw", Stream::new(raw.clone()).with_max_connections(0))
+                .with_auto_ban(BanConfig {
+                    threshold: 2,
+                    window: Duration::from_secs(60),
+                    duration: Duration::from_secs(60),
+                    max_duration: Duration::from_secs(60),
+                })
+        });
+        harness.start_server().await;
+
+        let status = |result: Result<_, tungstenite::Error>| match result {
+            Err(tungstenite::Error::Http(response)) => response.status().as_u16(),
+            _ => panic!("expected the handshake to be refused"),
+        };
+        for _ in 0..2 {
+            assert_eq!(
+                status(connect_async(format!("ws://{addr}/ws/raw")).await),
+                429
+            );
         }
 
-        async fn wait_for_messages_to_drain(&mut self) {
-            let mut drained = false;
-            for _ in 0..5 {
-                let len = self.sender.len();
-                if len > 0 {
-                    tokio::time::sleep(Duration::from_millis(5)).await;
-                    continue;
-                } else {
-                    drained = true;
-                    break;
-                }
+        // Refusals at a stream's connection limit count towards a ban like any other 429
+        assert_eq!(
+            status(connect_async(format!("ws://{addr}/ws/raw")).await),
+            403
+        );
+        assert_eq!(status(connect_async(format!("ws://{addr}/ws")).await), 403);
+    }
+
+    #[tokio::test]
+    async fn test_authorizer() {
+        let router = axum::Router::new().fallback(
+            async |axum::Json(request): axum::Json<serde_json::Value>| {
+                let allow = request["headers"]["x-client-name"] == "indexer";
+                axum::Json(serde_json::json!({"allow": allow}))
+            },
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let authorizer_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let addr = TestHarness::alloc_port().await;
+        let authorizer = Authorizer::new(AuthorizerConfig {
+            url: format!("http://{authorizer_addr}/authorize"),
+            timeout: Duration::from_secs(5),
+            cache_ttl: Duration::from_secs(60),
+            fail_open: false,
+        });
+        let mut harness =
+            TestHarness::new(addr).with_server(|server| server.with_authorizer(authorizer));
+        harness.start_server().await;
+
+        let client = harness.connect_client();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(harness.client_failed_to_connect(client));
+
+        let mut request = format!("ws://{addr}/ws").into_client_request().unwrap();
+        request
+            .headers_mut()
+            .insert("X-Client-Name", "indexer".parse().unwrap());
+        let (_stream, _) = connect_async(request).await.unwrap();
+        harness.wait_for_clients(1).await;
+    }
+
+    #[derive(Default)]
+    struct RecordingHooks {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl ConnectionHooks for RecordingHooks {
+        fn on_connect(&self, _client: IpAddr, _stream: Option<&str>, _headers: &HeaderMap) {
+            self.events.lock().unwrap().push("connect".to_string());
+        }
+
+        fn on_authenticated(&self, _client: IpAddr, _stream: Option<&str>, headers: &HeaderMap) {
+            let name = headers["X-Client-Name"].to_str().unwrap();
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("authenticated {name}"));
+        }
+
+        fn on_message_sent(&self, _: u64, _: &ClientConnection, message: &BroadcastMessage) {
+            let event = format!("sent {} bytes", message.size);
+            self.events.lock().unwrap().push(event);
+        }
+
+        fn on_disconnect(&self, _: u64, _: &ClientConnection, reason: DisconnectReason) {
+            let event = format!("disconnect {}", reason.as_str());
+            self.events.lock().unwrap().push(event);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hooks() {
+        let addr = TestHarness::alloc_port().await;
+        let hooks = Arc::new(RecordingHooks::default());
+        let auth = BasicAuth::parse("ops:hunter2").unwrap();
+        let mut harness = TestHarness::new(addr)
+            .with_server(|server| server.with_basic_auth(auth).with_hooks(hooks.clone()));
+        harness.start_server().await;
+
+        // Refused by basic auth, so only asked to connect
+        let client = harness.connect_client();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(harness.client_failed_to_connect(client));
+
+        let mut request = format!("ws://{addr}/ws").into_client_request().unwrap();
+        let headers = request.headers_mut();
+        headers.insert("Authorization", "Basic b3BzOmh1bnRlcjI=".parse().unwrap());
+        headers.insert("X-Client-Name", "indexer".parse().unwrap());
+        let (mut stream, _) = connect_async(request).await.unwrap();
+        harness.wait_for_clients(1).await;
+
+        harness.send_messages(vec!["one", "three"]);
+        for _ in 0..2 {
+            stream.next().await.unwrap().unwrap();
+        }
+        stream.close(None).await.unwrap();
+        drop(stream);
+
+        // It takes a couple of messages for dead clients to disconnect
+        for _ in 0..20 {
+            harness.send_messages(vec!["four"]);
+            harness.wait_for_messages_to_drain().await;
+            if harness.registry().client_count() == 0 {
+                break;
             }
-            assert!(drained);
         }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let events = hooks.events.lock().unwrap();
+        assert_eq!(
+            events[..5],
+            [
+                "connect",
+                "connect",
+                "authenticated indexer",
+                "sent 3 bytes",
+                "sent 5 bytes",
+            ]
+        );
+        assert!(
+            events.last().unwrap().starts_with("disconnect"),
+            "{events:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_closes_clients() {
+        let addr = TestHarness::alloc_port().await;
+        let hooks = Arc::new(RecordingHooks::default());
+        let mut harness =
+            TestHarness::new(addr).with_server(|server| server.with_hooks(hooks.clone()));
+        harness.start_server().await;
+
+        let mut request = format!("ws://{addr}/ws").into_client_request().unwrap();
+        request
+            .headers_mut()
+            .insert("X-Client-Name", "indexer".parse().unwrap());
+        let (mut stream, _) = connect_async(request).await.unwrap();
+        harness.wait_for_clients(1).await;
+        harness.cancel_token().cancel();
 
-        fn messages_for_client(&mut self, client_id: usize) -> Vec<String> {
-            match self.received_messages.lock().unwrap().get(&client_id) {
-                Some(messages) => messages.clone(),
-                None => vec![],
+        match stream.next().await.unwrap().unwrap() {
+            tungstenite::Message::Close(Some(frame)) => {
+                assert_eq!(
+                    frame.code,
+                    tungstenite::protocol::frame::coding::CloseCode::Away
+                )
             }
+            frame => panic!("expected a close frame, got {frame:?}"),
         }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(
+            hooks.events.lock().unwrap().last().unwrap(),
+            "disconnect shutdown"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sampling() {
+        let addr = TestHarness::alloc_port().await;
+        let mut harness = TestHarness::new(addr);
+        harness.start_server().await;
+
+        assert!(connect_async(format!("ws://{addr}/ws?sample=half"))
+            .await
+            .is_err());
+
+        let (mut stream, _) = connect_async(format!("ws://{addr}/ws?sample=2"))
+            .await
+            .unwrap();
+        harness.wait_for_clients(1).await;
 
-        async fn stop_client(&mut self, client_id: usize) {
-            if let Some(handle) = self.client_id_to_handle.remove(&client_id) {
-                handle.abort();
-                _ = handle.await;
-            } else {
-                assert!(false)
+        harness.send_messages(vec!["one", "two", "three", "four", "five"]);
+        let mut received = Vec::new();
+        for _ in 0..3 {
+            let message = stream.next().await.unwrap().unwrap();
+            received.push(String::from_utf8(message.into_data().to_vec()).unwrap());
+        }
+        assert_eq!(received, ["one", "three", "five"]);
+    }
+
+    #[cfg(feature = "wasm")]
+    #[tokio::test]
+    async fn test_wasm_transforms() {
+        use crate::transform::{Plugin, Plugins};
+        use crate::wasm::{WasmLimits, WasmPlugin};
+
+        // Drops messages starting with '-'
+        const MODULE: &str = r#"
+            (module
+              (memory (export "memory") 1)
+              (func (export "alloc") (param $len i32) (result i32)
+                (i32.const 0))
+              (func (export "transform") (param $ptr i32) (param $len i32) (result i64)
+                (if (i32.eq (i32.load8_u (local.get $ptr)) (i32.const 45))
+                  (then (return (i64.const -1))))
+                (i64.extend_i32_u (local.get $len))))
+        "#;
+        let limits = WasmLimits {
+            fuel: 100_000,
+            max_memory_bytes: 1 << 20,
+        };
+        let plugin = WasmPlugin::new("dashes", MODULE.as_bytes(), limits).unwrap();
+
+        let addr = TestHarness::alloc_port().await;
+        let raw = Registry::new(5, 1, Arc::new(Metrics::default()))
+            .with_transform(plugin.instantiate().unwrap());
+        let transforms = Plugins::default().with_plugin("dashes", Arc::new(plugin));
+        let mut harness = TestHarness::new(addr).with_server(|server| {
+            server
+                .with_transforms(transforms)
+                .with_stream("raw", Stream::new(raw.clone()))
+        });
+        harness.start_server().await;
+
+        match connect_async(format!("ws://{addr}/ws?transform=unknown")).await {
+            Err(tungstenite::Error::Http(response)) => assert_eq!(response.status(), 400),
+            other => panic!("expected 400, got {other:?}"),
+        }
+
+        // On a client's egress
+        let (mut client, _) = connect_async(format!("ws://{addr}/ws?transform=dashes"))
+            .await
+            .unwrap();
+        harness.wait_for_clients(1).await;
+        harness.send_messages(vec!["-one", "two"]);
+        let message = client.next().await.unwrap().unwrap();
+        assert_eq!(message.into_data(), "two");
+
+        // On a stream's ingest
+        let (mut client, _) = connect_async(format!("ws://{addr}/ws/raw")).await.unwrap();
+        while raw.client_count() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        raw.publish("-three".into());
+        raw.publish("four".into());
+        let message = client.next().await.unwrap().unwrap();
+        assert_eq!(message.into_data(), "four");
+    }
+
+    #[cfg(feature = "scripting")]
+    #[tokio::test]
+    async fn test_script_transforms() {
+        use crate::scripting::{ScriptLimits, ScriptPlugin};
+        use crate::transform::Plugins;
+
+        const SCRIPT: &str = r#"
+            fn transform(message) {
+                if message.starts_with("-") { () } else { "tagged:" + message }
             }
+        "#;
+        let limits = ScriptLimits {
+            max_operations: 10_000,
+            max_string_bytes: 1024,
+            max_collection_len: 100,
+        };
+        let plugin = ScriptPlugin::new("tag", SCRIPT, limits).unwrap();
+
+        let addr = TestHarness::alloc_port().await;
+        let transforms = Plugins::default().with_plugin("tag", Arc::new(plugin));
+        let mut harness =
+            TestHarness::new(addr).with_server(|server| server.with_transforms(transforms));
+        harness.start_server().await;
+
+        let (mut tagged, _) = connect_async(format!("ws://{addr}/ws?transform=tag"))
+            .await
+            .unwrap();
+        let plain = harness.connect_client();
+        harness.wait_for_clients(2).await;
+
+        harness.send_messages(vec!["-one", "two"]);
+        harness.wait_for_messages_to_drain().await;
+        let message = tagged.next().await.unwrap().unwrap();
+        assert_eq!(message.into_data(), "tagged:two");
+        assert_eq!(harness.messages_for_client(plain), ["-one", "two"]);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_timeout() {
+        let addr = TestHarness::alloc_port().await;
+        let mut harness = TestHarness::new(addr).with_server(|server| {
+            server.with_handshake_limits(HandshakeConfig {
+                timeout: Some(Duration::from_millis(200)),
+                max_pending: None,
+                max_pending_per_ip: None,
+                http2: false,
+            })
+        });
+        harness.start_server().await;
+
+        let mut slow = tokio::net::TcpStream::connect(addr).await.unwrap();
+        slow.write_all(b"GET /ws HTTP/1.1\r\n").await.unwrap();
+        let client = harness.connect_client();
+        harness.wait_for_clients(1).await;
+
+        // The unfinished handshake is closed, and the websocket client is left alone
+        let closed = tokio::time::timeout(Duration::from_secs(2), slow.read(&mut [0; 64])).await;
+        assert!(matches!(closed, Ok(Ok(0)) | Ok(Err(_))));
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        harness.send_messages(vec!["one"]);
+        harness.wait_for_messages_to_drain().await;
+        assert_eq!(vec!["one"], harness.messages_for_client(client));
+    }
+
+    #[tokio::test]
+    async fn test_http2_websocket() {
+        let addr = TestHarness::alloc_port().await;
+        let mut harness = TestHarness::new(addr).with_server(|server| {
+            server.with_handshake_limits(HandshakeConfig {
+                timeout: None,
+                max_pending: None,
+                max_pending_per_ip: None,
+                http2: true,
+            })
+        });
+        harness.start_server().await;
+
+        let io = TokioIo::new(tokio::net::TcpStream::connect(addr).await.unwrap());
+        let (mut send_request, conn) = http2::Builder::new(TokioExecutor::new())
+            .handshake(io)
+            .await
+            .unwrap();
+        tokio::spawn(conn);
+
+        // Two websockets multiplexed over the one connection
+        let mut streams = Vec::new();
+        for _ in 0..2 {
+            let request = http::Request::builder()
+                .method(http::Method::CONNECT)
+                .extension(Protocol::from_static("websocket"))
+                .uri(format!("http://{addr}/ws"))
+                .header("sec-websocket-version", "13")
+                .body(axum::body::Body::empty())
+                .unwrap();
+            let mut response = send_request.send_request(request).await.unwrap();
+            assert_eq!(response.status(), http::StatusCode::OK);
+            let upgraded = hyper::upgrade::on(&mut response).await.unwrap();
+            streams.push(
+                WebSocketStream::from_raw_socket(TokioIo::new(upgraded), Role::Client, None).await,
+            );
+        }
+        let plain = harness.connect_client();
+        harness.wait_for_clients(3).await;
+
+        harness.send_messages(vec!["one"]);
+        harness.wait_for_messages_to_drain().await;
+        assert_eq!(vec!["one"], harness.messages_for_client(plain));
+        for stream in &mut streams {
+            let frame = tokio::time::timeout(Duration::from_secs(5), stream.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            assert_eq!(frame.into_data(), "one");
         }
     }
 
     #[tokio::test]
-    async fn test_healthcheck() {
+    async fn test_grpc_subscribe() {
+        let addr = TestHarness::alloc_port().await;
+        let mut harness = TestHarness::new(addr).with_server(|server| {
+            server.with_handshake_limits(HandshakeConfig {
+                timeout: None,
+                max_pending: None,
+                max_pending_per_ip: None,
+                http2: true,
+            })
+        });
+        harness.start_server().await;
+
+        let channel = tonic::transport::Endpoint::from_shared(format!("http://{addr}"))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut client = grpc::FlashblocksClient::new(channel);
+        let request = |stream: &str| grpc::SubscribeRequest {
+            stream: stream.to_string(),
+        };
+
+        let status = client
+            .subscribe_flashblocks(request("missing"))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+
+        let mut body = client
+            .subscribe_flashblocks(request(""))
+            .await
+            .unwrap()
+            .into_inner();
+        harness.wait_for_clients(1).await;
+        harness.send_messages(vec!["one", "two"]);
+
+        let mut flashblocks = Vec::new();
+        while flashblocks.len() < 2 {
+            let flashblock = tokio::time::timeout(Duration::from_secs(5), body.message())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            flashblocks.push(flashblock);
+        }
+        assert_eq!(flashblocks[0].payload, "one");
+        assert_eq!(flashblocks[1].payload, "two");
+        assert_eq!(flashblocks[1].sequence, flashblocks[0].sequence + 1);
+
+        // Hanging up ends the subscription
+        drop(body);
+        harness.wait_for_clients(0).await;
+    }
+
+    #[cfg(feature = "graphql")]
+    #[tokio::test]
+    async fn test_graphql_subscription() {
+        use futures::SinkExt;
+        use serde_json::{json, Value};
+
         let addr = TestHarness::alloc_port().await;
         let mut harness = TestHarness::new(addr);
-        assert!(harness.healthcheck().await.is_err());
         harness.start_server().await;
-        assert!(harness.healthcheck().await.is_ok());
+
+        let mut request = format!("ws://{addr}/graphql/missing")
+            .into_client_request()
+            .unwrap();
+        request.headers_mut().insert(
+            "Sec-WebSocket-Protocol",
+            "graphql-transport-ws".parse().unwrap(),
+        );
+        assert!(connect_async(request).await.is_err());
+
+        let mut request = format!("ws://{addr}/graphql")
+            .into_client_request()
+            .unwrap();
+        request.headers_mut().insert(
+            "Sec-WebSocket-Protocol",
+            "graphql-transport-ws".parse().unwrap(),
+        );
+        let (mut stream, _) = connect_async(request).await.unwrap();
+        for message in [
+            json!({"type": "connection_init"}),
+            json!({
+                "type": "subscribe",
+                "id": "1",
+                "payload": {"query": "subscription { flashblocks { blockNumber index } }"},
+            }),
+        ] {
+            let message = tungstenite::Message::text(message.to_string());
+            stream.send(message).await.unwrap();
+        }
+
+        let mut receive = async || {
+            let message = tokio::time::timeout(Duration::from_secs(5), stream.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            serde_json::from_str::<Value>(message.to_text().unwrap()).unwrap()
+        };
+        assert_eq!(receive().await, json!({"type": "connection_ack"}));
+
+        harness.wait_for_clients(1).await;
+        harness.send_messages(vec![
+            r#"{"index":0,"diff":{"transactions":[]},"metadata":{"block_number":9}}"#,
+        ]);
+        assert_eq!(
+            receive().await,
+            json!({
+                "type": "next",
+                "id": "1",
+                "payload": {"data": {"flashblocks": {"blockNumber": 9, "index": 0}}},
+            })
+        );
+
+        // Hanging up ends the subscription
+        drop(stream);
+        harness.wait_for_clients(0).await;
     }
 
     #[tokio::test]
-    async fn test_clients_receive_messages() {
+    async fn test_sequence_envelope() {
         let addr = TestHarness::alloc_port().await;
+        let mut harness = TestHarness::new(addr);
+        harness.start_server().await;
+
+        let mut request = format!("ws://{addr}/ws").into_client_request().unwrap();
+        request.headers_mut().insert(
+            "Sec-WebSocket-Protocol",
+            envelope::PROTOCOL.parse().unwrap(),
+        );
+        let (mut stream, response) = connect_async(request).await.unwrap();
+        assert_eq!(
+            response.headers()["Sec-WebSocket-Protocol"],
+            envelope::PROTOCOL
+        );
+        let plain = harness.connect_client();
+        harness.wait_for_clients(2).await;
+
+        harness.send_messages(vec!["one", "two"]);
+        harness.wait_for_messages_to_drain().await;
+        assert_eq!(vec!["one", "two"], harness.messages_for_client(plain));
 
+        let mut received = Vec::new();
+        for _ in 0..2 {
+            let frame = tokio::time::timeout(Duration::from_secs(5), stream.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            received.push(envelope::open_envelope(frame.into_data()).unwrap());
+        }
+        assert_eq!(received[0].payload, "one");
+        assert_eq!(received[1].payload, "two");
+        assert_eq!(received[1].sequence, received[0].sequence + 1);
+        assert!(received[0].received_at_ms > 0);
+        // Published to the registry directly rather than from an upstream
+        assert_eq!(received[0].upstream, None);
+    }
+
+    #[tokio::test]
+    async fn test_embedded_proxy() {
+        let upstream_addr = spawn_mock_upstream(20).await;
+
+        let token = CancellationToken::new();
+        let mut proxy = ProxyBuilder::new()
+            .upstream(format!("ws://{upstream_addr}").parse().unwrap())
+            .build(SocketAddr::from(([127, 0, 0, 1], 0)));
+        proxy.spawn_subscribers(token.clone());
+
+        // Mount the proxy under an existing app
+        let app = axum::Router::new()
+            .route("/hello", axum::routing::get(|| async { "hello" }))
+            .nest("/flashblocks", proxy.router());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .unwrap()
+        });
+
+        let hello = reqwest::get(format!("http://{addr}/hello")).await.unwrap();
+        assert_eq!(hello.text().await.unwrap(), "hello");
+
+        let (mut stream, _) = connect_async(format!("ws://{addr}/flashblocks/ws"))
+            .await
+            .unwrap();
+        let msg = tokio::time::timeout(Duration::from_secs(5), stream.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let payload: serde_json::Value = serde_json::from_slice(&msg.into_data()).unwrap();
+        assert!(payload["metadata"]["block_number"].is_u64());
+
+        token.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_relay() {
+        let addr = TestHarness::alloc_port().await;
+        let mut harness = TestHarness::new(addr)
+            .with_server(|server| server.with_relay_token("secret".to_string()));
+        harness.start_server().await;
+
+        // Without the right token the relay endpoint is refused
+        let status = |result: Result<_, tungstenite::Error>| match result {
+            Err(tungstenite::Error::Http(response)) => response.status().as_u16(),
+            _ => panic!("expected the handshake to be refused"),
+        };
+        assert_eq!(
+            status(connect_async(format!("ws://{addr}/relay")).await),
+            401
+        );
+        for token in ["Bearer wrong", "Bearer secret2", "Bearer ", "secret"] {
+            let mut request = format!("ws://{addr}/relay").into_client_request().unwrap();
+            request
+                .headers_mut()
+                .insert("Authorization", token.parse().unwrap());
+            assert_eq!(status(connect_async(request).await), 401, "{token}");
+        }
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let received_clone = received.clone();
+        let metrics = Arc::new(Metrics::default());
+        let mut downstream = WebsocketSubscriber::new(
+            format!("ws://{addr}/relay").parse().unwrap(),
+            move |data: bytes::Bytes| {
+                received_clone
+                    .lock()
+                    .unwrap()
+                    .push(String::from_utf8(data.to_vec()).unwrap())
+            },
+            1,
+            metrics,
+        )
+        .with_relay_token("secret".to_string());
+        let status = downstream.status();
+        let token = harness.cancel_token();
+        tokio::spawn(async move { downstream.run(token).await });
+
+        while !status.is_connected() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        harness.send_messages(vec!["one", "two"]);
+        harness.wait_for_messages_to_drain().await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(*received.lock().unwrap(), vec!["one", "two"]);
+    }
+
+    #[tokio::test]
+    async fn test_loadtest() {
+        let addr = TestHarness::alloc_port().await;
         let mut harness = TestHarness::new(addr);
         harness.start_server().await;
 
-        let client_one = harness.connect_client();
-        let client_two = harness.connect_client();
+        let loadtest = tokio::spawn(loadtest::run(LoadTestArgs {
+            target: format!("ws://{}/ws", addr).parse().unwrap(),
+            clients: 2,
+            duration: 1,
+            ramp_up: 0,
+        }));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        for message in ["one", "two", "three"] {
+            harness.registry().publish(message.into());
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
 
+        let report = loadtest.await.unwrap();
+        assert_eq!(report.connected, 2);
+        assert_eq!(report.failed, 0);
+        assert_eq!(report.distinct_messages, 3);
+        assert_eq!(report.delivered, 6);
+        assert_eq!(report.drop_rate(), 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_multiple_acceptors() {
+        let addr = TestHarness::alloc_port().await;
+
+        let mut harness = TestHarness::new(addr).with_server(|server| server.with_acceptors(3));
+        harness.start_server().await;
+
+        let clients: Vec<_> = (0..3).map(|_| harness.connect_client()).collect();
         tokio::time::sleep(Duration::from_millis(100)).await;
 
         harness.send_messages(vec!["one", "two"]);
         harness.wait_for_messages_to_drain().await;
 
-        assert_eq!(vec!["one", "two"], harness.messages_for_client(client_one));
-        assert_eq!(vec!["one", "two"], harness.messages_for_client(client_two));
+        for client in clients {
+            assert_eq!(vec!["one", "two"], harness.messages_for_client(client));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_inherited_listener() {
+        let addr = TestHarness::alloc_port().await;
+        let listener = std::net::TcpListener::bind(addr).unwrap();
+        let fd = listener.into_raw_fd();
+
+        let mut harness = TestHarness::new(addr).with_server(|server| {
+            server
+                .with_acceptors(2)
+                .with_listener(server::inherit_listener(fd).unwrap())
+        });
+        harness.start_server().await;
+
+        let client = harness.connect_client();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        harness.send_messages(vec!["one", "two"]);
+        harness.wait_for_messages_to_drain().await;
+        assert_eq!(vec!["one", "two"], harness.messages_for_client(client));
     }
 
     #[tokio::test]
@@ -237,7 +1243,90 @@ mod test {
 
         // Client four was not able to be setup as the test has a limit of three
         assert!(harness.messages_for_client(client_four).is_empty());
-        assert!(harness.clients_failed_to_connect.lock().unwrap()[&client_four]);
+        assert!(harness.client_failed_to_connect(client_four));
+    }
+
+    #[tokio::test]
+    async fn test_waiting_room() {
+        let addr = TestHarness::alloc_port().await;
+        let mut harness = TestHarness::new(addr)
+            .with_server(|server| server.with_waiting_room(1, Duration::from_secs(5)));
+        harness.start_server().await;
+
+        let url = format!("ws://{addr}/ws");
+        let mut connected = Vec::new();
+        for _ in 0..3 {
+            connected.push(connect_async(&url).await.unwrap().0);
+        }
+
+        // The fourth client waits for a connection to be released, and the fifth finds the
+        // waiting room full
+        let waiting = tokio::spawn(connect_async(url.clone()));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        match connect_async(&url).await {
+            Err(tungstenite::Error::Http(response)) => assert_eq!(response.status(), 429),
+            _ => panic!("expected the fifth client to be refused"),
+        }
+        assert!(!waiting.is_finished());
+
+        // Closed clients are noticed when next sent a message
+        drop(connected.pop());
+        while !waiting.is_finished() {
+            harness.registry().publish("ping".into());
+            tokio::time::sleep(Duration::from_millis(25)).await;
+        }
+        assert!(waiting.await.unwrap().is_ok());
+        harness.wait_for_clients(3).await;
+    }
+
+    #[tokio::test]
+    async fn test_priority_admission() {
+        let addr = TestHarness::alloc_port().await;
+        let auth = BasicAuth::parse("ops:hunter2\nfree:letmein").unwrap();
+        let mut harness = TestHarness::new(addr).with_server(|server| {
+            server.with_basic_auth(auth).with_priority(PriorityConfig {
+                users: ["ops".to_string()].into(),
+                reserved: 1,
+                evict: true,
+            })
+        });
+        harness.start_server().await;
+
+        let connect = |credentials: &'static str| {
+            let mut request = format!("ws://{addr}/ws").into_client_request().unwrap();
+            request.headers_mut().insert(
+                "Authorization",
+                format!("Basic {credentials}").parse().unwrap(),
+            );
+            connect_async(request)
+        };
+        const OPS: &str = "b3BzOmh1bnRlcjI=";
+        const FREE: &str = "ZnJlZTpsZXRtZWlu";
+
+        let mut free = Vec::new();
+        for _ in 0..2 {
+            free.push(connect(FREE).await.unwrap().0);
+        }
+
+        // The last connection is reserved for clients with priority
+        match connect(FREE).await {
+            Err(tungstenite::Error::Http(response)) => assert_eq!(response.status(), 429),
+            _ => panic!("expected the client without priority to be refused"),
+        }
+        let _ops = connect(OPS).await.unwrap().0;
+        harness.wait_for_clients(3).await;
+
+        // At the global limit, a client without priority is evicted to make room
+        let _more_ops = connect(OPS).await.unwrap().0;
+        harness.wait_for_clients(3).await;
+        let mut evicted = 0;
+        for client in &mut free {
+            let closed = tokio::time::timeout(Duration::from_millis(100), client.next())
+                .await
+                .is_ok_and(|frame| !matches!(frame, Some(Ok(tungstenite::Message::Binary(_)))));
+            evicted += closed as usize;
+        }
+        assert_eq!(evicted, 1);
     }
 
     #[tokio::test]
@@ -247,7 +1336,7 @@ mod test {
         let mut harness = TestHarness::new(addr);
         harness.start_server().await;
 
-        assert_eq!(harness.sender.receiver_count(), 0);
+        assert_eq!(harness.registry().client_count(), 0);
 
         let client_one = harness.connect_client();
         let client_two = harness.connect_client();
@@ -255,7 +1344,7 @@ mod test {
 
         tokio::time::sleep(Duration::from_millis(100)).await;
 
-        assert_eq!(harness.sender.receiver_count(), 3);
+        assert_eq!(harness.registry().client_count(), 3);
 
         harness.send_messages(vec!["one", "two"]);
         harness.wait_for_messages_to_drain().await;
@@ -277,11 +1366,11 @@ mod test {
         harness.wait_for_messages_to_drain().await;
 
         // Client three is disconnected
-        assert_eq!(harness.sender.receiver_count(), 2);
+        assert_eq!(harness.registry().client_count(), 2);
 
         let client_four = harness.connect_client();
         tokio::time::sleep(Duration::from_millis(100)).await;
-        assert_eq!(harness.sender.receiver_count(), 3);
+        assert_eq!(harness.registry().client_count(), 3);
 
         harness.send_messages(vec!["five"]);
         harness.wait_for_messages_to_drain().await;
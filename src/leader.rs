@@ -0,0 +1,440 @@
+use crate::log_sampling::{self, EventClass};
+use crate::metrics::Metrics;
+use crate::subscriber::{UpstreamStatus, WebsocketSubscriber};
+use backoff::{backoff::Backoff, ExponentialBackoff};
+use bytes::Bytes;
+use redis::aio::MultiplexedConnection;
+use redis::{AsyncCommands, AsyncConnectionConfig, Client, RedisResult, Script};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+/// Takes the lock if it is free or extends it if this instance already holds it.
+const ACQUIRE_SCRIPT: &str = r#"
+local owner = redis.call('GET', KEYS[1])
+if owner == ARGV[1] then
+    redis.call('PEXPIRE', KEYS[1], ARGV[2])
+    return 1
+end
+if not owner then
+    redis.call('SET', KEYS[1], ARGV[1], 'PX', ARGV[2])
+    return 1
+end
+return 0
+"#;
+
+/// Releases the lock if this instance holds it.
+const RELEASE_SCRIPT: &str = r#"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+"#;
+
+const RELAY_READ_TIMEOUT: Duration = Duration::from_secs(1);
+const RELAY_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Upstream messages waiting to be relayed. Messages beyond it are dropped rather than holding up
+/// delivery to this instance's clients while Redis is slow or unreachable.
+const RELAY_QUEUE_SIZE: usize = 1024;
+/// How long to wait for Redis to connect or answer, for the lease and for relaying.
+const REDIS_TIMEOUT: Duration = Duration::from_secs(1);
+/// Longest wait between attempts to reconnect to Redis for relaying, during which messages are
+/// dropped.
+const RELAY_MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Elects one instance of a fleet, through a lease in Redis, to subscribe to the upstreams. The
+/// leader relays every upstream message to the other instances over a Redis pub/sub channel, so
+/// the upstream sees one connection per fleet rather than one per instance.
+///
+/// Relaying is off the upstream's path: messages are queued for a task that publishes them, so
+/// that Redis being slow or down costs the other instances messages but never delays this one's
+/// clients.
+pub struct LeaderElection {
+    client: Client,
+    lock_key: String,
+    channel: String,
+    instance_id: String,
+    lease: Duration,
+    leader: watch::Sender<bool>,
+    metrics: Arc<Metrics>,
+    relay_queue: mpsc::Sender<Bytes>,
+    /// Taken by [`LeaderElection::run`] to publish the queued messages.
+    relay_messages: Mutex<Option<mpsc::Receiver<Bytes>>>,
+}
+
+impl LeaderElection {
+    pub fn new(
+        redis_url: &str,
+        key_prefix: &str,
+        lease: Duration,
+        metrics: Arc<Metrics>,
+    ) -> RedisResult<Self> {
+        let (relay_queue, relay_messages) = mpsc::channel(RELAY_QUEUE_SIZE);
+
+        Ok(Self {
+            client: Client::open(redis_url)?,
+            lock_key: format!("{key_prefix}:upstream:leader"),
+            channel: format!("{key_prefix}:upstream:messages"),
+            instance_id: Uuid::new_v4().to_string(),
+            lease,
+            leader: watch::Sender::new(false),
+            metrics,
+            relay_queue,
+            relay_messages: Mutex::new(Some(relay_messages)),
+        })
+    }
+
+    pub fn is_leader(&self) -> bool {
+        *self.leader.borrow()
+    }
+
+    /// Status of the relay channel, to report alongside the upstreams.
+    pub fn relay_status(&self) -> Arc<UpstreamStatus> {
+        let addr = self.client.get_connection_info().addr.to_string();
+        let uri = format!("redis://{addr}/{}", self.channel)
+            .parse()
+            .unwrap_or_else(|_| "redis://relay".parse().unwrap());
+        Arc::new(UpstreamStatus::new(uri))
+    }
+
+    /// Campaigns for leadership until `token` is cancelled, renewing the lease at a third of its
+    /// duration, and relays messages while leader. Leadership is given up if Redis can't be
+    /// reached, as the lease may then expire.
+    pub async fn run(self: Arc<Self>, token: CancellationToken) {
+        info!(
+            message = "campaigning for upstream leadership",
+            instance_id = self.instance_id
+        );
+        if let Some(messages) = self.relay_messages.lock().unwrap().take() {
+            tokio::spawn(self.clone().publish_relayed(messages, token.clone()));
+        }
+
+        let mut ticker = tokio::time::interval(self.lease / 3);
+        let mut conn = None;
+
+        loop {
+            tokio::select! {
+                _ = token.cancelled() => break,
+                _ = ticker.tick() => {}
+            }
+
+            let leader = match self.try_acquire(&mut conn).await {
+                Ok(leader) => leader,
+                Err(e) => {
+                    error!(
+                        message = "failed to renew upstream leadership",
+                        error = e.to_string()
+                    );
+                    conn = None;
+                    false
+                }
+            };
+
+            if self.leader.send_replace(leader) != leader {
+                info!(
+                    message = if leader {
+                        "became upstream leader"
+                    } else {
+                        "no longer upstream leader"
+                    },
+                    instance_id = self.instance_id
+                );
+            }
+            self.metrics
+                .upstream_leader
+                .set(if leader { 1.0 } else { 0.0 });
+        }
+
+        if self.leader.send_replace(false) {
+            if let Err(e) = self.release(&mut conn).await {
+                warn!(
+                    message = "failed to release upstream leadership",
+                    error = e.to_string()
+                );
+            }
+        }
+    }
+
+    /// `conn`, connecting it first if it isn't already.
+    async fn connection<'a>(
+        &self,
+        conn: &'a mut Option<MultiplexedConnection>,
+    ) -> RedisResult<&'a mut MultiplexedConnection> {
+        if conn.is_none() {
+            let config = AsyncConnectionConfig::new()
+                .set_connection_timeout(REDIS_TIMEOUT)
+                .set_response_timeout(REDIS_TIMEOUT);
+            *conn = Some(
+                self.client
+                    .get_multiplexed_async_connection_with_config(&config)
+                    .await?,
+            );
+        }
+        Ok(conn.as_mut().unwrap())
+    }
+
+    async fn try_acquire(&self, conn: &mut Option<MultiplexedConnection>) -> RedisResult<bool> {
+        let conn = self.connection(conn).await?;
+        let acquired: i32 = Script::new(ACQUIRE_SCRIPT)
+            .key(&self.lock_key)
+            .arg(&self.instance_id)
+            .arg(self.lease.as_millis() as u64)
+            .invoke_async(conn)
+            .await?;
+        Ok(acquired == 1)
+    }
+
+    async fn release(&self, conn: &mut Option<MultiplexedConnection>) -> RedisResult<()> {
+        let conn = self.connection(conn).await?;
+        Script::new(RELEASE_SCRIPT)
+            .key(&self.lock_key)
+            .arg(&self.instance_id)
+            .invoke_async::<()>(conn)
+            .await
+    }
+
+    /// Queues a message received from upstream to be relayed to the other instances, dropping it
+    /// if the queue is full.
+    pub fn relay(&self, payload: Bytes) {
+        if self.relay_queue.try_send(payload).is_err() {
+            self.metrics.upstream_relay_dropped_messages.increment(1);
+            if let Some(suppressed) = log_sampling::sample(EventClass::Reconnects) {
+                warn!(
+                    message = "relay queue full, dropping upstream message",
+                    suppressed = suppressed
+                );
+            }
+        }
+    }
+
+    /// Publishes queued messages to the relay channel until `token` is cancelled. While Redis
+    /// can't be reached, reconnection is retried with backoff and messages are dropped.
+    async fn publish_relayed(
+        self: Arc<Self>,
+        mut messages: mpsc::Receiver<Bytes>,
+        token: CancellationToken,
+    ) {
+        let mut conn = None;
+        let mut backoff = ExponentialBackoff {
+            initial_interval: Duration::from_millis(100),
+            max_interval: RELAY_MAX_BACKOFF,
+            max_elapsed_time: None,
+            ..Default::default()
+        };
+        let mut retry_at = Instant::now();
+
+        loop {
+            let payload = tokio::select! {
+                _ = token.cancelled() => return,
+                payload = messages.recv() => match payload {
+                    Some(payload) => payload,
+                    None => return,
+                },
+            };
+            if conn.is_none() && Instant::now() < retry_at {
+                self.metrics.upstream_relay_dropped_messages.increment(1);
+                continue;
+            }
+
+            let result = match self.connection(&mut conn).await {
+                Ok(conn) => {
+                    conn.publish::<_, _, ()>(&self.channel, payload.as_ref())
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => backoff.reset(),
+                Err(e) => {
+                    error!(
+                        message = "failed to relay upstream message",
+                        error = e.to_string()
+                    );
+                    self.metrics.upstream_relay_dropped_messages.increment(1);
+                    conn = None;
+                    retry_at = Instant::now() + backoff.next_backoff().unwrap_or(RELAY_MAX_BACKOFF);
+                }
+            }
+        }
+    }
+
+    /// Passes messages relayed by the leader to `handler` while this instance isn't the leader,
+    /// until `token` is cancelled. Runs on its own thread, as the Redis pub/sub connection blocks.
+    pub fn spawn_relay_listener<F>(
+        self: Arc<Self>,
+        handler: F,
+        status: Arc<UpstreamStatus>,
+        token: CancellationToken,
+    ) where
+        F: Fn(Bytes) + Send + 'static,
+    {
+        std::thread::spawn(move || {
+            while !token.is_cancelled() {
+                if let Err(e) = self.listen_for_relayed(&handler, &status, &token) {
+                    error!(
+                        message = "upstream relay subscription failed",
+                        error = e.to_string()
+                    );
+                }
+                status.set_connected(false);
+                std::thread::sleep(RELAY_RECONNECT_DELAY);
+            }
+        });
+    }
+
+    fn listen_for_relayed<F>(
+        &self,
+        handler: &F,
+        status: &UpstreamStatus,
+        token: &CancellationToken,
+    ) -> RedisResult<()>
+    where
+        F: Fn(Bytes),
+    {
+        let mut conn = self.client.get_connection()?;
+        let mut pubsub = conn.as_pubsub();
+        pubsub.subscribe(&self.channel)?;
+        pubsub.set_read_timeout(Some(RELAY_READ_TIMEOUT))?;
+        status.set_connected(true);
+
+        while !token.is_cancelled() {
+            let msg = match pubsub.get_message() {
+                Ok(msg) => msg,
+                Err(e) if e.is_timeout() => continue,
+                Err(e) => return Err(e),
+            };
+
+            if !self.is_leader() {
+                status.record_message();
+                handler(Bytes::copy_from_slice(msg.get_payload_bytes()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Notified whenever this instance gains or loses leadership.
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.leader.subscribe()
+    }
+}
+
+/// Runs `subscriber` only while `leadership` says this instance is the leader, until `token` is
+/// cancelled.
+pub async fn run_while_leader<F>(
+    mut subscriber: WebsocketSubscriber<F>,
+    mut leadership: watch::Receiver<bool>,
+    token: CancellationToken,
+) where
+    F: Fn(Bytes) + Send + Sync + 'static,
+{
+    loop {
+        tokio::select! {
+            _ = token.cancelled() => return,
+            elected = wait_for_leadership(&mut leadership, true) => {
+                if !elected {
+                    return;
+                }
+            }
+        }
+
+        let term = token.child_token();
+        let run = subscriber.run_supervised(term.clone());
+        tokio::pin!(run);
+
+        tokio::select! {
+            _ = &mut run => return,
+            _ = wait_for_leadership(&mut leadership, false) => {
+                term.cancel();
+                run.await;
+            }
+        }
+    }
+}
+
+/// Waits until leadership is `leader`, returning false if the election has stopped.
+async fn wait_for_leadership(leadership: &mut watch::Receiver<bool>, leader: bool) -> bool {
+    leadership
+        .wait_for(|current| *current == leader)
+        .await
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_upstream::{self, MockUpstreamArgs};
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_relay_without_redis() {
+        let election = Arc::new(
+            LeaderElection::new(
+                "redis://127.0.0.1:1",
+                "test",
+                Duration::from_secs(3),
+                Arc::new(Metrics::default()),
+            )
+            .unwrap(),
+        );
+
+        // Relaying never waits on Redis, dropping messages once the queue is full
+        for _ in 0..RELAY_QUEUE_SIZE * 2 {
+            election.relay(Bytes::from("flashblock"));
+        }
+        assert_eq!(election.relay_queue.capacity(), 0);
+
+        // The queue is drained while Redis is unreachable, and leadership isn't taken
+        let token = CancellationToken::new();
+        tokio::spawn(election.clone().run(token.clone()));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(election.relay_queue.capacity(), RELAY_QUEUE_SIZE);
+        assert!(!election.is_leader());
+        token.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_run_while_leader() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr: SocketAddr = listener.local_addr().unwrap();
+        drop(listener);
+        tokio::spawn(mock_upstream::run(MockUpstreamArgs {
+            listen_addr: addr,
+            interval_ms: 20,
+            transactions: 1,
+            gap_every: 0,
+            disconnect_after: 0,
+        }));
+
+        let subscriber = WebsocketSubscriber::new(
+            format!("ws://{addr}").parse().unwrap(),
+            |_: Bytes| {},
+            1,
+            Arc::new(Metrics::default()),
+        );
+        let status = subscriber.status();
+        let (leadership, receiver) = watch::channel(false);
+        let token = CancellationToken::new();
+        let task = tokio::spawn(run_while_leader(subscriber, receiver, token.clone()));
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!status.is_connected());
+
+        leadership.send_replace(true);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(status.is_connected());
+
+        leadership.send_replace(false);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!status.is_connected());
+
+        token.cancel();
+        task.await.unwrap();
+    }
+}
@@ -0,0 +1,65 @@
+#[cfg(feature = "acme")]
+pub mod acme;
+pub mod admin;
+pub mod allocator;
+pub mod audit;
+pub mod auth;
+pub mod authorizer;
+pub mod ban;
+pub mod cache;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+pub mod client;
+pub mod envelope;
+pub mod error_reporting;
+pub mod filter;
+pub mod geoip;
+#[cfg(feature = "graphql")]
+pub mod graphql;
+pub mod grpc;
+pub mod handshake;
+#[cfg(feature = "harness")]
+pub mod harness;
+pub mod healthcheck;
+pub mod hooks;
+#[cfg(all(feature = "integration", test))]
+mod integration;
+pub mod leader;
+pub mod load_shedding;
+pub mod loadtest;
+pub mod log_sampling;
+pub mod memory_budget;
+pub mod metrics;
+pub mod metrics_catalog;
+pub mod metrics_server;
+pub mod mock_upstream;
+pub mod mqtt;
+pub mod panic_hook;
+pub mod priority;
+pub mod process_metrics;
+pub mod projection;
+pub mod proxy;
+pub mod rate_limit;
+pub mod recording;
+pub mod registry;
+pub mod relay;
+pub mod rotating_file;
+pub mod rpc;
+pub mod sampling;
+#[cfg(feature = "scripting")]
+pub mod scripting;
+pub mod server;
+#[cfg(test)]
+mod simulation;
+pub mod status_events;
+pub mod streams;
+pub mod subscriber;
+pub mod systemd;
+pub mod tail;
+pub mod tiers;
+pub mod transform;
+pub mod waiting_room;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "webtransport")]
+pub mod webtransport;
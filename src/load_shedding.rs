@@ -0,0 +1,173 @@
+use crate::metrics::Metrics;
+use crate::registry::Registry;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+/// Thresholds that define overload. Each check is skipped when unset.
+#[derive(Clone, Copy, Debug)]
+pub struct LoadShedConfig {
+    /// p99 of the age of the last message delivered to each client.
+    pub max_lag: Option<Duration>,
+    /// Largest client backlog, as a fraction of the per-client queue size.
+    pub max_queue_fraction: Option<f64>,
+    /// How many consecutive overloaded checks before shedding starts, and again before it
+    /// escalates.
+    pub sustained_checks: u32,
+    /// Fraction of clients to lag-drop on each check once shedding has escalated.
+    pub lag_drop_fraction: f64,
+}
+
+/// How aggressively load is currently being shed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum ShedLevel {
+    None = 0,
+    /// New connections are rejected.
+    RejectConnections = 1,
+    /// New connections are rejected and the slowest clients drop their backlog.
+    LagDrop = 2,
+}
+
+/// Sheds load deterministically when the proxy is overloaded, instead of letting every client
+/// degrade together: first new connections are rejected, then if the overload persists the
+/// clients with the largest backlogs are made to skip ahead.
+pub struct LoadShedder {
+    config: Mutex<LoadShedConfig>,
+    level: AtomicU8,
+}
+
+impl LoadShedder {
+    pub fn new(config: LoadShedConfig) -> Self {
+        Self {
+            config: Mutex::new(config),
+            level: AtomicU8::new(ShedLevel::None as u8),
+        }
+    }
+
+    pub fn level(&self) -> ShedLevel {
+        match self.level.load(Ordering::Relaxed) {
+            0 => ShedLevel::None,
+            1 => ShedLevel::RejectConnections,
+            _ => ShedLevel::LagDrop,
+        }
+    }
+
+    pub fn rejecting_connections(&self) -> bool {
+        self.level() >= ShedLevel::RejectConnections
+    }
+
+    pub fn config(&self) -> LoadShedConfig {
+        *self.config.lock().unwrap()
+    }
+
+    /// Changes the thresholds, which take effect from the next check.
+    pub fn set_config(&self, config: LoadShedConfig) {
+        *self.config.lock().unwrap() = config;
+    }
+
+    /// Checks for overload every `interval`, adjusting the shed level.
+    pub async fn run(
+        &self,
+        registry: Registry,
+        metrics: Arc<Metrics>,
+        interval: Duration,
+        token: CancellationToken,
+    ) {
+        let mut ticker = tokio::time::interval(interval);
+        let mut overloaded_checks = 0;
+        let mut healthy_checks = 0;
+
+        loop {
+            tokio::select! {
+                _ = token.cancelled() => return,
+                _ = ticker.tick() => {}
+            }
+
+            let config = self.config();
+            if is_overloaded(&config, &registry) {
+                overloaded_checks += 1;
+                healthy_checks = 0;
+            } else {
+                healthy_checks += 1;
+                overloaded_checks = 0;
+            }
+
+            let level = next_level(
+                self.level(),
+                overloaded_checks,
+                healthy_checks,
+                config.sustained_checks,
+            );
+            if level != self.level() {
+                warn!(message = "load shedding level changed", level = ?level);
+                self.level.store(level as u8, Ordering::Relaxed);
+                metrics.load_shed_level.set(level as u8 as f64);
+            }
+
+            if level == ShedLevel::LagDrop {
+                let count =
+                    (registry.client_count() as f64 * config.lag_drop_fraction).ceil() as usize;
+                let dropped = registry.lag_drop_slowest(count);
+                if dropped > 0 {
+                    info!(message = "lag-dropped slowest clients", clients = dropped);
+                    metrics
+                        .load_shed_lag_dropped_clients
+                        .increment(dropped as u64);
+                }
+            }
+        }
+    }
+}
+
+fn is_overloaded(config: &LoadShedConfig, registry: &Registry) -> bool {
+    let lag = registry.lag_summary();
+
+    let lagging = config
+        .max_lag
+        .is_some_and(|max_lag| Duration::from_millis(lag.millis_p99) > max_lag);
+
+    let queued = config.max_queue_fraction.is_some_and(|fraction| {
+        registry.queued_messages() as f64 >= fraction * registry.buffer_size() as f64
+    });
+
+    lagging || queued
+}
+
+/// Escalates one level after every `sustained` consecutive overloaded checks, and stops shedding
+/// entirely after `sustained` consecutive healthy checks.
+fn next_level(current: ShedLevel, overloaded: u32, healthy: u32, sustained: u32) -> ShedLevel {
+    let sustained = sustained.max(1);
+
+    if healthy >= sustained {
+        ShedLevel::None
+    } else if overloaded >= 2 * sustained {
+        ShedLevel::LagDrop
+    } else if overloaded >= sustained {
+        current.max(ShedLevel::RejectConnections)
+    } else {
+        current
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_level() {
+        use ShedLevel::*;
+
+        assert_eq!(next_level(None, 2, 0, 3), None);
+        assert_eq!(next_level(None, 3, 0, 3), RejectConnections);
+        assert_eq!(next_level(RejectConnections, 5, 0, 3), RejectConnections);
+        assert_eq!(next_level(RejectConnections, 6, 0, 3), LagDrop);
+        assert_eq!(next_level(LagDrop, 3, 0, 3), LagDrop);
+
+        // Recovering needs a sustained period without overload
+        assert_eq!(next_level(LagDrop, 0, 2, 3), LagDrop);
+        assert_eq!(next_level(LagDrop, 0, 3, 3), None);
+    }
+}
@@ -0,0 +1,240 @@
+use crate::registry::percentile;
+use axum::http::Uri;
+use futures::StreamExt;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+use tokio_tungstenite::connect_async;
+
+#[derive(clap::Args, Debug)]
+pub struct LoadTestArgs {
+    /// Websocket URL of the proxy to test, e.g. ws://localhost:8545/ws
+    #[arg(long)]
+    pub target: Uri,
+
+    /// Number of clients to connect
+    #[arg(long, default_value = "100")]
+    pub clients: usize,
+
+    /// Seconds to run the test for once all clients have been started
+    #[arg(long, default_value = "30")]
+    pub duration: u64,
+
+    /// Seconds over which to spread connecting the clients
+    #[arg(long, default_value = "0")]
+    pub ramp_up: u64,
+}
+
+/// What a single simulated client observed.
+#[derive(Debug, Default)]
+struct ClientResult {
+    connected_at: Option<Instant>,
+    disconnected_early: bool,
+    received: u64,
+    /// Microseconds between the first client receiving a message and this client receiving it.
+    latencies: Vec<u64>,
+}
+
+/// Summary of a load test run.
+#[derive(Debug, PartialEq)]
+pub struct LoadTestReport {
+    pub clients: usize,
+    pub connected: usize,
+    pub failed: usize,
+    pub disconnected_early: usize,
+    pub distinct_messages: usize,
+    pub delivered: u64,
+    pub expected: u64,
+    pub latency_p50_us: u64,
+    pub latency_p99_us: u64,
+    pub latency_max_us: u64,
+}
+
+impl LoadTestReport {
+    /// Fraction of the messages clients should have received that they didn't.
+    pub fn drop_rate(&self) -> f64 {
+        if self.expected == 0 {
+            return 0.0;
+        }
+        1.0 - self.delivered as f64 / self.expected as f64
+    }
+}
+
+impl fmt::Display for LoadTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "clients:            {}", self.clients)?;
+        writeln!(f, "  connected:        {}", self.connected)?;
+        writeln!(f, "  failed:           {}", self.failed)?;
+        writeln!(f, "  disconnected:     {}", self.disconnected_early)?;
+        writeln!(f, "messages:           {}", self.distinct_messages)?;
+        writeln!(f, "  delivered:        {}", self.delivered)?;
+        writeln!(f, "  expected:         {}", self.expected)?;
+        writeln!(f, "  drop rate:        {:.4}%", self.drop_rate() * 100.0)?;
+        writeln!(
+            f,
+            "fan-out spread (time after the first client received a message):"
+        )?;
+        writeln!(f, "  p50:              {}us", self.latency_p50_us)?;
+        writeln!(f, "  p99:              {}us", self.latency_p99_us)?;
+        write!(f, "  max:              {}us", self.latency_max_us)
+    }
+}
+
+/// Connects `args.clients` websocket clients to the target and records what each receives.
+///
+/// The proxy doesn't timestamp messages, so latency is measured as the spread between the first
+/// client receiving a message and each other client receiving it. A client is expected to receive
+/// every message first seen after it connected.
+pub async fn run(args: LoadTestArgs) -> LoadTestReport {
+    let first_seen: Arc<Mutex<HashMap<u64, Instant>>> = Arc::new(Mutex::new(HashMap::new()));
+    let ramp_up = Duration::from_secs(args.ramp_up);
+    let deadline = Instant::now() + ramp_up + Duration::from_secs(args.duration);
+    let target = args.target.to_string();
+
+    let mut tasks = JoinSet::new();
+    for index in 0..args.clients {
+        let delay = ramp_up.mul_f64(index as f64 / args.clients as f64);
+        let target = target.clone();
+        let first_seen = first_seen.clone();
+
+        tasks.spawn(async move {
+            tokio::time::sleep(delay).await;
+            run_client(&target, deadline, &first_seen).await
+        });
+    }
+
+    let results = tasks.join_all().await;
+    let first_seen: Vec<Instant> = first_seen.lock().unwrap().values().copied().collect();
+
+    summarize(args.clients, &results, &first_seen)
+}
+
+async fn run_client(
+    target: &str,
+    deadline: Instant,
+    first_seen: &Mutex<HashMap<u64, Instant>>,
+) -> ClientResult {
+    let mut result = ClientResult::default();
+
+    let Ok((stream, _)) = connect_async(target).await else {
+        return result;
+    };
+    result.connected_at = Some(Instant::now());
+
+    let (_, mut read) = stream.split();
+    let deadline = tokio::time::Instant::from_std(deadline);
+
+    loop {
+        let message = match tokio::time::timeout_at(deadline, read.next()).await {
+            Err(_) => break,
+            Ok(Some(Ok(message))) => message,
+            Ok(_) => {
+                result.disconnected_early = true;
+                break;
+            }
+        };
+
+        if !(message.is_binary() || message.is_text()) {
+            continue;
+        }
+
+        let received_at = Instant::now();
+        let mut hasher = DefaultHasher::new();
+        message.into_data().hash(&mut hasher);
+
+        let first = *first_seen
+            .lock()
+            .unwrap()
+            .entry(hasher.finish())
+            .or_insert(received_at);
+
+        result.received += 1;
+        result
+            .latencies
+            .push(received_at.duration_since(first).as_micros() as u64);
+    }
+
+    result
+}
+
+fn summarize(clients: usize, results: &[ClientResult], first_seen: &[Instant]) -> LoadTestReport {
+    let mut latencies: Vec<u64> = results
+        .iter()
+        .flat_map(|result| result.latencies.iter().copied())
+        .collect();
+    latencies.sort_unstable();
+
+    let expected = results
+        .iter()
+        .filter_map(|result| result.connected_at)
+        .map(|connected_at| {
+            first_seen
+                .iter()
+                .filter(|&&seen| seen >= connected_at)
+                .count() as u64
+        })
+        .sum();
+
+    LoadTestReport {
+        clients,
+        connected: results.iter().filter(|r| r.connected_at.is_some()).count(),
+        failed: results.iter().filter(|r| r.connected_at.is_none()).count(),
+        disconnected_early: results.iter().filter(|r| r.disconnected_early).count(),
+        distinct_messages: first_seen.len(),
+        delivered: results.iter().map(|result| result.received).sum(),
+        expected,
+        latency_p50_us: percentile(&latencies, 0.5),
+        latency_p99_us: percentile(&latencies, 0.99),
+        latency_max_us: latencies.last().copied().unwrap_or(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summarize() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        let first_seen = vec![at(10), at(20), at(30)];
+        let results = vec![
+            ClientResult {
+                connected_at: Some(at(0)),
+                disconnected_early: false,
+                received: 3,
+                latencies: vec![0, 0, 0],
+            },
+            ClientResult {
+                connected_at: Some(at(15)),
+                disconnected_early: true,
+                received: 1,
+                latencies: vec![400],
+            },
+            ClientResult::default(),
+        ];
+
+        let report = summarize(3, &results, &first_seen);
+        assert_eq!(
+            report,
+            LoadTestReport {
+                clients: 3,
+                connected: 2,
+                failed: 1,
+                disconnected_early: 1,
+                distinct_messages: 3,
+                delivered: 4,
+                expected: 5,
+                latency_p50_us: 0,
+                latency_p99_us: 400,
+                latency_max_us: 400,
+            }
+        );
+        assert!((report.drop_rate() - 0.2).abs() < 1e-9);
+    }
+}
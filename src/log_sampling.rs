@@ -0,0 +1,137 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::warn;
+
+/// Classes of high-frequency log events that can be sampled independently.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventClass {
+    /// Per-message trace logs on the ingest and fan out paths.
+    Messages,
+    /// Upstream connection errors and reconnect attempts.
+    Reconnects,
+    /// Clients falling behind the broadcast buffer.
+    Lag,
+}
+
+impl EventClass {
+    const ALL: [EventClass; 3] = [
+        EventClass::Messages,
+        EventClass::Reconnects,
+        EventClass::Lag,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            EventClass::Messages => "messages",
+            EventClass::Reconnects => "reconnects",
+            EventClass::Lag => "lag",
+        }
+    }
+}
+
+/// Logs one in every `rate` events of a class, defaulting to logging every event.
+struct Sampler {
+    rate: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Sampler {
+    const fn new() -> Self {
+        Self {
+            rate: AtomicU64::new(1),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    fn sample(&self) -> Option<u64> {
+        let rate = self.rate.load(Ordering::Relaxed);
+        let count = self.count.fetch_add(1, Ordering::Relaxed);
+
+        if count % rate != 0 {
+            return None;
+        }
+
+        Some(if count == 0 { 0 } else { rate - 1 })
+    }
+}
+
+static SAMPLERS: [Sampler; 3] = [Sampler::new(), Sampler::new(), Sampler::new()];
+
+fn sampler(class: EventClass) -> &'static Sampler {
+    &SAMPLERS[class as usize]
+}
+
+/// Returns whether an event of the given class should be logged. When it should, the number of
+/// events suppressed since the previous logged one is returned so it can be attached to the log.
+pub fn sample(class: EventClass) -> Option<u64> {
+    sampler(class).sample()
+}
+
+/// Configures sample rates from the format `messages=1000,reconnects=10,lag=100`. Classes that are
+/// not listed keep logging every event.
+pub fn configure(rates: &str) {
+    for (class, rate) in parse_sample_rates(rates) {
+        sampler(class).rate.store(rate, Ordering::Relaxed);
+    }
+}
+
+fn parse_sample_rates(rates: &str) -> Vec<(EventClass, u64)> {
+    let mut result = Vec::new();
+
+    for entry in rates.split(',') {
+        if entry.is_empty() {
+            continue;
+        }
+
+        let Some((name, rate)) = entry.split_once('=') else {
+            warn!(message = "malformed log sample rate", entry = entry);
+            continue;
+        };
+
+        let Some(class) = EventClass::ALL
+            .into_iter()
+            .find(|c| c.name() == name.trim())
+        else {
+            warn!(message = "unknown log sampling event class", entry = entry);
+            continue;
+        };
+
+        match rate.trim().parse::<u64>() {
+            Ok(rate) if rate > 0 => result.push((class, rate)),
+            _ => {
+                warn!(message = "invalid log sample rate", entry = entry);
+            }
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sample_rates() {
+        assert_eq!(parse_sample_rates(""), vec![]);
+        assert_eq!(
+            parse_sample_rates("messages=1000,lag=10"),
+            vec![(EventClass::Messages, 1000), (EventClass::Lag, 10)]
+        );
+        assert_eq!(
+            parse_sample_rates("reconnects=5,unknown=3,lag=0,messages=abc,lag"),
+            vec![(EventClass::Reconnects, 5)]
+        );
+    }
+
+    #[test]
+    fn test_sampler() {
+        let sampler = Sampler::new();
+        sampler.rate.store(3, Ordering::Relaxed);
+
+        let sampled: Vec<Option<u64>> = (0..7).map(|_| sampler.sample()).collect();
+        assert_eq!(
+            sampled,
+            vec![Some(0), None, None, Some(2), None, None, Some(2)]
+        );
+    }
+}
@@ -1,33 +1,122 @@
-mod client;
-#[cfg(all(feature = "integration", test))]
-mod integration;
-mod metrics;
-mod rate_limit;
-mod registry;
-mod server;
-mod subscriber;
-
-use crate::metrics::Metrics;
-use crate::rate_limit::{InMemoryRateLimit, RateLimit};
-use crate::registry::Registry;
-use crate::server::Server;
-use crate::subscriber::WebsocketSubscriber;
+use ::metrics::{KeyName, Label};
 use axum::http::Uri;
-use clap::Parser;
+use bytes::Bytes;
+use clap::{Parser, Subcommand};
 use dotenvy::dotenv;
-use metrics_exporter_prometheus::PrometheusBuilder;
-use rate_limit::RedisRateLimit;
+#[cfg(feature = "acme")]
+use flashblocks_websocket_proxy::acme::AcmeArgs;
+use flashblocks_websocket_proxy::audit::AUDIT_TARGET;
+use flashblocks_websocket_proxy::auth::BasicAuth;
+use flashblocks_websocket_proxy::authorizer::{Authorizer, AuthorizerConfig};
+use flashblocks_websocket_proxy::ban::BanConfig;
+use flashblocks_websocket_proxy::cache::FlashblockCache;
+#[cfg(feature = "chaos")]
+use flashblocks_websocket_proxy::chaos::{Chaos, ChaosArgs};
+use flashblocks_websocket_proxy::client::HeartbeatConfig;
+use flashblocks_websocket_proxy::filter::MessageFilter;
+use flashblocks_websocket_proxy::geoip::{AccessPolicy, GeoIp};
+use flashblocks_websocket_proxy::handshake::HandshakeConfig;
+use flashblocks_websocket_proxy::healthcheck::HealthcheckArgs;
+use flashblocks_websocket_proxy::leader::{self, LeaderElection};
+use flashblocks_websocket_proxy::load_shedding::{LoadShedConfig, LoadShedder};
+use flashblocks_websocket_proxy::loadtest::LoadTestArgs;
+use flashblocks_websocket_proxy::log_sampling::EventClass;
+use flashblocks_websocket_proxy::memory_budget::MemoryBudget;
+use flashblocks_websocket_proxy::metrics::Metrics;
+use flashblocks_websocket_proxy::metrics_server::MetricsAuth;
+use flashblocks_websocket_proxy::mock_upstream::MockUpstreamArgs;
+use flashblocks_websocket_proxy::mqtt::{self, MqttConfig, TopicTemplate};
+use flashblocks_websocket_proxy::priority::PriorityConfig;
+use flashblocks_websocket_proxy::rate_limit::{InMemoryRateLimit, RateLimit, RedisRateLimit};
+use flashblocks_websocket_proxy::recording::{self, Recorder};
+use flashblocks_websocket_proxy::registry::{LagStrategy, Registry};
+use flashblocks_websocket_proxy::rotating_file::{RotatingFile, Rotation, RotationConfig};
+#[cfg(feature = "scripting")]
+use flashblocks_websocket_proxy::scripting::ScriptArgs;
+use flashblocks_websocket_proxy::server::{self, ReadinessConfig, Server};
+use flashblocks_websocket_proxy::streams::{Stream, StreamConfig};
+use flashblocks_websocket_proxy::subscriber::UpstreamStatus;
+use flashblocks_websocket_proxy::subscriber::WebsocketSubscriber;
+use flashblocks_websocket_proxy::tail::TailArgs;
+use flashblocks_websocket_proxy::tiers::{self, Tier};
+use flashblocks_websocket_proxy::transform::{Plugins, TransformError};
+#[cfg(feature = "wasm")]
+use flashblocks_websocket_proxy::wasm::WasmArgs;
+#[cfg(feature = "webtransport")]
+use flashblocks_websocket_proxy::webtransport::{WebTransportArgs, WebTransportConfig};
+use flashblocks_websocket_proxy::{
+    allocator, error_reporting, healthcheck, loadtest, log_sampling, metrics_server, mock_upstream,
+    panic_hook, process_metrics, status_events, systemd, tail,
+};
+use metrics_exporter_dogstatsd::DogStatsDBuilder;
+use metrics_exporter_otel::OpenTelemetryRecorder;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder};
+use metrics_util::layers::FanoutBuilder;
+use opentelemetry::metrics::MeterProvider;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::{MetricExporter, WithExportConfig};
+use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
+use opentelemetry_sdk::Resource;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::signal::unix::{signal, SignalKind};
-use tokio::sync::broadcast;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
 use tokio_util::sync::CancellationToken;
-use tracing::{error, info, trace, warn, Level};
-use tracing_subscriber::EnvFilter;
+use tracing::{error, info, info_span, trace, warn, Instrument, Level};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter, Layer};
+
+/// Default histogram buckets for each histogram metric, keyed by metric name. Durations are in
+/// seconds and sizes in bytes.
+const HISTOGRAM_BUCKETS: &[(&str, &[f64])] = &[
+    (
+        "fan_out_latency",
+        &[
+            0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+        ],
+    ),
+    (
+        "connection_duration",
+        &[
+            1.0, 10.0, 60.0, 300.0, 900.0, 1800.0, 3600.0, 14400.0, 43200.0, 86400.0,
+        ],
+    ),
+    (
+        "waiting_room_wait",
+        &[0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0],
+    ),
+    ("upstream_message_size", MESSAGE_SIZE_BUCKETS),
+    ("sent_message_size", MESSAGE_SIZE_BUCKETS),
+    ("upstream_message_interval", MESSAGE_INTERVAL_BUCKETS),
+    ("sent_message_interval", MESSAGE_INTERVAL_BUCKETS),
+];
+
+const MESSAGE_SIZE_BUCKETS: &[f64] = &[
+    256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0, 4194304.0,
+];
+
+/// Finest around the 200ms flashblock interval, where cadence jitter shows.
+const MESSAGE_INTERVAL_BUCKETS: &[f64] = &[
+    0.001, 0.01, 0.05, 0.1, 0.15, 0.175, 0.19, 0.2, 0.21, 0.225, 0.25, 0.3, 0.5, 1.0, 2.0,
+];
 
 #[derive(Parser, Debug)]
-#[command(author, version, about)]
+#[command(author, version, about, args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    serve: ServeArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct ServeArgs {
     #[arg(
         long,
         env,
@@ -36,6 +125,101 @@ struct Args {
     )]
     listen_addr: SocketAddr,
 
+    #[arg(
+        long,
+        env,
+        default_value = "1",
+        help = "Number of listeners to accept connections on, sharing the listen address with SO_REUSEPORT (0 for one per core)"
+    )]
+    listen_acceptors: usize,
+
+    #[arg(
+        long,
+        env,
+        default_value = "10000",
+        help = "Close connections that haven't upgraded to a websocket within this many milliseconds of being accepted (0 to never)"
+    )]
+    handshake_timeout_ms: u64,
+
+    #[arg(
+        long,
+        env,
+        default_value = "0",
+        help = "Refuse new connections on accept with a 503 while this many are waiting to upgrade to a websocket (0 for no limit)"
+    )]
+    max_pending_handshakes: usize,
+
+    #[arg(
+        long,
+        env,
+        default_value = "0",
+        help = "Refuse new connections on accept with a 429 while this many from the same peer address are waiting to upgrade to a websocket (0 for no limit)"
+    )]
+    max_pending_handshakes_per_ip: usize,
+
+    #[arg(
+        long,
+        env,
+        default_value = "0",
+        help = "While the global connection limit is reached, hold up to this many handshakes and admit them in order as connections are released (0 to refuse them straight away)"
+    )]
+    waiting_room_size: usize,
+
+    #[arg(
+        long,
+        env,
+        default_value = "5",
+        help = "Longest a handshake waits in the waiting room before being refused with a 429"
+    )]
+    waiting_room_secs: u64,
+
+    #[arg(
+        long,
+        env,
+        default_value = "0",
+        help = "Keep this many connections below the global connection limit for clients with priority, e.g. --priority-users"
+    )]
+    reserved_connections: usize,
+
+    #[arg(
+        long,
+        env,
+        default_value = "false",
+        help = "When a client with priority arrives at the global connection limit, disconnect the websocket client without priority that is furthest behind to make room for it"
+    )]
+    evict_for_priority: bool,
+
+    #[arg(
+        long,
+        env,
+        default_value = "false",
+        help = "Accept cleartext HTTP/2 connections as well as HTTP/1.1, with websockets opened by extended CONNECT (RFC 8441)"
+    )]
+    http2: bool,
+
+    #[arg(
+        long,
+        env,
+        default_value = "1024",
+        help = "Number of connections the kernel queues for each listener before they are accepted, capped by net.core.somaxconn"
+    )]
+    listen_backlog: u32,
+
+    #[arg(
+        long,
+        env,
+        help = "Accept connections on this inherited listening socket instead of binding --listen-addr. Sockets passed by systemd socket activation are used without it"
+    )]
+    listen_fd: Option<i32>,
+
+    #[arg(
+        long,
+        env,
+        default_value = "false",
+        help = "Bind --listen-addr with SO_REUSEPORT even with one acceptor, so that a new process can take over the address before this one shuts down"
+    )]
+    reuse_port: bool,
+
     #[arg(
         long,
         env,
@@ -44,14 +228,82 @@ struct Args {
     )]
     upstream_ws: Vec<Uri>,
 
+    #[arg(
+        long = "stream",
+        env = "STREAMS",
+        value_delimiter = ';',
+        help = "Named stream to serve at /ws/{name}, e.g. name=base,upstream=wss://a.example/ws,buffer=50,max-connections=1000, or name=free,source=default,delay-ms=500,project=hash:/diff/transactions to republish another stream (repeatable, ';' separated in the environment)"
+    )]
+    streams: Vec<StreamConfig>,
+
+    #[arg(
+        long = "upstream-filter",
+        env = "UPSTREAM_FILTERS",
+        value_delimiter = ';',
+        help = "Drop upstream messages that start with a prefix (prefix:<text>) or have a value at a JSON pointer (pointer:/type=keepalive), e.g. keepalive frames (repeatable, ';' separated in the environment)"
+    )]
+    upstream_filters: Vec<MessageFilter>,
+
+    #[arg(
+        long,
+        env,
+        help = "Name of a transform, loaded with --wasm-module or --script, to run on every message published to the default stream"
+    )]
+    ingest_transform: Option<String>,
+
     #[arg(
         long,
         env,
         default_value = "20",
-        help = "Number of messages to buffer for lagging clients"
+        help = "Number of messages to queue for each client before it is considered lagging"
     )]
     message_buffer_size: usize,
 
+    #[arg(
+        long,
+        env,
+        default_value = "0",
+        help = "Evict the oldest buffered messages once client queues and the flashblock cache hold an estimated this many bytes (0 for no limit)"
+    )]
+    memory_budget_bytes: u64,
+
+    #[arg(
+        long,
+        env,
+        default_value = "1",
+        help = "Number of shards to spread the client list across"
+    )]
+    broadcast_shards: usize,
+
+    #[arg(
+        long,
+        env,
+        default_value = "0",
+        help = "Drop messages queued for a client for longer than this many milliseconds instead of delivering them late (0 disables)"
+    )]
+    message_ttl_ms: u64,
+
+    #[arg(
+        long,
+        env,
+        value_enum,
+        default_value = "skip",
+        help = "What to do when a client's queue is full: skip drops the queue and resumes with the next message, overwrite drops the oldest queued message"
+    )]
+    lag_strategy: LagStrategy,
+
+    /// Milliseconds between pings to each client (0 disables pings)
+    #[arg(long, env, default_value = "0")]
+    ping_interval_ms: u64,
+
+    /// Milliseconds after a ping within which the client's pong must arrive
+    #[arg(long, env, default_value = "5000")]
+    pong_timeout_ms: u64,
+
+    /// Consecutive pongs a client may miss before it is disconnected
+    #[arg(long, env, default_value = "2")]
+    max_missed_pongs: u32,
+
     #[arg(
         long,
         env,
@@ -83,6 +335,27 @@ struct Args {
     #[arg(long, env, default_value = "text")]
     log_format: String,
 
+    /// Log only one in N high-frequency events per class, in the format
+    /// --log-sample-rates messages=1000,reconnects=10,lag=100
+    #[arg(long, env, default_value = "")]
+    log_sample_rates: String,
+
+    /// Write connection audit events as JSON to this file instead of the application logs
+    #[arg(long, env)]
+    audit_log_file: Option<PathBuf>,
+
+    /// How often to rotate --audit-log-file
+    #[arg(long, env, value_enum, default_value = "daily")]
+    audit_log_rotation: Rotation,
+
+    /// Also rotate --audit-log-file before it grows past this many bytes (0 for no limit)
+    #[arg(long, env, default_value = "0")]
+    audit_log_max_bytes: u64,
+
+    /// Gzip rotated audit log files
+    #[arg(long, env, default_value = "false")]
+    audit_log_compress: bool,
+
     // Enable Prometheus metrics
     #[arg(long, env, default_value = "true")]
     metrics: bool,
@@ -95,10 +368,211 @@ struct Args {
     #[arg(long, env, default_value = "")]
     metrics_global_labels: String,
 
+    /// Override histogram buckets per metric, in the format
+    /// --metrics-histogram-buckets fan_out_latency=0.001,0.005,0.01;connection_duration=1,60,3600
+    #[arg(long, env, default_value = "")]
+    metrics_histogram_buckets: String,
+
+    /// Require this bearer token in the Authorization header of requests to the metrics endpoint
+    #[arg(long, env, hide_env_values = true)]
+    metrics_bearer_token: Option<String>,
+
+    /// Comma separated CIDRs or IP addresses allowed to read the metrics endpoint (default: any)
+    #[arg(long, env, default_value = "")]
+    metrics_allowed_cidrs: String,
+
     /// Add the hostname as a label to all Prometheus metrics
     #[arg(long, env, default_value = "false")]
     metrics_host_label: bool,
 
+    /// Pushgateway URL, including the grouping key, to push Prometheus metrics to (e.g.
+    /// http://pushgateway:9091/metrics/job/flashblocks-websocket-proxy/instance/proxy-0), can be
+    /// used alongside or instead of the Prometheus endpoint
+    #[arg(long, env)]
+    pushgateway_url: Option<String>,
+
+    /// Interval in seconds between pushes to the Pushgateway
+    #[arg(long, env, default_value = "15")]
+    pushgateway_interval: u64,
+
+    /// OTLP/HTTP endpoint to push metrics to (e.g. http://localhost:4318/v1/metrics), can be used
+    /// alongside or instead of the Prometheus endpoint
+    #[arg(long, env)]
+    otlp_metrics_endpoint: Option<String>,
+
+    /// Interval in seconds between OTLP metrics exports
+    #[arg(long, env, default_value = "30")]
+    otlp_metrics_interval: u64,
+
+    /// Address of a StatsD/DogStatsD agent to send metrics to, either host:port for UDP or
+    /// unix:///path / unixgram:///path for a Unix domain socket
+    #[arg(long, env)]
+    statsd_addr: Option<String>,
+
+    /// Webhook URL that proxy-internal failures (panics, repeated upstream failures) are POSTed
+    /// to as JSON
+    #[arg(long, env)]
+    error_webhook_url: Option<String>,
+
+    /// Number of consecutive failed upstream connection attempts before reporting an outage to
+    /// the error webhook
+    #[arg(long, env, default_value = "5")]
+    error_report_upstream_failures: u32,
+
+    /// Report not ready on /readyz unless an upstream has delivered a message within this many
+    /// milliseconds, e.g. 10000 (default: 0, disabled)
+    #[arg(long, env, default_value = "0")]
+    readiness_max_upstream_age_ms: u64,
+
+    /// Report not ready on /readyz once this fraction of the global connection limit is in use,
+    /// e.g. 0.95 (default: 0, disabled)
+    #[arg(long, env, default_value = "0")]
+    readiness_max_capacity: f64,
+
+    /// Shed load when the p99 age of the last message delivered to clients exceeds this many
+    /// milliseconds (0 disables the check)
+    #[arg(long, env, default_value = "0")]
+    load_shed_max_lag_ms: u64,
+
+    /// Shed load when a client's queue is this full, as a fraction of --message-buffer-size
+    /// (0 disables the check)
+    #[arg(long, env, default_value = "0")]
+    load_shed_max_queue_fraction: f64,
+
+    /// Seconds of sustained overload before rejecting new connections, and again before
+    /// lag-dropping the slowest clients
+    #[arg(long, env, default_value = "10")]
+    load_shed_sustained_secs: u32,
+
+    /// Fraction of clients, slowest first, to lag-drop each second while shedding load
+    #[arg(long, env, default_value = "0.1")]
+    load_shed_lag_drop_fraction: f64,
+
+    /// Seconds to keep serving, while reporting not ready, after SIGTERM or SIGINT before shutting
+    /// down, so load balancers can deregister the proxy first (0 shuts down immediately)
+    #[arg(long, env, default_value = "0")]
+    shutdown_delay: u64,
+
+    /// Number of tokio worker threads (default: number of CPUs available to the process)
+    #[arg(long, env)]
+    runtime_worker_threads: Option<usize>,
+
+    /// Maximum number of threads tokio spawns for blocking operations (default: 512)
+    #[arg(long, env)]
+    runtime_max_blocking_threads: Option<usize>,
+
+    /// Number of tasks a worker polls before checking for IO and timer events (default: 61)
+    #[arg(long, env)]
+    runtime_event_interval: Option<u32>,
+
+    /// Record every upstream message, with the time it was received, to this file
+    #[arg(long, env)]
+    record_file: Option<PathBuf>,
+
+    /// Serve the messages in this recording instead of subscribing to the upstreams
+    #[arg(long, env)]
+    replay_file: Option<PathBuf>,
+
+    /// Replay the recording this many times faster than it was recorded
+    #[arg(long, env, default_value = "1.0")]
+    replay_speed: f64,
+
+    /// Comma separated origins, e.g. https://app.example.com, that browsers may open websocket
+    /// connections from (default: any)
+    #[arg(long, env, value_delimiter = ',')]
+    allowed_origins: Vec<String>,
+
+    /// Require websocket clients to authenticate with HTTP Basic auth as one of the
+    /// username:password pairs in this file, one per line
+    #[arg(long, env)]
+    basic_auth_file: Option<PathBuf>,
+
+    /// Comma separated --basic-auth-file users admitted with priority near the global connection
+    /// limit
+    #[arg(long, env, value_delimiter = ',')]
+    priority_users: Vec<String>,
+
+    /// Ask the authorization service at this URL whether to admit each websocket client, by
+    /// POSTing the client's IP, origin, stream and some of its headers
+    #[arg(long, env)]
+    authorizer_url: Option<String>,
+
+    /// How long to wait for the authorization service before treating it as failed
+    #[arg(long, env, default_value = "500")]
+    authorizer_timeout_ms: u64,
+
+    /// How long to reuse the authorization service's decision for identical handshakes (0 to
+    /// always ask)
+    #[arg(long, env, default_value = "60")]
+    authorizer_cache_secs: u64,
+
+    /// Admit clients when the authorization service fails or times out, instead of refusing them
+    #[arg(long, env, default_value = "false")]
+    authorizer_fail_open: bool,
+
+    /// Attach the country and ASN of each client's address to its connection logs and metrics,
+    /// from these MaxMind databases (.mmdb), e.g. GeoLite2-Country and GeoLite2-ASN
+    #[arg(long, env, value_delimiter = ',')]
+    geoip_db: Vec<PathBuf>,
+
+    /// Only admit websocket clients from these countries (ISO codes, e.g. US,DE), according to
+    /// --geoip-db
+    #[arg(long, env, value_delimiter = ',')]
+    geoip_allow_countries: Vec<String>,
+
+    /// Refuse websocket clients from these countries (ISO codes), according to --geoip-db
+    #[arg(long, env, value_delimiter = ',')]
+    geoip_deny_countries: Vec<String>,
+
+    /// Only admit websocket clients from these autonomous systems, according to --geoip-db
+    #[arg(long, env, value_delimiter = ',')]
+    geoip_allow_asns: Vec<u32>,
+
+    /// Refuse websocket clients from these autonomous systems, according to --geoip-db
+    #[arg(long, env, value_delimiter = ',')]
+    geoip_deny_asns: Vec<u32>,
+
+    /// Maximum number of concurrently connected clients from any one autonomous system, according
+    /// to --geoip-db
+    #[arg(long, env)]
+    per_asn_connections_limit: Option<usize>,
+
+    /// Ban a client address for --auto-ban-duration-secs once it's been refused with a 401 or 429
+    /// this many times within --auto-ban-window-secs (0 to disable)
+    #[arg(long, env, default_value = "0")]
+    auto_ban_threshold: u32,
+
+    /// Period over which refusals are counted towards a ban
+    #[arg(long, env, default_value = "60")]
+    auto_ban_window_secs: u64,
+
+    /// How long an address's first ban lasts. Each further ban lasts twice as long as the last
+    #[arg(long, env, default_value = "60")]
+    auto_ban_duration_secs: u64,
+
+    /// Longest a ban may last. Addresses that stay out of trouble this long after a ban start over
+    #[arg(long, env, default_value = "3600")]
+    auto_ban_max_duration_secs: u64,
+
+    /// Serve downstream instances of the proxy on /relay, requiring this bearer token
+    #[arg(long, env, hide_env_values = true)]
+    relay_token: Option<String>,
+
+    /// Serve the admin API on /admin, for changing settings at runtime, requiring this bearer
+    /// token
+    #[arg(long, env, hide_env_values = true)]
+    admin_token: Option<String>,
+
+    /// Answer flashblocks_getLatest and flashblocks_getByNumber over JSON-RPC on /rpc, caching
+    /// the flashblocks of this many recent blocks (0 to disable)
+    #[arg(long, env, default_value = "0")]
+    rpc_cache_blocks: usize,
+
+    /// Treat the upstreams as instances of this proxy, connecting to their /relay endpoint with
+    /// this bearer token
+    #[arg(long, env, hide_env_values = true)]
+    upstream_relay_token: Option<String>,
+
     /// Maximum backoff allowed for upstream connections
     #[arg(long, env, default_value = "20")]
     subscriber_max_interval: u64,
@@ -117,128 +591,684 @@ struct Args {
         help = "Prefix for Redis keys"
     )]
     redis_key_prefix: String,
+
+    /// Elect one instance sharing --redis-url and --redis-key-prefix to subscribe to the
+    /// upstreams and relay messages to the others through Redis
+    #[arg(long, env, default_value = "false")]
+    upstream_leader_election: bool,
+
+    /// Seconds an elected leader holds the upstream lease without renewing it
+    #[arg(long, env, default_value = "10")]
+    upstream_leader_lease_secs: u64,
+
+    /// Send clients a `{"status":"degraded","type":"proxy_status"}` message when every upstream
+    /// is disconnected, and a `healthy` one once an upstream reconnects
+    #[arg(long, env, default_value = "false")]
+    client_status_events: bool,
+
+    /// Publish every message to the MQTT broker at this host:port
+    #[arg(long, env)]
+    mqtt_broker: Option<String>,
+
+    /// Topic to publish messages to, with {block_number} and {index} replaced by the message's,
+    /// e.g. flashblocks/{block_number}/{index}
+    #[arg(long, env, default_value = "flashblocks")]
+    mqtt_topic: TopicTemplate,
+
+    /// Quality of service to publish messages with: 0 (at most once) or 1 (at least once)
+    #[arg(long, env, default_value = "0", value_parser = clap::value_parser!(u8).range(0..=1))]
+    mqtt_qos: u8,
+
+    /// Client ID to connect to the broker with (default: flashblocks-websocket-proxy-<hostname>)
+    #[arg(long, env)]
+    mqtt_client_id: Option<String>,
+
+    /// Username to connect to the broker with
+    #[arg(long, env)]
+    mqtt_username: Option<String>,
+
+    /// Password to connect to the broker with
+    #[arg(long, env, hide_env_values = true)]
+    mqtt_password: Option<String>,
+
+    #[cfg(feature = "chaos")]
+    #[command(flatten)]
+    chaos: ChaosArgs,
+
+    #[cfg(feature = "webtransport")]
+    #[command(flatten)]
+    webtransport: WebTransportArgs,
+
+    #[cfg(feature = "acme")]
+    #[command(flatten)]
+    acme: AcmeArgs,
+
+    #[cfg(feature = "wasm")]
+    #[command(flatten)]
+    wasm: WasmArgs,
+
+    #[cfg(feature = "scripting")]
+    #[command(flatten)]
+    scripting: ScriptArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run the proxy (the default when no subcommand is given)
+    Serve(ServeArgs),
+    /// Validate the configuration and exit non-zero if anything is wrong
+    Check {
+        #[command(flatten)]
+        serve: ServeArgs,
+
+        #[command(flatten)]
+        check: CheckArgs,
+    },
+    /// Exit 0 if the proxy on this host reports ready, or 1 if not, e.g. for a Docker HEALTHCHECK
+    Healthcheck(HealthcheckArgs),
+    /// Open many websocket clients against a running proxy and report delivery latency and drops
+    Loadtest(LoadTestArgs),
+    /// Serve synthetic flashblocks, standing in for an upstream when running the proxy locally
+    MockUpstream(MockUpstreamArgs),
+    /// Print the messages sent by a proxy or upstream to stdout
+    Tail(TailArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct CheckArgs {
+    /// Also open a websocket connection to each upstream to check that it is reachable
+    #[arg(long)]
+    handshake: bool,
+
+    /// Seconds to wait for each upstream handshake
+    #[arg(long, default_value = "5")]
+    handshake_timeout: u64,
 }
 
-#[tokio::main]
-async fn main() {
+fn main() {
     dotenv().ok();
     let args = Args::parse();
 
+    match args.command.unwrap_or(Command::Serve(args.serve)) {
+        Command::Serve(args) => serve_runtime(&args).block_on(run(args)),
+        Command::Check { serve, check } => {
+            let mut problems = check_config(&serve);
+            if check.handshake {
+                let uris: Vec<_> = serve
+                    .upstream_ws
+                    .iter()
+                    .chain(serve.streams.iter().flat_map(|stream| &stream.upstreams))
+                    .cloned()
+                    .collect();
+                problems.extend(runtime().block_on(check_upstreams(
+                    &uris,
+                    Duration::from_secs(check.handshake_timeout),
+                )));
+            }
+
+            if !problems.is_empty() {
+                for problem in &problems {
+                    eprintln!("error: {problem}");
+                }
+                std::process::exit(1);
+            }
+            println!("configuration ok");
+        }
+        Command::Healthcheck(healthcheck_args) => {
+            if let Err(e) = runtime().block_on(healthcheck::run(healthcheck_args)) {
+                eprintln!("unhealthy: {e}");
+                std::process::exit(1);
+            }
+        }
+        Command::Loadtest(loadtest_args) => {
+            let report = runtime().block_on(loadtest::run(loadtest_args));
+            println!("{report}");
+        }
+        Command::MockUpstream(mock_args) => {
+            tracing_subscriber::fmt().with_ansi(false).init();
+            if let Err(e) = runtime().block_on(mock_upstream::run(mock_args)) {
+                eprintln!("error: {e}");
+                std::process::exit(1);
+            }
+        }
+        Command::Tail(tail_args) => {
+            if let Err(e) = runtime().block_on(tail::run(tail_args)) {
+                eprintln!("error: {e}");
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+fn runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Runtime::new().expect("failed to build tokio runtime")
+}
+
+/// Builds the runtime the proxy is served from, tuned by the `--runtime-*` flags.
+fn serve_runtime(args: &ServeArgs) -> tokio::runtime::Runtime {
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+
+    if let Some(worker_threads) = args.runtime_worker_threads {
+        runtime.worker_threads(worker_threads);
+    }
+
+    if let Some(max_blocking_threads) = args.runtime_max_blocking_threads {
+        runtime.max_blocking_threads(max_blocking_threads);
+    }
+
+    if let Some(event_interval) = args.runtime_event_interval {
+        runtime.event_interval(event_interval);
+    }
+
+    runtime.build().expect("failed to build tokio runtime")
+}
+
+async fn run(args: ServeArgs) {
     let log_format = args.log_format.to_lowercase();
     let log_level = args.log_level.to_string();
 
-    if log_format == "json" {
-        tracing_subscriber::fmt()
-            .json()
-            .with_env_filter(EnvFilter::new(log_level))
-            .with_ansi(false)
-            .init();
+    let app_layer = if log_format == "json" {
+        fmt::layer().json().with_ansi(false).boxed()
     } else {
-        tracing_subscriber::fmt()
-            .with_env_filter(EnvFilter::new(log_level))
-            .with_ansi(false)
-            .init();
-    }
+        fmt::layer().with_ansi(false).boxed()
+    };
 
-    if args.metrics {
-        info!(
-            message = "starting metrics server",
-            address = args.metrics_addr.to_string()
-        );
+    // Keep the guard alive for the lifetime of the process so buffered audit events are flushed.
+    let (audit_layer, _audit_guard) = match &args.audit_log_file {
+        Some(path) => {
+            let file = RotatingFile::open(
+                path,
+                RotationConfig {
+                    rotation: args.audit_log_rotation,
+                    max_bytes: (args.audit_log_max_bytes > 0).then_some(args.audit_log_max_bytes),
+                    compress: args.audit_log_compress,
+                },
+            )
+            .expect("failed to open audit log file");
+            let (writer, guard) = tracing_appender::non_blocking(file);
 
-        let mut builder = PrometheusBuilder::new().with_http_listener(args.metrics_addr);
+            let layer = fmt::layer()
+                .json()
+                .with_ansi(false)
+                .with_writer(writer)
+                .with_filter(Targets::new().with_target(AUDIT_TARGET, Level::INFO));
 
-        if args.metrics_host_label {
-            let hostname = hostname::get()
-                .expect("could not find hostname")
-                .into_string()
-                .expect("could not convert hostname to string");
-            builder = builder.add_global_label("hostname", hostname);
+            (Some(layer), Some(guard))
         }
+        None => (None, None),
+    };
 
-        for (key, value) in parse_global_metrics(args.metrics_global_labels) {
-            builder = builder.add_global_label(key, value);
-        }
+    let app_filter = if audit_layer.is_some() {
+        EnvFilter::new(format!("{log_level},{AUDIT_TARGET}=off"))
+    } else {
+        EnvFilter::new(log_level)
+    };
 
-        builder
-            .install()
-            .expect("failed to setup Prometheus endpoint")
+    tracing_subscriber::registry()
+        .with(app_layer.with_filter(app_filter))
+        .with(audit_layer)
+        .init();
+
+    log_sampling::configure(&args.log_sample_rates);
+
+    if let Some(url) = args.error_webhook_url.clone() {
+        error_reporting::init(url, args.error_report_upstream_failures);
     }
 
-    // Validate that we have at least one upstream URI
-    if args.upstream_ws.is_empty() {
-        error!(message = "no upstream URIs provided");
-        panic!("No upstream URIs provided");
+    let mut global_labels = parse_global_metrics(args.metrics_global_labels.clone());
+
+    if args.metrics_host_label {
+        let hostname = hostname::get()
+            .expect("could not find hostname")
+            .into_string()
+            .expect("could not convert hostname to string");
+        global_labels.push(("hostname".to_string(), hostname));
     }
 
-    info!(message = "using upstream URIs", uris = ?args.upstream_ws);
+    let histogram_buckets = parse_histogram_buckets(&args.metrics_histogram_buckets)
+        .expect("invalid histogram buckets");
 
-    let metrics = Arc::new(Metrics::default());
-    let metrics_clone = metrics.clone();
+    let mut recorders = FanoutBuilder::default();
 
-    let (send, _rec) = broadcast::channel(args.message_buffer_size);
-    let sender = send.clone();
+    if args.metrics || args.pushgateway_url.is_some() {
+        let mut builder = PrometheusBuilder::new();
 
-    let listener = move |data: String| {
-        trace!(message = "received data", data = data);
-        // Subtract one from receiver count, as we have to keep one receiver open at all times (see _rec)
-        // to avoid the channel being closed. However this is not an active client connection.
-        metrics_clone
-            .active_connections
-            .set((send.receiver_count() - 1) as f64);
+        for (name, buckets) in &histogram_buckets {
+            builder = builder
+                .set_buckets_for_metric(Matcher::Suffix(name.to_string()), buckets)
+                .expect("invalid histogram buckets");
+        }
 
-        match send.send(data) {
-            Ok(_) => (),
-            Err(e) => error!(message = "failed to send data", error = e.to_string()),
+        for (key, value) in &global_labels {
+            builder = builder.add_global_label(key, value);
         }
-    };
 
-    let token = CancellationToken::new();
-    let mut subscriber_tasks = Vec::new();
+        let recorder = builder.build_recorder();
 
-    // Start a subscriber for each upstream URI
-    for (index, uri) in args.upstream_ws.iter().enumerate() {
-        let uri_clone = uri.clone();
-        let listener_clone = listener.clone();
-        let token_clone = token.clone();
-        let metrics_clone = metrics.clone();
-
-        let mut subscriber = WebsocketSubscriber::new(
-            uri_clone.clone(),
-            listener_clone,
-            args.subscriber_max_interval,
-            metrics_clone,
-        );
+        if args.metrics {
+            info!(
+                message = "starting metrics server",
+                address = args.metrics_addr.to_string()
+            );
+
+            let auth = MetricsAuth {
+                bearer_token: args.metrics_bearer_token.clone(),
+                allowed_networks: metrics_server::parse_allowed_networks(
+                    &args.metrics_allowed_cidrs,
+                )
+                .expect("invalid metrics allowed CIDRs"),
+            };
+
+            tokio::spawn(metrics_server::serve(
+                args.metrics_addr,
+                recorder.handle(),
+                auth,
+            ));
+        }
 
-        let task = tokio::spawn(async move {
+        if let Some(url) = args.pushgateway_url.clone() {
             info!(
-                message = "starting subscriber",
-                index = index,
-                uri = uri_clone.to_string()
+                message = "pushing metrics to Pushgateway",
+                url = url,
+                interval = args.pushgateway_interval
             );
-            subscriber.run(token_clone).await;
-        });
 
-        subscriber_tasks.push(task);
+            tokio::spawn(metrics_server::push(
+                url,
+                recorder.handle(),
+                Duration::from_secs(args.pushgateway_interval),
+            ));
+        }
+
+        recorders = recorders.add_recorder(recorder);
     }
 
-    let registry = Registry::new(sender, metrics.clone());
+    let otlp_provider = args.otlp_metrics_endpoint.clone().map(|endpoint| {
+        info!(
+            message = "starting OTLP metrics exporter",
+            endpoint = endpoint,
+            interval = args.otlp_metrics_interval
+        );
 
-    let rate_limiter = match &args.redis_url {
-        Some(redis_url) => {
-            info!(message = "Using Redis rate limiter", redis_url = redis_url);
-            match RedisRateLimit::new(
-                redis_url,
-                args.global_connections_limit,
-                args.per_ip_connections_limit,
-                &args.redis_key_prefix,
-            ) {
-                Ok(limiter) => {
-                    info!(message = "Connected to Redis successfully");
-                    Arc::new(limiter) as Arc<dyn RateLimit>
-                }
-                Err(e) => {
-                    error!(
+        otlp_meter_provider(
+            endpoint,
+            Duration::from_secs(args.otlp_metrics_interval),
+            &global_labels,
+        )
+    });
+
+    if let Some(provider) = &otlp_provider {
+        let recorder = OpenTelemetryRecorder::new(provider.meter(env!("CARGO_PKG_NAME")));
+        for (name, buckets) in &histogram_buckets {
+            recorder.set_histogram_bounds(
+                &KeyName::from(format!("websocket_proxy.{name}")),
+                buckets.clone(),
+            );
+        }
+        recorders = recorders.add_recorder(recorder);
+    }
+
+    if let Some(statsd_addr) = &args.statsd_addr {
+        info!(
+            message = "starting DogStatsD metrics exporter",
+            address = statsd_addr
+        );
+
+        let recorder = DogStatsDBuilder::default()
+            .with_remote_address(statsd_addr)
+            .expect("invalid DogStatsD address")
+            .with_global_labels(
+                global_labels
+                    .iter()
+                    .map(|(key, value)| Label::new(key.clone(), value.clone()))
+                    .collect(),
+            )
+            .build()
+            .expect("failed to setup DogStatsD exporter");
+        recorders = recorders.add_recorder(recorder);
+    }
+
+    if args.metrics
+        || args.pushgateway_url.is_some()
+        || otlp_provider.is_some()
+        || args.statsd_addr.is_some()
+    {
+        ::metrics::set_global_recorder(recorders.build())
+            .expect("failed to install metrics recorder");
+    }
+
+    // Validate that we have at least one upstream URI
+    if args.upstream_ws.is_empty() && args.replay_file.is_none() && args.streams.is_empty() {
+        error!(message = "no upstream URIs provided");
+        panic!("No upstream URIs provided");
+    }
+
+    info!(message = "using upstream URIs", uris = ?args.upstream_ws);
+    info!(message = "using allocator", allocator = allocator::NAME);
+
+    let metrics = Arc::new(Metrics::default());
+    panic_hook::install(metrics.clone());
+    let metrics_clone = metrics.clone();
+
+    let mut registry = Registry::new(
+        args.message_buffer_size,
+        args.broadcast_shards,
+        metrics.clone(),
+    );
+    if args.message_ttl_ms > 0 {
+        registry = registry.with_message_ttl(Duration::from_millis(args.message_ttl_ms));
+    }
+    registry = registry.with_lag_strategy(args.lag_strategy);
+    if let Some(heartbeat) = heartbeat_config(&args) {
+        registry = registry.with_heartbeat(heartbeat);
+    }
+    let transforms = load_transforms(&args).expect("failed to load transforms");
+    if let Some(name) = &args.ingest_transform {
+        registry = registry.with_transform(
+            transforms
+                .instantiate(name)
+                .expect("failed to instantiate --ingest-transform"),
+        );
+    }
+    let publisher = registry.clone();
+
+    let recorder = args.record_file.as_deref().map(|path| {
+        info!(message = "recording upstream messages", path = %path.display());
+        Arc::new(Recorder::create(path).expect("failed to create recording file"))
+    });
+
+    let flashblock_cache =
+        (args.rpc_cache_blocks > 0).then(|| Arc::new(FlashblockCache::new(args.rpc_cache_blocks)));
+    let cache = flashblock_cache.clone();
+
+    let publish = move |data: Bytes, upstream: Option<u16>| {
+        if let Some(recorder) = &recorder {
+            recorder.record(&data);
+        }
+        if let Some(cache) = &cache {
+            cache.insert(&data);
+        }
+        if let Some(suppressed) = log_sampling::sample(EventClass::Messages) {
+            trace!(
+                message = "received data",
+                data = %String::from_utf8_lossy(&data),
+                suppressed = suppressed
+            );
+        }
+        let clients = publisher.publish_from(data, upstream);
+        metrics_clone.active_connections.set(clients as f64);
+    };
+
+    let token = CancellationToken::new();
+    let mut subscriber_tasks = Vec::new();
+    let mut upstream_statuses = Vec::new();
+
+    let election = args.upstream_leader_election.then(|| {
+        let redis_url = args
+            .redis_url
+            .as_deref()
+            .expect("--upstream-leader-election requires --redis-url");
+        Arc::new(
+            LeaderElection::new(
+                redis_url,
+                &args.redis_key_prefix,
+                Duration::from_secs(args.upstream_leader_lease_secs),
+                metrics.clone(),
+            )
+            .expect("invalid Redis URL"),
+        )
+    });
+
+    if let Some(election) = &election {
+        let status = election.relay_status();
+        upstream_statuses.push(status.clone());
+        let publish = publish.clone();
+        election.clone().spawn_relay_listener(
+            move |data: Bytes| publish(data, None),
+            status,
+            token.clone(),
+        );
+        tokio::spawn(election.clone().run(token.clone()));
+    }
+
+    let relay = election.clone();
+    let listener = move |data: Bytes, upstream: Option<u16>| {
+        if let Some(relay) = &relay {
+            relay.relay(data.clone());
+        }
+        publish(data, upstream);
+    };
+
+    let upstreams = match &args.replay_file {
+        Some(path) => {
+            let status = Arc::new(UpstreamStatus::new(
+                format!("file://localhost{}", path.display())
+                    .parse()
+                    .unwrap_or_else(|_| Uri::from_static("file://replay")),
+            ));
+            upstream_statuses.push(status.clone());
+
+            let path = path.clone();
+            let listener = listener.clone();
+            let speed = args.replay_speed;
+            let token = token.clone();
+            // Keep serving once the recording has been replayed, so clients can still connect
+            subscriber_tasks.push(tokio::spawn(async move {
+                let result = recording::replay(
+                    &path,
+                    speed,
+                    move |data: Bytes| listener(data, None),
+                    status,
+                    token.clone(),
+                )
+                .await;
+                if let Err(e) = result {
+                    error!(
+                        message = "failed to replay recording",
+                        error = e.to_string()
+                    );
+                }
+                token.cancelled().await;
+            }));
+
+            &[][..]
+        }
+        None => &args.upstream_ws[..],
+    };
+
+    #[cfg(feature = "chaos")]
+    if args.chaos.enabled() {
+        warn!(message = "injecting faults into upstream messages", config = ?args.chaos);
+    }
+
+    // Start a subscriber for each upstream URI
+    for (index, uri) in upstreams.iter().enumerate() {
+        let leadership = election.as_ref().map(|election| election.subscribe());
+        let listener = listener.clone();
+        let (status, task) = spawn_subscriber(
+            &args,
+            index,
+            uri,
+            move |data: Bytes| listener(data, Some(index as u16)),
+            metrics.clone(),
+            leadership,
+            token.clone(),
+        );
+        upstream_statuses.push(status);
+        subscriber_tasks.push(task);
+    }
+
+    if args.client_status_events && !upstream_statuses.is_empty() {
+        tokio::spawn(status_events::announce(
+            registry.clone(),
+            upstream_statuses.clone(),
+            Duration::from_secs(1),
+            token.clone(),
+        ));
+    }
+
+    if let Some(broker) = &args.mqtt_broker {
+        let client_id = args.mqtt_client_id.clone().unwrap_or_else(|| {
+            let hostname = hostname::get()
+                .ok()
+                .and_then(|hostname| hostname.into_string().ok())
+                .unwrap_or_default();
+            format!("flashblocks-websocket-proxy-{hostname}")
+        });
+        tokio::spawn(mqtt::bridge(
+            registry.clone(),
+            MqttConfig {
+                broker: broker.clone(),
+                client_id,
+                username: args.mqtt_username.clone(),
+                password: args.mqtt_password.clone(),
+                topic: args.mqtt_topic.clone(),
+                at_least_once: args.mqtt_qos == 1,
+                max_backoff: Duration::from_secs(args.subscriber_max_interval),
+            },
+            metrics.clone(),
+            token.clone(),
+        ));
+    }
+
+    let mut streams: Vec<(String, Stream)> = Vec::new();
+    for config in &args.streams {
+        let stream_metrics = Arc::new(Metrics::for_stream(&config.name));
+        let mut stream_registry = Registry::new(
+            config.buffer_size.unwrap_or(args.message_buffer_size),
+            args.broadcast_shards,
+            stream_metrics.clone(),
+        );
+        if args.message_ttl_ms > 0 {
+            stream_registry =
+                stream_registry.with_message_ttl(Duration::from_millis(args.message_ttl_ms));
+        }
+        stream_registry = stream_registry.with_lag_strategy(args.lag_strategy);
+        if let Some(heartbeat) = heartbeat_config(&args) {
+            stream_registry = stream_registry.with_heartbeat(heartbeat);
+        }
+        if let Some(name) = &config.transform {
+            stream_registry = stream_registry.with_transform(
+                transforms
+                    .instantiate(name)
+                    .unwrap_or_else(|e| panic!("--stream {}: {e}", config.name)),
+            );
+        }
+
+        info!(
+            message = "serving stream",
+            stream = config.name,
+            uris = ?config.upstreams,
+            source = config.source,
+            delay_ms = config.delay.map(|delay| delay.as_millis() as u64)
+        );
+        if let Some(source) = &config.source {
+            let source_registry = match source.as_str() {
+                "default" => registry.clone(),
+                name => streams
+                    .iter()
+                    .find(|(stream, _)| stream == name)
+                    .map(|(_, stream)| stream.registry().clone())
+                    .unwrap_or_else(|| panic!("--stream {}: unknown source {name}", config.name)),
+            };
+            tokio::spawn(tiers::mirror(
+                source_registry,
+                stream_registry.clone(),
+                Tier {
+                    delay: config.delay,
+                    projections: config.projections.clone(),
+                },
+                token.clone(),
+            ));
+        }
+        let mut stream_upstreams = Vec::new();
+        for (index, uri) in config.upstreams.iter().enumerate() {
+            let publisher = stream_registry.clone();
+            let publisher_metrics = stream_metrics.clone();
+            let (status, task) = spawn_subscriber(
+                &args,
+                index,
+                uri,
+                move |data: Bytes| {
+                    let clients = publisher.publish_from(data, Some(index as u16));
+                    publisher_metrics.active_connections.set(clients as f64);
+                },
+                stream_metrics.clone(),
+                None,
+                token.clone(),
+            );
+            stream_upstreams.push(status);
+            subscriber_tasks.push(task);
+        }
+        upstream_statuses.extend(stream_upstreams.iter().cloned());
+
+        if args.client_status_events {
+            tokio::spawn(status_events::announce(
+                stream_registry.clone(),
+                stream_upstreams.clone(),
+                Duration::from_secs(1),
+                token.clone(),
+            ));
+        }
+
+        let lag_registry = stream_registry.clone();
+        let lag_token = token.clone();
+        tokio::spawn(async move {
+            lag_registry
+                .report_lag_metrics(Duration::from_secs(1), lag_token)
+                .await;
+        });
+
+        let mut stream = Stream::new(stream_registry).with_upstreams(stream_upstreams);
+        if let Some(max_connections) = config.max_connections {
+            stream = stream.with_max_connections(max_connections);
+        }
+        streams.push((config.name.clone(), stream));
+    }
+
+    if args.memory_budget_bytes > 0 {
+        let budget = streams.iter().fold(
+            MemoryBudget::new(args.memory_budget_bytes).with_registry(registry.clone()),
+            |budget, (_, stream)| budget.with_registry(stream.registry().clone()),
+        );
+        let budget = match &flashblock_cache {
+            Some(cache) => budget.with_cache(cache.clone()),
+            None => budget,
+        };
+        tokio::spawn(budget.run(metrics.clone(), Duration::from_secs(1), token.clone()));
+    }
+
+    tokio::spawn(process_metrics::report(
+        metrics.clone(),
+        registry.clone(),
+        Duration::from_secs(5),
+        token.clone(),
+    ));
+
+    let lag_registry = registry.clone();
+    let lag_token = token.clone();
+    tokio::spawn(async move {
+        lag_registry
+            .report_lag_metrics(Duration::from_secs(1), lag_token)
+            .await;
+    });
+
+    let rate_limiter = match &args.redis_url {
+        Some(redis_url) => {
+            info!(message = "Using Redis rate limiter", redis_url = redis_url);
+            match RedisRateLimit::new(
+                redis_url,
+                args.global_connections_limit,
+                args.per_ip_connections_limit,
+                &args.redis_key_prefix,
+            ) {
+                Ok(limiter) => {
+                    info!(message = "Connected to Redis successfully");
+                    Arc::new(limiter) as Arc<dyn RateLimit>
+                }
+                Err(e) => {
+                    error!(
                         message =
                             "Failed to connect to Redis, falling back to in-memory rate limiting",
                         error = e.to_string()
@@ -259,36 +1289,641 @@ async fn main() {
         }
     };
 
+    let load_shedder = (args.load_shed_max_lag_ms > 0 || args.load_shed_max_queue_fraction > 0.0)
+        .then(|| {
+            Arc::new(LoadShedder::new(LoadShedConfig {
+                max_lag: (args.load_shed_max_lag_ms > 0)
+                    .then(|| Duration::from_millis(args.load_shed_max_lag_ms)),
+                max_queue_fraction: (args.load_shed_max_queue_fraction > 0.0)
+                    .then_some(args.load_shed_max_queue_fraction),
+                sustained_checks: args.load_shed_sustained_secs,
+                lag_drop_fraction: args.load_shed_lag_drop_fraction,
+            }))
+        });
+
+    if let Some(load_shedder) = &load_shedder {
+        let load_shedder = load_shedder.clone();
+        let registry = registry.clone();
+        let metrics = metrics.clone();
+        let token = token.clone();
+        tokio::spawn(async move {
+            load_shedder
+                .run(registry, metrics, Duration::from_secs(1), token)
+                .await;
+        });
+    }
+
+    let inherited_listener = args.listen_fd.or_else(systemd::listen_fd).map(|fd| {
+        server::inherit_listener(fd).expect("failed to accept on the inherited listener")
+    });
+    let listen_addr = match &inherited_listener {
+        Some(listener) => listener.local_addr().unwrap(),
+        None => args.listen_addr,
+    };
+
+    if systemd::enabled() {
+        tokio::spawn(systemd::notify_ready(
+            listen_addr,
+            upstream_statuses.clone(),
+            token.clone(),
+        ));
+        tokio::spawn(systemd::run_watchdog(token.clone()));
+    }
+
+    let acceptors = match args.listen_acceptors {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    };
+
     let server = Server::new(
-        args.listen_addr,
+        listen_addr,
         registry.clone(),
         metrics,
         rate_limiter,
         args.ip_addr_http_header,
-    );
+        upstream_statuses,
+        ReadinessConfig {
+            max_upstream_age: (args.readiness_max_upstream_age_ms > 0)
+                .then(|| Duration::from_millis(args.readiness_max_upstream_age_ms)),
+            max_capacity: (args.readiness_max_capacity > 0.0)
+                .then_some(args.readiness_max_capacity),
+        },
+    )
+    .with_acceptors(acceptors)
+    .with_listen_backlog(args.listen_backlog)
+    .with_reuse_port(args.reuse_port)
+    .with_handshake_limits(HandshakeConfig {
+        timeout: (args.handshake_timeout_ms > 0)
+            .then(|| Duration::from_millis(args.handshake_timeout_ms)),
+        max_pending: (args.max_pending_handshakes > 0).then_some(args.max_pending_handshakes),
+        max_pending_per_ip: (args.max_pending_handshakes_per_ip > 0)
+            .then_some(args.max_pending_handshakes_per_ip),
+        http2: args.http2,
+    });
+    let server = match inherited_listener {
+        Some(listener) => server.with_listener(listener),
+        None => server,
+    };
+    let server = match load_shedder {
+        Some(load_shedder) => server.with_load_shedder(load_shedder),
+        None => server,
+    };
+    let server = match args.relay_token {
+        Some(token) => server.with_relay_token(token),
+        None => server,
+    };
+    let server = match &args.authorizer_url {
+        Some(url) => server.with_authorizer(Authorizer::new(AuthorizerConfig {
+            url: url.clone(),
+            timeout: Duration::from_millis(args.authorizer_timeout_ms),
+            cache_ttl: Duration::from_secs(args.authorizer_cache_secs),
+            fail_open: args.authorizer_fail_open,
+        })),
+        None => server,
+    };
+    let server = match args.admin_token {
+        Some(token) => server.with_admin_token(token),
+        None => server,
+    };
+    let server = match flashblock_cache {
+        Some(cache) => server.with_json_rpc(cache),
+        None => server,
+    };
+    let server = server.with_allowed_origins(args.allowed_origins.clone());
+    let server = match &args.basic_auth_file {
+        Some(path) => {
+            server.with_basic_auth(BasicAuth::load(path).expect("failed to read --basic-auth-file"))
+        }
+        None => server,
+    };
+    let server = if args.geoip_db.is_empty() {
+        server
+    } else {
+        server
+            .with_geoip(GeoIp::open(&args.geoip_db).expect("failed to read --geoip-db"))
+            .with_access_policy(AccessPolicy {
+                allow_countries: args.geoip_allow_countries.clone(),
+                deny_countries: args.geoip_deny_countries.clone(),
+                allow_asns: args.geoip_allow_asns.clone(),
+                deny_asns: args.geoip_deny_asns.clone(),
+            })
+    };
+    let server = match args.per_asn_connections_limit {
+        Some(limit) => server.with_asn_limit(limit),
+        None => server,
+    };
+    let server = match args.waiting_room_size {
+        0 => server,
+        size => server.with_waiting_room(size, Duration::from_secs(args.waiting_room_secs)),
+    };
+    let server = server.with_priority(PriorityConfig {
+        users: args.priority_users.iter().cloned().collect(),
+        reserved: args.reserved_connections,
+        evict: args.evict_for_priority,
+    });
+    let server = match args.auto_ban_threshold {
+        0 => server,
+        threshold => server.with_auto_ban(BanConfig {
+            threshold,
+            window: Duration::from_secs(args.auto_ban_window_secs),
+            duration: Duration::from_secs(args.auto_ban_duration_secs),
+            max_duration: Duration::from_secs(args.auto_ban_max_duration_secs),
+        }),
+    };
+    #[cfg(feature = "webtransport")]
+    let server = match (
+        args.webtransport.webtransport_addr,
+        &args.webtransport.webtransport_cert,
+        &args.webtransport.webtransport_key,
+    ) {
+        (Some(addr), Some(cert), Some(key)) => server.with_webtransport(
+            WebTransportConfig::load(addr, cert, key)
+                .await
+                .expect("failed to read --webtransport-cert or --webtransport-key"),
+        ),
+        _ => server,
+    };
+    #[cfg(feature = "acme")]
+    let server = match args.acme.config() {
+        Some(config) => server.with_acme(config),
+        None => server,
+    };
+    let server = server.with_transforms(transforms);
+    let server = streams.into_iter().fold(server, |server, (name, stream)| {
+        server.with_stream(name, stream)
+    });
+    let shutdown_notice = CancellationToken::new();
+    let server = server.with_shutdown_notice(shutdown_notice.clone());
     let server_task = server.listen(token.clone());
+    tokio::pin!(server_task);
 
     let mut interrupt = signal(SignalKind::interrupt()).unwrap();
     let mut terminate = signal(SignalKind::terminate()).unwrap();
 
-    tokio::select! {
+    let mut server_running = true;
+    let signalled = tokio::select! {
         _ = futures::future::join_all(subscriber_tasks) => {
             info!("all subscriber tasks terminated");
-            token.cancel();
+            false
         },
-        _ = server_task => {
+        _ = &mut server_task => {
             info!("server task terminated");
-            token.cancel();
+            server_running = false;
+            false
         }
         _ = interrupt.recv() => {
             info!("process interrupted, shutting down");
-            token.cancel();
+            true
         }
         _ = terminate.recv() => {
             info!("process terminated, shutting down");
-            token.cancel();
+            true
+        }
+    };
+
+    if systemd::enabled() {
+        systemd::notify_stopping();
+    }
+
+    if signalled && args.shutdown_delay > 0 {
+        info!(
+            message = "reporting not ready before shutting down",
+            seconds = args.shutdown_delay
+        );
+        shutdown_notice.cancel();
+
+        // A second signal skips the rest of the delay.
+        tokio::select! {
+            _ = &mut server_task => server_running = false,
+            _ = tokio::time::sleep(Duration::from_secs(args.shutdown_delay)) => {}
+            _ = interrupt.recv() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    token.cancel();
+    // Waits for clients to be told the proxy is going away and their disconnects recorded
+    if server_running {
+        server_task.await;
+    }
+
+    if let Some(provider) = otlp_provider {
+        if let Err(e) = provider.shutdown() {
+            warn!(
+                message = "failed to flush OTLP metrics",
+                error = e.to_string()
+            );
+        }
+    }
+}
+
+/// Client heartbeats as configured by `args`, or `None` if pings are disabled.
+fn heartbeat_config(args: &ServeArgs) -> Option<HeartbeatConfig> {
+    (args.ping_interval_ms > 0).then(|| HeartbeatConfig {
+        ping_interval: Duration::from_millis(args.ping_interval_ms),
+        pong_timeout: Duration::from_millis(args.pong_timeout_ms),
+        max_missed_pongs: args.max_missed_pongs,
+    })
+}
+
+/// Starts a subscription to `uri`, configured from `args`, that passes each message to `handler`.
+/// With `leadership`, the subscription only runs while this instance is the upstream leader.
+fn spawn_subscriber<F>(
+    args: &ServeArgs,
+    index: usize,
+    uri: &Uri,
+    handler: F,
+    metrics: Arc<Metrics>,
+    leadership: Option<watch::Receiver<bool>>,
+    token: CancellationToken,
+) -> (Arc<UpstreamStatus>, JoinHandle<()>)
+where
+    F: Fn(Bytes) + Send + Sync + 'static,
+{
+    let mut subscriber =
+        WebsocketSubscriber::new(uri.clone(), handler, args.subscriber_max_interval, metrics);
+    if let Some(token) = &args.upstream_relay_token {
+        subscriber = subscriber.with_relay_token(token.clone());
+    }
+    if !args.upstream_filters.is_empty() {
+        subscriber = subscriber.with_filters(args.upstream_filters.clone());
+    }
+    #[cfg(feature = "chaos")]
+    if args.chaos.enabled() {
+        subscriber = subscriber.with_chaos(Chaos::new(args.chaos.clone()));
+    }
+    let status = subscriber.status();
+
+    let uri = uri.clone();
+    // Spanned so that a panic is reported with the upstream it happened on
+    let span = info_span!("upstream", index = index, uri = %uri);
+    let task = tokio::spawn(
+        async move {
+            info!(
+                message = "starting subscriber",
+                index = index,
+                uri = uri.to_string()
+            );
+            match leadership {
+                Some(leadership) => leader::run_while_leader(subscriber, leadership, token).await,
+                None => subscriber.run_supervised(token).await,
+            }
+        }
+        .instrument(span),
+    );
+
+    (status, task)
+}
+
+/// Validates the configuration without starting anything, returning a description of each
+/// problem found.
+fn check_config(args: &ServeArgs) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if args.upstream_ws.is_empty() && args.replay_file.is_none() && args.streams.is_empty() {
+        problems.push("no upstream URIs provided, set --upstream-ws".to_string());
+    }
+
+    if let Some(path) = &args.replay_file {
+        if !path.is_file() {
+            problems.push(format!("--replay-file {}: not a file", path.display()));
+        }
+    }
+
+    if args.replay_speed <= 0.0 {
+        problems.push(format!(
+            "--replay-speed {}: must be greater than 0",
+            args.replay_speed
+        ));
+    }
+    for uri in &args.upstream_ws {
+        if !matches!(uri.scheme_str(), Some("ws") | Some("wss")) {
+            problems.push(format!("--upstream-ws {uri}: scheme must be ws or wss"));
+        }
+        if uri.host().is_none() {
+            problems.push(format!("--upstream-ws {uri}: missing host"));
+        }
+    }
+
+    if args.metrics && args.metrics_addr == args.listen_addr {
+        problems.push(format!(
+            "--metrics-addr and --listen-addr are both {}",
+            args.listen_addr
+        ));
+    }
+
+    if !matches!(args.log_format.to_lowercase().as_str(), "json" | "text") {
+        problems.push(format!(
+            "--log-format {}: must be json or text",
+            args.log_format
+        ));
+    }
+
+    if let Some(path) = &args.audit_log_file {
+        if path.file_name().is_none() {
+            problems.push(format!(
+                "--audit-log-file {}: must be a file path",
+                path.display()
+            ));
+        }
+    }
+
+    if let Err(e) = parse_histogram_buckets(&args.metrics_histogram_buckets) {
+        problems.push(format!("--metrics-histogram-buckets: {e}"));
+    }
+
+    if let Err(e) = metrics_server::parse_allowed_networks(&args.metrics_allowed_cidrs) {
+        problems.push(format!("--metrics-allowed-cidrs: {e}"));
+    }
+
+    if let Some(url) = &args.pushgateway_url {
+        if let Err(e) = reqwest::Url::parse(url) {
+            problems.push(format!("--pushgateway-url {url}: {e}"));
+        }
+        if args.pushgateway_interval == 0 {
+            problems.push("--pushgateway-interval must be at least 1".to_string());
+        }
+    }
+
+    if let Some(endpoint) = &args.otlp_metrics_endpoint {
+        if let Err(e) = reqwest::Url::parse(endpoint) {
+            problems.push(format!("--otlp-metrics-endpoint {endpoint}: {e}"));
+        }
+    }
+
+    if args.mqtt_password.is_some() && args.mqtt_username.is_none() {
+        problems.push("--mqtt-password requires --mqtt-username".to_string());
+    }
+
+    if let Some(statsd_addr) = &args.statsd_addr {
+        if let Err(e) = DogStatsDBuilder::default().with_remote_address(statsd_addr) {
+            problems.push(format!("--statsd-addr {statsd_addr}: {e}"));
+        }
+    }
+
+    if let Some(url) = &args.error_webhook_url {
+        if let Err(e) = reqwest::Url::parse(url) {
+            problems.push(format!("--error-webhook-url {url}: {e}"));
+        }
+    }
+
+    if let Some(url) = &args.redis_url {
+        if let Err(e) = redis::Client::open(url.as_str()) {
+            problems.push(format!("--redis-url {url}: {e}"));
+        }
+    }
+
+    if args.upstream_leader_election && args.redis_url.is_none() {
+        problems.push("--upstream-leader-election requires --redis-url".to_string());
+    }
+
+    if args.upstream_leader_election && !args.streams.is_empty() {
+        problems.push(
+            "--upstream-leader-election only relays the default stream, it can't be used with --stream"
+                .to_string(),
+        );
+    }
+
+    for origin in args
+        .allowed_origins
+        .iter()
+        .filter(|origin| !origin.is_empty())
+    {
+        let valid = origin.parse::<Uri>().is_ok_and(|uri| {
+            uri.scheme().is_some()
+                && uri.authority().is_some()
+                && uri.path_and_query().is_none_or(|path| path == "/")
+        });
+        if !valid {
+            problems.push(format!(
+                "--allowed-origins {origin}: must be a scheme and host, e.g. https://app.example.com"
+            ));
+        }
+    }
+
+    if args.ping_interval_ms > 0
+        && (args.pong_timeout_ms == 0 || args.pong_timeout_ms > args.ping_interval_ms)
+    {
+        problems.push(format!(
+            "--pong-timeout-ms must be between 1 and --ping-interval-ms ({})",
+            args.ping_interval_ms
+        ));
+    }
+
+    if let Some(path) = &args.basic_auth_file {
+        if let Err(e) = BasicAuth::load(path) {
+            problems.push(format!("--basic-auth-file {}: {e}", path.display()));
+        }
+    } else if !args.priority_users.is_empty() {
+        problems.push("--priority-users requires --basic-auth-file".to_string());
+    }
+    if args.reserved_connections > 0 && args.reserved_connections >= args.global_connections_limit {
+        problems.push(format!(
+            "--reserved-connections must be less than --global-connections-limit ({})",
+            args.global_connections_limit
+        ));
+    }
+
+    if !args.geoip_db.is_empty() {
+        if let Err(e) = GeoIp::open(&args.geoip_db) {
+            problems.push(format!("--geoip-db: {e}"));
+        }
+    } else if !args.geoip_allow_countries.is_empty()
+        || !args.geoip_deny_countries.is_empty()
+        || !args.geoip_allow_asns.is_empty()
+        || !args.geoip_deny_asns.is_empty()
+    {
+        problems.push("--geoip-allow-* and --geoip-deny-* require --geoip-db".to_string());
+    }
+    if args.per_asn_connections_limit.is_some() && args.geoip_db.is_empty() {
+        problems.push("--per-asn-connections-limit requires --geoip-db".to_string());
+    }
+    if args.waiting_room_size > 0
+        && args.handshake_timeout_ms > 0
+        && args.waiting_room_secs * 1000 >= args.handshake_timeout_ms
+    {
+        problems.push(
+            "--waiting-room-secs must be less than --handshake-timeout-ms, which would cut waiting handshakes off"
+                .to_string(),
+        );
+    }
+    if args.auto_ban_threshold > 0 {
+        if args.auto_ban_window_secs == 0 || args.auto_ban_duration_secs == 0 {
+            problems.push(
+                "--auto-ban-window-secs and --auto-ban-duration-secs must be at least 1"
+                    .to_string(),
+            );
+        }
+        if args.auto_ban_max_duration_secs < args.auto_ban_duration_secs {
+            problems.push(
+                "--auto-ban-max-duration-secs must be at least --auto-ban-duration-secs"
+                    .to_string(),
+            );
+        }
+    }
+    for code in args
+        .geoip_allow_countries
+        .iter()
+        .chain(&args.geoip_deny_countries)
+    {
+        if code.len() != 2 || !code.bytes().all(|b| b.is_ascii_alphabetic()) {
+            problems.push(format!(
+                "--geoip-*-countries {code}: not an ISO 3166 country code"
+            ));
+        }
+    }
+
+    if args.listen_backlog == 0 {
+        problems.push("--listen-backlog must be at least 1".to_string());
+    }
+
+    if let Some(url) = &args.authorizer_url {
+        if let Err(e) = reqwest::Url::parse(url) {
+            problems.push(format!("--authorizer-url {url}: {e}"));
+        }
+        if args.authorizer_timeout_ms == 0 {
+            problems.push("--authorizer-timeout-ms must be at least 1".to_string());
+        }
+    }
+
+    let mut names = std::collections::HashSet::new();
+    for stream in &args.streams {
+        if let Some(source) = &stream.source {
+            if source != "default" && !names.contains(source) {
+                problems.push(format!(
+                    "--stream {}: source {source} must be default or an earlier stream",
+                    stream.name
+                ));
+            }
+        }
+        if !names.insert(&stream.name) {
+            problems.push(format!("--stream {}: duplicate stream name", stream.name));
+        }
+        for uri in &stream.upstreams {
+            if !matches!(uri.scheme_str(), Some("ws") | Some("wss")) {
+                problems.push(format!(
+                    "--stream {}: upstream {uri} must be a ws:// or wss:// URI",
+                    stream.name
+                ));
+            }
+        }
+    }
+
+    for (flag, value) in [
+        ("--readiness-max-capacity", args.readiness_max_capacity),
+        (
+            "--load-shed-max-queue-fraction",
+            args.load_shed_max_queue_fraction,
+        ),
+        (
+            "--load-shed-lag-drop-fraction",
+            args.load_shed_lag_drop_fraction,
+        ),
+    ] {
+        if !(0.0..=1.0).contains(&value) {
+            problems.push(format!("{flag} {value}: must be between 0 and 1"));
+        }
+    }
+
+    #[cfg(feature = "chaos")]
+    for (flag, value) in args.chaos.probabilities() {
+        if !(0.0..=1.0).contains(&value) {
+            problems.push(format!("{flag} {value}: must be between 0 and 1"));
+        }
+    }
+
+    #[cfg(feature = "webtransport")]
+    problems.extend(args.webtransport.problems());
+
+    #[cfg(feature = "acme")]
+    problems.extend(args.acme.problems());
+
+    match load_transforms(args) {
+        Ok(transforms) => {
+            if let Some(name) = &args.ingest_transform {
+                if !transforms.contains(name) {
+                    problems.push(format!("--ingest-transform {name}: unknown transform"));
+                }
+            }
+            for stream in &args.streams {
+                if let Some(name) = &stream.transform {
+                    if !transforms.contains(name) {
+                        problems.push(format!(
+                            "--stream {}: unknown transform {name}",
+                            stream.name
+                        ));
+                    }
+                }
+            }
+        }
+        Err(e) => problems.push(e.to_string()),
+    }
+
+    problems
+}
+
+/// The transforms loaded from the files they're configured with, by name.
+#[cfg_attr(
+    not(any(feature = "wasm", feature = "scripting")),
+    allow(unused_variables)
+)]
+fn load_transforms(args: &ServeArgs) -> Result<Plugins, TransformError> {
+    let transforms = Plugins::default();
+    #[cfg(feature = "wasm")]
+    let transforms = args.wasm.load(transforms)?;
+    #[cfg(feature = "scripting")]
+    let transforms = args.scripting.load(transforms)?;
+    Ok(transforms)
+}
+
+/// Opens and closes a websocket connection to each upstream, returning a description of each one
+/// that couldn't be reached.
+async fn check_upstreams(uris: &[Uri], timeout: Duration) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    for uri in uris {
+        match tokio::time::timeout(timeout, tokio_tungstenite::connect_async(uri)).await {
+            Ok(Ok((mut stream, _))) => {
+                let _ = stream.close(None).await;
+            }
+            Ok(Err(e)) => problems.push(format!("upstream {uri}: {e}")),
+            Err(_) => problems.push(format!(
+                "upstream {uri}: no handshake within {}s",
+                timeout.as_secs()
+            )),
         }
     }
+
+    problems
+}
+
+fn otlp_meter_provider(
+    endpoint: String,
+    interval: Duration,
+    labels: &[(String, String)],
+) -> SdkMeterProvider {
+    let exporter = MetricExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .expect("failed to setup OTLP metrics exporter");
+
+    let reader = PeriodicReader::builder(exporter)
+        .with_interval(interval)
+        .build();
+
+    let resource = Resource::builder()
+        .with_service_name(env!("CARGO_PKG_NAME"))
+        .with_attributes(
+            labels
+                .iter()
+                .map(|(key, value)| KeyValue::new(key.clone(), value.clone())),
+        )
+        .build();
+
+    SdkMeterProvider::builder()
+        .with_reader(reader)
+        .with_resource(resource)
+        .build()
 }
 
 fn parse_global_metrics(metrics: String) -> Vec<(String, String)> {
@@ -329,9 +1964,179 @@ fn parse_global_metrics(metrics: String) -> Vec<(String, String)> {
     result
 }
 
+/// Returns the histogram buckets for every histogram metric, replacing the defaults for any
+/// metric listed in `overrides`.
+fn parse_histogram_buckets(overrides: &str) -> Result<Vec<(String, Vec<f64>)>, String> {
+    let mut result: Vec<(String, Vec<f64>)> = HISTOGRAM_BUCKETS
+        .iter()
+        .map(|(name, buckets)| (name.to_string(), buckets.to_vec()))
+        .collect();
+
+    for family in overrides.split(';') {
+        if family.trim().is_empty() {
+            continue;
+        }
+
+        let (name, buckets) = family
+            .split_once('=')
+            .ok_or_else(|| format!("missing '=' in {family}"))?;
+
+        let buckets = buckets
+            .split(',')
+            .map(|bucket| bucket.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("invalid bucket for {name}: {e}"))?;
+
+        if buckets.is_empty() || buckets.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(format!("buckets for {name} must be strictly increasing"));
+        }
+
+        match result
+            .iter_mut()
+            .find(|(existing, _)| existing == name.trim())
+        {
+            Some((_, existing)) => *existing = buckets,
+            None => return Err(format!("unknown histogram {name}")),
+        }
+    }
+
+    Ok(result)
+}
+
 #[cfg(test)]
 mod test {
-    use crate::parse_global_metrics;
+    use crate::{check_config, parse_global_metrics, parse_histogram_buckets, Args};
+    use clap::Parser;
+
+    #[test]
+    fn test_check_config() {
+        let args = Args::parse_from(["proxy", "--upstream-ws", "ws://localhost:8546"]);
+        assert!(check_config(&args.serve).is_empty());
+
+        let args = Args::parse_from([
+            "proxy",
+            "--upstream-ws",
+            "http://localhost:8546",
+            "--metrics-allowed-cidrs",
+            "10.0.0.0/33",
+            "--load-shed-lag-drop-fraction",
+            "2",
+        ]);
+        let problems = check_config(&args.serve);
+        assert_eq!(problems.len(), 3);
+        assert!(problems[0].contains("scheme must be ws or wss"));
+
+        let args = Args::parse_from(["proxy"]);
+        assert_eq!(
+            check_config(&args.serve),
+            ["no upstream URIs provided, set --upstream-ws"]
+        );
+
+        // Streams alone are enough, but their names must be unique
+        let args = Args::parse_from(["proxy", "--stream", "name=raw,upstream=ws://localhost:8546"]);
+        assert!(check_config(&args.serve).is_empty());
+
+        let args = Args::parse_from([
+            "proxy",
+            "--stream",
+            "name=raw,upstream=ws://localhost:8546",
+            "--stream",
+            "name=raw,upstream=http://localhost:8547",
+        ]);
+        assert_eq!(check_config(&args.serve).len(), 2);
+
+        // A stream's source must already be defined
+        let args = Args::parse_from([
+            "proxy",
+            "--stream",
+            "name=delayed,source=raw,delay-ms=500",
+            "--stream",
+            "name=raw,upstream=ws://localhost:8546",
+            "--stream",
+            "name=free,source=default",
+        ]);
+        assert_eq!(
+            check_config(&args.serve),
+            ["--stream delayed: source raw must be default or an earlier stream"]
+        );
+
+        let args = Args::parse_from([
+            "proxy",
+            "--upstream-ws",
+            "ws://localhost:8546",
+            "--global-connections-limit",
+            "100",
+            "--reserved-connections",
+            "100",
+            "--priority-users",
+            "enterprise",
+        ]);
+        assert_eq!(
+            check_config(&args.serve),
+            [
+                "--priority-users requires --basic-auth-file",
+                "--reserved-connections must be less than --global-connections-limit (100)",
+            ]
+        );
+
+        let args = Args::parse_from([
+            "proxy",
+            "--upstream-ws",
+            "ws://localhost:8546",
+            "--allowed-origins",
+            "https://app.example.com,http://localhost:3000/,app.example.com,https://app.example.com/path",
+        ]);
+        assert_eq!(check_config(&args.serve).len(), 2);
+        let args = Args::parse_from([
+            "proxy",
+            "--upstream-ws",
+            "ws://localhost:8546",
+            "--geoip-deny-countries",
+            "CU,Cuba",
+        ]);
+        assert_eq!(check_config(&args.serve).len(), 2);
+
+        // Transforms must be loaded
+        let args = Args::parse_from([
+            "proxy",
+            "--upstream-ws",
+            "ws://localhost:8546",
+            "--ingest-transform",
+            "redact",
+            "--stream",
+            "name=free,source=default,transform=redact",
+        ]);
+        assert_eq!(
+            check_config(&args.serve),
+            [
+                "--ingest-transform redact: unknown transform",
+                "--stream free: unknown transform redact",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_histogram_buckets() {
+        let defaults = parse_histogram_buckets("").unwrap();
+        assert_eq!(defaults.len(), 7);
+
+        let buckets = parse_histogram_buckets("fan_out_latency=0.001, 0.01,0.05").unwrap();
+        assert_eq!(
+            buckets[0],
+            ("fan_out_latency".to_string(), vec![0.001, 0.01, 0.05])
+        );
+        assert_eq!(buckets[1], defaults[1]);
+
+        let buckets =
+            parse_histogram_buckets("fan_out_latency=1;connection_duration=1,2;").unwrap();
+        assert_eq!(buckets[0].1, vec![1.0]);
+        assert_eq!(buckets[1].1, vec![1.0, 2.0]);
+
+        assert!(parse_histogram_buckets("fan_out_latency").is_err());
+        assert!(parse_histogram_buckets("fan_out_latency=").is_err());
+        assert!(parse_histogram_buckets("fan_out_latency=0.1,0.01").is_err());
+        assert!(parse_histogram_buckets("unknown=1,2").is_err());
+    }
 
     #[test]
     fn test_parse_global_metrics() {
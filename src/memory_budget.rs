@@ -0,0 +1,162 @@
+//! A budget for the memory held by buffered messages, so that a backlog building up for slow
+//! clients can't grow until the proxy runs out of memory. Client queues of every stream and the
+//! flashblock cache are counted against it, and when it is exceeded the oldest buffered data is
+//! evicted: first the cache's oldest blocks, then the oldest messages of the longest client
+//! queues.
+//!
+//! Usage is estimated the same way as `broadcast_buffer_bytes`, and a payload held by both the
+//! cache and a client queue is counted twice, so the estimate errs on the high side.
+
+use crate::cache::FlashblockCache;
+use crate::metrics::Metrics;
+use crate::registry::Registry;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+pub struct MemoryBudget {
+    max_bytes: u64,
+    registries: Vec<Registry>,
+    cache: Option<Arc<FlashblockCache>>,
+}
+
+impl MemoryBudget {
+    pub fn new(max_bytes: u64) -> Self {
+        Self {
+            max_bytes,
+            registries: Vec::new(),
+            cache: None,
+        }
+    }
+
+    /// Count the client queues of `registry` against the budget.
+    pub fn with_registry(mut self, registry: Registry) -> Self {
+        self.registries.push(registry);
+        self
+    }
+
+    /// Count `cache` against the budget.
+    pub fn with_cache(mut self, cache: Arc<FlashblockCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Estimated bytes currently held.
+    pub fn used_bytes(&self) -> u64 {
+        self.queued_bytes() + self.cache.as_ref().map_or(0, |cache| cache.bytes() as u64)
+    }
+
+    fn queued_bytes(&self) -> u64 {
+        self.registries
+            .iter()
+            .map(Registry::buffered_bytes_estimate)
+            .sum()
+    }
+
+    /// Evicts the oldest buffered data until the estimate is back within the budget, returning
+    /// the estimated bytes evicted.
+    pub fn enforce(&self) -> u64 {
+        let before = self.used_bytes();
+        let mut used = before;
+        if used <= self.max_bytes {
+            return 0;
+        }
+
+        // The cache holds the oldest data, but its latest block is kept
+        if let Some(cache) = &self.cache {
+            while used > self.max_bytes {
+                match cache.evict_oldest() {
+                    Some(freed) => used = used.saturating_sub(freed as u64),
+                    None => break,
+                }
+            }
+        }
+
+        let queued = self.queued_bytes();
+        if used > self.max_bytes && queued > 0 {
+            // Shorten every stream's queues by the same fraction
+            let available = self.max_bytes.saturating_sub(used - queued);
+            let fraction = available as f64 / queued as f64;
+            for registry in &self.registries {
+                let max_len = (registry.queued_messages() as f64 * fraction) as usize;
+                registry.trim_queues(max_len);
+            }
+            used = self.used_bytes();
+        }
+
+        before.saturating_sub(used)
+    }
+
+    /// Enforces the budget every `interval`.
+    pub async fn run(self, metrics: Arc<Metrics>, interval: Duration, token: CancellationToken) {
+        let mut ticker = tokio::time::interval(interval);
+
+        loop {
+            tokio::select! {
+                _ = token.cancelled() => return,
+                _ = ticker.tick() => {}
+            }
+
+            let evicted = self.enforce();
+            if evicted > 0 {
+                warn!(
+                    message = "evicted buffered data to stay within the memory budget",
+                    bytes = evicted,
+                    budget = self.max_bytes
+                );
+                metrics.memory_budget_evicted_bytes.increment(evicted);
+            }
+            metrics
+                .memory_budget_used_bytes
+                .set(self.used_bytes() as f64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn flashblock(block_number: u64) -> Bytes {
+        Bytes::from(format!(
+            r#"{{"index":0,"metadata":{{"block_number":{block_number}}}}}"#
+        ))
+    }
+
+    #[test]
+    fn test_enforce() {
+        let registry = Registry::new(10, 1, Arc::new(Metrics::default()));
+        let _client = registry.register();
+        let cache = Arc::new(FlashblockCache::new(10));
+
+        for block_number in 100..108 {
+            registry.publish(flashblock(block_number));
+            cache.insert(&flashblock(block_number));
+        }
+        let size = flashblock(100).len() as u64;
+
+        // 8 queued messages and 8 cached blocks
+        let budget = MemoryBudget::new(12 * size)
+            .with_registry(registry.clone())
+            .with_cache(cache.clone());
+        assert_eq!(budget.used_bytes(), 16 * size);
+
+        // The cache's oldest blocks go first
+        assert_eq!(budget.enforce(), 4 * size);
+        assert_eq!(cache.block(103), None);
+        assert!(cache.block(104).is_some());
+        assert_eq!(registry.queued_messages(), 8);
+        assert_eq!(budget.enforce(), 0);
+
+        // Then client queues, once only the latest block is cached
+        let budget = MemoryBudget::new(5 * size)
+            .with_registry(registry.clone())
+            .with_cache(cache.clone());
+        assert_eq!(budget.enforce(), 7 * size);
+        assert_eq!(cache.latest_block_number(), Some(107));
+        assert_eq!(cache.block(106), None);
+        assert_eq!(registry.queued_messages(), 4);
+    }
+}
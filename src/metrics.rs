@@ -1,35 +1,553 @@
-use metrics::{Counter, Gauge};
+use crate::client::ClientLabels;
+use metrics::{counter, describe_counter, Counter, Gauge, Histogram, Label};
 use metrics_derive::Metrics;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+const DROPPED_MESSAGES: &str = "websocket_proxy.dropped_messages";
+const DISCONNECTS: &str = "websocket_proxy.disconnects";
+const CLIENT_CONNECTIONS: &str = "websocket_proxy.client_connections";
+const CLIENT_LAG_EVENTS: &str = "websocket_proxy.client_lag_events";
+const CLIENT_DROPPED_MESSAGES: &str = "websocket_proxy.client_dropped_messages";
+const COUNTRY_CONNECTIONS: &str = "websocket_proxy.country_connections";
+
+/// Most distinct client name and version pairs that get their own series. Clients beyond this are
+/// counted under `other`, so that clients can't blow up the number of series.
+const MAX_CLIENT_LABELS: usize = 100;
+
+/// Reason a message was not delivered to a client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DropCause {
+    /// The client fell behind the broadcast buffer and skipped messages.
+    Lagged,
+    /// Writing the message to the client failed.
+    SendFailed,
+    /// The message sat in the client's queue for longer than the message TTL.
+    Expired,
+    /// The message was overwritten in the client's queue by a newer one.
+    Overwritten,
+}
+
+impl DropCause {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DropCause::Lagged => "lagged",
+            DropCause::SendFailed => "send_failed",
+            DropCause::Expired => "expired",
+            DropCause::Overwritten => "overwritten",
+        }
+    }
+}
+
+/// Count of dropped messages, labeled by `cause`.
+pub struct DroppedMessages {
+    lagged: Counter,
+    send_failed: Counter,
+    expired: Counter,
+    overwritten: Counter,
+}
+
+impl Default for DroppedMessages {
+    fn default() -> Self {
+        describe_counter!(
+            DROPPED_MESSAGES,
+            "Count of messages that were not delivered to a client, by cause"
+        );
+
+        Self::with_labels(Vec::new())
+    }
+}
+
+impl DroppedMessages {
+    fn with_labels(labels: Vec<Label>) -> Self {
+        let counter = |cause: DropCause| {
+            let mut labels = labels.clone();
+            labels.push(Label::new("cause", cause.as_str()));
+            counter!(DROPPED_MESSAGES, labels)
+        };
+
+        Self {
+            lagged: counter(DropCause::Lagged),
+            send_failed: counter(DropCause::SendFailed),
+            expired: counter(DropCause::Expired),
+            overwritten: counter(DropCause::Overwritten),
+        }
+    }
+
+    pub fn increment(&self, cause: DropCause, count: u64) {
+        match cause {
+            DropCause::Lagged => self.lagged.increment(count),
+            DropCause::SendFailed => self.send_failed.increment(count),
+            DropCause::Expired => self.expired.increment(count),
+            DropCause::Overwritten => self.overwritten.increment(count),
+        }
+    }
+}
+
+/// Reason a client connection was closed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The client closed the connection, or went away without closing it cleanly.
+    ClientInitiated,
+    /// The proxy is shutting down.
+    Shutdown,
+    /// Writing to the client failed for any other reason.
+    Error,
+    /// The client stopped answering pings. Counted as `idle`.
+    PongTimeout,
+    /// The client was disconnected to make room for a client with priority.
+    Evicted,
+}
+
+impl DisconnectReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DisconnectReason::ClientInitiated => "client_initiated",
+            DisconnectReason::Shutdown => "shutdown",
+            DisconnectReason::Error => "error",
+            DisconnectReason::PongTimeout => "idle",
+            DisconnectReason::Evicted => "evicted",
+        }
+    }
+}
+
+/// Count of client disconnects, labeled by `reason`.
+pub struct Disconnects {
+    client_initiated: Counter,
+    shutdown: Counter,
+    error: Counter,
+    idle: Counter,
+    evicted: Counter,
+}
+
+impl Default for Disconnects {
+    fn default() -> Self {
+        describe_counter!(
+            DISCONNECTS,
+            "Count of client disconnects, by reason: client_initiated, shutdown, error, idle (missed pongs) or evicted"
+        );
+
+        Self::with_labels(Vec::new())
+    }
+}
+
+impl Disconnects {
+    fn with_labels(labels: Vec<Label>) -> Self {
+        let counter = |reason: DisconnectReason| {
+            let mut labels = labels.clone();
+            labels.push(Label::new("reason", reason.as_str()));
+            counter!(DISCONNECTS, labels)
+        };
+
+        Self {
+            client_initiated: counter(DisconnectReason::ClientInitiated),
+            shutdown: counter(DisconnectReason::Shutdown),
+            error: counter(DisconnectReason::Error),
+            idle: counter(DisconnectReason::PongTimeout),
+            evicted: counter(DisconnectReason::Evicted),
+        }
+    }
+
+    pub fn increment(&self, reason: DisconnectReason) {
+        match reason {
+            DisconnectReason::ClientInitiated => self.client_initiated.increment(1),
+            DisconnectReason::Shutdown => self.shutdown.increment(1),
+            DisconnectReason::Error => self.error.increment(1),
+            DisconnectReason::PongTimeout => self.idle.increment(1),
+            DisconnectReason::Evicted => self.evicted.increment(1),
+        }
+    }
+}
+
+/// Counters for the clients sharing a name and version.
+#[derive(Clone)]
+pub struct ClientCounters {
+    pub connections: Counter,
+    pub lag_events: Counter,
+    pub dropped_messages: Counter,
+}
+
+/// Counters labeled by the `client_name` and `client_version` that clients identify as. Clients
+/// that send neither are counted as `unknown`.
+pub struct ClientLabelMetrics {
+    labels: Vec<Label>,
+    counters: Mutex<HashMap<ClientLabels, ClientCounters>>,
+}
+
+impl Default for ClientLabelMetrics {
+    fn default() -> Self {
+        describe_counter!(
+            CLIENT_CONNECTIONS,
+            "Count of new connections opened, by client name and version"
+        );
+        describe_counter!(
+            CLIENT_LAG_EVENTS,
+            "Count of times that a client lagged, by client name and version"
+        );
+        describe_counter!(
+            CLIENT_DROPPED_MESSAGES,
+            "Count of messages that were not delivered to a client, by client name and version"
+        );
+
+        Self::with_labels(Vec::new())
+    }
+}
+
+impl ClientLabelMetrics {
+    fn with_labels(labels: Vec<Label>) -> Self {
+        Self {
+            labels,
+            counters: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The counters for clients identifying as `client`, or those for `other` once
+    /// [`MAX_CLIENT_LABELS`] pairs have been seen.
+    pub fn counters(&self, client: &ClientLabels) -> ClientCounters {
+        let mut counters = self.counters.lock().unwrap();
+        if let Some(existing) = counters.get(client) {
+            return existing.clone();
+        }
+
+        if counters.len() >= MAX_CLIENT_LABELS {
+            return self.register("other", "other");
+        }
+
+        let registered = self.register(
+            client.name.as_deref().unwrap_or("unknown"),
+            client.version.as_deref().unwrap_or("unknown"),
+        );
+        counters.insert(client.clone(), registered.clone());
+        registered
+    }
+
+    fn register(&self, name: &str, version: &str) -> ClientCounters {
+        let mut labels = self.labels.clone();
+        labels.push(Label::new("client_name", name.to_string()));
+        labels.push(Label::new("client_version", version.to_string()));
+
+        ClientCounters {
+            connections: counter!(CLIENT_CONNECTIONS, labels.clone()),
+            lag_events: counter!(CLIENT_LAG_EVENTS, labels.clone()),
+            dropped_messages: counter!(CLIENT_DROPPED_MESSAGES, labels),
+        }
+    }
+}
+
+/// Count of new connections opened, labeled by the `country` the client's address is in according
+/// to the GeoIP databases. Only ISO 3166 alpha-2 codes get their own series, which bounds the number
+/// of series, and anything else is counted as `unknown`.
+pub struct CountryConnections {
+    labels: Vec<Label>,
+    counters: Mutex<HashMap<String, Counter>>,
+}
+
+impl Default for CountryConnections {
+    fn default() -> Self {
+        describe_counter!(
+            COUNTRY_CONNECTIONS,
+            "Count of new connections opened, by the country of the client's address"
+        );
+
+        Self::with_labels(Vec::new())
+    }
+}
+
+impl CountryConnections {
+    fn with_labels(labels: Vec<Label>) -> Self {
+        Self {
+            labels,
+            counters: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn increment(&self, country: Option<&str>) {
+        let country = country
+            .filter(|code| code.len() == 2 && code.bytes().all(|b| b.is_ascii_uppercase()))
+            .unwrap_or("unknown");
+
+        let mut counters = self.counters.lock().unwrap();
+        let counter = counters.entry(country.to_string()).or_insert_with(|| {
+            let mut labels = self.labels.clone();
+            labels.push(Label::new("country", country.to_string()));
+            counter!(COUNTRY_CONNECTIONS, labels)
+        });
+        counter.increment(1);
+    }
+}
+
+/// Time between consecutive messages, for the message interval histograms.
+#[derive(Debug, Default)]
+pub struct Cadence {
+    last: Option<Instant>,
+}
+
+impl Cadence {
+    /// Records a message, returning how long it came after the previous one.
+    pub fn tick(&mut self) -> Option<Duration> {
+        let now = Instant::now();
+        self.last.replace(now).map(|last| now - last)
+    }
+
+    /// Forgets the previous message, so that a gap that isn't down to cadence isn't measured.
+    pub fn reset(&mut self) {
+        self.last = None;
+    }
+}
+
 #[derive(Metrics)]
 #[metrics(scope = "websocket_proxy")]
 pub struct Metrics {
     #[metric(describe = "Messages sent to clients")]
     pub sent_messages: Counter,
 
+    #[metric(
+        describe = "Time in seconds between a message entering the broadcast channel and being written to a client"
+    )]
+    pub fan_out_latency: Histogram,
+
+    #[metric(describe = "Size in bytes of messages written to clients")]
+    pub sent_message_size: Histogram,
+
+    #[metric(
+        describe = "Time in seconds between consecutive messages written to a client, excluding clients that sample or cap their rate"
+    )]
+    pub sent_message_interval: Histogram,
+
     #[metric(describe = "Count of messages that were unable to be sent")]
     pub failed_messages: Counter,
 
     #[metric(describe = "Count of new connections opened")]
     pub new_connections: Counter,
 
+    #[metric(describe = "Count of gRPC SubscribeFlashblocks calls admitted")]
+    pub grpc_subscriptions: Counter,
+
+    #[metric(describe = "Count of WebTransport sessions admitted")]
+    pub webtransport_sessions: Counter,
+
+    #[metric(describe = "Count of GraphQL flashblock subscriptions started")]
+    pub graphql_subscriptions: Counter,
+
+    #[metric(describe = "Count of JSON-RPC requests answered from the flashblock cache")]
+    pub rpc_requests: Counter,
+
+    #[metric(describe = "Messages published to the MQTT broker, and acknowledged at QoS 1")]
+    pub mqtt_published_messages: Counter,
+
+    #[metric(describe = "Count of times the MQTT bridge failed to connect or lost its connection")]
+    pub mqtt_connection_errors: Counter,
+
     #[metric(describe = "Count of number of connections closed")]
     pub closed_connections: Counter,
 
+    #[metric(describe = "Time in seconds that client connections stayed open")]
+    pub connection_duration: Histogram,
+
+    #[metric(skip)]
+    pub disconnects: Disconnects,
+
     #[metric(describe = "Number of client connections currently open")]
     pub active_connections: Gauge,
 
     #[metric(describe = "Count of rate limited request")]
     pub rate_limited_requests: Counter,
 
+    #[metric(describe = "Count of websocket upgrades refused for missing or invalid credentials")]
+    pub unauthorized_requests: Counter,
+
+    #[metric(describe = "Count of websocket upgrades refused by the authorization service")]
+    pub authorizer_denied_requests: Counter,
+
+    #[metric(
+        describe = "Count of websocket upgrades the authorization service failed to decide, which were admitted or refused by --authorizer-fail-open"
+    )]
+    pub authorizer_errors: Counter,
+
+    #[metric(
+        describe = "Count of connections closed for not upgrading within the handshake timeout"
+    )]
+    pub handshake_timeouts: Counter,
+
+    #[metric(
+        describe = "Count of connections refused on accept because too many were waiting to upgrade"
+    )]
+    pub rejected_handshakes: Counter,
+
+    #[metric(
+        describe = "Count of connections refused on accept because too many from the same address were waiting to upgrade"
+    )]
+    pub ip_rejected_handshakes: Counter,
+
+    #[metric(describe = "Count of websocket upgrades refused because of their Origin header")]
+    pub rejected_origins: Counter,
+
+    #[metric(
+        describe = "Count of websocket upgrades refused because of the country or ASN of the client's address"
+    )]
+    pub geo_policy_denied_requests: Counter,
+
+    #[metric(
+        describe = "Count of websocket upgrades refused because their autonomous system was at --per-asn-connections-limit"
+    )]
+    pub asn_limited_requests: Counter,
+
+    #[metric(describe = "Handshakes waiting in the waiting room for a connection to be released")]
+    pub waiting_room_size: Gauge,
+
+    #[metric(describe = "Time in seconds handshakes admitted from the waiting room waited")]
+    pub waiting_room_wait: Histogram,
+
+    #[metric(
+        describe = "Count of handshakes refused because the waiting room was full or they waited too long"
+    )]
+    pub waiting_room_refusals: Counter,
+
+    #[metric(
+        describe = "Count of websocket upgrades refused because the client's address is banned"
+    )]
+    pub banned_requests: Counter,
+
+    #[metric(describe = "Count of addresses banned after repeated 401 and 429 refusals")]
+    pub auto_bans: Counter,
+
+    #[metric(describe = "Count of websocket clients admitted with priority")]
+    pub priority_connections: Counter,
+
+    #[metric(
+        describe = "Count of websocket upgrades without priority refused because only reserved connections were left"
+    )]
+    pub reserved_refusals: Counter,
+
+    #[metric(describe = "Count of clients evicted to make room for a client with priority")]
+    pub priority_evictions: Counter,
+
+    #[metric(
+        describe = "Current load shedding level (0: none, 1: rejecting connections, 2: lag-dropping clients)"
+    )]
+    pub load_shed_level: Gauge,
+
+    #[metric(describe = "Count of connections rejected by load shedding")]
+    pub load_shed_rejected_connections: Counter,
+
+    #[metric(describe = "Count of clients made to drop their backlog by load shedding")]
+    pub load_shed_lag_dropped_clients: Counter,
+
     #[metric(describe = "Count of times that a client lagged")]
     pub lag_events: Counter,
 
+    #[metric(
+        describe = "Count of messages not sent to clients that asked for a sample of the stream"
+    )]
+    pub sampled_out_messages: Counter,
+
+    #[metric(describe = "Count of messages dropped by a transform, including those it failed on")]
+    pub transform_dropped_messages: Counter,
+
+    #[metric(describe = "Count of messages a transform failed on, e.g. by running out of fuel")]
+    pub transform_errors: Counter,
+
+    #[metric(skip)]
+    pub dropped_messages: DroppedMessages,
+
+    #[metric(skip)]
+    pub client_labels: ClientLabelMetrics,
+
+    #[metric(skip)]
+    pub country_connections: CountryConnections,
+
+    #[metric(describe = "Largest number of messages any client is behind the newest message")]
+    pub client_lag_messages_max: Gauge,
+
+    #[metric(describe = "99th percentile of the number of messages clients are behind")]
+    pub client_lag_messages_p99: Gauge,
+
+    #[metric(describe = "Largest age in milliseconds of the last message delivered to any client")]
+    pub client_lag_ms_max: Gauge,
+
+    #[metric(
+        describe = "99th percentile age in milliseconds of the last message delivered to clients"
+    )]
+    pub client_lag_ms_p99: Gauge,
+
+    #[metric(
+        describe = "Messages queued for the client furthest behind, as a fraction of the message buffer size"
+    )]
+    pub broadcast_buffer_occupancy: Gauge,
+
+    #[metric(
+        describe = "Number of clients whose queue is within one message of the message buffer size"
+    )]
+    pub clients_near_overflow: Gauge,
+
+    #[metric(describe = "Resident memory of the proxy process in bytes")]
+    pub process_resident_memory_bytes: Gauge,
+
+    #[metric(describe = "Number of file descriptors open by the proxy process")]
+    pub process_open_fds: Gauge,
+
+    #[metric(describe = "Number of sockets open by the proxy process")]
+    pub process_open_sockets: Gauge,
+
+    #[metric(
+        describe = "Bytes in use according to the allocator (jemalloc: allocated, mimalloc: committed)"
+    )]
+    pub allocator_allocated_bytes: Gauge,
+
+    #[metric(describe = "Bytes of physical memory held by the allocator")]
+    pub allocator_resident_bytes: Gauge,
+
+    #[metric(describe = "Estimated bytes of message payloads retained by the broadcast buffer")]
+    pub broadcast_buffer_bytes: Gauge,
+
+    #[metric(
+        describe = "Estimated bytes held by client queues and the flashblock cache, counted against --memory-budget-bytes"
+    )]
+    pub memory_budget_used_bytes: Gauge,
+
+    #[metric(describe = "Estimated bytes evicted to stay within --memory-budget-bytes")]
+    pub memory_budget_evicted_bytes: Counter,
+
+    #[metric(describe = "Count of panics in any task")]
+    pub panics_total: Counter,
+
+    #[metric(describe = "Count of upstream subscribers restarted after panicking")]
+    pub upstream_subscriber_panics: Counter,
+
     #[metric(describe = "Count of times upstream receiver was closed/errored")]
     pub upstream_errors: Counter,
 
+    #[metric(describe = "Size in bytes of messages received from the upstream source")]
+    pub upstream_message_size: Histogram,
+
+    #[metric(
+        describe = "Time in seconds between consecutive messages received from an upstream connection"
+    )]
+    pub upstream_message_interval: Histogram,
+
     #[metric(describe = "Count of messages received from the upstream source")]
     pub upstream_messages: Gauge,
 
+    #[metric(
+        describe = "Whether this instance is the elected leader subscribing to the upstreams (1) or not (0)"
+    )]
+    pub upstream_leader: Gauge,
+
+    #[metric(
+        describe = "Count of upstream messages the leader couldn't relay to the other instances because Redis was slow or unreachable"
+    )]
+    pub upstream_relay_dropped_messages: Counter,
+
+    #[metric(
+        describe = "Count of messages an upstream proxy relayed that never arrived, by sequence number"
+    )]
+    pub upstream_relay_missed_messages: Counter,
+
+    #[metric(describe = "Count of upstream messages dropped by --upstream-filter")]
+    pub upstream_filtered_messages: Counter,
+
     // New metrics for multiple upstream connections
     #[metric(describe = "Number of active upstream connections")]
     pub upstream_connections: Gauge,
@@ -43,3 +561,117 @@ pub struct Metrics {
     #[metric(describe = "Number of failed upstream connection attempts")]
     pub upstream_connection_failures: Counter,
 }
+
+impl Metrics {
+    /// Metrics for a named stream, with every series labeled `stream=<name>`. The default stream's
+    /// metrics are unlabeled.
+    pub fn for_stream(name: &str) -> Self {
+        let labels = vec![Label::new("stream", name.to_string())];
+
+        Self {
+            dropped_messages: DroppedMessages::with_labels(labels.clone()),
+            disconnects: Disconnects::with_labels(labels.clone()),
+            client_labels: ClientLabelMetrics::with_labels(labels.clone()),
+            country_connections: CountryConnections::with_labels(labels.clone()),
+            ..Self::new_with_labels(labels)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metrics_exporter_prometheus::PrometheusBuilder;
+
+    #[test]
+    fn test_stream_labels() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+
+        let (default, stream) = metrics::with_local_recorder(&recorder, || {
+            (
+                Metrics::new_with_labels(Vec::<Label>::new()),
+                Metrics::for_stream("raw"),
+            )
+        });
+        default.sent_messages.increment(1);
+        stream.sent_messages.increment(2);
+        stream.dropped_messages.increment(DropCause::Lagged, 3);
+        stream.disconnects.increment(DisconnectReason::Shutdown);
+        stream.disconnects.increment(DisconnectReason::PongTimeout);
+
+        let rendered = handle.render();
+        assert!(rendered.contains("websocket_proxy_sent_messages 1"));
+        assert!(rendered.contains("websocket_proxy_sent_messages{stream=\"raw\"} 2"));
+        assert!(rendered
+            .contains("websocket_proxy_dropped_messages{stream=\"raw\",cause=\"lagged\"} 3"));
+        assert!(
+            rendered.contains("websocket_proxy_disconnects{stream=\"raw\",reason=\"shutdown\"} 1")
+        );
+        assert!(rendered.contains("websocket_proxy_disconnects{stream=\"raw\",reason=\"idle\"} 1"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cadence() {
+        let mut cadence = Cadence::default();
+        assert_eq!(cadence.tick(), None);
+        tokio::time::advance(Duration::from_millis(200)).await;
+        assert_eq!(cadence.tick(), Some(Duration::from_millis(200)));
+        assert_eq!(cadence.tick(), Some(Duration::ZERO));
+
+        cadence.reset();
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert_eq!(cadence.tick(), None);
+    }
+
+    #[test]
+    fn test_client_label_limit() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+
+        metrics::with_local_recorder(&recorder, || {
+            let metrics = ClientLabelMetrics::with_labels(Vec::new());
+            metrics
+                .counters(&ClientLabels::default())
+                .lag_events
+                .increment(1);
+
+            for version in 0..MAX_CLIENT_LABELS + 5 {
+                let client = ClientLabels {
+                    name: Some("sdk".to_string()),
+                    version: Some(version.to_string()),
+                };
+                metrics.counters(&client).connections.increment(1);
+            }
+        });
+
+        let rendered = handle.render();
+        assert!(rendered.contains(
+            "websocket_proxy_client_lag_events{client_name=\"unknown\",client_version=\"unknown\"} 1"
+        ));
+        assert!(rendered.contains(
+            "websocket_proxy_client_connections{client_name=\"sdk\",client_version=\"0\"} 1"
+        ));
+        assert!(rendered.contains(
+            "websocket_proxy_client_connections{client_name=\"other\",client_version=\"other\"} 6"
+        ));
+    }
+
+    #[test]
+    fn test_country_connections() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+
+        metrics::with_local_recorder(&recorder, || {
+            let metrics = CountryConnections::with_labels(Vec::new());
+            metrics.increment(Some("DE"));
+            metrics.increment(Some("DE"));
+            metrics.increment(None);
+            metrics.increment(Some("not a country"));
+        });
+
+        let rendered = handle.render();
+        assert!(rendered.contains("websocket_proxy_country_connections{country=\"DE\"} 2"));
+        assert!(rendered.contains("websocket_proxy_country_connections{country=\"unknown\"} 2"));
+    }
+}
@@ -0,0 +1,198 @@
+//! A description of every metric the proxy emits, served as JSON on `/metrics/catalog` so that
+//! dashboards and alerts can be generated from it. It's built by constructing [`Metrics`] against a
+//! recorder that only takes notes, so it lists exactly what the struct registers and describes.
+
+use crate::client::ClientLabels;
+use crate::metrics::Metrics;
+use metrics::{Counter, Gauge, Histogram, Key, KeyName, Metadata, Recorder, SharedString, Unit};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetricType {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl MetricType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MetricType::Counter => "counter",
+            MetricType::Gauge => "gauge",
+            MetricType::Histogram => "histogram",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MetricDescription {
+    /// The name as exported to Prometheus, e.g. `websocket_proxy_sent_messages`.
+    pub name: String,
+    pub metric_type: MetricType,
+    pub description: String,
+    pub unit: Option<&'static str>,
+    /// Labels the metric's series may carry. `stream` is only set on the series of named streams.
+    pub labels: Vec<String>,
+}
+
+impl MetricDescription {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "name": self.name,
+            "type": self.metric_type.as_str(),
+            "description": self.description,
+            "unit": self.unit,
+            "labels": self.labels,
+        })
+    }
+}
+
+#[derive(Default)]
+struct Entry {
+    metric_type: Option<MetricType>,
+    description: String,
+    unit: Option<Unit>,
+    labels: BTreeSet<String>,
+}
+
+#[derive(Default)]
+struct CatalogRecorder {
+    entries: Mutex<BTreeMap<String, Entry>>,
+}
+
+impl CatalogRecorder {
+    fn describe(
+        &self,
+        key: KeyName,
+        metric_type: MetricType,
+        unit: Option<Unit>,
+        description: &str,
+    ) {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(key.as_str().to_string()).or_default();
+        entry.metric_type = Some(metric_type);
+        entry.unit = unit;
+        entry.description = description.to_string();
+    }
+
+    fn register(&self, key: &Key, metric_type: MetricType) {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(key.name().to_string()).or_default();
+        entry.metric_type.get_or_insert(metric_type);
+        entry
+            .labels
+            .extend(key.labels().map(|label| label.key().to_string()));
+    }
+}
+
+impl Recorder for CatalogRecorder {
+    fn describe_counter(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.describe(key, MetricType::Counter, unit, &description);
+    }
+
+    fn describe_gauge(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.describe(key, MetricType::Gauge, unit, &description);
+    }
+
+    fn describe_histogram(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.describe(key, MetricType::Histogram, unit, &description);
+    }
+
+    fn register_counter(&self, key: &Key, _metadata: &Metadata<'_>) -> Counter {
+        self.register(key, MetricType::Counter);
+        Counter::noop()
+    }
+
+    fn register_gauge(&self, key: &Key, _metadata: &Metadata<'_>) -> Gauge {
+        self.register(key, MetricType::Gauge);
+        Gauge::noop()
+    }
+
+    fn register_histogram(&self, key: &Key, _metadata: &Metadata<'_>) -> Histogram {
+        self.register(key, MetricType::Histogram);
+        Histogram::noop()
+    }
+}
+
+/// Every metric the proxy emits, sorted by name.
+pub fn catalog() -> Vec<MetricDescription> {
+    let recorder = CatalogRecorder::default();
+
+    metrics::with_local_recorder(&recorder, || {
+        for metrics in [Metrics::default(), Metrics::for_stream("stream")] {
+            // Series labeled by client are registered when a client first connects
+            metrics.client_labels.counters(&ClientLabels::default());
+            metrics.country_connections.increment(None);
+        }
+    });
+
+    recorder
+        .entries
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .filter_map(|(name, entry)| {
+            Some(MetricDescription {
+                name: name.replace('.', "_"),
+                metric_type: entry.metric_type?,
+                description: entry.description,
+                unit: entry.unit.map(|unit| unit.as_str()),
+                labels: entry.labels.into_iter().collect(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_catalog() {
+        let catalog = catalog();
+        let find = |name: &str| catalog.iter().find(|metric| metric.name == name).unwrap();
+
+        assert_eq!(
+            find("websocket_proxy_sent_messages"),
+            &MetricDescription {
+                name: "websocket_proxy_sent_messages".to_string(),
+                metric_type: MetricType::Counter,
+                description: "Messages sent to clients".to_string(),
+                unit: None,
+                labels: vec!["stream".to_string()],
+            }
+        );
+        assert_eq!(
+            find("websocket_proxy_fan_out_latency").metric_type,
+            MetricType::Histogram
+        );
+        assert_eq!(
+            find("websocket_proxy_disconnects").labels,
+            ["reason", "stream"]
+        );
+        assert_eq!(
+            find("websocket_proxy_client_connections").labels,
+            ["client_name", "client_version", "stream"]
+        );
+        assert_eq!(
+            find("websocket_proxy_country_connections").labels,
+            ["country", "stream"]
+        );
+
+        assert_eq!(
+            find("websocket_proxy_sent_messages").to_json(),
+            json!({
+                "name": "websocket_proxy_sent_messages",
+                "type": "counter",
+                "description": "Messages sent to clients",
+                "unit": null,
+                "labels": ["stream"],
+            })
+        );
+
+        // Everything the struct registers is described
+        assert!(catalog.iter().all(|metric| !metric.description.is_empty()));
+    }
+}
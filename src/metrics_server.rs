@@ -0,0 +1,260 @@
+use crate::auth::constant_time_eq;
+use crate::metrics_catalog;
+use axum::extract::{ConnectInfo, State};
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use ipnet::IpNet;
+use metrics_exporter_prometheus::PrometheusHandle;
+use serde_json::{json, Value};
+use std::net::{AddrParseError, IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+const UPKEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Access control for the Prometheus endpoint. Requests must come from one of the allowed
+/// networks (if any are configured) and carry the bearer token (if one is configured).
+#[derive(Clone, Debug, Default)]
+pub struct MetricsAuth {
+    pub bearer_token: Option<String>,
+    pub allowed_networks: Vec<IpNet>,
+}
+
+impl MetricsAuth {
+    fn allows_addr(&self, addr: IpAddr) -> bool {
+        let addr = addr.to_canonical();
+        self.allowed_networks.is_empty()
+            || self
+                .allowed_networks
+                .iter()
+                .any(|network| network.contains(&addr))
+    }
+
+    fn allows_headers(&self, headers: &HeaderMap) -> bool {
+        let Some(expected) = &self.bearer_token else {
+            return true;
+        };
+
+        headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|token| constant_time_eq(token.as_bytes(), expected.as_bytes()))
+    }
+}
+
+/// Parses a comma separated list of CIDRs or plain IP addresses, e.g. `10.0.0.0/8,127.0.0.1`.
+pub fn parse_allowed_networks(networks: &str) -> Result<Vec<IpNet>, AddrParseError> {
+    networks
+        .split(',')
+        .map(str::trim)
+        .filter(|network| !network.is_empty())
+        .map(|network| {
+            network
+                .parse::<IpNet>()
+                .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+        })
+        .collect()
+}
+
+#[derive(Clone)]
+struct MetricsState {
+    handle: PrometheusHandle,
+    auth: MetricsAuth,
+    catalog: Arc<Value>,
+}
+
+/// Serves the Prometheus exposition format on every path of `addr`, except `/metrics/catalog`,
+/// which describes the metrics as JSON. See [`crate::metrics_catalog`].
+pub async fn serve(addr: SocketAddr, handle: PrometheusHandle, auth: MetricsAuth) {
+    let upkeep_handle = handle.clone();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(UPKEEP_INTERVAL);
+        loop {
+            ticker.tick().await;
+            upkeep_handle.run_upkeep();
+        }
+    });
+
+    let router = Router::new()
+        .route("/metrics/catalog", get(catalog_handler))
+        .fallback(metrics_handler)
+        .with_state(MetricsState {
+            handle,
+            auth,
+            catalog: Arc::new(json!({
+                "metrics": metrics_catalog::catalog()
+                    .iter()
+                    .map(|metric| metric.to_json())
+                    .collect::<Vec<_>>(),
+            })),
+        });
+
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .expect("failed to bind metrics listener");
+
+    info!(
+        message = "metrics server listening",
+        address = listener.local_addr().unwrap().to_string()
+    );
+
+    axum::serve(
+        listener,
+        router.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap()
+}
+
+/// Pushes the Prometheus exposition format to a Pushgateway at `url`, e.g.
+/// `http://pushgateway:9091/metrics/job/flashblocks-websocket-proxy/instance/proxy-0`, every
+/// `interval`. Each push replaces the metrics previously pushed under the same grouping key.
+pub async fn push(url: String, handle: PrometheusHandle, interval: Duration) {
+    let client = reqwest::Client::new();
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+        handle.run_upkeep();
+
+        let result = client
+            .put(&url)
+            .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(handle.render())
+            .timeout(interval)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(e) = result {
+            warn!(
+                message = "failed to push metrics to the Pushgateway",
+                error = e.to_string()
+            );
+        }
+    }
+}
+
+async fn metrics_handler(
+    State(state): State<MetricsState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Response {
+    if let Some(response) = refusal(&state.auth, addr, &headers) {
+        return response;
+    }
+
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.handle.render(),
+    )
+        .into_response()
+}
+
+async fn catalog_handler(
+    State(state): State<MetricsState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Response {
+    if let Some(response) = refusal(&state.auth, addr, &headers) {
+        return response;
+    }
+
+    Json(state.catalog.as_ref().clone()).into_response()
+}
+
+/// The response to refuse a request that `auth` doesn't allow with.
+fn refusal(auth: &MetricsAuth, addr: SocketAddr, headers: &HeaderMap) -> Option<Response> {
+    if !auth.allows_addr(addr.ip()) {
+        return Some(StatusCode::FORBIDDEN.into_response());
+    }
+
+    if !auth.allows_headers(headers) {
+        return Some((StatusCode::UNAUTHORIZED, [(WWW_AUTHENTICATE, "Bearer")]).into_response());
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{HeaderValue, Method};
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn test_push() {
+        let pushed = Arc::new(Mutex::new(Vec::new()));
+        let pushed_clone = pushed.clone();
+        let router = Router::new().fallback(async move |method: Method, body: String| {
+            pushed_clone.lock().unwrap().push((method, body));
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        metrics::with_local_recorder(&recorder, || {
+            metrics::counter!("websocket_proxy.sent_messages").increment(3)
+        });
+
+        let push = tokio::spawn(push(
+            format!("http://{addr}/metrics/job/proxy"),
+            handle,
+            Duration::from_millis(50),
+        ));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        push.abort();
+
+        let pushed = pushed.lock().unwrap();
+        assert!(!pushed.is_empty());
+        assert_eq!(pushed[0].0, Method::PUT);
+        assert!(pushed[0].1.contains("websocket_proxy_sent_messages 3"));
+    }
+
+    #[test]
+    fn test_parse_allowed_networks() {
+        assert_eq!(parse_allowed_networks("").unwrap(), vec![]);
+        assert_eq!(
+            parse_allowed_networks("10.0.0.0/8, 127.0.0.1,::1").unwrap(),
+            vec![
+                "10.0.0.0/8".parse::<IpNet>().unwrap(),
+                "127.0.0.1/32".parse::<IpNet>().unwrap(),
+                "::1/128".parse::<IpNet>().unwrap(),
+            ]
+        );
+        assert!(parse_allowed_networks("10.0.0.0/8,nonsense").is_err());
+    }
+
+    #[test]
+    fn test_metrics_auth() {
+        let open = MetricsAuth::default();
+        assert!(open.allows_addr("1.2.3.4".parse().unwrap()));
+        assert!(open.allows_headers(&HeaderMap::new()));
+
+        let auth = MetricsAuth {
+            bearer_token: Some("secret".to_string()),
+            allowed_networks: parse_allowed_networks("10.0.0.0/8").unwrap(),
+        };
+        assert!(auth.allows_addr("10.1.2.3".parse().unwrap()));
+        assert!(auth.allows_addr("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!auth.allows_addr("11.1.2.3".parse().unwrap()));
+
+        let headers = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(AUTHORIZATION, HeaderValue::from_str(value).unwrap());
+            headers
+        };
+        assert!(auth.allows_headers(&headers("Bearer secret")));
+        assert!(!auth.allows_headers(&headers("Bearer wrong")));
+        assert!(!auth.allows_headers(&headers("secret")));
+        assert!(!auth.allows_headers(&HeaderMap::new()));
+    }
+}
@@ -0,0 +1,153 @@
+use futures::{SinkExt, StreamExt};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tokio_tungstenite::accept_async;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{info, warn};
+
+/// Number of flashblocks published per block, as by the sequencer with 200ms flashblocks and 2s
+/// blocks.
+const FLASHBLOCKS_PER_BLOCK: u64 = 10;
+
+#[derive(clap::Args, Debug)]
+pub struct MockUpstreamArgs {
+    /// Address to accept proxy connections on
+    #[arg(long, default_value = "127.0.0.1:8546")]
+    pub listen_addr: SocketAddr,
+
+    /// Milliseconds between flashblocks
+    #[arg(long, default_value = "200")]
+    pub interval_ms: u64,
+
+    /// Number of transactions in each flashblock, to control the message size
+    #[arg(long, default_value = "40")]
+    pub transactions: usize,
+
+    /// Skip every Nth flashblock, leaving a gap in the stream (0 disables)
+    #[arg(long, default_value = "0")]
+    pub gap_every: u64,
+
+    /// Close each connection after sending it this many messages (0 disables)
+    #[arg(long, default_value = "0")]
+    pub disconnect_after: u64,
+}
+
+/// Serves synthetic flashblocks to every connected websocket client, standing in for a sequencer
+/// when running the proxy locally.
+pub async fn run(args: MockUpstreamArgs) -> std::io::Result<()> {
+    let listener = TcpListener::bind(args.listen_addr).await?;
+    let (sender, _) = broadcast::channel(16);
+    info!(
+        message = "serving mock flashblocks",
+        address = args.listen_addr.to_string()
+    );
+
+    let disconnect_after = args.disconnect_after;
+    let publisher = sender.clone();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_millis(args.interval_ms));
+        for sequence in 0u64.. {
+            ticker.tick().await;
+            if !skipped(sequence, args.gap_every) {
+                let _ = publisher.send(flashblock(sequence, args.transactions));
+            }
+        }
+    });
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let receiver = sender.subscribe();
+        tokio::spawn(async move {
+            info!(message = "proxy connected", peer = peer.to_string());
+            serve_connection(stream, receiver, disconnect_after).await;
+            info!(message = "proxy disconnected", peer = peer.to_string());
+        });
+    }
+}
+
+async fn serve_connection(
+    stream: TcpStream,
+    mut receiver: broadcast::Receiver<String>,
+    disconnect_after: u64,
+) {
+    let mut ws_stream = match accept_async(stream).await {
+        Ok(ws_stream) => ws_stream,
+        Err(e) => {
+            warn!(
+                message = "websocket handshake failed",
+                error = e.to_string()
+            );
+            return;
+        }
+    };
+
+    let mut sent = 0;
+    loop {
+        if disconnect_after > 0 && sent >= disconnect_after {
+            let _ = ws_stream.close(None).await;
+            return;
+        }
+
+        tokio::select! {
+            msg = receiver.recv() => match msg {
+                Ok(payload) => {
+                    if ws_stream.send(Message::Text(payload.into())).await.is_err() {
+                        return;
+                    }
+                    sent += 1;
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+            msg = ws_stream.next() => match msg {
+                Some(Ok(_)) => continue,
+                _ => return,
+            },
+        }
+    }
+}
+
+/// Whether the `sequence`th flashblock is left out of the stream to simulate a gap.
+fn skipped(sequence: u64, gap_every: u64) -> bool {
+    gap_every > 0 && sequence % gap_every == gap_every - 1
+}
+
+/// Builds the `sequence`th flashblock, shaped like the sequencer's but with placeholder data.
+fn flashblock(sequence: u64, transactions: usize) -> String {
+    let transactions: Vec<String> = (0..transactions)
+        .map(|i| format!("\"0x02f8b0{}\"", format!("{:02x}", i % 256).repeat(170)))
+        .collect();
+
+    format!(
+        r#"{{"payload_id":"0x{payload_id:016x}","index":{index},"diff":{{"state_root":"0x{root}","receipts_root":"0x{root}","logs_bloom":"0x{bloom}","gas_used":"0x1c9c380","block_hash":"0x{root}","transactions":[{transactions}],"withdrawals":[]}},"metadata":{{"block_number":{block_number}}}}}"#,
+        payload_id = sequence / FLASHBLOCKS_PER_BLOCK,
+        index = sequence % FLASHBLOCKS_PER_BLOCK,
+        block_number = sequence / FLASHBLOCKS_PER_BLOCK,
+        root = "ab".repeat(32),
+        bloom = "00".repeat(256),
+        transactions = transactions.join(","),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flashblock() {
+        let payload: serde_json::Value = serde_json::from_str(&flashblock(23, 2)).unwrap();
+        assert_eq!(payload["index"], 3);
+        assert_eq!(payload["metadata"]["block_number"], 2);
+        assert_eq!(payload["diff"]["transactions"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_skipped() {
+        assert!(!(0..10).any(|sequence| skipped(sequence, 0)));
+
+        let skipped: Vec<u64> = (0..10).filter(|&sequence| skipped(sequence, 3)).collect();
+        assert_eq!(skipped, [2, 5, 8]);
+    }
+}
@@ -0,0 +1,445 @@
+//! A bridge publishing each message to an MQTT broker, so that lightweight consumers can
+//! subscribe through a broker they already run instead of holding websockets to the proxy.
+//!
+//! The MQTT client is [`rumqttc`]'s, connecting with a clean session and publishing at QoS 0 or
+//! QoS 1. This module only forwards the registry's messages to it, picks their topics, and backs
+//! off between attempts to reconnect. At QoS 1, messages the broker hadn't acknowledged when the
+//! connection was lost are published again after reconnecting.
+
+use crate::metrics::{DropCause, Metrics};
+use crate::registry::{Delivery, Registry, Subscription};
+use axum::extract::ws::Message;
+use backoff::{backoff::Backoff, ExponentialBackoff};
+use rumqttc::{AsyncClient, Event, MqttOptions, Outgoing, Packet, QoS};
+use serde_json::Value;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+/// How often the broker expects to hear from the bridge.
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+/// The largest packet MQTT can carry, so that no flashblock is too large to publish.
+const MAX_PACKET_SIZE: usize = 268_435_455;
+
+/// Messages handed to the client that it hasn't sent yet. Beyond these, messages queue in the
+/// bridge's subscription like a client's.
+const REQUEST_CAPACITY: usize = 64;
+
+/// How long to wait for the broker to be told the bridge is disconnecting on shutdown.
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug)]
+pub struct MqttConfig {
+    /// The broker's `host:port`.
+    pub broker: String,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub topic: TopicTemplate,
+    /// Wait for the broker to acknowledge each message (QoS 1) instead of sending and forgetting
+    /// (QoS 0).
+    pub at_least_once: bool,
+    /// Longest to wait between attempts to connect to the broker.
+    pub max_backoff: Duration,
+}
+
+/// The topic to publish each message to, as configured with `--mqtt-topic`. `{block_number}` and
+/// `{index}` are replaced with the message's `metadata.block_number` and `index`, or `unknown`
+/// for messages without them, e.g. `flashblocks/{block_number}/{index}`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TopicTemplate {
+    parts: Vec<TopicPart>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum TopicPart {
+    Literal(String),
+    BlockNumber,
+    Index,
+}
+
+impl FromStr for TopicTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("MQTT topic is empty".to_string());
+        }
+        if s.contains(['+', '#']) {
+            return Err(format!("MQTT topic {s} can't contain wildcards"));
+        }
+
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(TopicPart::Literal(rest[..start].to_string()));
+            }
+            let Some(end) = rest[start..].find('}') else {
+                return Err(format!("unclosed placeholder in MQTT topic {s}"));
+            };
+            parts.push(match &rest[start + 1..start + end] {
+                "block_number" => TopicPart::BlockNumber,
+                "index" => TopicPart::Index,
+                placeholder => {
+                    return Err(format!(
+                        "unknown placeholder {{{placeholder}}} in MQTT topic {s}"
+                    ))
+                }
+            });
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(TopicPart::Literal(rest.to_string()));
+        }
+
+        Ok(Self { parts })
+    }
+}
+
+impl TopicTemplate {
+    /// The topic for `payload`. Payloads are only parsed as JSON when the template has
+    /// placeholders.
+    pub fn render(&self, payload: &[u8]) -> String {
+        let mut json = None;
+        let mut field = |pointer: &str| {
+            let json = json.get_or_insert_with(|| serde_json::from_slice::<Value>(payload).ok());
+            json.as_ref()
+                .and_then(|json| json.pointer(pointer))
+                .and_then(|value| value.as_u64())
+                .map_or("unknown".to_string(), |value| value.to_string())
+        };
+
+        self.parts
+            .iter()
+            .map(|part| match part {
+                TopicPart::Literal(literal) => literal.clone(),
+                TopicPart::BlockNumber => field("/metadata/block_number"),
+                TopicPart::Index => field("/index"),
+            })
+            .collect()
+    }
+}
+
+impl MqttConfig {
+    fn options(&self) -> Result<MqttOptions, String> {
+        let (host, port) = self
+            .broker
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse().ok()?)))
+            .ok_or_else(|| format!("MQTT broker {} isn't a host:port", self.broker))?;
+
+        let mut options = MqttOptions::new(&self.client_id, host, port);
+        options
+            .set_keep_alive(KEEP_ALIVE)
+            .set_clean_session(true)
+            .set_max_packet_size(MAX_PACKET_SIZE, MAX_PACKET_SIZE);
+        if let Some(username) = &self.username {
+            options.set_credentials(username, self.password.clone().unwrap_or_default());
+        }
+        Ok(options)
+    }
+
+    fn qos(&self) -> QoS {
+        if self.at_least_once {
+            QoS::AtLeastOnce
+        } else {
+            QoS::AtMostOnce
+        }
+    }
+}
+
+/// Publishes every message in `registry` to the broker until `token` is cancelled, reconnecting
+/// whenever the connection is lost. Messages received while disconnected are queued like a
+/// client's, and dropped if the queue overflows.
+pub async fn bridge(
+    registry: Registry,
+    config: MqttConfig,
+    metrics: Arc<Metrics>,
+    token: CancellationToken,
+) {
+    let options = match config.options() {
+        Ok(options) => options,
+        Err(e) => {
+            warn!(message = "not starting MQTT bridge", error = e);
+            return;
+        }
+    };
+    let (client, mut eventloop) = AsyncClient::new(options, REQUEST_CAPACITY);
+    let mut backoff = ExponentialBackoff {
+        initial_interval: Duration::from_secs(1).min(config.max_backoff),
+        max_interval: config.max_backoff,
+        max_elapsed_time: None,
+        ..Default::default()
+    };
+
+    info!(message = "starting MQTT bridge", broker = config.broker);
+    let forwarding = tokio::spawn(forward(
+        registry.register_internal(),
+        client.clone(),
+        config.clone(),
+        metrics.clone(),
+        token.clone(),
+    ));
+
+    loop {
+        let event = tokio::select! {
+            _ = token.cancelled() => break,
+            event = eventloop.poll() => event,
+        };
+        match event {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!(message = "connected to MQTT broker", broker = config.broker);
+                backoff.reset();
+            }
+            Ok(Event::Incoming(Packet::PubAck(_))) => metrics.mqtt_published_messages.increment(1),
+            Ok(Event::Outgoing(Outgoing::Publish(_))) if !config.at_least_once => {
+                metrics.mqtt_published_messages.increment(1)
+            }
+            Ok(_) => {}
+            Err(e) => {
+                metrics.mqtt_connection_errors.increment(1);
+                let delay = backoff.next_backoff().unwrap_or(config.max_backoff);
+                warn!(
+                    message = "MQTT bridge disconnected",
+                    broker = config.broker,
+                    error = e.to_string(),
+                    retry_in_ms = delay.as_millis() as u64
+                );
+                tokio::select! {
+                    _ = token.cancelled() => break,
+                    _ = tokio::time::sleep(delay) => {}
+                }
+            }
+        }
+    }
+
+    // Say goodbye to the broker if still connected, without waiting on messages in flight
+    forwarding.abort();
+    if client.try_disconnect().is_ok() {
+        let _ = tokio::time::timeout(DISCONNECT_TIMEOUT, async {
+            while let Ok(event) = eventloop.poll().await {
+                if matches!(event, Event::Outgoing(Outgoing::Disconnect)) {
+                    return;
+                }
+            }
+        })
+        .await;
+    }
+}
+
+/// Hands `subscription`'s messages to `client` to publish, until `token` is cancelled.
+async fn forward(
+    mut subscription: Subscription,
+    client: AsyncClient,
+    config: MqttConfig,
+    metrics: Arc<Metrics>,
+    token: CancellationToken,
+) {
+    let mut batch = Vec::new();
+    loop {
+        batch.clear();
+        let delivery = tokio::select! {
+            _ = token.cancelled() => return,
+            delivery = subscription.recv_many(&mut batch, 64) => delivery,
+        };
+        match delivery {
+            Delivery::Messages(_) => {}
+            Delivery::Lagged(dropped) => {
+                metrics
+                    .dropped_messages
+                    .increment(DropCause::Lagged, dropped);
+                continue;
+            }
+            Delivery::Overwritten(dropped) => {
+                metrics
+                    .dropped_messages
+                    .increment(DropCause::Overwritten, dropped);
+                continue;
+            }
+        }
+
+        for msg in &batch {
+            let Message::Binary(payload) = &msg.frame else {
+                unreachable!("broadcast messages are binary frames");
+            };
+            let topic = config.topic.render(payload);
+            if client
+                .publish_bytes(topic, config.qos(), false, payload.clone())
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::{Bytes, BytesMut};
+    use rumqttc::{ConnAck, ConnectReturnCode, PubAck, Publish};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn test_topic_template() {
+        let template: TopicTemplate = "flashblocks/{block_number}/{index}".parse().unwrap();
+        assert_eq!(
+            template.render(br#"{"index":3,"metadata":{"block_number":100}}"#),
+            "flashblocks/100/3"
+        );
+        assert_eq!(
+            template.render(br#"{"type":"proxy_status"}"#),
+            "flashblocks/unknown/unknown"
+        );
+        assert_eq!(
+            "flashblocks".parse::<TopicTemplate>().unwrap().render(b""),
+            "flashblocks"
+        );
+
+        for invalid in [
+            "",
+            "flashblocks/#",
+            "flashblocks/+/x",
+            "a/{hash}",
+            "a/{index",
+        ] {
+            assert!(invalid.parse::<TopicTemplate>().is_err(), "{invalid}");
+        }
+    }
+
+    /// A connection to the bridge, as seen by the broker.
+    struct Broker {
+        stream: TcpStream,
+        buf: BytesMut,
+    }
+
+    impl Broker {
+        /// Accepts a connection and answers its CONNECT.
+        async fn accept(listener: &TcpListener) -> Self {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut broker = Self {
+                stream,
+                buf: BytesMut::new(),
+            };
+            let Packet::Connect(connect) = broker.read().await else {
+                panic!("expected CONNECT");
+            };
+            assert_eq!(connect.client_id, "test");
+            assert!(connect.clean_session);
+            broker
+                .write(|buf| ConnAck::new(ConnectReturnCode::Success, false).write(buf))
+                .await;
+            broker
+        }
+
+        async fn read(&mut self) -> Packet {
+            loop {
+                match rumqttc::mqttbytes::v4::read(&mut self.buf, MAX_PACKET_SIZE) {
+                    Ok(packet) => return packet,
+                    Err(rumqttc::Error::InsufficientBytes(_)) => {
+                        assert_ne!(self.stream.read_buf(&mut self.buf).await.unwrap(), 0);
+                    }
+                    Err(e) => panic!("{e:?}"),
+                }
+            }
+        }
+
+        async fn read_publish(&mut self) -> Publish {
+            loop {
+                match self.read().await {
+                    Packet::Publish(publish) => return publish,
+                    Packet::PingReq => {}
+                    packet => panic!("expected PUBLISH, got {packet:?}"),
+                }
+            }
+        }
+
+        async fn write(
+            &mut self,
+            packet: impl FnOnce(&mut BytesMut) -> Result<usize, rumqttc::Error>,
+        ) {
+            let mut buf = BytesMut::new();
+            packet(&mut buf).unwrap();
+            self.stream.write_all(&buf).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bridge() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let metrics = Arc::new(Metrics::default());
+        let registry = Registry::new(16, 1, metrics.clone());
+        let config = MqttConfig {
+            broker: listener.local_addr().unwrap().to_string(),
+            client_id: "test".to_string(),
+            username: None,
+            password: None,
+            topic: "flashblocks/{index}".parse().unwrap(),
+            at_least_once: true,
+            max_backoff: Duration::from_millis(100),
+        };
+        let token = CancellationToken::new();
+        let bridge = tokio::spawn(bridge(registry.clone(), config, metrics, token.clone()));
+
+        let mut broker = Broker::accept(&listener).await;
+        registry.publish(Bytes::from(r#"{"index":1}"#));
+        let publish = broker.read_publish().await;
+        assert_eq!(publish.topic, "flashblocks/1");
+        assert_eq!(publish.qos, QoS::AtLeastOnce);
+        assert_eq!(&publish.payload[..], br#"{"index":1}"#);
+
+        // Dropping the connection without acknowledging the message has it published again
+        drop(broker);
+        let mut broker = Broker::accept(&listener).await;
+        let republished = broker.read_publish().await;
+        assert_eq!(republished.topic, "flashblocks/1");
+        assert_eq!(republished.pkid, publish.pkid);
+        broker
+            .write(|buf| PubAck::new(publish.pkid).write(buf))
+            .await;
+
+        registry.publish(Bytes::from(r#"{"index":2}"#));
+        let publish = broker.read_publish().await;
+        assert_eq!(publish.topic, "flashblocks/2");
+        assert_eq!(&publish.payload[..], br#"{"index":2}"#);
+
+        token.cancel();
+        bridge.await.unwrap();
+        loop {
+            match broker.read().await {
+                Packet::Disconnect => break,
+                Packet::PingReq => {}
+                packet => panic!("expected DISCONNECT, got {packet:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_broker_address() {
+        let config = |broker: &str| MqttConfig {
+            broker: broker.to_string(),
+            client_id: "test".to_string(),
+            username: None,
+            password: None,
+            topic: "flashblocks".parse().unwrap(),
+            at_least_once: false,
+            max_backoff: Duration::from_secs(1),
+        };
+        assert_eq!(
+            config("broker.local:1883")
+                .options()
+                .unwrap()
+                .broker_address(),
+            ("broker.local".to_string(), 1883)
+        );
+        for invalid in ["broker.local", "broker.local:mqtt"] {
+            assert!(config(invalid).options().is_err(), "{invalid}");
+        }
+    }
+}
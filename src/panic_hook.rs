@@ -0,0 +1,67 @@
+//! A panic hook that reports panics as structured error events, rather than leaving them to stderr
+//! and to whichever join handle happens to observe them. The event is logged in the span of the
+//! task that panicked, so it carries the upstream or client the task was serving.
+
+use crate::error_reporting;
+use crate::metrics::Metrics;
+use serde_json::json;
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::sync::Arc;
+use tracing::error;
+
+/// Replaces the default panic hook with one that logs the panic, counts it in `panics_total` and
+/// forwards it to the error webhook if one is configured.
+pub fn install(metrics: Arc<Metrics>) {
+    std::panic::set_hook(Box::new(move |panic_info| {
+        let message = panic_message(panic_info.payload());
+        let location = panic_info.location().map(|l| l.to_string());
+        let thread = std::thread::current().name().map(str::to_string);
+        let task = tokio::task::try_id().map(|id| id.to_string());
+        let backtrace = Backtrace::force_capture().to_string();
+
+        error!(
+            message = "panic",
+            panic = message,
+            location = location,
+            thread = thread,
+            task = task,
+            backtrace = backtrace
+        );
+        metrics.panics_total.increment(1);
+
+        error_reporting::report_panic(
+            message,
+            json!({
+                "location": location,
+                "thread": thread,
+                "task": task,
+                "backtrace": backtrace,
+            }),
+        );
+    }));
+}
+
+/// The message a panic was raised with.
+pub fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_panic_message() {
+        let payload = std::panic::catch_unwind(|| panic!("static")).unwrap_err();
+        assert_eq!(panic_message(&*payload), "static");
+        let payload = std::panic::catch_unwind(|| panic!("formatted {}", 1)).unwrap_err();
+        assert_eq!(panic_message(&*payload), "formatted 1");
+        let payload = std::panic::catch_unwind(|| std::panic::panic_any(1)).unwrap_err();
+        assert_eq!(panic_message(&*payload), "unknown panic");
+    }
+}
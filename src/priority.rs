@@ -0,0 +1,64 @@
+//! Priority admission, so that paying clients aren't locked out by anonymous ones when the proxy is
+//! near its global connection limit. A client has priority if it authenticates as one of
+//! [`PriorityConfig::users`] with Basic auth, the authorization service answers
+//! `{"allow": true, "priority": true}` for it, or it's a downstream proxy on `/relay`.
+//!
+//! The last [`PriorityConfig::reserved`] connections below the global limit are kept for clients
+//! with priority, and with [`PriorityConfig::evict`] a client with priority that arrives at the
+//! global limit disconnects the websocket client without priority that is furthest behind to take
+//! its place.
+
+use crate::rate_limit::RateLimitOccupancy;
+use std::collections::HashSet;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PriorityConfig {
+    /// Basic auth users admitted with priority.
+    pub users: HashSet<String>,
+    /// Connections below the global limit that only clients with priority may take.
+    pub reserved: usize,
+    /// Evict a client without priority to admit one with priority at the global limit.
+    pub evict: bool,
+}
+
+impl PriorityConfig {
+    /// Whether the connection limit applying to a client with or without `priority` has been
+    /// reached.
+    pub fn at_limit(&self, occupancy: RateLimitOccupancy, priority: bool) -> bool {
+        let limit = match priority {
+            true => occupancy.global_limit,
+            false => occupancy.global_limit.saturating_sub(self.reserved),
+        };
+        occupancy.active_connections >= limit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reserved_connections() {
+        let config = PriorityConfig {
+            reserved: 2,
+            ..Default::default()
+        };
+        let occupancy = |active_connections| RateLimitOccupancy {
+            active_connections,
+            global_limit: 10,
+        };
+
+        assert!(!config.at_limit(occupancy(7), false));
+        assert!(config.at_limit(occupancy(8), false));
+        assert!(!config.at_limit(occupancy(9), true));
+        assert!(config.at_limit(occupancy(10), true));
+
+        // Reserving more than the limit leaves nothing for clients without priority
+        let config = PriorityConfig {
+            reserved: 20,
+            ..Default::default()
+        };
+        assert!(config.at_limit(occupancy(0), false));
+        assert!(!config.at_limit(occupancy(0), true));
+    }
+}
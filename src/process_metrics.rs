@@ -0,0 +1,109 @@
+use crate::allocator;
+use crate::metrics::Metrics;
+use crate::registry::Registry;
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// Resource usage of the proxy process, read from procfs. Fields are `None` where the platform
+/// doesn't expose them.
+#[derive(Debug, Default)]
+pub struct ProcessStats {
+    pub resident_memory_bytes: Option<u64>,
+    pub open_fds: Option<u64>,
+    pub open_sockets: Option<u64>,
+}
+
+impl ProcessStats {
+    pub fn read() -> Self {
+        let (open_fds, open_sockets) = match read_fds() {
+            Some((fds, sockets)) => (Some(fds), Some(sockets)),
+            None => (None, None),
+        };
+
+        Self {
+            resident_memory_bytes: fs::read_to_string("/proc/self/status")
+                .ok()
+                .and_then(|status| parse_resident_memory(&status)),
+            open_fds,
+            open_sockets,
+        }
+    }
+}
+
+/// Periodically exports process resource usage, allocator statistics and the estimated memory
+/// held by client queues.
+pub async fn report(
+    metrics: Arc<Metrics>,
+    registry: Registry,
+    interval: Duration,
+    token: CancellationToken,
+) {
+    let mut ticker = tokio::time::interval(interval);
+
+    loop {
+        tokio::select! {
+            _ = token.cancelled() => return,
+            _ = ticker.tick() => {}
+        }
+
+        let stats = ProcessStats::read();
+        if let Some(rss) = stats.resident_memory_bytes {
+            metrics.process_resident_memory_bytes.set(rss as f64);
+        }
+        if let Some(fds) = stats.open_fds {
+            metrics.process_open_fds.set(fds as f64);
+        }
+        if let Some(sockets) = stats.open_sockets {
+            metrics.process_open_sockets.set(sockets as f64);
+        }
+
+        allocator::report(&metrics);
+
+        metrics
+            .broadcast_buffer_bytes
+            .set(registry.buffered_bytes_estimate() as f64);
+    }
+}
+
+fn parse_resident_memory(status: &str) -> Option<u64> {
+    let kb = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+
+    Some(kb * 1024)
+}
+
+fn read_fds() -> Option<(u64, u64)> {
+    let mut fds = 0;
+    let mut sockets = 0;
+
+    for entry in fs::read_dir("/proc/self/fd").ok()?.flatten() {
+        fds += 1;
+        if fs::read_link(entry.path())
+            .is_ok_and(|target| target.to_string_lossy().starts_with("socket:"))
+        {
+            sockets += 1;
+        }
+    }
+
+    Some((fds, sockets))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_resident_memory() {
+        let status = "Name:\tproxy\nVmPeak:\t  20000 kB\nVmRSS:\t   12345 kB\nThreads:\t4\n";
+        assert_eq!(parse_resident_memory(status), Some(12345 * 1024));
+        assert_eq!(parse_resident_memory("Name:\tproxy\n"), None);
+    }
+}
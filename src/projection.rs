@@ -0,0 +1,226 @@
+//! Projections of message payloads, so that tiers of service can be offered different views of one
+//! stream, e.g. a free tier with transactions reduced to their hashes. See [`crate::tiers`].
+
+use bytes::Bytes;
+use serde_json::Value;
+use std::str::FromStr;
+use tiny_keccak::{Hasher, Keccak};
+
+/// A projection as configured with `project=` on a stream. Each applies to JSON messages with a
+/// value at the JSON pointer; other messages are left alone.
+///
+/// - `omit:<pointer>` removes the value, e.g. `omit:/diff/withdrawals`
+/// - `count:<pointer>` replaces an array with its length, e.g. `count:/diff/transactions`
+/// - `hash:<pointer>` replaces each hex encoded item of an array with its keccak256 hash, which
+///   for the raw transactions in a flashblock are their transaction hashes, e.g.
+///   `hash:/diff/transactions`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Projection {
+    Omit(String),
+    Count(String),
+    Hash(String),
+}
+
+impl FromStr for Projection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, pointer) = s.split_once(':').ok_or_else(|| {
+            format!("projection {s} must be omit:, count: or hash: and a JSON pointer")
+        })?;
+        if !pointer.starts_with('/') {
+            return Err(format!("JSON pointer {pointer} must start with '/'"));
+        }
+
+        let pointer = pointer.to_string();
+        match kind {
+            "omit" => Ok(Projection::Omit(pointer)),
+            "count" => Ok(Projection::Count(pointer)),
+            "hash" => Ok(Projection::Hash(pointer)),
+            kind => Err(format!("unknown projection {kind}")),
+        }
+    }
+}
+
+/// Applies `projections` to `payload`, returning it unchanged if it isn't JSON or none of them
+/// apply.
+pub fn project(projections: &[Projection], payload: Bytes) -> Bytes {
+    if projections.is_empty() {
+        return payload;
+    }
+    let Ok(mut json) = serde_json::from_slice::<Value>(&payload) else {
+        return payload;
+    };
+
+    let mut changed = false;
+    for projection in projections {
+        changed |= match projection {
+            Projection::Omit(pointer) => omit(&mut json, pointer),
+            Projection::Count(pointer) => match json.pointer_mut(pointer) {
+                Some(value @ Value::Array(_)) => {
+                    *value = Value::from(value.as_array().map_or(0, Vec::len));
+                    true
+                }
+                _ => false,
+            },
+            Projection::Hash(pointer) => match json.pointer_mut(pointer) {
+                Some(Value::Array(items)) => {
+                    for item in items.iter_mut() {
+                        if let Some(bytes) = item.as_str().and_then(decode_hex) {
+                            *item = Value::String(encode_hex(&keccak256(&bytes)));
+                        }
+                    }
+                    true
+                }
+                _ => false,
+            },
+        };
+    }
+
+    match changed {
+        true => serde_json::to_vec(&json).map_or(payload, Bytes::from),
+        false => payload,
+    }
+}
+
+fn omit(json: &mut Value, pointer: &str) -> bool {
+    let Some((parent, key)) = pointer.rsplit_once('/') else {
+        return false;
+    };
+    let key = key.replace("~1", "/").replace("~0", "~");
+
+    match json.pointer_mut(parent) {
+        Some(Value::Object(object)) => object.remove(&key).is_some(),
+        Some(Value::Array(array)) => match key.parse::<usize>() {
+            Ok(index) if index < array.len() => {
+                array.remove(index);
+                true
+            }
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    let s = s.strip_prefix("0x")?;
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::from("0x"), |mut hex, byte| {
+        hex.push_str(&format!("{byte:02x}"));
+        hex
+    })
+}
+
+/// Ethereum's keccak256, i.e. Keccak with the original padding rather than SHA-3's.
+fn keccak256(data: &[u8]) -> [u8; 32] {
+    let mut keccak = Keccak::v256();
+    keccak.update(data);
+    let mut hash = [0u8; 32];
+    keccak.finalize(&mut hash);
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_projection() {
+        assert_eq!(
+            "hash:/diff/transactions".parse(),
+            Ok(Projection::Hash("/diff/transactions".to_string()))
+        );
+        assert_eq!(
+            "omit:/diff/withdrawals".parse(),
+            Ok(Projection::Omit("/diff/withdrawals".to_string()))
+        );
+        for invalid in ["/diff", "count:diff", "drop:/diff"] {
+            assert!(invalid.parse::<Projection>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_keccak256() {
+        let hash = |data: &[u8]| encode_hex(&keccak256(data));
+        assert_eq!(
+            hash(b""),
+            "0xc5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470"
+        );
+        assert_eq!(
+            hash(b"abc"),
+            "0x4e03657aea45a94fc7d47ba826c8d667c0d1e6e33a64a036ec44f58fa12d6c45"
+        );
+        assert_eq!(
+            hash(b"The quick brown fox jumps over the lazy dog"),
+            "0x4d741b6f1eb29cb2a9b9911c82f56fa8d73b04959d3d9d222895df6c0b28aa15"
+        );
+
+        // Either side of the 136 byte rate, where padding needs a block of its own, and the 200
+        // byte message of 0xa3 from the Keccak test vectors, which spans two blocks
+        assert_eq!(
+            hash(&[b'a'; 135]),
+            "0x34367dc248bbd832f4e3e69dfaac2f92638bd0bbd18f2912ba4ef454919cf446"
+        );
+        assert_eq!(
+            hash(&[b'a'; 136]),
+            "0xa6c4d403279fe3e0af03729caada8374b5ca54d8065329a3ebcaeb4b60aa386e"
+        );
+        assert_eq!(
+            hash(&[0xa3; 200]),
+            "0x3a57666b048777f2c953dc4456f45a2588e1cb6f2da760122d530ac2ce607d4a"
+        );
+    }
+
+    #[test]
+    fn test_project() {
+        let payload = Bytes::from(
+            json!({
+                "index": 1,
+                "diff": {"transactions": ["0x", "0x616263", "not hex"], "withdrawals": []},
+            })
+            .to_string(),
+        );
+
+        let projected = project(
+            &[
+                "omit:/diff/withdrawals".parse().unwrap(),
+                "hash:/diff/transactions".parse().unwrap(),
+            ],
+            payload.clone(),
+        );
+        assert_eq!(
+            serde_json::from_slice::<Value>(&projected).unwrap(),
+            json!({
+                "index": 1,
+                "diff": {"transactions": [
+                    "0xc5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470",
+                    "0x4e03657aea45a94fc7d47ba826c8d667c0d1e6e33a64a036ec44f58fa12d6c45",
+                    "not hex",
+                ]},
+            })
+        );
+
+        let projected = project(&["count:/diff/transactions".parse().unwrap()], payload);
+        assert_eq!(
+            serde_json::from_slice::<Value>(&projected).unwrap()["diff"]["transactions"],
+            3
+        );
+
+        // Payloads that aren't JSON or that no projection applies to are passed through as is
+        let ping = Bytes::from("ping");
+        let projections = ["count:/missing".parse().unwrap()];
+        assert_eq!(project(&projections, ping.clone()), ping);
+        let unchanged = Bytes::from(r#"{"a": 1}"#);
+        assert_eq!(project(&projections, unchanged.clone()), unchanged);
+    }
+}
@@ -0,0 +1,213 @@
+use crate::hooks::ConnectionHooks;
+use crate::metrics::Metrics;
+use crate::rate_limit::{InMemoryRateLimit, RateLimit};
+use crate::registry::Registry;
+use crate::server::{ReadinessConfig, Server};
+use crate::subscriber::WebsocketSubscriber;
+use axum::http::Uri;
+use axum::Router;
+use bytes::Bytes;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+type Handler = Box<dyn Fn(Bytes) + Send + Sync>;
+
+/// Builds a [`Proxy`] with the same defaults as the binary, for embedding the fan-out in another
+/// service.
+pub struct ProxyBuilder {
+    upstreams: Vec<Uri>,
+    registry: Option<Registry>,
+    message_buffer_size: usize,
+    broadcast_shards: usize,
+    rate_limiter: Option<Arc<dyn RateLimit>>,
+    ip_addr_http_header: String,
+    readiness: ReadinessConfig,
+    subscriber_max_interval: u64,
+    metrics: Option<Arc<Metrics>>,
+    hooks: Option<Arc<dyn ConnectionHooks>>,
+}
+
+impl Default for ProxyBuilder {
+    fn default() -> Self {
+        Self {
+            upstreams: Vec::new(),
+            registry: None,
+            message_buffer_size: 20,
+            broadcast_shards: 1,
+            rate_limiter: None,
+            ip_addr_http_header: "X-Forwarded-For".to_string(),
+            readiness: ReadinessConfig::default(),
+            subscriber_max_interval: 20,
+            metrics: None,
+            hooks: None,
+        }
+    }
+}
+
+impl ProxyBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an upstream websocket to subscribe to.
+    pub fn upstream(mut self, uri: Uri) -> Self {
+        self.upstreams.push(uri);
+        self
+    }
+
+    /// Number of messages to queue for each client before it is considered lagging.
+    pub fn message_buffer_size(mut self, size: usize) -> Self {
+        self.message_buffer_size = size;
+        self
+    }
+
+    /// Number of shards to spread the client list across.
+    pub fn broadcast_shards(mut self, shards: usize) -> Self {
+        self.broadcast_shards = shards;
+        self
+    }
+
+    /// Publish to this registry instead of one built from the buffer size and shard count, e.g.
+    /// to set a message TTL or lag strategy.
+    pub fn registry(mut self, registry: Registry) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    /// Limits connections with this rate limiter. Defaults to an in-memory limit of 100
+    /// connections, 10 per IP.
+    pub fn rate_limiter(mut self, rate_limiter: Arc<dyn RateLimit>) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Header to use to determine the client's origin IP.
+    pub fn ip_addr_http_header(mut self, header: impl Into<String>) -> Self {
+        self.ip_addr_http_header = header.into();
+        self
+    }
+
+    pub fn readiness(mut self, readiness: ReadinessConfig) -> Self {
+        self.readiness = readiness;
+        self
+    }
+
+    /// Maximum backoff in seconds between upstream connection attempts.
+    pub fn subscriber_max_interval(mut self, seconds: u64) -> Self {
+        self.subscriber_max_interval = seconds;
+        self
+    }
+
+    pub fn metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Calls `hooks` through the lifecycle of each websocket client. See [`crate::hooks`].
+    pub fn hooks(mut self, hooks: Arc<dyn ConnectionHooks>) -> Self {
+        self.hooks = Some(hooks);
+        self
+    }
+
+    /// Builds the proxy. `listen_addr` is only used by [`Proxy::run`]; it is ignored when the
+    /// proxy's router is served by the caller.
+    pub fn build(self, listen_addr: SocketAddr) -> Proxy {
+        let metrics = self.metrics.unwrap_or_else(|| Arc::new(Metrics::default()));
+        let registry = self.registry.unwrap_or_else(|| {
+            Registry::new(
+                self.message_buffer_size,
+                self.broadcast_shards,
+                metrics.clone(),
+            )
+        });
+        let rate_limiter = self
+            .rate_limiter
+            .unwrap_or_else(|| Arc::new(InMemoryRateLimit::new(100, 10)));
+
+        let subscribers: Vec<_> = self
+            .upstreams
+            .into_iter()
+            .enumerate()
+            .map(|(index, uri)| {
+                let publisher = registry.clone();
+                let metrics_clone = metrics.clone();
+                let handler: Handler = Box::new(move |data: Bytes| {
+                    let clients = publisher.publish_from(data, Some(index as u16));
+                    metrics_clone.active_connections.set(clients as f64);
+                });
+
+                WebsocketSubscriber::new(
+                    uri,
+                    handler,
+                    self.subscriber_max_interval,
+                    metrics.clone(),
+                )
+            })
+            .collect();
+
+        let server = Server::new(
+            listen_addr,
+            registry.clone(),
+            metrics,
+            rate_limiter,
+            self.ip_addr_http_header,
+            subscribers.iter().map(|s| s.status()).collect(),
+            self.readiness,
+        );
+        let server = match self.hooks {
+            Some(hooks) => server.with_hooks(hooks),
+            None => server,
+        };
+
+        Proxy {
+            registry,
+            server,
+            subscribers,
+        }
+    }
+}
+
+/// Upstream subscriptions fanned out to websocket clients, either served on its own listener with
+/// [`Proxy::run`] or mounted in an existing axum app with [`Proxy::router`] and
+/// [`Proxy::spawn_subscribers`].
+pub struct Proxy {
+    registry: Registry,
+    server: Server,
+    subscribers: Vec<WebsocketSubscriber<Handler>>,
+}
+
+impl Proxy {
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+
+    /// The proxy's routes (`/ws` and the health endpoints), see [`Server::router`].
+    pub fn router(&self) -> Router {
+        self.server.router()
+    }
+
+    /// Starts subscribing to the upstreams, until `token` is cancelled.
+    pub fn spawn_subscribers(&mut self, token: CancellationToken) -> Vec<JoinHandle<()>> {
+        self.subscribers
+            .drain(..)
+            .map(|mut subscriber| {
+                let token = token.clone();
+                tokio::spawn(async move { subscriber.run(token).await })
+            })
+            .collect()
+    }
+
+    /// Subscribes to the upstreams and serves clients on the listen address until `token` is
+    /// cancelled.
+    pub async fn run(mut self, token: CancellationToken) {
+        let subscribers = self.spawn_subscribers(token.clone());
+        self.server.listen(token).await;
+        futures::future::join_all(subscribers).await;
+    }
+}
@@ -4,7 +4,7 @@ use std::sync::{Arc, Mutex};
 use tracing::{debug, error, warn};
 
 use thiserror::Error;
-use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
 
 use redis::{Client, Commands, RedisError};
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -22,18 +22,53 @@ pub struct Ticket {
     addr: IpAddr,
     _permit: OwnedSemaphorePermit,
     rate_limiter: Arc<dyn RateLimit>,
+    _asn_permit: Option<AsnPermit>,
+    _stream_permit: Option<OwnedSemaphorePermit>,
+    release_notice: Option<Arc<Notify>>,
+}
+
+impl Ticket {
+    /// Hold `permit` for as long as the ticket.
+    pub fn with_asn_permit(mut self, permit: AsnPermit) -> Self {
+        self._asn_permit = Some(permit);
+        self
+    }
+
+    /// Hold `permit`, a connection slot on a named stream, for as long as the ticket.
+    pub fn with_stream_permit(mut self, permit: OwnedSemaphorePermit) -> Self {
+        self._stream_permit = Some(permit);
+        self
+    }
+
+    /// Notify one waiter on `notice` when the ticket is released.
+    pub fn with_release_notice(mut self, notice: Arc<Notify>) -> Self {
+        self.release_notice = Some(notice);
+        self
+    }
 }
 
 impl Drop for Ticket {
     fn drop(&mut self) {
-        self.rate_limiter.release(self.addr)
+        self.rate_limiter.release(self.addr);
+        if let Some(notice) = &self.release_notice {
+            notice.notify_one();
+        }
     }
 }
 
+/// Snapshot of how many connection slots are in use on this instance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitOccupancy {
+    pub active_connections: usize,
+    pub global_limit: usize,
+}
+
 pub trait RateLimit: Send + Sync {
     fn try_acquire(self: Arc<Self>, addr: IpAddr) -> Result<Ticket, RateLimitError>;
 
     fn release(&self, ticket: IpAddr);
+
+    fn occupancy(&self) -> RateLimitOccupancy;
 }
 
 struct Inner {
@@ -42,6 +77,7 @@ struct Inner {
 }
 
 pub struct InMemoryRateLimit {
+    global_limit: usize,
     per_ip_limit: usize,
     inner: Mutex<Inner>,
 }
@@ -49,6 +85,7 @@ pub struct InMemoryRateLimit {
 impl InMemoryRateLimit {
     pub fn new(global_limit: usize, per_ip_limit: usize) -> Self {
         Self {
+            global_limit,
             per_ip_limit,
             inner: Mutex::new(Inner {
                 active_connections: HashMap::new(),
@@ -94,6 +131,9 @@ impl RateLimit for InMemoryRateLimit {
             addr,
             _permit: permit,
             rate_limiter: self.clone(),
+            _asn_permit: None,
+            _stream_permit: None,
+            release_notice: None,
         })
     }
 
@@ -121,6 +161,70 @@ impl RateLimit for InMemoryRateLimit {
             inner.active_connections.insert(addr, new_count);
         }
     }
+
+    fn occupancy(&self) -> RateLimitOccupancy {
+        let inner = self.inner.lock().unwrap();
+
+        RateLimitOccupancy {
+            active_connections: self.global_limit - inner.semaphore.available_permits(),
+            global_limit: self.global_limit,
+        }
+    }
+}
+
+/// Limits the connections from each autonomous system on this instance, so that clients spread
+/// over a cloud provider's addresses can't take up the global limit while each stays under the
+/// per-IP limit.
+pub struct AsnLimit {
+    per_asn_limit: usize,
+    active_connections: Mutex<HashMap<u32, usize>>,
+}
+
+impl AsnLimit {
+    pub fn new(per_asn_limit: usize) -> Self {
+        Self {
+            per_asn_limit,
+            active_connections: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn try_acquire(self: Arc<Self>, asn: u32) -> Result<AsnPermit, RateLimitError> {
+        let mut active_connections = self.active_connections.lock().unwrap();
+        let count = active_connections.entry(asn).or_default();
+
+        if *count + 1 > self.per_asn_limit {
+            debug!(message = "ASN limit exceeded", asn = asn);
+            return Err(RateLimitError::Limit {
+                reason: String::from("ASN limit exceeded"),
+            });
+        }
+        *count += 1;
+        drop(active_connections);
+
+        Ok(AsnPermit { asn, limit: self })
+    }
+
+    fn release(&self, asn: u32) {
+        let mut active_connections = self.active_connections.lock().unwrap();
+        if let Some(count) = active_connections.get_mut(&asn) {
+            *count -= 1;
+            if *count == 0 {
+                active_connections.remove(&asn);
+            }
+        }
+    }
+}
+
+#[clippy::has_significant_drop]
+pub struct AsnPermit {
+    asn: u32,
+    limit: Arc<AsnLimit>,
+}
+
+impl Drop for AsnPermit {
+    fn drop(&mut self) {
+        self.limit.release(self.asn)
+    }
 }
 
 pub struct RedisRateLimit {
@@ -439,6 +543,9 @@ impl RateLimit for RedisRateLimit {
             addr,
             _permit: permit,
             rate_limiter: self,
+            _asn_permit: None,
+            _stream_permit: None,
+            release_notice: None,
         })
     }
 
@@ -470,6 +577,13 @@ impl RateLimit for RedisRateLimit {
             }
         }
     }
+
+    fn occupancy(&self) -> RateLimitOccupancy {
+        RateLimitOccupancy {
+            active_connections: self.global_limit - self.semaphore.available_permits(),
+            global_limit: self.global_limit,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -520,6 +634,14 @@ mod tests {
             1
         );
 
+        assert_eq!(
+            rate_limiter.occupancy(),
+            RateLimitOccupancy {
+                active_connections: 1,
+                global_limit: GLOBAL_LIMIT,
+            }
+        );
+
         drop(c1);
 
         assert_eq!(
@@ -531,6 +653,7 @@ mod tests {
                 .available_permits(),
             GLOBAL_LIMIT
         );
+        assert_eq!(rate_limiter.occupancy().active_connections, 0);
         assert_eq!(
             rate_limiter.inner.lock().unwrap().active_connections.len(),
             0
@@ -703,6 +826,37 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_asn_limits() {
+        let rate_limiter = Arc::new(InMemoryRateLimit::new(GLOBAL_LIMIT, PER_IP_LIMIT));
+        let asn_limit = Arc::new(AsnLimit::new(1));
+        let user_1 = IpAddr::from_str("127.0.0.1").unwrap();
+        let user_2 = IpAddr::from_str("127.0.0.2").unwrap();
+
+        let c1 = rate_limiter
+            .clone()
+            .try_acquire(user_1)
+            .unwrap()
+            .with_asn_permit(asn_limit.clone().try_acquire(16509).unwrap());
+
+        // Another address in the same ASN is refused, but not one in another ASN
+        assert!(asn_limit.clone().try_acquire(16509).is_err());
+        let c2 = asn_limit.clone().try_acquire(14061).unwrap();
+
+        // Dropping the ticket releases its ASN permit along with its slot
+        drop(c1);
+        assert_eq!(rate_limiter.occupancy().active_connections, 0);
+        let c3 = rate_limiter
+            .clone()
+            .try_acquire(user_2)
+            .unwrap()
+            .with_asn_permit(asn_limit.clone().try_acquire(16509).unwrap());
+
+        drop(c2);
+        drop(c3);
+        assert!(asn_limit.active_connections.lock().unwrap().is_empty());
+    }
+
     #[tokio::test]
     #[cfg(all(feature = "integration", test))]
     async fn test_instance_tracking_and_cleanup() {
@@ -743,7 +897,7 @@ mod tests {
                 let mut conn = redis_client.get_connection().unwrap();
 
                 let exists: bool = redis::cmd("EXISTS")
-                    .arg(format!("test:instance:instance1:heartbeat"))
+                    .arg("test:instance:instance1:heartbeat")
                     .query(&mut conn)
                     .unwrap();
                 assert!(exists, "Instance1 heartbeat should exist initially");
@@ -768,7 +922,7 @@ mod tests {
             let mut conn = redis_client.get_connection().unwrap();
 
             let exists: bool = redis::cmd("EXISTS")
-                .arg(format!("test:instance:instance1:heartbeat"))
+                .arg("test:instance:instance1:heartbeat")
                 .query(&mut conn)
                 .unwrap();
             assert!(
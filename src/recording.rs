@@ -0,0 +1,155 @@
+//! Recording of the upstream stream, for replaying the exact messages that triggered a bug.
+//!
+//! A recording is a sequence of entries, each an 8-byte big-endian count of microseconds since
+//! recording started, a 4-byte big-endian payload length, and the payload.
+
+use crate::subscriber::UpstreamStatus;
+use bytes::{Buf, Bytes};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+const HEADER_LEN: usize = 12;
+
+/// Appends upstream messages to a recording file.
+pub struct Recorder {
+    file: Mutex<BufWriter<File>>,
+    started_at: Instant,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            file: Mutex::new(BufWriter::new(File::create(path)?)),
+            started_at: Instant::now(),
+        })
+    }
+
+    /// Records `payload` as received now. Each entry is flushed as it is written, so that the
+    /// recording survives the proxy being killed.
+    pub fn record(&self, payload: &[u8]) {
+        let offset = self.started_at.elapsed().as_micros() as u64;
+        let mut file = self.file.lock().unwrap();
+
+        let result = file
+            .write_all(&offset.to_be_bytes())
+            .and_then(|_| file.write_all(&(payload.len() as u32).to_be_bytes()))
+            .and_then(|_| file.write_all(payload))
+            .and_then(|_| file.flush());
+
+        if let Err(e) = result {
+            error!(message = "failed to record message", error = e.to_string());
+        }
+    }
+}
+
+/// Reads the next entry from `recording`, returning its offset from the start of the recording
+/// and its payload.
+pub fn next_entry(recording: &mut Bytes) -> io::Result<Option<(Duration, Bytes)>> {
+    if recording.is_empty() {
+        return Ok(None);
+    }
+    if recording.len() < HEADER_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "truncated recording entry",
+        ));
+    }
+
+    let offset = Duration::from_micros(recording.get_u64());
+    let len = recording.get_u32() as usize;
+    if recording.len() < len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "truncated recording payload",
+        ));
+    }
+
+    Ok(Some((offset, recording.split_to(len))))
+}
+
+/// Passes the messages in the recording at `path` to `handler` with their original spacing,
+/// divided by `speed`. Returns once the recording has been replayed or `token` is cancelled.
+pub async fn replay<F>(
+    path: &Path,
+    speed: f64,
+    handler: F,
+    status: Arc<UpstreamStatus>,
+    token: CancellationToken,
+) -> io::Result<()>
+where
+    F: Fn(Bytes),
+{
+    let mut recording = Bytes::from(tokio::fs::read(path).await?);
+    info!(
+        message = "replaying recording",
+        path = %path.display(),
+        speed = speed
+    );
+
+    let started_at = tokio::time::Instant::now();
+    status.set_connected(true);
+    let mut replayed = 0;
+
+    while let Some((offset, payload)) = next_entry(&mut recording)? {
+        tokio::select! {
+            _ = token.cancelled() => break,
+            _ = tokio::time::sleep_until(started_at + offset.div_f64(speed)) => {}
+        }
+
+        status.record_message();
+        handler(payload);
+        replayed += 1;
+    }
+
+    status.set_connected(false);
+    info!(
+        message = "finished replaying recording",
+        messages = replayed
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let path = std::env::temp_dir().join(format!("recording-{}", uuid::Uuid::new_v4()));
+        let recorder = Recorder::create(&path).unwrap();
+        recorder.record(b"one");
+        recorder.record(b"");
+        recorder.record(b"three");
+        drop(recorder);
+
+        let replayed = Arc::new(Mutex::new(Vec::new()));
+        let replayed_clone = replayed.clone();
+        let status = Arc::new(UpstreamStatus::new("file://recording".parse().unwrap()));
+        replay(
+            &path,
+            10.0,
+            move |payload| replayed_clone.lock().unwrap().push(payload),
+            status.clone(),
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(*replayed.lock().unwrap(), ["one", "", "three"]);
+        assert!(status.last_message_age().is_some());
+
+        // A recording cut off mid-entry is an error
+        let mut truncated = Bytes::from(std::fs::read(&path).unwrap());
+        truncated.truncate(truncated.len() - 1);
+        std::fs::write(&path, &truncated).unwrap();
+        let result = replay(&path, 10.0, |_| {}, status, CancellationToken::new()).await;
+        assert!(result.is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
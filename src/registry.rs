@@ -1,35 +1,756 @@
-use crate::client::ClientConnection;
-use crate::metrics::Metrics;
-use std::sync::Arc;
-use tokio::sync::broadcast::error::RecvError;
-use tokio::sync::broadcast::Sender;
-use tracing::{info, trace, warn};
+use crate::audit;
+use crate::client::{
+    disconnect_reason, ClientConnection, Heartbeat, HeartbeatAction, HeartbeatConfig,
+};
+use crate::log_sampling::{self, EventClass};
+use crate::metrics::{Cadence, DisconnectReason, DropCause, Metrics};
+use crate::sampling::RateCap;
+use crate::transform::{self, Transform};
+use axum::extract::ws::Message;
+use bytes::Bytes;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{info, info_span, trace, warn, Instrument};
 
+/// How long a client is given to receive its close frame on shutdown.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A message published to clients, tagged with the time it was published so that per-client
+/// delivery latency can be measured. The time is tokio's, so it follows a paused test clock. The
+/// websocket message is built once when published and its payload is reference counted, so
+/// handing it to each client doesn't copy or rebuild it.
+#[derive(Clone, Debug)]
+pub struct BroadcastMessage {
+    pub frame: Message,
+    pub size: usize,
+    pub received_at: Instant,
+    /// Position of the message in the sequence published by this proxy, starting from zero.
+    pub sequence: u64,
+    /// Wall clock time the message was published, in milliseconds since the Unix epoch, for
+    /// clients to measure delivery latency against.
+    pub received_at_ms: u64,
+    /// Index of the upstream the message came from, when known.
+    pub upstream: Option<u16>,
+}
+
+impl BroadcastMessage {
+    pub fn new(payload: Bytes) -> Self {
+        Self {
+            size: payload.len(),
+            frame: Message::Binary(payload),
+            received_at: Instant::now(),
+            sequence: 0,
+            received_at_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis() as u64),
+            upstream: None,
+        }
+    }
+}
+
+/// What to do with a new message when a client's queue is already full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LagStrategy {
+    /// Treat the client as lagging: drop its whole queue and resume with the next new message.
+    #[default]
+    Skip,
+    /// Overwrite the oldest queued message, so the queue always holds the newest messages. Suited
+    /// to consumers that only care about the freshest data.
+    Overwrite,
+}
+
+/// A client's bounded message queue, shared between the fan-out loop and the client's task.
+struct ClientQueue {
+    messages: Mutex<VecDeque<BroadcastMessage>>,
+    capacity: usize,
+    ready: Notify,
+}
+
+impl ClientQueue {
+    fn new(capacity: usize) -> Self {
+        Self {
+            messages: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            ready: Notify::new(),
+        }
+    }
+
+    /// Queues `msg`, returning false if the queue is full. With [`LagStrategy::Overwrite`] a full
+    /// queue makes room by dropping its oldest message instead.
+    fn push(&self, msg: BroadcastMessage, strategy: LagStrategy, state: &ClientState) -> bool {
+        let mut messages = self.messages.lock().unwrap();
+        if messages.len() >= self.capacity {
+            match strategy {
+                LagStrategy::Skip => return false,
+                LagStrategy::Overwrite => {
+                    messages.pop_front();
+                    state.overwritten.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        messages.push_back(msg);
+        drop(messages);
+
+        self.ready.notify_one();
+        true
+    }
+
+    fn len(&self) -> usize {
+        self.messages.lock().unwrap().len()
+    }
+}
+
+/// The registry's handle on a subscribed client: its queue plus the state it shares with the
+/// client's task.
+struct ClientHandle {
+    queue: Arc<ClientQueue>,
+    state: Arc<ClientState>,
+}
+
+/// State shared between the fan-out loop and a client's task.
+#[derive(Default)]
+struct ClientState {
+    /// Messages that couldn't be queued because the client's queue was full, not yet accounted
+    /// for by the client's task.
+    skipped: AtomicU64,
+    /// Messages pushed out of the client's queue by newer ones, or to stay within the memory
+    /// budget, not yet accounted for by the client's task.
+    overwritten: AtomicU64,
+    /// How far behind the newest message the client is, updated by the client's task after
+    /// every delivered message.
+    lag_messages: AtomicU64,
+    lag_millis: AtomicU64,
+    /// Set by load shedding to make the client drop its queue.
+    lag_drop: AtomicBool,
+    /// Whether the client may be disconnected to make room for a client with priority. See
+    /// [`crate::priority`].
+    evictable: bool,
+    /// Whether the subscriber is part of the proxy, such as a tier mirroring the stream, rather
+    /// than a client. Internal subscribers aren't counted as clients, and are never lag-dropped
+    /// or evicted.
+    internal: bool,
+    /// Cancelled to disconnect the client.
+    evicted: CancellationToken,
+}
+
+/// The evictable client furthest behind, from [`Registry::eviction_candidate`].
+pub struct EvictionCandidate {
+    /// Messages waiting in the client's queue.
+    pub backlog: usize,
+    /// How long the last message delivered to the client took to reach it.
+    pub lag: Duration,
+    state: Arc<ClientState>,
+}
+
+impl EvictionCandidate {
+    /// How far behind the client is, for comparing candidates from different registries.
+    pub fn behind(&self) -> (usize, Duration) {
+        (self.backlog, self.lag)
+    }
+
+    /// Disconnects the client.
+    pub fn evict(&self) {
+        self.state.evicted.cancel();
+    }
+}
+
+/// Lag of the connected clients, aggregated by [`Registry::lag_summary`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LagSummary {
+    pub messages_max: u64,
+    pub messages_p99: u64,
+    pub millis_max: u64,
+    pub millis_p99: u64,
+}
+
+/// How full the connected clients' queues are, from [`Registry::queue_occupancy`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QueueOccupancy {
+    /// Length of the fullest queue, as a fraction of the buffer size.
+    pub max_fraction: f64,
+    /// Clients whose queue is full or one message short of it, and so will lag or have messages
+    /// overwritten if they don't catch up before the next message.
+    pub near_overflow: usize,
+}
+
+/// Settings for each client's queue and connection, which can be changed while the registry is
+/// serving. Each client keeps the settings in effect when it subscribed.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientSettings {
+    /// Number of messages each client may fall behind before it is considered lagging.
+    pub buffer_size: usize,
+    pub message_ttl: Option<Duration>,
+    pub heartbeat: Option<HeartbeatConfig>,
+}
+
+type Shard = Mutex<HashMap<u64, ClientHandle>>;
+
+/// What a client's queue yields next.
+#[derive(Debug)]
+pub enum Delivery {
+    /// This many messages were appended to the caller's buffer.
+    Messages(usize),
+    /// The client fell behind and this many messages were dropped; delivery resumes with the
+    /// next published message.
+    Lagged(u64),
+    /// This many messages were overwritten by newer ones, or evicted to stay within the memory
+    /// budget, before the client got to them. Queued messages are unaffected.
+    Overwritten(u64),
+}
+
+/// A client's registration with the registry. Dropping it deregisters the client.
+pub struct Subscription {
+    id: u64,
+    shard: usize,
+    shards: Arc<Vec<Shard>>,
+    queue: Arc<ClientQueue>,
+    state: Arc<ClientState>,
+}
+
+impl Subscription {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Cancelled when the client is evicted. See [`EvictionCandidate::evict`].
+    pub fn eviction(&self) -> CancellationToken {
+        self.state.evicted.clone()
+    }
+
+    /// Waits for the next message for this client and appends it to `buffer`, along with up to
+    /// `limit - 1` more that are already queued so that they can be written together.
+    pub async fn recv_many(
+        &mut self,
+        buffer: &mut Vec<BroadcastMessage>,
+        limit: usize,
+    ) -> Delivery {
+        loop {
+            let overwritten = self.state.overwritten.swap(0, Ordering::Relaxed);
+            if overwritten > 0 {
+                return Delivery::Overwritten(overwritten);
+            }
+
+            {
+                let mut messages = self.queue.messages.lock().unwrap();
+                if !messages.is_empty() {
+                    let skipped = self.state.skipped.swap(0, Ordering::Relaxed);
+                    let lag_drop = self.state.lag_drop.swap(false, Ordering::Relaxed);
+                    if skipped > 0 || lag_drop {
+                        // Skip to the newest message: everything still queued for this client is
+                        // dropped as well as the messages that didn't fit in the queue.
+                        let dropped = skipped + messages.len() as u64;
+                        messages.clear();
+                        return Delivery::Lagged(dropped);
+                    }
+
+                    let received = messages.len().min(limit.max(1));
+                    buffer.extend(messages.drain(..received));
+                    return Delivery::Messages(received);
+                }
+            }
+
+            self.queue.ready.notified().await;
+        }
+    }
+
+    /// Like [`Subscription::recv_many`], but with `cap` the client's queue is drained as messages
+    /// arrive and only the newest is delivered, once the cap allows. See [`RateCap`].
+    pub async fn recv_capped(
+        &mut self,
+        buffer: &mut Vec<BroadcastMessage>,
+        limit: usize,
+        cap: Option<&mut RateCap>,
+    ) -> Delivery {
+        let Some(cap) = cap else {
+            return self.recv_many(buffer, limit).await;
+        };
+
+        loop {
+            if let Some(msg) = cap.release() {
+                buffer.push(msg);
+                return Delivery::Messages(1);
+            }
+
+            let delivery = match cap.deadline() {
+                Some(deadline) => tokio::select! {
+                    delivery = self.recv_many(buffer, usize::MAX) => delivery,
+                    _ = tokio::time::sleep_until(deadline) => continue,
+                },
+                None => self.recv_many(buffer, usize::MAX).await,
+            };
+            match delivery {
+                Delivery::Messages(_) => cap.offer(buffer),
+                delivery => return delivery,
+            }
+        }
+    }
+
+    /// Records that `msg` was delivered to the client, returning how long it took to reach it.
+    pub fn record_delivered(&self, msg: &BroadcastMessage) -> Duration {
+        let elapsed = msg.received_at.elapsed();
+        self.state
+            .lag_messages
+            .store(self.queue.len() as u64, Ordering::Relaxed);
+        self.state
+            .lag_millis
+            .store(elapsed.as_millis() as u64, Ordering::Relaxed);
+        elapsed
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.shards[self.shard].lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Fans messages out to clients. Each client has its own bounded queue, drained by a task that
+/// writes to the client's websocket. Clients are spread round-robin over one or more shards, each
+/// with its own lock, so that subscribing and disconnecting clients don't contend with publishing
+/// to the whole client list.
 #[derive(Clone)]
 pub struct Registry {
-    sender: Sender<String>,
+    shards: Arc<Vec<Shard>>,
+    settings: Arc<Mutex<ClientSettings>>,
     metrics: Arc<Metrics>,
+    next_client_id: Arc<AtomicU64>,
+    avg_message_bytes: Arc<AtomicU64>,
+    next_sequence: Arc<AtomicU64>,
+    lag_strategy: LagStrategy,
+    /// Applied to every message published. See [`crate::transform`].
+    transform: Option<Arc<Mutex<Box<dyn Transform>>>>,
+    /// Cancelled to disconnect every client, by [`Registry::close_clients`].
+    shutdown: CancellationToken,
+    tasks: TaskTracker,
 }
 
 impl Registry {
-    pub fn new(sender: Sender<String>, metrics: Arc<Metrics>) -> Self {
-        Self { sender, metrics }
+    pub fn new(buffer_size: usize, shards: usize, metrics: Arc<Metrics>) -> Self {
+        let shards = (0..shards.max(1)).map(|_| Shard::default()).collect();
+
+        Self {
+            shards: Arc::new(shards),
+            settings: Arc::new(Mutex::new(ClientSettings {
+                buffer_size,
+                message_ttl: None,
+                heartbeat: None,
+            })),
+            metrics,
+            next_client_id: Arc::new(AtomicU64::new(0)),
+            avg_message_bytes: Arc::new(AtomicU64::new(0)),
+            next_sequence: Arc::new(AtomicU64::new(0)),
+            lag_strategy: LagStrategy::default(),
+            transform: None,
+            shutdown: CancellationToken::new(),
+            tasks: TaskTracker::new(),
+        }
+    }
+
+    /// Drop messages that have been queued for a client for longer than `ttl` instead of
+    /// delivering them late, as a newer message will already have superseded them.
+    pub fn with_message_ttl(self, ttl: Duration) -> Self {
+        self.settings.lock().unwrap().message_ttl = Some(ttl);
+        self
+    }
+
+    /// Sets what happens to a client whose queue is full when a new message is published.
+    pub fn with_lag_strategy(mut self, strategy: LagStrategy) -> Self {
+        self.lag_strategy = strategy;
+        self
+    }
+
+    /// Runs `transform` on every message published, before it's queued for clients.
+    pub fn with_transform(mut self, transform: Box<dyn Transform>) -> Self {
+        self.transform = Some(Arc::new(Mutex::new(transform)));
+        self
+    }
+
+    /// Ping clients and disconnect those that stop answering, rather than waiting for a write to
+    /// a dead connection to fail.
+    pub fn with_heartbeat(self, heartbeat: HeartbeatConfig) -> Self {
+        self.settings.lock().unwrap().heartbeat = Some(heartbeat);
+        self
+    }
+
+    pub fn settings(&self) -> ClientSettings {
+        *self.settings.lock().unwrap()
+    }
+
+    /// Changes the settings for clients that subscribe from now on.
+    pub fn set_settings(&self, settings: ClientSettings) {
+        *self.settings.lock().unwrap() = settings;
+    }
+
+    /// Publishes a message to every subscribed client, returning the number of clients currently
+    /// subscribed.
+    pub fn publish(&self, payload: Bytes) -> usize {
+        self.publish_from(payload, None)
+    }
+
+    /// Publishes `payload` as received from the upstream at index `upstream`, which clients
+    /// that asked for the sequence envelope are told. See [`crate::envelope`].
+    pub fn publish_from(&self, payload: Bytes, upstream: Option<u16>) -> usize {
+        self.metrics
+            .upstream_message_size
+            .record(payload.len() as f64);
+        let payload = match &self.transform {
+            Some(transform) => {
+                let mut transform = transform.lock().unwrap();
+                match transform::apply(transform.as_mut(), payload, &self.metrics) {
+                    Some(payload) => payload,
+                    None => return self.client_count(),
+                }
+            }
+            None => payload,
+        };
+
+        // Exponentially weighted so the buffer estimate follows changes in message size without
+        // being thrown off by a single outlier.
+        let size = payload.len() as u64;
+        let avg = self.avg_message_bytes.load(Ordering::Relaxed);
+        let avg = if avg == 0 { size } else { (avg * 7 + size) / 8 };
+        self.avg_message_bytes.store(avg, Ordering::Relaxed);
+
+        let mut message = BroadcastMessage::new(payload);
+        message.sequence = self.next_sequence.fetch_add(1, Ordering::Relaxed);
+        message.upstream = upstream;
+        let mut clients = 0;
+
+        for shard in self.shards.iter() {
+            let shard = shard.lock().unwrap();
+            for client in shard.values() {
+                if !client
+                    .queue
+                    .push(message.clone(), self.lag_strategy, &client.state)
+                {
+                    client.state.skipped.fetch_add(1, Ordering::Relaxed);
+                }
+                if !client.state.internal {
+                    clients += 1;
+                }
+            }
+        }
+
+        clients
+    }
+
+    /// Largest number of messages queued for a single client.
+    pub fn queued_messages(&self) -> usize {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .lock()
+                    .unwrap()
+                    .values()
+                    .map(|client| client.queue.len())
+                    .collect::<Vec<_>>()
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Approximate number of payload bytes currently retained by client queues. Payloads are
+    /// shared between clients, so each message is only counted once.
+    pub fn buffered_bytes_estimate(&self) -> u64 {
+        self.queued_messages() as u64 * self.avg_message_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn queue_occupancy(&self) -> QueueOccupancy {
+        let mut occupancy = QueueOccupancy::default();
+
+        for shard in self.shards.iter() {
+            for client in shard.lock().unwrap().values() {
+                let capacity = client.queue.capacity;
+                let len = client.queue.len();
+                occupancy.max_fraction = occupancy.max_fraction.max(len as f64 / capacity as f64);
+                if len + 1 >= capacity {
+                    occupancy.near_overflow += 1;
+                }
+            }
+        }
+
+        occupancy
+    }
+
+    /// Number of clients currently subscribed, not counting internal subscribers.
+    pub fn client_count(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                shard
+                    .lock()
+                    .unwrap()
+                    .values()
+                    .filter(|client| !client.state.internal)
+                    .count()
+            })
+            .sum()
+    }
+
+    /// The metrics that this registry's clients are counted in.
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    /// Number of messages each client may fall behind before it is considered lagging.
+    pub fn buffer_size(&self) -> usize {
+        self.settings().buffer_size
+    }
+
+    /// Largest number of messages any client currently has queued.
+    pub fn max_client_backlog(&self) -> u64 {
+        self.client_states()
+            .iter()
+            .map(|state| state.lag_messages.load(Ordering::Relaxed))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// State of every client, leaving out internal subscribers.
+    fn client_states(&self) -> Vec<Arc<ClientState>> {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .lock()
+                    .unwrap()
+                    .values()
+                    .filter(|client| !client.state.internal)
+                    .map(|client| client.state.clone())
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Adds a client to the registry, returning the queue it should deliver messages from.
+    pub fn register(&self) -> Subscription {
+        self.register_with(ClientState::default())
+    }
+
+    /// Like [`Registry::register`], for a client that may be evicted to make room for one with
+    /// priority.
+    pub fn register_evictable(&self) -> Subscription {
+        self.register_with(ClientState {
+            evictable: true,
+            ..Default::default()
+        })
+    }
+
+    /// Like [`Registry::register`], for a subscriber that is part of the proxy rather than a
+    /// client, such as a tier mirroring the stream. See [`ClientState::internal`].
+    pub fn register_internal(&self) -> Subscription {
+        self.register_with(ClientState {
+            internal: true,
+            ..Default::default()
+        })
+    }
+
+    fn register_with(&self, state: ClientState) -> Subscription {
+        let id = self.next_client_id.fetch_add(1, Ordering::Relaxed);
+        let shard = id as usize % self.shards.len();
+        let queue = Arc::new(ClientQueue::new(self.buffer_size().max(1)));
+        let state = Arc::new(state);
+
+        self.shards[shard].lock().unwrap().insert(
+            id,
+            ClientHandle {
+                queue: queue.clone(),
+                state: state.clone(),
+            },
+        );
+
+        Subscription {
+            id,
+            shard,
+            shards: self.shards.clone(),
+            queue,
+            state,
+        }
     }
 
     pub async fn subscribe(&self, mut client: ClientConnection) {
-        info!(message = "subscribing client", client = client.id());
+        info!(
+            message = "subscribing client",
+            client = client.id(),
+            client_name = client.labels().name,
+            client_version = client.labels().version,
+            country = client.location().country,
+            asn = client.location().asn,
+            as_org = client.location().as_org
+        );
 
-        let mut receiver = self.sender.subscribe();
+        let mut subscription = match client.priority() {
+            true => self.register(),
+            false => self.register_evictable(),
+        };
+        let client_id = subscription.id();
+        let evicted = subscription.eviction();
+        let shutdown = self.shutdown.clone();
         let metrics = self.metrics.clone();
+        let client_counters = metrics.client_labels.counters(client.labels());
         metrics.new_connections.increment(1);
+        client_counters.connections.increment(1);
+        metrics
+            .country_connections
+            .increment(client.location().country.as_deref());
+        audit::client_connected(client_id, &client);
+        let hooks = client.hooks().cloned();
+        let mut rate_cap = client.max_rate().map(RateCap::new);
 
-        tokio::spawn(async move {
-            loop {
-                match receiver.recv().await {
-                    Ok(msg) => match client.send(msg.clone()).await {
+        let settings = self.settings();
+        let batch_limit = settings.buffer_size.max(1);
+        let message_ttl = settings.message_ttl;
+        let mut heartbeat = settings.heartbeat.map(Heartbeat::new);
+        // Spanned so that a panic is reported with the client it happened on
+        let span = info_span!(
+            "client",
+            client = client.id(),
+            client_name = client.labels().name
+        );
+
+        self.tasks.spawn(
+            async move {
+                let mut batch = Vec::with_capacity(batch_limit);
+                // Clients that sample or cap their rate skip messages on purpose, so their
+                // intervals say nothing about the proxy's cadence
+                let mut cadence = (rate_cap.is_none() && !client.sampled()).then(Cadence::default);
+
+                let reason = loop {
+                    batch.clear();
+
+                    // Clients are only read from when pinged, for their pongs
+                    let delivery = match &mut heartbeat {
+                        None => tokio::select! {
+                            delivery = subscription.recv_capped(&mut batch, batch_limit, rate_cap.as_mut()) => delivery,
+                            _ = evicted.cancelled() => break DisconnectReason::Evicted,
+                            _ = shutdown.cancelled() => break DisconnectReason::Shutdown,
+                        },
+                        Some(heartbeat) => tokio::select! {
+                            _ = evicted.cancelled() => break DisconnectReason::Evicted,
+                            _ = shutdown.cancelled() => break DisconnectReason::Shutdown,
+                            delivery = subscription.recv_capped(&mut batch, batch_limit, rate_cap.as_mut()) => delivery,
+                            _ = tokio::time::sleep_until(heartbeat.due()) => {
+                                match heartbeat.tick() {
+                                    HeartbeatAction::Ping => {
+                                        if let Err(e) = client.ping().await {
+                                            break disconnect_reason(&e);
+                                        }
+                                    }
+                                    HeartbeatAction::Evict => {
+                                        info!(
+                                            message = "client stopped answering pings",
+                                            client = client.id(),
+                                            client_name = client.labels().name,
+                                            client_version = client.labels().version
+                                        );
+                                        break DisconnectReason::PongTimeout;
+                                    }
+                                    HeartbeatAction::Wait => {}
+                                }
+                                continue;
+                            }
+                            frame = client.recv() => {
+                                match frame {
+                                    Some(Ok(Message::Pong(_))) => heartbeat.record_pong(),
+                                    Some(Ok(Message::Close(_))) | None => {
+                                        break DisconnectReason::ClientInitiated
+                                    }
+                                    Some(Ok(_)) => {}
+                                    Some(Err(e)) => break disconnect_reason(&e),
+                                }
+                                continue;
+                            }
+                        },
+                    };
+
+                    match delivery {
+                        Delivery::Messages(_) => {}
+                        Delivery::Lagged(dropped) => {
+                            if let Some(suppressed) = log_sampling::sample(EventClass::Lag) {
+                                info!(
+                                    message = "client is lagging",
+                                    client = client.id(),
+                                    client_name = client.labels().name,
+                                    client_version = client.labels().version,
+                                    suppressed = suppressed
+                                );
+                            }
+                            metrics.lag_events.increment(1);
+                            metrics
+                                .dropped_messages
+                                .increment(DropCause::Lagged, dropped);
+                            client_counters.lag_events.increment(1);
+                            client_counters.dropped_messages.increment(dropped);
+                            client.record_dropped(dropped);
+                            continue;
+                        }
+                        Delivery::Overwritten(dropped) => {
+                            metrics
+                                .dropped_messages
+                                .increment(DropCause::Overwritten, dropped);
+                            client_counters.dropped_messages.increment(dropped);
+                            client.record_dropped(dropped);
+                            continue;
+                        }
+                    };
+
+                    if let Some(ttl) = message_ttl {
+                        let queued = batch.len();
+                        batch.retain(|msg| msg.received_at.elapsed() <= ttl);
+
+                        let expired = (queued - batch.len()) as u64;
+                        if expired > 0 {
+                            metrics
+                                .dropped_messages
+                                .increment(DropCause::Expired, expired);
+                            client_counters.dropped_messages.increment(expired);
+                            client.record_dropped(expired);
+                        }
+                        if batch.is_empty() {
+                            continue;
+                        }
+                    }
+
+                    let coalesced = rate_cap.as_mut().map_or(0, RateCap::take_coalesced);
+                    let sampled_out = coalesced + client.sample(&mut batch);
+                    if sampled_out > 0 {
+                        metrics.sampled_out_messages.increment(sampled_out);
+                    }
+                    client.transform(&mut batch, &metrics);
+                    if batch.is_empty() {
+                        continue;
+                    }
+
+                    match client.send_batch(&batch).await {
                         Ok(_) => {
-                            trace!(message = "message sent to client", client = client.id());
-                            metrics.sent_messages.increment(1);
+                            if let Some(suppressed) = log_sampling::sample(EventClass::Messages) {
+                                trace!(
+                                    message = "message sent to client",
+                                    client = client.id(),
+                                    batch = batch.len(),
+                                    suppressed = suppressed
+                                );
+                            }
+                            metrics.sent_messages.increment(batch.len() as u64);
+
+                            for msg in &batch {
+                                metrics.sent_message_size.record(msg.size as f64);
+                                if let Some(interval) = cadence.as_mut().and_then(Cadence::tick) {
+                                    metrics.sent_message_interval.record(interval.as_secs_f64());
+                                }
+                                let elapsed = subscription.record_delivered(msg);
+                                metrics.fan_out_latency.record(elapsed.as_secs_f64());
+                                if let Some(hooks) = &hooks {
+                                    hooks.on_message_sent(client_id, &client, msg);
+                                }
+                            }
                         }
                         Err(e) => {
                             warn!(
@@ -37,24 +758,437 @@ impl Registry {
                                 client = client.id(),
                                 error = e.to_string()
                             );
-                            metrics.failed_messages.increment(1);
-                            break;
+                            let failed = batch.len() as u64;
+                            metrics.failed_messages.increment(failed);
+                            metrics
+                                .dropped_messages
+                                .increment(DropCause::SendFailed, failed);
+                            client_counters.dropped_messages.increment(failed);
+                            client.record_dropped(failed);
+                            break disconnect_reason(&e);
                         }
-                    },
-                    Err(RecvError::Closed) => {
-                        info!(message = "upstream connection closed", client = client.id());
-                        break;
-                    }
-                    Err(RecvError::Lagged(_)) => {
-                        info!(message = "client is lagging", client = client.id());
-                        metrics.lag_events.increment(1);
-                        receiver = receiver.resubscribe();
                     }
+                };
+
+                drop(subscription);
+                if reason == DisconnectReason::Shutdown {
+                    // Best effort: the client may be too slow to take the frame in time
+                    let _ = tokio::time::timeout(CLOSE_TIMEOUT, client.close()).await;
+                }
+                metrics.closed_connections.increment(1);
+                metrics.disconnects.increment(reason);
+                metrics
+                    .connection_duration
+                    .record(client.connected_for().as_secs_f64());
+                info!(
+                    message = "client disconnected",
+                    client = client.id(),
+                    client_name = client.labels().name,
+                    client_version = client.labels().version,
+                    reason = reason.as_str()
+                );
+                audit::client_disconnected(client_id, &client, reason);
+                if let Some(hooks) = &hooks {
+                    hooks.on_disconnect(client_id, &client, reason);
                 }
             }
+            .instrument(span),
+        );
+    }
+
+    /// Disconnects every client subscribed with [`Registry::subscribe`], telling it the proxy is
+    /// going away, and waits up to `timeout` for their disconnects to be recorded. Clients that
+    /// subscribe afterwards are disconnected straight away.
+    pub async fn close_clients(&self, timeout: Duration) {
+        self.shutdown.cancel();
+        self.tasks.close();
+        if tokio::time::timeout(timeout, self.tasks.wait())
+            .await
+            .is_err()
+        {
+            warn!(
+                message = "timed out waiting for clients to disconnect",
+                clients = self.tasks.len()
+            );
+        }
+    }
+
+    /// Periodically aggregates the lag of every connected client into max/p99 gauges, along with
+    /// how full their queues are.
+    pub async fn report_lag_metrics(&self, interval: Duration, token: CancellationToken) {
+        let mut ticker = tokio::time::interval(interval);
+
+        loop {
+            tokio::select! {
+                _ = token.cancelled() => return,
+                _ = ticker.tick() => {}
+            }
+
+            let lag = self.lag_summary();
+            self.metrics
+                .client_lag_messages_max
+                .set(lag.messages_max as f64);
+            self.metrics
+                .client_lag_messages_p99
+                .set(lag.messages_p99 as f64);
+            self.metrics.client_lag_ms_max.set(lag.millis_max as f64);
+            self.metrics.client_lag_ms_p99.set(lag.millis_p99 as f64);
+
+            let occupancy = self.queue_occupancy();
+            self.metrics
+                .broadcast_buffer_occupancy
+                .set(occupancy.max_fraction);
+            self.metrics
+                .clients_near_overflow
+                .set(occupancy.near_overflow as f64);
+        }
+    }
+
+    /// Aggregates the lag of every connected client.
+    pub fn lag_summary(&self) -> LagSummary {
+        let (mut messages, mut millis): (Vec<u64>, Vec<u64>) = self
+            .client_states()
+            .iter()
+            .map(|state| {
+                (
+                    state.lag_messages.load(Ordering::Relaxed),
+                    state.lag_millis.load(Ordering::Relaxed),
+                )
+            })
+            .unzip();
+
+        messages.sort_unstable();
+        millis.sort_unstable();
+
+        LagSummary {
+            messages_max: messages.last().copied().unwrap_or(0),
+            messages_p99: percentile(&messages, 0.99),
+            millis_max: millis.last().copied().unwrap_or(0),
+            millis_p99: percentile(&millis, 0.99),
+        }
+    }
+
+    /// Evicts the oldest messages of every client queue holding more than `max_len`, so that it
+    /// holds the newest `max_len`. Clients account for them as overwritten. Returns the number of
+    /// messages evicted.
+    pub fn trim_queues(&self, max_len: usize) -> u64 {
+        let mut evicted = 0;
+
+        for shard in self.shards.iter() {
+            for client in shard.lock().unwrap().values() {
+                let mut messages = client.queue.messages.lock().unwrap();
+                let excess = messages.len().saturating_sub(max_len);
+                if excess == 0 {
+                    continue;
+                }
+                messages.drain(..excess);
+                drop(messages);
+
+                client
+                    .state
+                    .overwritten
+                    .fetch_add(excess as u64, Ordering::Relaxed);
+                client.queue.ready.notify_one();
+                evicted += excess as u64;
+            }
+        }
 
-            metrics.closed_connections.increment(1);
-            info!(message = "client disconnected", client = client.id());
+        evicted
+    }
+
+    /// The evictable client with the largest backlog, or the longest lag if they're level, that
+    /// hasn't already been evicted.
+    pub fn eviction_candidate(&self) -> Option<EvictionCandidate> {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .lock()
+                    .unwrap()
+                    .values()
+                    .filter(|client| {
+                        client.state.evictable
+                            && !client.state.internal
+                            && !client.state.evicted.is_cancelled()
+                    })
+                    .map(|client| EvictionCandidate {
+                        backlog: client.queue.len(),
+                        lag: Duration::from_millis(client.state.lag_millis.load(Ordering::Relaxed)),
+                        state: client.state.clone(),
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .max_by_key(EvictionCandidate::behind)
+    }
+
+    /// Forces up to `count` of the clients with the largest backlog to drop their queue and skip
+    /// to the next message, as if they had lagged. Returns the number of clients affected.
+    pub fn lag_drop_slowest(&self, count: usize) -> usize {
+        let mut backlogs: Vec<(usize, Arc<ClientState>)> = self
+            .shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .lock()
+                    .unwrap()
+                    .values()
+                    .filter(|client| !client.state.internal)
+                    .map(|client| {
+                        let backlog = client.queue.len();
+                        (backlog, client.state.clone())
+                    })
+                    .filter(|(backlog, _)| *backlog > 0)
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        backlogs.sort_unstable_by_key(|(backlog, _)| std::cmp::Reverse(*backlog));
+
+        backlogs
+            .iter()
+            .take(count)
+            .map(|(_, state)| state.lag_drop.store(true, Ordering::Relaxed))
+            .count()
+    }
+}
+
+/// Returns the value at the given percentile of an already sorted slice, or zero if it is empty.
+pub(crate) fn percentile(sorted: &[u64], percentile: f64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+
+    let rank = (percentile * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_lag_drop_slowest() {
+        let registry = Registry::new(4, 1, Arc::new(Metrics::default()));
+        let mut caught_up = registry.register();
+        let mut behind = registry.register();
+        let _also_behind = registry.register();
+
+        registry.publish(Bytes::from("one"));
+        registry.publish(Bytes::from("two"));
+
+        let mut batch = Vec::new();
+        caught_up.recv_many(&mut batch, 4).await;
+
+        // Only clients with a backlog are dropped
+        assert_eq!(registry.lag_drop_slowest(5), 2);
+        assert!(matches!(
+            behind.recv_many(&mut batch, 4).await,
+            Delivery::Lagged(2)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_internal_subscriber() {
+        let registry = Registry::new(4, 2, Arc::new(Metrics::default()));
+        let mut mirror = registry.register_internal();
+        let _client = registry.register_evictable();
+        assert_eq!(registry.client_count(), 1);
+
+        assert_eq!(registry.publish(Bytes::from("one")), 1);
+        registry.publish(Bytes::from("two"));
+
+        // The mirror is as far behind as the client, but only the client is dropped or evicted
+        assert_eq!(registry.lag_drop_slowest(5), 1);
+        let mut batch = Vec::new();
+        assert!(matches!(
+            mirror.recv_many(&mut batch, 4).await,
+            Delivery::Messages(2)
+        ));
+        registry.eviction_candidate().unwrap().evict();
+        assert!(registry.eviction_candidate().is_none());
+        assert!(!mirror.eviction().is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn test_eviction_candidate() {
+        let registry = Registry::new(4, 2, Arc::new(Metrics::default()));
+        let _priority = registry.register();
+        let mut caught_up = registry.register_evictable();
+        let behind = registry.register_evictable();
+        assert!(registry.eviction_candidate().is_some());
+
+        registry.publish(Bytes::from("one"));
+        registry.publish(Bytes::from("two"));
+        let mut batch = Vec::new();
+        caught_up.recv_many(&mut batch, 4).await;
+
+        let candidate = registry.eviction_candidate().unwrap();
+        assert_eq!(candidate.backlog, 2);
+        candidate.evict();
+        assert!(behind.eviction().is_cancelled());
+        assert!(!caught_up.eviction().is_cancelled());
+
+        // Evicted clients aren't picked again, and clients with priority never are
+        let candidate = registry.eviction_candidate().unwrap();
+        assert_eq!(candidate.backlog, 0);
+        candidate.evict();
+        assert!(caught_up.eviction().is_cancelled());
+        assert!(registry.eviction_candidate().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_queue_occupancy() {
+        let registry = Registry::new(4, 2, Arc::new(Metrics::default()));
+        assert_eq!(registry.queue_occupancy(), QueueOccupancy::default());
+
+        let mut caught_up = registry.register();
+        let _behind = registry.register();
+        for message in ["one", "two", "three"] {
+            registry.publish(Bytes::from(message));
+        }
+
+        let mut batch = Vec::new();
+        caught_up.recv_many(&mut batch, 4).await;
+        assert_eq!(
+            registry.queue_occupancy(),
+            QueueOccupancy {
+                max_fraction: 0.75,
+                near_overflow: 1,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subscription() {
+        let registry = Registry::new(2, 2, Arc::new(Metrics::default()));
+        let mut first = registry.register();
+        let second = registry.register();
+        assert_eq!(registry.client_count(), 2);
+
+        let mut batch = Vec::new();
+        assert_eq!(registry.publish(Bytes::from("one")), 2);
+        assert_eq!(registry.publish(Bytes::from("three")), 2);
+        assert!(matches!(
+            first.recv_many(&mut batch, 10).await,
+            Delivery::Messages(2)
+        ));
+        assert_eq!(batch.iter().map(|msg| msg.size).collect::<Vec<_>>(), [3, 5]);
+
+        drop(second);
+        assert_eq!(registry.client_count(), 1);
+
+        // Two messages fit in the queue, the other two are skipped. The client drops everything
+        // and waits for the next message.
+        for _ in 0..4 {
+            registry.publish(Bytes::from("lagging"));
+        }
+        assert_eq!(registry.queued_messages(), 2);
+        assert!(matches!(
+            first.recv_many(&mut batch, 10).await,
+            Delivery::Lagged(4)
+        ));
+        assert_eq!(registry.queued_messages(), 0);
+
+        batch.clear();
+        registry.publish(Bytes::from("latest"));
+        registry.publish(Bytes::from("newer"));
+        assert!(matches!(
+            first.recv_many(&mut batch, 1).await,
+            Delivery::Messages(1)
+        ));
+        assert_eq!(batch.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_overwrite_strategy() {
+        let registry = Registry::new(2, 1, Arc::new(Metrics::default()))
+            .with_lag_strategy(LagStrategy::Overwrite);
+        let mut client = registry.register();
+
+        for payload in ["one", "two", "three", "four!"] {
+            registry.publish(Bytes::from(payload));
+        }
+        assert_eq!(registry.queued_messages(), 2);
+
+        // The oldest messages are overwritten and the newest two are still delivered.
+        let mut batch = Vec::new();
+        assert!(matches!(
+            client.recv_many(&mut batch, 10).await,
+            Delivery::Overwritten(2)
+        ));
+        assert!(batch.is_empty());
+        assert!(matches!(
+            client.recv_many(&mut batch, 10).await,
+            Delivery::Messages(2)
+        ));
+        assert_eq!(batch.iter().map(|msg| msg.size).collect::<Vec<_>>(), [5, 5]);
+    }
+
+    #[tokio::test]
+    async fn test_trim_queues() {
+        let registry = Registry::new(4, 2, Arc::new(Metrics::default()));
+        let mut first = registry.register();
+        let _second = registry.register();
+
+        for payload in ["one", "two", "three", "four!"] {
+            registry.publish(Bytes::from(payload));
+        }
+        assert_eq!(registry.trim_queues(4), 0);
+        assert_eq!(registry.trim_queues(1), 6);
+        assert_eq!(registry.queued_messages(), 1);
+
+        // The newest message is still delivered after the evicted ones are accounted for
+        let mut batch = Vec::new();
+        assert!(matches!(
+            first.recv_many(&mut batch, 10).await,
+            Delivery::Overwritten(3)
+        ));
+        assert!(matches!(
+            first.recv_many(&mut batch, 10).await,
+            Delivery::Messages(1)
+        ));
+        assert_eq!(batch[0].size, 5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_recv_capped() {
+        let registry = Registry::new(2, 1, Arc::new(Metrics::default()));
+        let mut client = registry.register();
+        let mut cap = RateCap::new(10.0);
+
+        let mut batch = Vec::new();
+        registry.publish(Bytes::from("one"));
+        assert!(matches!(
+            client.recv_capped(&mut batch, 10, Some(&mut cap)).await,
+            Delivery::Messages(1)
+        ));
+
+        // More messages than fit in the queue arrive before the next send is allowed, and the
+        // client neither lags nor gets more than the newest
+        let publisher = registry.clone();
+        tokio::spawn(async move {
+            for payload in ["two", "three", "four", "five!"] {
+                publisher.publish(Bytes::from(payload));
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
         });
+        batch.clear();
+        assert!(matches!(
+            client.recv_capped(&mut batch, 10, Some(&mut cap)).await,
+            Delivery::Messages(1)
+        ));
+        assert_eq!(batch[0].size, 5);
+        assert_eq!(cap.take_coalesced(), 3);
+    }
+
+    #[test]
+    fn test_percentile() {
+        assert_eq!(percentile(&[], 0.99), 0);
+        assert_eq!(percentile(&[7], 0.99), 7);
+        assert_eq!(percentile(&[1, 2, 3, 4], 0.5), 2);
+        assert_eq!(percentile(&[1, 2, 3, 4], 0.0), 1);
+
+        let values: Vec<u64> = (1..=200).collect();
+        assert_eq!(percentile(&values, 0.99), 198);
+        assert_eq!(percentile(&values, 1.0), 200);
     }
 }
@@ -0,0 +1,94 @@
+//! Envelope for relaying messages between instances of the proxy. A downstream proxy connects to
+//! the upstream proxy's `/relay` endpoint, authenticating with a bearer token, and receives each
+//! message as a binary frame of the message's 8-byte big-endian sequence number followed by the
+//! payload. The sequence numbers let the downstream proxy detect messages it missed.
+
+use crate::registry::BroadcastMessage;
+use axum::extract::ws::Message;
+use bytes::{BufMut, Bytes, BytesMut};
+
+const SEQUENCE_LEN: usize = 8;
+
+/// Builds the relay frame for `msg`.
+pub fn envelope(msg: &BroadcastMessage) -> Message {
+    let Message::Binary(payload) = &msg.frame else {
+        unreachable!("broadcast messages are binary frames");
+    };
+
+    let mut frame = BytesMut::with_capacity(SEQUENCE_LEN + payload.len());
+    frame.put_u64(msg.sequence);
+    frame.put_slice(payload);
+    Message::Binary(frame.freeze())
+}
+
+/// Splits a relay frame into its sequence number and payload, or returns `None` if it is too
+/// short to be one.
+pub fn open_envelope(mut frame: Bytes) -> Option<(u64, Bytes)> {
+    if frame.len() < SEQUENCE_LEN {
+        return None;
+    }
+
+    let payload = frame.split_off(SEQUENCE_LEN);
+    let sequence = u64::from_be_bytes(frame.as_ref().try_into().unwrap());
+    Some((sequence, payload))
+}
+
+/// Tracks the sequence numbers received from an upstream proxy to count missed messages.
+#[derive(Debug, Default)]
+pub struct SequenceTracker {
+    last: Option<u64>,
+}
+
+impl SequenceTracker {
+    /// Records `sequence`, returning how many messages were skipped since the last one. A
+    /// sequence number that goes backwards means the upstream proxy restarted, which isn't
+    /// counted as a gap.
+    pub fn observe(&mut self, sequence: u64) -> u64 {
+        let gap = match self.last {
+            Some(last) if sequence > last => sequence - last - 1,
+            _ => 0,
+        };
+        self.last = Some(sequence);
+        gap
+    }
+
+    /// Forgets the last sequence number, e.g. after reconnecting, when messages published while
+    /// disconnected are expected to be missing.
+    pub fn reset(&mut self) {
+        self.last = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_envelope() {
+        let mut msg = BroadcastMessage::new(Bytes::from("payload"));
+        msg.sequence = 258;
+
+        let Message::Binary(frame) = envelope(&msg) else {
+            panic!("expected a binary frame");
+        };
+        assert_eq!(&frame[..8], &[0, 0, 0, 0, 0, 0, 1, 2]);
+        assert_eq!(open_envelope(frame), Some((258, Bytes::from("payload"))));
+
+        assert_eq!(open_envelope(Bytes::from("short")), None);
+    }
+
+    #[test]
+    fn test_sequence_tracker() {
+        let mut tracker = SequenceTracker::default();
+        assert_eq!(tracker.observe(5), 0);
+        assert_eq!(tracker.observe(6), 0);
+        assert_eq!(tracker.observe(9), 2);
+
+        // The upstream restarted
+        assert_eq!(tracker.observe(0), 0);
+        assert_eq!(tracker.observe(1), 0);
+
+        tracker.reset();
+        assert_eq!(tracker.observe(10), 0);
+    }
+}
@@ -0,0 +1,280 @@
+//! A log file that is rotated by time and size, for the audit log. The current file is always at
+//! the configured path, and a rotated one is renamed to the path suffixed with the UTC time it was
+//! rotated at, e.g. `audit.log.2025-01-01T00-00-00`, then optionally gzipped in the background to
+//! `audit.log.2025-01-01T00-00-00.gz`. Rotated files are never deleted.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// How often the file is rotated regardless of its size.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Rotation {
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
+
+impl Rotation {
+    /// The period that `secs`, in seconds since the Unix epoch, falls in.
+    fn period(&self, secs: u64) -> u64 {
+        match self {
+            Rotation::Hourly => secs / 3600,
+            Rotation::Daily => secs / 86400,
+            Rotation::Never => 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RotationConfig {
+    pub rotation: Rotation,
+    /// Rotate before a write would take the file past this size.
+    pub max_bytes: Option<u64>,
+    /// Gzip rotated files.
+    pub compress: bool,
+}
+
+pub struct RotatingFile {
+    path: PathBuf,
+    config: RotationConfig,
+    file: File,
+    written: u64,
+    period: u64,
+}
+
+impl RotatingFile {
+    /// Opens `path` for appending, creating it if needed. An existing file from an earlier period
+    /// is rotated on the first write.
+    pub fn open(path: &Path, config: RotationConfig) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |since| since.as_secs());
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            config,
+            file,
+            written: metadata.len(),
+            period: config.rotation.period(modified),
+        })
+    }
+
+    fn rotate(&mut self, now: u64) -> io::Result<()> {
+        self.file.flush()?;
+
+        let mut rotated = suffixed(&self.path, &timestamp(now));
+        let mut collision = 0;
+        while rotated.exists() || gzipped(&rotated).exists() {
+            collision += 1;
+            rotated = suffixed(&self.path, &format!("{}.{collision}", timestamp(now)));
+        }
+        fs::rename(&self.path, &rotated)?;
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.written = 0;
+
+        if self.config.compress {
+            std::thread::spawn(move || {
+                if let Err(e) = compress(&rotated) {
+                    warn!(
+                        message = "failed to compress rotated log file",
+                        path = %rotated.display(),
+                        error = e.to_string()
+                    );
+                }
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let period = self.config.rotation.period(now);
+        let full = self
+            .config
+            .max_bytes
+            .is_some_and(|max_bytes| self.written + buf.len() as u64 > max_bytes);
+
+        if self.written > 0 && (period != self.period || full) {
+            self.rotate(now)?;
+        }
+        self.period = period;
+
+        self.file.write_all(buf)?;
+        self.written += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".");
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+fn gzipped(path: &Path) -> PathBuf {
+    suffixed(path, "gz")
+}
+
+/// Formats seconds since the Unix epoch as a UTC time usable in a file name,
+/// e.g. `2025-01-01T13-05-00`.
+fn timestamp(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let secs_of_day = secs % 86400;
+
+    // Civil date from days since the epoch, after Howard Hinnant's `civil_from_days`
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}-{:02}-{:02}",
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+/// Gzips `path` to `path.gz` and removes it.
+fn compress(path: &Path) -> io::Result<()> {
+    let data = fs::read(path)?;
+
+    let mut gz = Vec::with_capacity(data.len() / 4 + 18);
+    // No file name or modification time, compressed with deflate by an unknown OS
+    gz.extend_from_slice(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff]);
+    gz.extend_from_slice(&miniz_oxide::deflate::compress_to_vec(&data, 6));
+    gz.extend_from_slice(&crc32(&data).to_le_bytes());
+    gz.extend_from_slice(&(data.len() as u32).to_le_bytes());
+
+    let destination = gzipped(path);
+    let partial = suffixed(&destination, "partial");
+    fs::write(&partial, gz)?;
+    fs::rename(&partial, &destination)?;
+    fs::remove_file(path)
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("{name}-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn rotated_files(dir: &Path) -> Vec<PathBuf> {
+        let mut files: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.file_name().unwrap() != "audit.log")
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(timestamp(0), "1970-01-01T00-00-00");
+        assert_eq!(timestamp(951_827_696), "2000-02-29T12-34-56");
+        assert_eq!(timestamp(1_735_689_599), "2024-12-31T23-59-59");
+    }
+
+    #[test]
+    fn test_rotate_by_size() {
+        let dir = temp_dir("rotate-by-size");
+        let path = dir.join("audit.log");
+        let config = RotationConfig {
+            rotation: Rotation::Never,
+            max_bytes: Some(10),
+            compress: false,
+        };
+
+        let mut file = RotatingFile::open(&path, config).unwrap();
+        for line in ["first\n", "second\n", "third\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        file.flush().unwrap();
+
+        // Each line takes the file past 10 bytes, so each goes to a new one
+        assert_eq!(fs::read_to_string(&path).unwrap(), "third\n");
+        let rotated = rotated_files(&dir);
+        assert_eq!(rotated.len(), 2);
+        assert_eq!(fs::read_to_string(&rotated[0]).unwrap(), "first\n");
+        assert_eq!(fs::read_to_string(&rotated[1]).unwrap(), "second\n");
+
+        // Appended to after reopening
+        let mut file = RotatingFile::open(&path, config).unwrap();
+        file.write_all(b"4\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "third\n4\n");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_compress() {
+        let dir = temp_dir("compress");
+        let path = dir.join("audit.log.2025-01-01T00-00-00");
+        let data = "{\"event\":\"connect\"}\n".repeat(100);
+        fs::write(&path, &data).unwrap();
+
+        compress(&path).unwrap();
+        assert!(!path.exists());
+
+        let gz = fs::read(gzipped(&path)).unwrap();
+        assert_eq!(&gz[..3], &[0x1f, 0x8b, 8]);
+        let (deflated, trailer) = gz[10..].split_at(gz.len() - 18);
+        let inflated = miniz_oxide::inflate::decompress_to_vec(deflated).unwrap();
+        assert_eq!(inflated, data.as_bytes());
+        assert_eq!(trailer[..4], crc32(data.as_bytes()).to_le_bytes());
+        assert_eq!(trailer[4..], (data.len() as u32).to_le_bytes());
+
+        // The check value from the CRC-32 specification
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
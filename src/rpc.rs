@@ -0,0 +1,239 @@
+//! A JSON-RPC 2.0 endpoint on `/rpc`, answered from the [`FlashblockCache`], so that existing
+//! JSON-RPC clients can poll the proxy for flashblocks:
+//!
+//! - `flashblocks_getLatest()` returns the most recent flashblock, or null
+//! - `flashblocks_getByNumber(blockNumber)` returns the flashblocks of the block, ordered by
+//!   index, or null if it isn't cached. The block number is a number, a hex quantity such as
+//!   `"0x1b4"`, or `"latest"`.
+//!
+//! Batches and notifications are supported as in the specification.
+
+use crate::cache::FlashblockCache;
+use crate::metrics::Metrics;
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+#[derive(Clone)]
+struct RpcState {
+    cache: Arc<FlashblockCache>,
+    metrics: Arc<Metrics>,
+}
+
+/// The JSON-RPC route, to be merged into the server's router.
+pub fn router(cache: Arc<FlashblockCache>, metrics: Arc<Metrics>) -> Router {
+    Router::new()
+        .route("/rpc", post(handle))
+        .with_state(RpcState { cache, metrics })
+}
+
+async fn handle(State(state): State<RpcState>, body: Bytes) -> Response {
+    let response = match serde_json::from_slice::<Value>(&body) {
+        Err(e) => Some(error(
+            Value::Null,
+            PARSE_ERROR,
+            &format!("parse error: {e}"),
+        )),
+        Ok(Value::Array(batch)) if batch.is_empty() => {
+            Some(error(Value::Null, INVALID_REQUEST, "empty batch"))
+        }
+        Ok(Value::Array(batch)) => {
+            let responses: Vec<_> = batch
+                .iter()
+                .filter_map(|request| call(&state, request))
+                .collect();
+            (!responses.is_empty()).then_some(Value::Array(responses))
+        }
+        Ok(request) => call(&state, &request),
+    };
+
+    match response {
+        Some(response) => Json(response).into_response(),
+        // Only notifications, which aren't answered
+        None => StatusCode::NO_CONTENT.into_response(),
+    }
+}
+
+/// Answers a single request, or returns `None` for a notification.
+fn call(state: &RpcState, request: &Value) -> Option<Value> {
+    let Some(object) = request.as_object() else {
+        return Some(error(Value::Null, INVALID_REQUEST, "invalid request"));
+    };
+    let id = object.get("id")?.clone();
+    let (Some("2.0"), Some(method)) = (
+        object.get("jsonrpc").and_then(Value::as_str),
+        object.get("method").and_then(Value::as_str),
+    ) else {
+        return Some(error(id, INVALID_REQUEST, "invalid request"));
+    };
+    state.metrics.rpc_requests.increment(1);
+
+    let params = match object.get("params") {
+        None => Vec::new(),
+        Some(Value::Array(params)) => params.clone(),
+        Some(_) => return Some(error(id, INVALID_PARAMS, "params must be an array")),
+    };
+
+    let result = match (method, params.as_slice()) {
+        ("flashblocks_getLatest", []) => parse(state.cache.latest()),
+        ("flashblocks_getLatest", _) => {
+            return Some(error(id, INVALID_PARAMS, "expected no params"));
+        }
+        ("flashblocks_getByNumber", [block_number]) => {
+            let block_number = match block_number {
+                Value::String(tag) if tag == "latest" => state.cache.latest_block_number(),
+                Value::String(hex) => match hex
+                    .strip_prefix("0x")
+                    .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+                {
+                    Some(block_number) => Some(block_number),
+                    None => return Some(error(id, INVALID_PARAMS, "invalid block number")),
+                },
+                Value::Number(number) => match number.as_u64() {
+                    Some(block_number) => Some(block_number),
+                    None => return Some(error(id, INVALID_PARAMS, "invalid block number")),
+                },
+                _ => return Some(error(id, INVALID_PARAMS, "invalid block number")),
+            };
+            block_number
+                .and_then(|block_number| state.cache.block(block_number))
+                .map_or(Value::Null, |flashblocks| {
+                    flashblocks
+                        .into_iter()
+                        .map(|payload| parse(Some(payload)))
+                        .collect()
+                })
+        }
+        ("flashblocks_getByNumber", _) => {
+            return Some(error(id, INVALID_PARAMS, "expected [blockNumber]"));
+        }
+        (method, _) => {
+            return Some(error(
+                id,
+                METHOD_NOT_FOUND,
+                &format!("method {method} not found"),
+            ))
+        }
+    };
+
+    Some(json!({"jsonrpc": "2.0", "id": id, "result": result}))
+}
+
+/// A cached flashblock as JSON. Only valid JSON is cached, so this only fails to null.
+fn parse(payload: Option<Bytes>) -> Value {
+    payload
+        .and_then(|payload| serde_json::from_slice(&payload).ok())
+        .unwrap_or(Value::Null)
+}
+
+fn error(id: Value, code: i64, message: &str) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn serve(cache: Arc<FlashblockCache>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = router(cache, Arc::new(Metrics::default()));
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        format!("http://{addr}/rpc")
+    }
+
+    async fn send(url: &str, body: &str) -> (StatusCode, Value) {
+        let response = reqwest::Client::new()
+            .post(url)
+            .body(body.to_string())
+            .send()
+            .await
+            .unwrap();
+        let status = response.status();
+        (status, response.json().await.unwrap_or(Value::Null))
+    }
+
+    #[tokio::test]
+    async fn test_rpc() {
+        let cache = Arc::new(FlashblockCache::new(4));
+        let url = serve(cache.clone()).await;
+
+        let (_, response) = send(
+            &url,
+            r#"{"jsonrpc":"2.0","id":1,"method":"flashblocks_getLatest"}"#,
+        )
+        .await;
+        assert_eq!(response, json!({"jsonrpc": "2.0", "id": 1, "result": null}));
+
+        for index in 0..2 {
+            cache.insert(&Bytes::from(format!(
+                r#"{{"index":{index},"metadata":{{"block_number":436}}}}"#
+            )));
+        }
+
+        let (_, response) = send(
+            &url,
+            r#"{"jsonrpc":"2.0","id":"a","method":"flashblocks_getLatest","params":[]}"#,
+        )
+        .await;
+        assert_eq!(response["id"], "a");
+        assert_eq!(response["result"]["index"], 1);
+
+        for block_number in ["436", r#""0x1b4""#, r#""latest""#] {
+            let (_, response) = send(
+                &url,
+                &format!(
+                    r#"{{"jsonrpc":"2.0","id":2,"method":"flashblocks_getByNumber","params":[{block_number}]}}"#
+                ),
+            )
+            .await;
+            let flashblocks = response["result"].as_array().unwrap();
+            assert_eq!(flashblocks.len(), 2, "{block_number}");
+            assert_eq!(flashblocks[0]["index"], 0);
+        }
+
+        // A batch, with a notification that isn't answered and errors that are
+        let (status, response) = send(
+            &url,
+            r#"[
+                {"jsonrpc":"2.0","id":1,"method":"flashblocks_getByNumber","params":[1]},
+                {"jsonrpc":"2.0","method":"flashblocks_getLatest"},
+                {"jsonrpc":"2.0","id":2,"method":"eth_blockNumber"},
+                {"jsonrpc":"2.0","id":3,"method":"flashblocks_getByNumber","params":["0xzz"]},
+                {"id":4,"method":"flashblocks_getLatest"}
+            ]"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            response,
+            json!([
+                {"jsonrpc": "2.0", "id": 1, "result": null},
+                {"jsonrpc": "2.0", "id": 2, "error": {"code": METHOD_NOT_FOUND, "message": "method eth_blockNumber not found"}},
+                {"jsonrpc": "2.0", "id": 3, "error": {"code": INVALID_PARAMS, "message": "invalid block number"}},
+                {"jsonrpc": "2.0", "id": 4, "error": {"code": INVALID_REQUEST, "message": "invalid request"}},
+            ])
+        );
+
+        let (status, _) = send(
+            &url,
+            r#"{"jsonrpc":"2.0","method":"flashblocks_getLatest"}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (_, response) = send(&url, "{").await;
+        assert_eq!(response["error"]["code"], PARSE_ERROR);
+        let (_, response) = send(&url, "[]").await;
+        assert_eq!(response["error"]["code"], INVALID_REQUEST);
+    }
+}
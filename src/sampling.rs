@@ -0,0 +1,265 @@
+//! Reduced-rate delivery for clients that don't need every flashblock, such as dashboards and
+//! alerting, requested with the `sample` query parameter on the handshake:
+//!
+//! - `sample=<n>` - every `n`th message, starting with the first one delivered
+//! - `sample=final` - only the last flashblock of each block, sent once the next block's first
+//!   flashblock arrives
+//!
+//! Messages that aren't flashblocks, i.e. don't have a `metadata.block_number`, are always sent
+//! by `final`.
+//!
+//! Clients can also cap the rate they're sent messages at with `max_rate=<messages per second>`,
+//! in which case messages that arrive sooner than the client can take them are coalesced into the
+//! newest. See [`RateCap`].
+
+use crate::registry::BroadcastMessage;
+use axum::extract::ws::Message;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Sampling {
+    EveryNth(u64),
+    Final,
+}
+
+impl FromStr for Sampling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "final" {
+            return Ok(Sampling::Final);
+        }
+
+        match s.parse::<u64>() {
+            Ok(n) if n > 0 => Ok(Sampling::EveryNth(n)),
+            _ => Err(format!(
+                "invalid sample {s}: expected final or a positive number"
+            )),
+        }
+    }
+}
+
+/// A client's position in its sampled stream.
+#[derive(Debug)]
+pub struct Sampler {
+    sampling: Sampling,
+    seen: u64,
+    /// The latest flashblock of the current block, for [`Sampling::Final`].
+    held: Option<(u64, BroadcastMessage)>,
+}
+
+impl Sampler {
+    pub fn new(sampling: Sampling) -> Self {
+        Self {
+            sampling,
+            seen: 0,
+            held: None,
+        }
+    }
+
+    /// Removes the messages the client doesn't want from `batch`, returning how many were
+    /// skipped. With [`Sampling::Final`] a block's latest flashblock is held back until the next
+    /// block starts, so `batch` may instead gain the flashblock held from an earlier batch.
+    pub fn sample(&mut self, batch: &mut Vec<BroadcastMessage>) -> u64 {
+        match self.sampling {
+            Sampling::EveryNth(n) => {
+                let received = batch.len();
+                batch.retain(|_| {
+                    let keep = self.seen % n == 0;
+                    self.seen += 1;
+                    keep
+                });
+                (received - batch.len()) as u64
+            }
+            Sampling::Final => {
+                let mut skipped = 0;
+                let mut sampled = Vec::with_capacity(batch.len());
+                for msg in batch.drain(..) {
+                    let Some(block_number) = block_number(&msg) else {
+                        sampled.push(msg);
+                        continue;
+                    };
+
+                    match self.held.take() {
+                        Some((held_block, held)) if held_block != block_number => {
+                            sampled.push(held)
+                        }
+                        Some(_) => skipped += 1,
+                        None => {}
+                    }
+                    self.held = Some((block_number, msg));
+                }
+                *batch = sampled;
+                skipped
+            }
+        }
+    }
+}
+
+/// Paces delivery to a client at no more than one message per interval. Messages that arrive
+/// before the interval is up are held, each replacing the last, and the newest is sent once it is
+/// up, so a slow client gets the freshest data at the rate it asked for rather than lagging.
+#[derive(Debug)]
+pub struct RateCap {
+    interval: Duration,
+    next_send: Instant,
+    pending: Option<BroadcastMessage>,
+    coalesced: u64,
+}
+
+impl RateCap {
+    pub fn new(max_rate: f64) -> Self {
+        Self {
+            interval: Duration::from_secs_f64(1.0 / max_rate),
+            next_send: Instant::now(),
+            pending: None,
+            coalesced: 0,
+        }
+    }
+
+    /// Holds the newest message of `batch` to be sent, leaving `batch` empty. The others, and any
+    /// message already held, are coalesced into it.
+    pub fn offer(&mut self, batch: &mut Vec<BroadcastMessage>) {
+        if let Some(newest) = batch.pop() {
+            self.coalesced += batch.len() as u64 + u64::from(self.pending.is_some());
+            self.pending = Some(newest);
+        }
+        batch.clear();
+    }
+
+    /// When the held message may be sent, if one is held.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending.as_ref().map(|_| self.next_send)
+    }
+
+    /// The held message, if it may be sent now.
+    pub fn release(&mut self) -> Option<BroadcastMessage> {
+        let now = Instant::now();
+        if now < self.next_send {
+            return None;
+        }
+
+        let msg = self.pending.take()?;
+        self.next_send = now + self.interval;
+        Some(msg)
+    }
+
+    /// Messages coalesced since the last call.
+    pub fn take_coalesced(&mut self) -> u64 {
+        std::mem::take(&mut self.coalesced)
+    }
+}
+
+/// Parses a `max_rate` of messages per second.
+pub fn parse_max_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate.is_finite() && rate > 0.0 => Ok(rate),
+        _ => Err(format!(
+            "invalid max_rate {s}: expected a positive number of messages per second"
+        )),
+    }
+}
+
+fn block_number(msg: &BroadcastMessage) -> Option<u64> {
+    let Message::Binary(payload) = &msg.frame else {
+        return None;
+    };
+    let json: serde_json::Value = serde_json::from_slice(payload).ok()?;
+    json.pointer("/metadata/block_number")?.as_u64()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn message(payload: &str) -> BroadcastMessage {
+        BroadcastMessage::new(Bytes::from(payload.to_string()))
+    }
+
+    fn flashblock(block_number: u64, index: u64) -> BroadcastMessage {
+        message(&format!(
+            r#"{{"index":{index},"metadata":{{"block_number":{block_number}}}}}"#
+        ))
+    }
+
+    fn payloads(batch: &[BroadcastMessage]) -> Vec<String> {
+        batch
+            .iter()
+            .map(|msg| match &msg.frame {
+                Message::Binary(payload) => String::from_utf8(payload.to_vec()).unwrap(),
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_cap() {
+        let mut cap = RateCap::new(2.0);
+        assert_eq!(cap.deadline(), None);
+
+        let mut batch = vec![message("one")];
+        cap.offer(&mut batch);
+        assert!(batch.is_empty());
+        assert_eq!(payloads(&[cap.release().unwrap()]), ["one"]);
+        assert!(cap.release().is_none());
+
+        // Within the interval messages are held and coalesced into the newest
+        let start = Instant::now();
+        cap.offer(&mut vec![message("two"), message("three")]);
+        cap.offer(&mut vec![message("four")]);
+        assert_eq!(cap.deadline(), Some(start + Duration::from_millis(500)));
+        assert!(cap.release().is_none());
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert_eq!(payloads(&[cap.release().unwrap()]), ["four"]);
+        assert_eq!(cap.take_coalesced(), 2);
+        assert_eq!(cap.take_coalesced(), 0);
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("final".parse(), Ok(Sampling::Final));
+        assert_eq!("10".parse(), Ok(Sampling::EveryNth(10)));
+        assert!("0".parse::<Sampling>().is_err());
+        assert!("half".parse::<Sampling>().is_err());
+
+        assert_eq!(parse_max_rate("0.5"), Ok(0.5));
+        assert!(parse_max_rate("0").is_err());
+        assert!(parse_max_rate("inf").is_err());
+    }
+
+    #[test]
+    fn test_every_nth() {
+        let mut sampler = Sampler::new(Sampling::EveryNth(3));
+
+        let mut batch: Vec<_> = (0..4).map(|i| message(&i.to_string())).collect();
+        assert_eq!(sampler.sample(&mut batch), 2);
+        assert_eq!(payloads(&batch), ["0", "3"]);
+
+        // The count carries over between batches
+        let mut batch: Vec<_> = (4..7).map(|i| message(&i.to_string())).collect();
+        assert_eq!(sampler.sample(&mut batch), 2);
+        assert_eq!(payloads(&batch), ["6"]);
+    }
+
+    #[test]
+    fn test_final() {
+        let mut sampler = Sampler::new(Sampling::Final);
+
+        let mut batch = vec![flashblock(1, 0), flashblock(1, 1), message("ping")];
+        assert_eq!(sampler.sample(&mut batch), 1);
+        assert_eq!(payloads(&batch), ["ping"]);
+
+        // The last flashblock of block 1 is sent once block 2 starts
+        let mut batch = vec![flashblock(1, 2), flashblock(2, 0)];
+        assert_eq!(sampler.sample(&mut batch), 1);
+        assert_eq!(payloads(&batch), payloads(&[flashblock(1, 2)]));
+
+        let mut batch = vec![flashblock(3, 0)];
+        assert_eq!(sampler.sample(&mut batch), 0);
+        assert_eq!(payloads(&batch), payloads(&[flashblock(2, 0)]));
+    }
+}
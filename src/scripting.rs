@@ -0,0 +1,318 @@
+//! Transforms written as rhai scripts, for quick one-off transformations like redacting a field or
+//! tagging messages. Only built with the `scripting` feature. See [`crate::transform`] for where
+//! they run.
+//!
+//! A script defines `fn transform(message)`, which returns the message to send in its place, or
+//! `()` to drop it:
+//!
+//! ```text
+//! fn transform(message) {
+//!     message.metadata.remove("receipts");
+//!     message.tier = "free";
+//!     message
+//! }
+//! ```
+//!
+//! JSON messages are passed as rhai values, with objects as object maps, and other messages as
+//! strings, or blobs if they aren't UTF-8. What's returned is sent as JSON, except that strings
+//! and blobs are sent as they are, and a message returned unchanged is sent exactly as it was
+//! received. Only the function is run, not the rest of the script.
+//!
+//! Scripts are sandboxed: they can't import modules, and rhai has no access to files or the
+//! network. Each message may take at most `--script-max-operations` operations, strings are
+//! capped at `--script-max-string-bytes` and arrays and object maps at
+//! `--script-max-collection-len` items, and calls and expressions can only nest so deep. `print`
+//! and `debug` are logged at debug level.
+
+use crate::transform::{Plugin, Plugins, Transform, TransformError, TransformFile, Verdict};
+use bytes::Bytes;
+use clap::builder::RangedU64ValueParser;
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::serde::{from_dynamic, to_dynamic};
+use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, Scope, AST};
+use serde_json::Value;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::debug;
+
+/// How deeply a script's function calls may nest.
+const MAX_CALL_LEVELS: usize = 32;
+
+/// How deeply a script's expressions may nest, both at the top level and in functions.
+const MAX_EXPR_DEPTH: usize = 64;
+
+/// The function a script defines.
+const TRANSFORM_FN: &str = "transform";
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct ScriptArgs {
+    /// rhai script to load as a named transform, as name=path, e.g. tag=/etc/proxy/tag.rhai
+    /// (repeatable, ';' separated in the environment)
+    #[arg(long = "script", env = "SCRIPTS", value_delimiter = ';')]
+    pub scripts: Vec<TransformFile>,
+
+    /// Operations a script may take on each message
+    #[arg(long, env, default_value = "1000000", value_parser = clap::value_parser!(u64).range(1..))]
+    pub script_max_operations: u64,
+
+    /// Longest string a script may build, in bytes
+    #[arg(long, env, default_value = "4194304", value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub script_max_string_bytes: usize,
+
+    /// Most items in an array or object map a script may build
+    #[arg(long, env, default_value = "100000", value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub script_max_collection_len: usize,
+}
+
+impl ScriptArgs {
+    /// Adds the scripts to `plugins`.
+    pub fn load(&self, plugins: Plugins) -> Result<Plugins, TransformError> {
+        let limits = ScriptLimits {
+            max_operations: self.script_max_operations,
+            max_string_bytes: self.script_max_string_bytes,
+            max_collection_len: self.script_max_collection_len,
+        };
+
+        self.scripts.iter().try_fold(plugins, |plugins, script| {
+            if plugins.contains(&script.name) {
+                return Err(TransformError::new(
+                    &script.name,
+                    "another transform has the same name",
+                ));
+            }
+            let plugin = ScriptPlugin::load(&script.name, &script.path, limits)?;
+            Ok(plugins.with_plugin(script.name.clone(), Arc::new(plugin)))
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ScriptLimits {
+    /// Operations for each message.
+    pub max_operations: u64,
+    pub max_string_bytes: usize,
+    /// Most items in an array or object map.
+    pub max_collection_len: usize,
+}
+
+/// A compiled script. Calls don't keep any state, so each stream or client that uses it shares
+/// the one copy.
+#[derive(Clone)]
+pub struct ScriptPlugin {
+    name: String,
+    engine: Arc<Engine>,
+    ast: Arc<AST>,
+}
+
+impl ScriptPlugin {
+    /// Compiles `script`, checking that it defines the transform function.
+    pub fn new(name: &str, script: &str, limits: ScriptLimits) -> Result<Self, TransformError> {
+        let mut engine = Engine::new();
+        engine
+            .set_module_resolver(DummyModuleResolver::new())
+            .set_max_operations(limits.max_operations)
+            .set_max_string_size(limits.max_string_bytes)
+            .set_max_array_size(limits.max_collection_len)
+            .set_max_map_size(limits.max_collection_len)
+            .set_max_call_levels(MAX_CALL_LEVELS)
+            .set_max_expr_depths(MAX_EXPR_DEPTH, MAX_EXPR_DEPTH);
+        let script_name = name.to_string();
+        engine.on_print(move |text| debug!(message = "script printed", script = script_name, text));
+        let script_name = name.to_string();
+        engine.on_debug(move |text, _, position| {
+            debug!(message = "script debug", script = script_name, text, position = %position)
+        });
+
+        let ast = engine
+            .compile(script)
+            .map_err(|e| TransformError::new(name, e))?;
+        if !ast
+            .iter_functions()
+            .any(|f| f.name == TRANSFORM_FN && f.params.len() == 1)
+        {
+            return Err(TransformError::new(
+                name,
+                format!("script doesn't define fn {TRANSFORM_FN}(message)"),
+            ));
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            engine: Arc::new(engine),
+            ast: Arc::new(ast),
+        })
+    }
+
+    /// Reads and compiles the script at `path`.
+    pub fn load(name: &str, path: &Path, limits: ScriptLimits) -> Result<Self, TransformError> {
+        let script = std::fs::read_to_string(path)
+            .map_err(|e| TransformError::new(name, format!("{}: {e}", path.display())))?;
+        Self::new(name, &script, limits)
+    }
+}
+
+impl Plugin for ScriptPlugin {
+    fn instantiate(&self) -> Result<Box<dyn Transform>, TransformError> {
+        Ok(Box::new(self.clone()))
+    }
+}
+
+impl Transform for ScriptPlugin {
+    fn apply(&mut self, payload: &Bytes) -> Result<Verdict, TransformError> {
+        let error = |e: Box<EvalAltResult>| TransformError::new(&self.name, e);
+
+        let json = serde_json::from_slice::<Value>(payload).ok();
+        let message = match &json {
+            Some(json) => to_dynamic(json).map_err(error)?,
+            None => match std::str::from_utf8(payload) {
+                Ok(text) => Dynamic::from(text.to_string()),
+                Err(_) => Dynamic::from_blob(payload.to_vec()),
+            },
+        };
+
+        let result: Dynamic = self
+            .engine
+            .call_fn_with_options(
+                CallFnOptions::new().eval_ast(false),
+                &mut Scope::new(),
+                &self.ast,
+                TRANSFORM_FN,
+                (message,),
+            )
+            .map_err(error)?;
+        if result.is_unit() {
+            return Ok(Verdict::Drop);
+        }
+        // Compared as JSON, since object maps don't keep the order of the message's keys
+        if json.is_some() && from_dynamic::<Value>(&result).ok() == json {
+            return Ok(Verdict::Keep);
+        }
+
+        let output = if result.is_string() {
+            result
+                .into_string()
+                .map_err(|e| TransformError::new(&self.name, e))?
+                .into_bytes()
+        } else if result.is_blob() {
+            result
+                .into_blob()
+                .map_err(|e| TransformError::new(&self.name, e))?
+        } else {
+            let value: Value = from_dynamic(&result).map_err(error)?;
+            serde_json::to_vec(&value).map_err(|e| TransformError::new(&self.name, e))?
+        };
+        match output == payload.as_ref() {
+            true => Ok(Verdict::Keep),
+            false => Ok(Verdict::Replace(output.into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const LIMITS: ScriptLimits = ScriptLimits {
+        max_operations: 10_000,
+        max_string_bytes: 1024,
+        max_collection_len: 100,
+    };
+
+    fn transform(script: &str) -> Box<dyn Transform> {
+        ScriptPlugin::new("test", script, LIMITS)
+            .unwrap()
+            .instantiate()
+            .unwrap()
+    }
+
+    fn json(verdict: Verdict) -> Value {
+        match verdict {
+            Verdict::Replace(payload) => serde_json::from_slice(&payload).unwrap(),
+            verdict => panic!("expected a replacement, got {verdict:?}"),
+        }
+    }
+
+    #[test]
+    fn test_transform() {
+        let mut redact = transform(
+            r#"
+            fn transform(message) {
+                if type_of(message) != "map" {
+                    return message;
+                }
+                if message.index == 0 {
+                    return ();
+                }
+                message.metadata.remove("receipts");
+                message.tier = "free";
+                message
+            }
+            "#,
+        );
+
+        let message = json!({"index": 1, "metadata": {"receipts": {}, "block_number": 2}});
+        assert_eq!(
+            json(redact.apply(&message.to_string().into()).unwrap()),
+            json!({"index": 1, "metadata": {"block_number": 2}, "tier": "free"})
+        );
+        let message = json!({"index": 0, "metadata": {}});
+        assert_eq!(
+            redact.apply(&message.to_string().into()).unwrap(),
+            Verdict::Drop
+        );
+
+        // Unchanged messages are kept as they are, whatever order their keys are in
+        assert_eq!(redact.apply(&Bytes::from("ping")).unwrap(), Verdict::Keep);
+        let mut identity = transform("fn transform(message) { message }");
+        assert_eq!(
+            identity
+                .apply(&Bytes::from(r#"{"z": 1, "a": [2, "3"]}"#))
+                .unwrap(),
+            Verdict::Keep
+        );
+        assert_eq!(
+            identity.apply(&Bytes::from(vec![0xff, 0xfe])).unwrap(),
+            Verdict::Keep
+        );
+
+        let mut tag = transform(r#"fn transform(message) { "tagged:" + message }"#);
+        assert_eq!(
+            tag.apply(&Bytes::from("ping")).unwrap(),
+            Verdict::Replace(Bytes::from("tagged:ping"))
+        );
+    }
+
+    #[test]
+    fn test_limits() {
+        for script in [
+            // Operations
+            "fn transform(message) { loop {} }",
+            // String size
+            r#"fn transform(message) { let s = "x"; loop { s += s; } }"#,
+            // Array size
+            "fn transform(message) { let a = []; loop { a.push(1); } }",
+            // Call depth
+            "fn recurse(n) { recurse(n + 1) } fn transform(message) { recurse(0) }",
+            // Modules
+            r#"fn transform(message) { import "os" as os; message }"#,
+        ] {
+            let error = transform(script).apply(&Bytes::from("ping")).unwrap_err();
+            assert_eq!(error.name, "test", "{script}");
+        }
+    }
+
+    #[test]
+    fn test_invalid_scripts() {
+        for script in [
+            "fn transform(message) {",
+            "fn other(message) { message }",
+            "fn transform() { () }",
+        ] {
+            assert!(
+                ScriptPlugin::new("test", script, LIMITS).is_err(),
+                "{script}"
+            );
+        }
+    }
+}
@@ -1,19 +1,80 @@
-use crate::client::ClientConnection;
+#[cfg(feature = "acme")]
+use crate::acme::{AcmeConfig, AcmeListener};
+use crate::admin;
+use crate::audit;
+use crate::auth::{constant_time_eq, BasicAuth};
+use crate::authorizer::{Authorizer, Decision};
+use crate::ban::{BanConfig, BanList};
+use crate::cache::FlashblockCache;
+use crate::client::{ClientConnection, ClientLabels};
+use crate::envelope;
+use crate::geoip::{AccessPolicy, GeoIp, Location};
+#[cfg(feature = "graphql")]
+use crate::graphql::{self, FlashblocksSchema};
+use crate::grpc::{self, Flashblocks, FlashblocksServer, SubscribeRequest};
+use crate::handshake::{HandshakeConfig, HandshakeListener, Handshakes};
+use crate::hooks::ConnectionHooks;
+use crate::load_shedding::LoadShedder;
 use crate::metrics::Metrics;
-use crate::rate_limit::{RateLimit, RateLimitError};
-use crate::registry::Registry;
+use crate::priority::PriorityConfig;
+use crate::rate_limit::{AsnLimit, RateLimit, RateLimitError, Ticket};
+use crate::registry::{EvictionCandidate, Registry};
+use crate::rpc;
+use crate::sampling::{self, Sampling};
+use crate::streams::Stream;
+use crate::subscriber::UpstreamStatus;
+use crate::transform::Plugins;
+use crate::waiting_room::{WaitError, WaitingRoom};
+#[cfg(feature = "webtransport")]
+use crate::webtransport::{self, WebTransportConfig};
+#[cfg(feature = "graphql")]
+use async_graphql_axum::{GraphQLProtocol, GraphQLWebSocket};
 use axum::body::Body;
-use axum::extract::{ConnectInfo, State, WebSocketUpgrade};
+use axum::extract::{ConnectInfo, Path, Query, State, WebSocketUpgrade};
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
 use axum::routing::{any, get};
-use axum::{Error, Router};
+use axum::serve::{Listener, ListenerExt};
+use axum::{Error, Json, Router};
+use http::header::{AUTHORIZATION, ORIGIN, RETRY_AFTER, WWW_AUTHENTICATE};
 use http::{HeaderMap, HeaderValue};
-use serde_json::json;
+use serde_json::{json, Map};
+use std::collections::HashMap;
 use std::net::{IpAddr, SocketAddr};
+use std::os::fd::{FromRawFd, RawFd};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpSocket};
+use tokio::task::JoinHandle;
 use tokio_util::sync::CancellationToken;
 use tracing::{info, warn};
+#[cfg(feature = "webtransport")]
+use wtransport::endpoint::{endpoint_side, SessionRequest};
+#[cfg(feature = "webtransport")]
+use wtransport::{Endpoint, VarInt};
+
+const BASIC_AUTH_CHALLENGE: &str = "Basic realm=\"flashblocks-websocket-proxy\"";
+
+const DEFAULT_LISTEN_BACKLOG: u32 = 1024;
+
+/// How long a client with priority waits for the connection of a client evicted to make room for
+/// it to be released, and how often it checks.
+const EVICTION_WAIT: Duration = Duration::from_secs(1);
+const EVICTION_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long to wait on shutdown for clients' disconnects to be recorded.
+const CLIENT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Conditions under which the proxy reports itself as ready to receive traffic on `/readyz`. Each
+/// check is skipped when unset.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReadinessConfig {
+    /// At least one upstream must have delivered a message within this window.
+    pub max_upstream_age: Option<Duration>,
+    /// The fraction of the global connection limit in use must stay below this threshold.
+    pub max_capacity: Option<f64>,
+}
 
 #[derive(Clone)]
 struct ServerState {
@@ -21,6 +82,29 @@ struct ServerState {
     rate_limiter: Arc<dyn RateLimit>,
     metrics: Arc<Metrics>,
     ip_addr_http_header: String,
+    upstreams: Vec<Arc<UpstreamStatus>>,
+    readiness: ReadinessConfig,
+    load_shedder: Option<Arc<LoadShedder>>,
+    shutdown_notice: CancellationToken,
+    relay_token: Option<String>,
+    streams: Arc<HashMap<String, Stream>>,
+    allowed_origins: Arc<Vec<String>>,
+    basic_auth: Option<Arc<BasicAuth>>,
+    authorizer: Option<Arc<Authorizer>>,
+    geoip: Option<Arc<GeoIp>>,
+    access_policy: Arc<AccessPolicy>,
+    asn_limit: Option<Arc<AsnLimit>>,
+    hooks: Option<Arc<dyn ConnectionHooks>>,
+    bans: Option<Arc<BanList>>,
+    waiting_room: Option<Arc<WaitingRoom>>,
+    priority: Arc<PriorityConfig>,
+    /// Clients with priority waiting for an evicted client's connection to be released, which
+    /// clients without priority may not take in the meantime.
+    pending_evictions: Arc<AtomicUsize>,
+    handshakes: Arc<Handshakes>,
+    transforms: Plugins,
+    #[cfg(feature = "graphql")]
+    graphql: FlashblocksSchema,
 }
 
 #[derive(Clone)]
@@ -30,6 +114,36 @@ pub struct Server {
     rate_limiter: Arc<dyn RateLimit>,
     metrics: Arc<Metrics>,
     ip_addr_http_header: String,
+    upstreams: Vec<Arc<UpstreamStatus>>,
+    readiness: ReadinessConfig,
+    acceptors: usize,
+    listen_backlog: u32,
+    reuse_port: bool,
+    inherited_listener: Option<Arc<std::net::TcpListener>>,
+    load_shedder: Option<Arc<LoadShedder>>,
+    shutdown_notice: CancellationToken,
+    relay_token: Option<String>,
+    streams: Arc<HashMap<String, Stream>>,
+    allowed_origins: Arc<Vec<String>>,
+    basic_auth: Option<Arc<BasicAuth>>,
+    authorizer: Option<Arc<Authorizer>>,
+    geoip: Option<Arc<GeoIp>>,
+    access_policy: Arc<AccessPolicy>,
+    asn_limit: Option<Arc<AsnLimit>>,
+    hooks: Option<Arc<dyn ConnectionHooks>>,
+    bans: Option<Arc<BanList>>,
+    waiting_room: Option<Arc<WaitingRoom>>,
+    priority: Arc<PriorityConfig>,
+    pending_evictions: Arc<AtomicUsize>,
+    admin_token: Option<String>,
+    flashblock_cache: Option<Arc<FlashblockCache>>,
+    handshake: HandshakeConfig,
+    handshakes: Arc<Handshakes>,
+    transforms: Plugins,
+    #[cfg(feature = "webtransport")]
+    webtransport: Option<WebTransportConfig>,
+    #[cfg(feature = "acme")]
+    acme: Option<AcmeConfig>,
 }
 
 impl Server {
@@ -39,6 +153,8 @@ impl Server {
         metrics: Arc<Metrics>,
         rate_limiter: Arc<dyn RateLimit>,
         ip_addr_http_header: String,
+        upstreams: Vec<Arc<UpstreamStatus>>,
+        readiness: ReadinessConfig,
     ) -> Self {
         Self {
             listen_addr,
@@ -46,66 +162,698 @@ impl Server {
             rate_limiter,
             metrics,
             ip_addr_http_header,
+            upstreams,
+            readiness,
+            acceptors: 1,
+            listen_backlog: DEFAULT_LISTEN_BACKLOG,
+            reuse_port: false,
+            inherited_listener: None,
+            load_shedder: None,
+            shutdown_notice: CancellationToken::new(),
+            relay_token: None,
+            streams: Arc::new(HashMap::new()),
+            allowed_origins: Arc::new(Vec::new()),
+            basic_auth: None,
+            authorizer: None,
+            geoip: None,
+            access_policy: Arc::new(AccessPolicy::default()),
+            asn_limit: None,
+            hooks: None,
+            bans: None,
+            waiting_room: None,
+            priority: Arc::new(PriorityConfig::default()),
+            pending_evictions: Arc::new(AtomicUsize::new(0)),
+            admin_token: None,
+            flashblock_cache: None,
+            handshake: HandshakeConfig::default(),
+            handshakes: Arc::new(Handshakes::default()),
+            transforms: Plugins::default(),
+            #[cfg(feature = "webtransport")]
+            webtransport: None,
+            #[cfg(feature = "acme")]
+            acme: None,
         }
     }
 
-    pub async fn listen(&self, cancellation_token: CancellationToken) {
+    /// Accept connections on `acceptors` listeners bound to the same address with SO_REUSEPORT,
+    /// each served by its own task.
+    pub fn with_acceptors(mut self, acceptors: usize) -> Self {
+        self.acceptors = acceptors;
+        self
+    }
+
+    /// Queue up to `backlog` connections in the kernel that haven't been accepted yet. Beyond that
+    /// the kernel refuses or drops new connections.
+    pub fn with_listen_backlog(mut self, backlog: u32) -> Self {
+        self.listen_backlog = backlog;
+        self
+    }
+
+    /// Bind the listen address with SO_REUSEPORT even with a single acceptor, so that the next
+    /// process can bind it before this one stops accepting.
+    pub fn with_reuse_port(mut self, reuse_port: bool) -> Self {
+        self.reuse_port = reuse_port;
+        self
+    }
+
+    /// Accept connections on an already listening socket, such as one passed by systemd socket
+    /// activation, instead of binding the listen address. Every acceptor accepts from it.
+    pub fn with_listener(mut self, listener: std::net::TcpListener) -> Self {
+        self.inherited_listener = Some(Arc::new(listener));
+        self
+    }
+
+    /// Reject new connections, and report not ready, while the load shedder says so.
+    pub fn with_load_shedder(mut self, load_shedder: Arc<LoadShedder>) -> Self {
+        self.load_shedder = Some(load_shedder);
+        self
+    }
+
+    /// Report not ready once `notice` is cancelled, while still serving, so that load balancers
+    /// can stop routing to the proxy before it shuts down.
+    pub fn with_shutdown_notice(mut self, notice: CancellationToken) -> Self {
+        self.shutdown_notice = notice;
+        self
+    }
+
+    /// Serve downstream proxies on `/relay`, authenticated with `token` as a bearer token. See
+    /// [`crate::relay`].
+    pub fn with_relay_token(mut self, token: String) -> Self {
+        self.relay_token = Some(token);
+        self
+    }
+
+    /// Serve `stream` at `/ws/{name}`, in addition to the default stream at `/ws`. See
+    /// [`crate::streams`].
+    pub fn with_stream(mut self, name: impl Into<String>, stream: Stream) -> Self {
+        Arc::make_mut(&mut self.streams).insert(name.into(), stream);
+        self
+    }
+
+    /// Refuse websocket upgrades with an `Origin` header that isn't one of `origins`, e.g.
+    /// `https://app.example.com`, so that other websites can't connect from their users' browsers.
+    /// Clients that send no `Origin`, which browsers always do, are unaffected.
+    pub fn with_allowed_origins(mut self, origins: Vec<String>) -> Self {
+        self.allowed_origins = Arc::new(
+            origins
+                .iter()
+                .map(|origin| normalize_origin(origin))
+                .filter(|origin| !origin.is_empty())
+                .collect(),
+        );
+        self
+    }
+
+    /// Require websocket clients to authenticate with HTTP Basic auth as one of `auth`'s users.
+    /// Downstream proxies on `/relay` authenticate with the relay token instead.
+    pub fn with_basic_auth(mut self, auth: BasicAuth) -> Self {
+        self.basic_auth = Some(Arc::new(auth));
+        self
+    }
+
+    /// Ask an external service whether to admit each websocket client. Downstream proxies on
+    /// `/relay` authenticate with the relay token instead. See [`crate::authorizer`].
+    pub fn with_authorizer(mut self, authorizer: Authorizer) -> Self {
+        self.authorizer = Some(Arc::new(authorizer));
+        self
+    }
+
+    /// Attach the country and ASN of each websocket client's address, according to `geoip`, to
+    /// its connection logs and metrics. See [`crate::geoip`].
+    pub fn with_geoip(mut self, geoip: GeoIp) -> Self {
+        self.geoip = Some(Arc::new(geoip));
+        self
+    }
+
+    /// Refuse websocket clients whose address `policy` doesn't allow, according to the databases
+    /// given to [`Server::with_geoip`]. Downstream proxies on `/relay` are exempt.
+    pub fn with_access_policy(mut self, policy: AccessPolicy) -> Self {
+        self.access_policy = Arc::new(policy);
+        self
+    }
+
+    /// Limit the connections from each autonomous system to `per_asn_limit`, according to the
+    /// databases given to [`Server::with_geoip`]. Clients whose ASN isn't known aren't limited.
+    pub fn with_asn_limit(mut self, per_asn_limit: usize) -> Self {
+        self.asn_limit = Some(Arc::new(AsnLimit::new(per_asn_limit)));
+        self
+    }
+
+    /// Call `hooks` through the lifecycle of each websocket client. See [`crate::hooks`].
+    pub fn with_hooks(mut self, hooks: Arc<dyn ConnectionHooks>) -> Self {
+        self.hooks = Some(hooks);
+        self
+    }
+
+    /// Ban client addresses that keep being refused with a `401` or `429`. See [`crate::ban`].
+    pub fn with_auto_ban(mut self, config: BanConfig) -> Self {
+        self.bans = Some(Arc::new(BanList::new(config)));
+        self
+    }
+
+    /// Hold up to `capacity` handshakes for up to `max_wait` while the global connection limit is
+    /// reached, admitting them in order as connections are released. See
+    /// [`crate::waiting_room`].
+    pub fn with_waiting_room(mut self, capacity: usize, max_wait: Duration) -> Self {
+        self.waiting_room = Some(Arc::new(WaitingRoom::new(capacity, max_wait)));
+        self
+    }
+
+    /// Admit clients with priority ahead of others near the global connection limit. See
+    /// [`crate::priority`].
+    pub fn with_priority(mut self, config: PriorityConfig) -> Self {
+        self.priority = Arc::new(config);
+        self
+    }
+
+    /// Limit the connections that haven't completed the websocket handshake, and choose whether
+    /// they may speak HTTP/2. Only applies to connections accepted by [`Server::listen`]. See
+    /// [`crate::handshake`].
+    pub fn with_handshake_limits(mut self, config: HandshakeConfig) -> Self {
+        self.handshake = config;
+        self
+    }
+
+    /// Serve the admin API on `/admin`, authenticated with `token` as a bearer token. See
+    /// [`crate::admin`].
+    pub fn with_admin_token(mut self, token: String) -> Self {
+        self.admin_token = Some(token);
+        self
+    }
+
+    /// Serve TLS on the listener with certificates provisioned by ACME. See [`crate::acme`].
+    #[cfg(feature = "acme")]
+    pub fn with_acme(mut self, config: AcmeConfig) -> Self {
+        self.acme = Some(config);
+        self
+    }
+
+    /// Serve WebTransport sessions on a QUIC listener alongside the websocket one. See
+    /// [`crate::webtransport`].
+    #[cfg(feature = "webtransport")]
+    pub fn with_webtransport(mut self, config: WebTransportConfig) -> Self {
+        self.webtransport = Some(config);
+        self
+    }
+
+    /// Let websocket clients ask for one of `transforms` to be run on their messages with the
+    /// `transform` query parameter. See [`crate::transform`].
+    pub fn with_transforms(mut self, transforms: Plugins) -> Self {
+        self.transforms = transforms;
+        self
+    }
+
+    /// Answer JSON-RPC queries on `/rpc` from `cache`. See [`crate::rpc`].
+    pub fn with_json_rpc(mut self, cache: Arc<FlashblockCache>) -> Self {
+        self.flashblock_cache = Some(cache);
+        self
+    }
+
+    /// The proxy's routes, for serving from an existing axum app. The app must be served with
+    /// `into_make_service_with_connect_info::<SocketAddr>()` so that client addresses are known.
+    pub fn router(&self) -> Router {
         let router = Router::new()
-            .route("/healthz", get(healthz_handler))
+            .route("/healthz", get(livez_handler))
+            .route("/livez", get(livez_handler))
+            .route("/readyz", get(readyz_handler))
+            .route("/status", get(status_handler))
             .route("/ws", any(websocket_handler))
-            .with_state(ServerState {
-                registry: self.registry.clone(),
-                rate_limiter: self.rate_limiter.clone(),
-                metrics: self.metrics.clone(),
-                ip_addr_http_header: self.ip_addr_http_header.clone(),
-            });
-
-        let listener = tokio::net::TcpListener::bind(self.listen_addr)
-            .await
-            .unwrap();
+            .route("/ws/{stream}", any(stream_handler))
+            .route("/relay", any(relay_handler));
+        #[cfg(feature = "graphql")]
+        let router = router
+            .route("/graphql", any(graphql_handler))
+            .route("/graphql/{stream}", any(graphql_handler));
+        let router = router
+            .route_service(
+                &format!("/{}/{{*method}}", grpc::SERVICE_NAME),
+                FlashblocksServer::new(FlashblocksService {
+                    state: self.state(),
+                }),
+            )
+            .with_state(self.state());
+
+        let router = match &self.flashblock_cache {
+            Some(cache) => router.merge(rpc::router(cache.clone(), self.metrics.clone())),
+            None => router,
+        };
+
+        match &self.admin_token {
+            Some(token) => router.nest(
+                "/admin",
+                admin::router(
+                    token.clone(),
+                    self.registry.clone(),
+                    self.load_shedder.clone(),
+                    self.bans.clone(),
+                ),
+            ),
+            None => router,
+        }
+    }
+
+    /// Why the proxy isn't ready to receive traffic, as reported on `/readyz`. Empty when ready.
+    pub fn readiness_failures(&self) -> Vec<&'static str> {
+        readiness_failures(&self.state())
+    }
+
+    fn state(&self) -> ServerState {
+        ServerState {
+            registry: self.registry.clone(),
+            rate_limiter: self.rate_limiter.clone(),
+            metrics: self.metrics.clone(),
+            ip_addr_http_header: self.ip_addr_http_header.clone(),
+            upstreams: self.upstreams.clone(),
+            readiness: self.readiness,
+            load_shedder: self.load_shedder.clone(),
+            shutdown_notice: self.shutdown_notice.clone(),
+            relay_token: self.relay_token.clone(),
+            streams: self.streams.clone(),
+            allowed_origins: self.allowed_origins.clone(),
+            basic_auth: self.basic_auth.clone(),
+            authorizer: self.authorizer.clone(),
+            geoip: self.geoip.clone(),
+            access_policy: self.access_policy.clone(),
+            asn_limit: self.asn_limit.clone(),
+            hooks: self.hooks.clone(),
+            bans: self.bans.clone(),
+            waiting_room: self.waiting_room.clone(),
+            priority: self.priority.clone(),
+            pending_evictions: self.pending_evictions.clone(),
+            handshakes: self.handshakes.clone(),
+            transforms: self.transforms.clone(),
+            #[cfg(feature = "graphql")]
+            graphql: graphql::schema(),
+        }
+    }
+
+    pub async fn listen(&self, cancellation_token: CancellationToken) {
+        let router = self.router();
+
+        let acceptors = self.acceptors.max(1);
+        let listeners = match &self.inherited_listener {
+            Some(listener) => (0..acceptors)
+                .map(|_| {
+                    let listener = listener.try_clone()?;
+                    listener.set_nonblocking(true)?;
+                    TcpListener::from_std(listener)
+                })
+                .collect::<std::io::Result<Vec<_>>>()
+                .unwrap(),
+            None => {
+                let reuse_port = self.reuse_port || acceptors > 1;
+                let listener = bind(self.listen_addr, reuse_port, self.listen_backlog).unwrap();
+                // Bind the remaining acceptors to the resolved address, in case the port was
+                // picked by the OS.
+                let addr = listener.local_addr().unwrap();
+                let mut listeners = vec![listener];
+                for _ in 1..acceptors {
+                    listeners.push(bind(addr, true, self.listen_backlog).unwrap());
+                }
+                listeners
+            }
+        };
 
         info!(
             message = "starting server",
-            address = listener.local_addr().unwrap().to_string()
+            address = listeners[0].local_addr().unwrap().to_string(),
+            acceptors = acceptors,
+            inherited = self.inherited_listener.is_some()
         );
 
-        axum::serve(
-            listener,
-            router.into_make_service_with_connect_info::<SocketAddr>(),
-        )
-        .with_graceful_shutdown(cancellation_token.cancelled_owned())
-        .await
-        .unwrap()
+        #[cfg(feature = "acme")]
+        let acme = self
+            .acme
+            .as_ref()
+            .map(|config| config.start(self.handshake.http2, cancellation_token.clone()));
+
+        let tasks: Vec<_> = listeners
+            .into_iter()
+            .map(|listener| {
+                let listener = HandshakeListener::new(
+                    listener,
+                    self.handshake,
+                    self.handshakes.clone(),
+                    self.metrics.clone(),
+                );
+                #[cfg(feature = "acme")]
+                if let Some(tls) = &acme {
+                    let listener = AcmeListener::new(listener, tls.clone());
+                    return serve(listener, router.clone(), cancellation_token.clone());
+                }
+                serve(listener, router.clone(), cancellation_token.clone())
+            })
+            .collect();
+
+        #[cfg(feature = "webtransport")]
+        let webtransport = self.webtransport.as_ref().map(|config| {
+            let endpoint = config.endpoint().unwrap();
+            info!(
+                message = "starting WebTransport listener",
+                address = endpoint.local_addr().unwrap().to_string()
+            );
+            tokio::spawn(serve_webtransport(
+                self.state(),
+                endpoint,
+                cancellation_token.clone(),
+            ))
+        });
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        // The graceful shutdown doesn't wait for upgraded websockets, so their clients are closed
+        // here
+        let registries = std::iter::once(&self.registry)
+            .chain(self.streams.values().map(Stream::registry))
+            .map(|registry| registry.close_clients(CLIENT_SHUTDOWN_TIMEOUT));
+        futures::future::join_all(registries).await;
+        #[cfg(feature = "webtransport")]
+        if let Some(task) = webtransport {
+            task.await.unwrap();
+        }
     }
 }
 
-async fn healthz_handler() -> impl IntoResponse {
+/// Serves `router` on `listener` until `token` is cancelled.
+fn serve<L>(listener: L, router: Router, token: CancellationToken) -> JoinHandle<()>
+where
+    L: Listener<Addr = SocketAddr>,
+{
+    // Tapped only so that the client's address is available to handlers as ConnectInfo
+    let listener = listener.tap_io(|_| {});
+    let serve = axum::serve(
+        listener,
+        router.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(token.cancelled_owned());
+
+    tokio::spawn(async move { serve.await.unwrap() })
+}
+
+/// Binds a listener on `addr`. With `reuse_port`, several listeners can bind the same address and
+/// the kernel balances incoming connections between them.
+fn bind(addr: SocketAddr, reuse_port: bool, backlog: u32) -> std::io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+
+    socket.set_reuseaddr(true)?;
+    socket.set_reuseport(reuse_port)?;
+    socket.bind(addr)?;
+    socket.listen(backlog)
+}
+
+/// Takes ownership of the listening TCP socket `fd`, inherited from the process that started the
+/// proxy.
+pub fn inherit_listener(fd: RawFd) -> std::io::Result<std::net::TcpListener> {
+    // SAFETY: the caller hands over `fd`, which nothing else in the process uses.
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    // Fails if `fd` isn't a socket
+    listener.local_addr()?;
+    Ok(listener)
+}
+
+/// Liveness only reflects that the process is serving requests; a missing upstream or full
+/// capacity is not something a restart would fix.
+async fn livez_handler() -> impl IntoResponse {
     StatusCode::OK
 }
 
+async fn readyz_handler(State(state): State<ServerState>) -> impl IntoResponse {
+    let failures = readiness_failures(&state);
+
+    if failures.is_empty() {
+        (StatusCode::OK, Json(json!({"ready": true})))
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({"ready": false, "reasons": failures})),
+        )
+    }
+}
+
+fn readiness_failures(state: &ServerState) -> Vec<&'static str> {
+    let mut failures = Vec::new();
+
+    if state.shutdown_notice.is_cancelled() {
+        failures.push("shutting down");
+    }
+
+    if let Some(max_age) = state.readiness.max_upstream_age {
+        let fresh = state.upstreams.iter().any(|upstream| {
+            upstream.is_connected()
+                && upstream
+                    .last_message_age()
+                    .is_some_and(|age| age <= max_age)
+        });
+
+        if !fresh {
+            failures.push("no upstream has delivered a recent message");
+        }
+    }
+
+    if let Some(max_capacity) = state.readiness.max_capacity {
+        let occupancy = state.rate_limiter.occupancy();
+        let capacity = occupancy.active_connections as f64 / occupancy.global_limit as f64;
+
+        if capacity >= max_capacity {
+            failures.push("connection capacity threshold reached");
+        }
+    }
+
+    if state
+        .load_shedder
+        .as_ref()
+        .is_some_and(|shedder| shedder.rejecting_connections())
+    {
+        failures.push("shedding load");
+    }
+
+    failures
+}
+
+async fn status_handler(State(state): State<ServerState>) -> impl IntoResponse {
+    let upstreams: Vec<_> = state
+        .upstreams
+        .iter()
+        .map(|upstream| {
+            json!({
+                "uri": upstream.uri().to_string(),
+                "connected": upstream.is_connected(),
+                "last_message_age_ms": upstream
+                    .last_message_age()
+                    .map(|age| age.as_millis() as u64),
+            })
+        })
+        .collect();
+
+    let streams: Map<_, _> = state
+        .streams
+        .iter()
+        .map(|(name, stream)| {
+            let upstreams: Vec<_> = stream
+                .upstreams()
+                .iter()
+                .map(|upstream| upstream.uri().to_string())
+                .collect();
+
+            (
+                name.clone(),
+                json!({
+                    "upstreams": upstreams,
+                    "clients": stream.registry().client_count(),
+                    "max_connections": stream.max_connections(),
+                    "buffer_size": stream.registry().buffer_size(),
+                }),
+            )
+        })
+        .collect();
+
+    let rate_limit = state.rate_limiter.occupancy();
+
+    Json(json!({
+        "build": {
+            "name": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "upstreams": upstreams,
+        "clients": {
+            "active": state.registry.client_count(),
+        },
+        "buffer": {
+            "size": state.registry.buffer_size(),
+            "max_client_backlog": state.registry.max_client_backlog(),
+        },
+        "rate_limit": {
+            "active_connections": rate_limit.active_connections,
+            "global_limit": rate_limit.global_limit,
+        },
+        "streams": streams,
+    }))
+}
+
+/// What a websocket client asked for on the handshake.
+#[derive(Clone, Debug, Default)]
+struct ClientOptions {
+    /// A downstream proxy on `/relay`.
+    relay: bool,
+    /// See [`crate::sampling`].
+    sampling: Option<Sampling>,
+    max_rate: Option<f64>,
+    /// Name of the transform to run on the client's messages. See [`crate::transform`].
+    transform: Option<String>,
+}
+
+impl ClientOptions {
+    /// The options in the handshake's query parameters.
+    fn from_query(query: &HashMap<String, String>) -> Result<Self, String> {
+        let sampling = query
+            .get("sample")
+            .map(|sample| sample.parse())
+            .transpose()?;
+        let max_rate = query
+            .get("max_rate")
+            .map(|max_rate| sampling::parse_max_rate(max_rate))
+            .transpose()?;
+
+        Ok(Self {
+            relay: false,
+            sampling,
+            max_rate,
+            transform: query.get("transform").cloned(),
+        })
+    }
+}
+
 async fn websocket_handler(
     State(state): State<ServerState>,
+    Query(query): Query<HashMap<String, String>>,
     ws: WebSocketUpgrade,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     headers: HeaderMap,
-) -> impl IntoResponse {
-    let connect_addr = addr.ip();
+) -> Response {
+    let options = match ClientOptions::from_query(&query) {
+        Ok(options) => options,
+        Err(message) => {
+            return (StatusCode::BAD_REQUEST, Json(json!({"message": message}))).into_response()
+        }
+    };
+
+    let registry = state.registry.clone();
+    upgrade(state, registry, None, ws, addr, headers, options).await
+}
 
-    let client_addr = match headers.get(state.ip_addr_http_header) {
-        None => connect_addr,
-        Some(value) => extract_addr(value, connect_addr),
+async fn stream_handler(
+    State(state): State<ServerState>,
+    Path(name): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+    ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(stream) = state.streams.get(&name) else {
+        return StatusCode::NOT_FOUND.into_response();
     };
 
-    let ticket = match state.rate_limiter.try_acquire(client_addr) {
-        Ok(ticket) => ticket,
-        Err(RateLimitError::Limit { reason }) => {
-            state.metrics.rate_limited_requests.increment(1);
+    let options = match ClientOptions::from_query(&query) {
+        Ok(options) => options,
+        Err(message) => {
+            return (StatusCode::BAD_REQUEST, Json(json!({"message": message}))).into_response()
+        }
+    };
 
-            return Response::builder()
-                .status(StatusCode::TOO_MANY_REQUESTS)
-                .body(Body::from(json!({"message": reason}).to_string()))
-                .unwrap();
+    let registry = stream.registry().clone();
+    upgrade(state, registry, Some(&name), ws, addr, headers, options).await
+}
+
+async fn relay_handler(
+    State(state): State<ServerState>,
+    ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(relay_token) = &state.relay_token else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let authorized = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| constant_time_eq(token.as_bytes(), relay_token.as_bytes()));
+    let relay_addr = client_addr(&state, addr, &headers);
+    if let Some(response) = refuse_banned(&state, &state.registry, relay_addr) {
+        return response;
+    }
+    if !authorized {
+        audit::auth_failed(relay_addr, None, "relay_token");
+        record_refusal(&state, &state.registry, relay_addr);
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    let registry = state.registry.clone();
+    let options = ClientOptions {
+        relay: true,
+        ..Default::default()
+    };
+    upgrade(state, registry, None, ws, addr, headers, options).await
+}
+
+/// Admits a websocket client to `registry`, the named `stream`'s if it has one, or a downstream
+/// proxy if `options.relay` is set.
+async fn upgrade(
+    state: ServerState,
+    registry: Registry,
+    stream: Option<&str>,
+    ws: WebSocketUpgrade,
+    addr: SocketAddr,
+    headers: HeaderMap,
+    options: ClientOptions,
+) -> Response {
+    let relay = options.relay;
+    let transform = match &options.transform {
+        Some(name) if !state.transforms.contains(name) => {
+            let message = format!("unknown transform {name}");
+            return (StatusCode::BAD_REQUEST, Json(json!({"message": message}))).into_response();
         }
+        Some(name) => match state.transforms.instantiate(name) {
+            Ok(transform) => Some(transform),
+            Err(e) => {
+                warn!(
+                    message = "failed to instantiate transform",
+                    error = e.to_string()
+                );
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        },
+        None => None,
+    };
+
+    if let Some(hooks) = &state.hooks {
+        hooks.on_connect(client_addr(&state, addr, &headers), stream, &headers);
+    }
+
+    let (client_addr, location, ticket, priority) =
+        match admit(&state, &registry, stream, addr, &headers, relay).await {
+            Ok(admitted) => admitted,
+            Err(response) => return response,
+        };
+
+    let hooks = state.hooks.clone();
+    if let Some(hooks) = &hooks {
+        hooks.on_authenticated(client_addr, stream, &headers);
+    }
+
+    let labels = ClientLabels::from_headers(&headers);
+    let handshakes = state.handshakes.clone();
+    let ws = if relay {
+        ws
+    } else {
+        ws.protocols([envelope::PROTOCOL])
     };
 
     ws.on_failed_upgrade(move |e: Error| {
@@ -116,12 +864,507 @@ async fn websocket_handler(
         )
     })
     .on_upgrade(async move |socket| {
-        let client = ClientConnection::new(client_addr, ticket, socket);
-        state.registry.subscribe(client).await;
+        handshakes.complete(addr);
+        let enveloped = socket.protocol().is_some();
+        let mut client = ClientConnection::new(client_addr, ticket, socket)
+            .with_labels(labels)
+            .with_location(location);
+        if let Some(hooks) = hooks {
+            client = client.with_hooks(hooks);
+        }
+        if relay {
+            client = client.with_relay_envelope();
+        } else if enveloped {
+            client = client.with_sequence_envelope();
+        }
+        if let Some(sampling) = options.sampling {
+            client = client.with_sampling(sampling);
+        }
+        if let Some(max_rate) = options.max_rate {
+            client = client.with_max_rate(max_rate);
+        }
+        if let Some(transform) = transform {
+            client = client.with_transform(transform);
+        }
+        if priority {
+            client = client.with_priority();
+        }
+        registry.subscribe(client).await;
+    })
+    .into_response()
+}
+
+/// Serves GraphQL subscriptions to the requested registry over a websocket, after the same checks
+/// as websocket upgrades. See [`crate::graphql`].
+#[cfg(feature = "graphql")]
+async fn graphql_handler(
+    State(state): State<ServerState>,
+    stream: Option<Path<String>>,
+    protocol: GraphQLProtocol,
+    ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Response {
+    let stream = stream.map(|Path(name)| name);
+    let registry = match &stream {
+        None => state.registry.clone(),
+        Some(name) => match state.streams.get(name) {
+            None => return StatusCode::NOT_FOUND.into_response(),
+            Some(stream) => stream.registry().clone(),
+        },
+    };
+
+    let (_, _, ticket, _) =
+        match admit(&state, &registry, stream.as_deref(), addr, &headers, false).await {
+            Ok(admitted) => admitted,
+            Err(response) => return response,
+        };
+
+    let schema = state.graphql.clone();
+    let handshakes = state.handshakes.clone();
+    ws.protocols(async_graphql::http::ALL_WEBSOCKET_PROTOCOLS)
+        .on_upgrade(async move |socket| {
+            handshakes.complete(addr);
+            let mut data = async_graphql::Data::default();
+            data.insert(registry);
+            GraphQLWebSocket::new(socket, schema, protocol)
+                .with_data(data)
+                .serve()
+                .await;
+            drop(ticket);
+        })
+        .into_response()
+}
+
+/// Streams the requested registry to `SubscribeFlashblocks` calls, after the same checks as
+/// websocket upgrades. See [`crate::grpc`].
+struct FlashblocksService {
+    state: ServerState,
+}
+
+#[tonic::async_trait]
+impl Flashblocks for FlashblocksService {
+    type SubscribeFlashblocksStream = grpc::FlashblockStream;
+
+    async fn subscribe_flashblocks(
+        &self,
+        request: tonic::Request<SubscribeRequest>,
+    ) -> Result<tonic::Response<grpc::FlashblockStream>, tonic::Status> {
+        let state = &self.state;
+        let Some(&ConnectInfo(addr)) = request.extensions().get::<ConnectInfo<SocketAddr>>() else {
+            return Err(tonic::Status::internal("client address unknown"));
+        };
+        let headers = request.metadata().clone().into_headers();
+        let stream = Some(request.into_inner().stream).filter(|name| !name.is_empty());
+
+        let registry = match &stream {
+            None => state.registry.clone(),
+            Some(name) => match state.streams.get(name) {
+                None => return Err(tonic::Status::not_found("unknown stream")),
+                Some(stream) => stream.registry().clone(),
+            },
+        };
+
+        let (_, _, ticket, _) = admit(state, &registry, stream.as_deref(), addr, &headers, false)
+            .await
+            .map_err(|response| grpc::refusal(response.status()))?;
+
+        // The call takes the place of a websocket upgrade as far as the handshake limits go
+        state.handshakes.complete(addr);
+        Ok(tonic::Response::new(grpc::subscribe(
+            registry.register(),
+            ticket,
+            registry.metrics().clone(),
+        )))
+    }
+}
+
+/// Accepts WebTransport sessions on `endpoint` until cancelled. See [`crate::webtransport`].
+#[cfg(feature = "webtransport")]
+async fn serve_webtransport(
+    state: ServerState,
+    endpoint: Endpoint<endpoint_side::Server>,
+    cancellation_token: CancellationToken,
+) {
+    loop {
+        let incoming = tokio::select! {
+            incoming = endpoint.accept() => incoming,
+            _ = cancellation_token.cancelled() => break,
+        };
+
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Ok(request) = incoming.await {
+                webtransport_session(state, request).await;
+            }
+        });
+    }
+
+    endpoint.close(VarInt::from_u32(0), b"shutting down");
+}
+
+/// Streams the requested registry to a WebTransport session, after the same checks as websocket
+/// upgrades.
+#[cfg(feature = "webtransport")]
+async fn webtransport_session(state: ServerState, request: SessionRequest) {
+    let stream = match webtransport::requested_stream(request.path()) {
+        Some(stream) => stream.map(str::to_string),
+        None => return webtransport::refuse(request, StatusCode::NOT_FOUND).await,
+    };
+    let registry = match &stream {
+        None => state.registry.clone(),
+        Some(name) => match state.streams.get(name) {
+            None => return webtransport::refuse(request, StatusCode::NOT_FOUND).await,
+            Some(stream) => stream.registry().clone(),
+        },
+    };
+
+    // Addresses can be IPv4-mapped on a dual-stack socket
+    let remote = request.remote_address();
+    let addr = SocketAddr::new(remote.ip().to_canonical(), remote.port());
+    let headers = webtransport::headers(&request, &state.ip_addr_http_header);
+    let (_, _, ticket, _) =
+        match admit(&state, &registry, stream.as_deref(), addr, &headers, false).await {
+            Ok(admitted) => admitted,
+            Err(response) => return webtransport::refuse(request, response.status()).await,
+        };
+
+    if let Ok(connection) = request.accept().await {
+        webtransport::subscribe(
+            connection,
+            registry.register(),
+            ticket,
+            registry.metrics().clone(),
+        )
+        .await;
+    }
+}
+
+/// Checks that a client may subscribe to `registry`, subject to load shedding and rate limits,
+/// returning its address, where that is, its rate limit ticket and whether it has priority, or
+/// the response to refuse it with.
+async fn admit(
+    state: &ServerState,
+    registry: &Registry,
+    stream: Option<&str>,
+    addr: SocketAddr,
+    headers: &HeaderMap,
+    relay: bool,
+) -> Result<(IpAddr, Location, Ticket, bool), Response> {
+    let client_addr = client_addr(state, addr, headers);
+    if let Some(response) = refuse_banned(state, registry, client_addr) {
+        return Err(response);
+    }
+
+    let location = state
+        .geoip
+        .as_ref()
+        .map(|geoip| geoip.lookup(client_addr))
+        .unwrap_or_default();
+
+    if !origin_allowed(&state.allowed_origins, headers) {
+        registry.metrics().rejected_origins.increment(1);
+        audit::auth_failed(client_addr, stream, "origin");
+
+        return Err(Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body(Body::from(
+                json!({"message": "origin not allowed"}).to_string(),
+            ))
+            .unwrap());
+    }
+
+    if !relay && !state.access_policy.allows(&location) {
+        registry.metrics().geo_policy_denied_requests.increment(1);
+        audit::auth_failed(client_addr, stream, "geo_policy");
+
+        return Err(Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body(Body::from(
+                json!({"message": "not available from this location"}).to_string(),
+            ))
+            .unwrap());
+    }
+
+    // Downstream proxies are trusted with priority, having authenticated with the relay token
+    let mut priority = relay;
+
+    if let Some(auth) = state.basic_auth.as_ref().filter(|_| !relay) {
+        let Some(user) = auth.authenticate(headers) else {
+            registry.metrics().unauthorized_requests.increment(1);
+            audit::auth_failed(client_addr, stream, "basic_auth");
+            record_refusal(state, registry, client_addr);
+
+            return Err(Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .header(WWW_AUTHENTICATE, BASIC_AUTH_CHALLENGE)
+                .body(Body::from(
+                    json!({"message": "authentication required"}).to_string(),
+                ))
+                .unwrap());
+        };
+        priority |= state.priority.users.contains(user);
+    }
+
+    if let Some(authorizer) = state.authorizer.as_ref().filter(|_| !relay) {
+        let decision = authorizer.authorize(client_addr, stream, headers).await;
+        match decision {
+            Decision::Allow => {}
+            Decision::Priority => priority = true,
+            Decision::Deny => registry.metrics().authorizer_denied_requests.increment(1),
+            Decision::FailedOpen | Decision::FailedClosed => {
+                registry.metrics().authorizer_errors.increment(1)
+            }
+        }
+
+        if !decision.allows() {
+            audit::auth_failed(client_addr, stream, "authorizer");
+            return Err(Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(Body::from(json!({"message": "not authorized"}).to_string()))
+                .unwrap());
+        }
+    }
+
+    if state
+        .load_shedder
+        .as_ref()
+        .is_some_and(|shedder| shedder.rejecting_connections())
+    {
+        state.metrics.load_shed_rejected_connections.increment(1);
+
+        return Err(Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body(Body::from(
+                json!({"message": "server overloaded, try again later"}).to_string(),
+            ))
+            .unwrap());
+    }
+
+    let ticket = match acquire(state, client_addr, priority) {
+        Err(e) if priority && state.priority.evict && at_limit(state, true) => {
+            evict_for_ticket(state, client_addr).await.ok_or(e)
+        }
+        acquired => acquired,
+    };
+    let ticket = match ticket {
+        Ok(ticket) => ticket,
+        Err(RateLimitError::Limit { .. })
+            if state.waiting_room.is_some() && at_limit(state, priority) =>
+        {
+            match wait_for_ticket(state, registry, client_addr, priority).await {
+                Ok(ticket) => ticket,
+                Err(response) => return Err(response),
+            }
+        }
+        Err(RateLimitError::Limit { reason }) => {
+            registry.metrics().rate_limited_requests.increment(1);
+            if !priority && at_limit(state, false) && !at_limit(state, true) {
+                registry.metrics().reserved_refusals.increment(1);
+            }
+            record_refusal(state, registry, client_addr);
+
+            return Err(Response::builder()
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .body(Body::from(json!({"message": reason}).to_string()))
+                .unwrap());
+        }
+    };
+
+    // Reserved along with the global slot, so concurrent handshakes can't overshoot the stream's
+    // limit before any of them has registered
+    let reserved = match stream.and_then(|name| state.streams.get(name)) {
+        Some(named) => named.reserve(),
+        None => Ok(None),
+    };
+    let ticket = match reserved {
+        Ok(Some(permit)) => ticket.with_stream_permit(permit),
+        Ok(None) => ticket,
+        Err(RateLimitError::Limit { reason }) => {
+            registry.metrics().rate_limited_requests.increment(1);
+            record_refusal(state, registry, client_addr);
+
+            return Err(Response::builder()
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .body(Body::from(json!({"message": reason}).to_string()))
+                .unwrap());
+        }
+    };
+
+    let ticket = match (&state.asn_limit, location.asn) {
+        (Some(asn_limit), Some(asn)) => match asn_limit.clone().try_acquire(asn) {
+            Ok(permit) => ticket.with_asn_permit(permit),
+            Err(RateLimitError::Limit { reason }) => {
+                registry.metrics().rate_limited_requests.increment(1);
+                registry.metrics().asn_limited_requests.increment(1);
+                record_refusal(state, registry, client_addr);
+
+                return Err(Response::builder()
+                    .status(StatusCode::TOO_MANY_REQUESTS)
+                    .body(Body::from(json!({"message": reason}).to_string()))
+                    .unwrap());
+            }
+        },
+        _ => ticket,
+    };
+
+    let ticket = match &state.waiting_room {
+        Some(room) => ticket.with_release_notice(room.released()),
+        None => ticket,
+    };
+
+    if priority {
+        registry.metrics().priority_connections.increment(1);
+    }
+    Ok((client_addr, location, ticket, priority))
+}
+
+/// Whether the connection limit applying to a client with or without `priority` has been reached.
+fn at_limit(state: &ServerState, priority: bool) -> bool {
+    state
+        .priority
+        .at_limit(state.rate_limiter.occupancy(), priority)
+}
+
+/// Takes a rate limit ticket for a client, unless it doesn't have priority and only connections
+/// reserved for clients with priority are left.
+fn acquire(
+    state: &ServerState,
+    client_addr: IpAddr,
+    priority: bool,
+) -> Result<Ticket, RateLimitError> {
+    if !priority
+        && (state.pending_evictions.load(Ordering::Relaxed) > 0
+            || (state.priority.reserved > 0 && at_limit(state, false)))
+    {
+        return Err(RateLimitError::Limit {
+            reason: "remaining connections are reserved".to_string(),
+        });
+    }
+    state.rate_limiter.clone().try_acquire(client_addr)
+}
+
+/// Evicts the client without priority that is furthest behind, on any stream, for a client with
+/// priority refused because the global connection limit was reached, and takes its connection.
+async fn evict_for_ticket(state: &ServerState, client_addr: IpAddr) -> Option<Ticket> {
+    let candidate = std::iter::once(&state.registry)
+        .chain(state.streams.values().map(Stream::registry))
+        .filter_map(Registry::eviction_candidate)
+        .max_by_key(EvictionCandidate::behind)?;
+
+    state.pending_evictions.fetch_add(1, Ordering::Relaxed);
+    candidate.evict();
+    state.metrics.priority_evictions.increment(1);
+    let ticket = tokio::time::timeout(EVICTION_WAIT, async {
+        loop {
+            if let Ok(ticket) = state.rate_limiter.clone().try_acquire(client_addr) {
+                return ticket;
+            }
+            tokio::time::sleep(EVICTION_RETRY_INTERVAL).await;
+        }
     })
+    .await;
+    state.pending_evictions.fetch_sub(1, Ordering::Relaxed);
+
+    ticket.ok()
+}
+
+/// Waits in the waiting room for a connection to be released, for a client refused because the
+/// connection limit applying to it was reached.
+async fn wait_for_ticket(
+    state: &ServerState,
+    registry: &Registry,
+    client_addr: IpAddr,
+    priority: bool,
+) -> Result<Ticket, Response> {
+    let room = state.waiting_room.as_ref().unwrap();
+    let started = Instant::now();
+    registry.metrics().waiting_room_size.increment(1);
+    let result = room
+        .wait(|| acquire(state, client_addr, priority).ok())
+        .await;
+    registry.metrics().waiting_room_size.decrement(1);
+
+    let message = match result {
+        Ok(ticket) => {
+            registry
+                .metrics()
+                .waiting_room_wait
+                .record(started.elapsed().as_secs_f64());
+            return Ok(ticket);
+        }
+        Err(WaitError::Full) => "server at capacity and waiting room full",
+        Err(WaitError::Expired) => "server at capacity, gave up waiting",
+    };
+    registry.metrics().rate_limited_requests.increment(1);
+    registry.metrics().waiting_room_refusals.increment(1);
+    record_refusal(state, registry, client_addr);
+
+    Err(Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .body(Body::from(json!({"message": message}).to_string()))
+        .unwrap())
+}
+
+/// A refusal for `client_addr` if it's banned.
+fn refuse_banned(
+    state: &ServerState,
+    registry: &Registry,
+    client_addr: IpAddr,
+) -> Option<Response> {
+    let remaining = state.bans.as_ref()?.banned(client_addr)?;
+    registry.metrics().banned_requests.increment(1);
+
+    Some(
+        Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .header(RETRY_AFTER, remaining.as_secs().max(1))
+            .body(Body::from(
+                json!({"message": "too many refused requests, try again later"}).to_string(),
+            ))
+            .unwrap(),
+    )
 }
 
-fn extract_addr(header: &HeaderValue, fallback: IpAddr) -> IpAddr {
+/// Counts a `401` or `429` refusal of `client_addr` towards banning it.
+fn record_refusal(state: &ServerState, registry: &Registry, client_addr: IpAddr) {
+    if let Some(bans) = &state.bans {
+        if bans.record_refusal(client_addr).is_some() {
+            registry.metrics().auto_bans.increment(1);
+        }
+    }
+}
+
+/// Whether the request's `Origin` is one of `allowed`, or doesn't need to be: when any origin is
+/// allowed, or the request has none.
+fn origin_allowed(allowed: &[String], headers: &HeaderMap) -> bool {
+    if allowed.is_empty() {
+        return true;
+    }
+
+    match headers.get(ORIGIN) {
+        None => true,
+        Some(origin) => origin
+            .to_str()
+            .is_ok_and(|origin| allowed.contains(&normalize_origin(origin))),
+    }
+}
+
+fn normalize_origin(origin: &str) -> String {
+    origin.trim().trim_end_matches('/').to_ascii_lowercase()
+}
+
+/// The client's address, from the IP address header if it has one.
+fn client_addr(state: &ServerState, addr: SocketAddr, headers: &HeaderMap) -> IpAddr {
+    match headers.get(&state.ip_addr_http_header) {
+        None => addr.ip(),
+        Some(value) => extract_addr(value, addr.ip()),
+    }
+}
+
+/// The client's address from the last entry of the IP address header, or `fallback` if there
+/// isn't a valid one.
+pub fn extract_addr(header: &HeaderValue, fallback: IpAddr) -> IpAddr {
     if header.is_empty() {
         return fallback;
     }
@@ -131,7 +1374,7 @@ fn extract_addr(header: &HeaderValue, fallback: IpAddr) -> IpAddr {
             let raw_value = header_value
                 .split(',')
                 .map(|ip| ip.trim().to_string())
-                .last();
+                .next_back();
 
             if let Some(raw_value) = raw_value {
                 return raw_value.parse::<IpAddr>().unwrap_or(fallback);
@@ -171,4 +1414,30 @@ mod tests {
         test("400.0.0.1", fb);
         test("120.0.0.1.0", fb);
     }
+
+    #[test]
+    fn test_origin_allowed() {
+        let allowed = vec![normalize_origin("https://App.example.com/")];
+        let headers = |origin: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(ORIGIN, HeaderValue::from_str(origin).unwrap());
+            headers
+        };
+
+        assert!(origin_allowed(&allowed, &HeaderMap::new()));
+        assert!(origin_allowed(
+            &allowed,
+            &headers("https://app.example.com")
+        ));
+        assert!(!origin_allowed(
+            &allowed,
+            &headers("https://evil.example.com")
+        ));
+        assert!(!origin_allowed(
+            &allowed,
+            &headers("http://app.example.com")
+        ));
+        assert!(!origin_allowed(&allowed, &headers("null")));
+        assert!(origin_allowed(&[], &headers("https://evil.example.com")));
+    }
 }
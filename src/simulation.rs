@@ -0,0 +1,454 @@
+//! Deterministic simulation of the fan-out pipeline. Scripted upstreams publish to a registry and
+//! scripted clients drain it, on a single-threaded runtime with tokio's clock paused: time only
+//! moves when every task is waiting on a timer, and then jumps straight to the next one. A
+//! scenario therefore plays out the same way on every run however loaded the machine is, and
+//! minutes of simulated time take milliseconds.
+
+use crate::registry::{Delivery, Registry};
+use crate::subscriber::UpstreamStatus;
+use bytes::Bytes;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::{sleep_until, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// Something a scripted upstream does, at a time relative to the start of the simulation.
+#[derive(Clone, Copy, Debug)]
+enum UpstreamEvent {
+    Connect,
+    /// Publish a message, if connected.
+    Message,
+    Disconnect,
+}
+
+/// How a scripted client reads its queue.
+#[derive(Clone, Copy, Debug, Default)]
+struct ClientBehavior {
+    /// Time taken to write each message to the client.
+    per_message: Duration,
+    /// Stop reading for a while after this many messages.
+    stall: Option<(usize, Duration)>,
+    /// Disconnect after this many messages.
+    leave_after: Option<usize>,
+}
+
+impl ClientBehavior {
+    fn reader(per_message_ms: u64) -> Self {
+        Self {
+            per_message: Duration::from_millis(per_message_ms),
+            ..Default::default()
+        }
+    }
+
+    fn stalls(mut self, after: usize, for_ms: u64) -> Self {
+        self.stall = Some((after, Duration::from_millis(for_ms)));
+        self
+    }
+
+    fn leaves_after(mut self, messages: usize) -> Self {
+        self.leave_after = Some(messages);
+        self
+    }
+}
+
+/// What a scripted client saw, in simulated milliseconds since the start of the simulation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum ClientEvent {
+    Received(u64, String),
+    Lagged(u64, u64),
+    Overwritten(u64, u64),
+    Left(u64),
+}
+
+type Trace = Arc<Mutex<Vec<ClientEvent>>>;
+
+struct Simulation {
+    registry: Registry,
+    started_at: Instant,
+    token: CancellationToken,
+    traces: Vec<Trace>,
+}
+
+impl Simulation {
+    fn new(registry: Registry) -> Self {
+        Self {
+            registry,
+            started_at: Instant::now(),
+            token: CancellationToken::new(),
+            traces: Vec::new(),
+        }
+    }
+
+    fn at(&self, ms: u64) -> Instant {
+        self.started_at + Duration::from_millis(ms)
+    }
+
+    fn now_ms(started_at: Instant) -> u64 {
+        started_at.elapsed().as_millis() as u64
+    }
+
+    /// Plays `script`, in time order, until it ends or the simulation is shut down. Messages are
+    /// named after the upstream and their position in its stream, e.g. `a3`.
+    fn upstream(
+        &self,
+        name: &'static str,
+        script: Vec<(u64, UpstreamEvent)>,
+    ) -> Arc<UpstreamStatus> {
+        let status = Arc::new(UpstreamStatus::new(
+            format!("ws://{name}.sim").parse().unwrap(),
+        ));
+        let registry = self.registry.clone();
+        let token = self.token.clone();
+        let mut events: Vec<_> = script
+            .into_iter()
+            .map(|(ms, event)| (self.at(ms), event))
+            .collect();
+        events.sort_by_key(|(at, _)| *at);
+
+        let upstream = status.clone();
+        tokio::spawn(async move {
+            let mut published = 0;
+            for (at, event) in events {
+                if !wait_until(at, &token).await {
+                    return;
+                }
+
+                match event {
+                    UpstreamEvent::Connect => upstream.set_connected(true),
+                    UpstreamEvent::Disconnect => upstream.set_connected(false),
+                    UpstreamEvent::Message if upstream.is_connected() => {
+                        upstream.record_message();
+                        registry.publish(Bytes::from(format!("{name}{published}")));
+                        published += 1;
+                    }
+                    UpstreamEvent::Message => {}
+                }
+            }
+        });
+
+        status
+    }
+
+    /// Registers a client that reads its queue according to `behavior`, returning its index.
+    fn client(&mut self, behavior: ClientBehavior) -> usize {
+        let trace = Trace::default();
+        self.traces.push(trace.clone());
+
+        let mut subscription = self.registry.register();
+        let started_at = self.started_at;
+        let batch_limit = self.registry.buffer_size().max(1);
+
+        tokio::spawn(async move {
+            let mut batch = Vec::with_capacity(batch_limit);
+            let mut received = 0;
+
+            loop {
+                batch.clear();
+                let event = match subscription.recv_many(&mut batch, batch_limit).await {
+                    Delivery::Messages(_) => None,
+                    Delivery::Lagged(dropped) => {
+                        Some(ClientEvent::Lagged(Self::now_ms(started_at), dropped))
+                    }
+                    Delivery::Overwritten(dropped) => {
+                        Some(ClientEvent::Overwritten(Self::now_ms(started_at), dropped))
+                    }
+                };
+                if let Some(event) = event {
+                    trace.lock().unwrap().push(event);
+                    continue;
+                }
+
+                for msg in batch.drain(..) {
+                    tokio::time::sleep(behavior.per_message).await;
+                    subscription.record_delivered(&msg);
+                    let payload = String::from_utf8(msg.frame.into_data().to_vec()).unwrap();
+                    trace
+                        .lock()
+                        .unwrap()
+                        .push(ClientEvent::Received(Self::now_ms(started_at), payload));
+                    received += 1;
+
+                    if behavior.leave_after == Some(received) {
+                        trace
+                            .lock()
+                            .unwrap()
+                            .push(ClientEvent::Left(Self::now_ms(started_at)));
+                        return;
+                    }
+                    if let Some((after, duration)) = behavior.stall {
+                        if received == after {
+                            tokio::time::sleep(duration).await;
+                        }
+                    }
+                }
+            }
+        });
+
+        self.traces.len() - 1
+    }
+
+    /// Advances the simulation to `ms` milliseconds after it started.
+    async fn run_until(&self, ms: u64) {
+        sleep_until(self.at(ms)).await;
+    }
+
+    fn trace(&self, client: usize) -> Vec<ClientEvent> {
+        self.traces[client].lock().unwrap().clone()
+    }
+
+    fn received(&self, client: usize) -> Vec<String> {
+        self.trace(client)
+            .into_iter()
+            .filter_map(|event| match event {
+                ClientEvent::Received(_, payload) => Some(payload),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn traces(&self) -> Vec<Vec<ClientEvent>> {
+        (0..self.traces.len()).map(|i| self.trace(i)).collect()
+    }
+}
+
+impl Drop for Simulation {
+    fn drop(&mut self) {
+        self.token.cancel();
+    }
+}
+
+/// Sleeps until `at`, returning false if `token` is cancelled first.
+async fn wait_until(at: Instant, token: &CancellationToken) -> bool {
+    tokio::select! {
+        biased;
+        _ = token.cancelled() => false,
+        _ = sleep_until(at) => true,
+    }
+}
+
+/// A script that connects at the start of the simulation and publishes `count` messages, one
+/// every `interval_ms` starting at `start_ms`.
+fn stream(start_ms: u64, count: u64, interval_ms: u64) -> Vec<(u64, UpstreamEvent)> {
+    let mut script = vec![(0, UpstreamEvent::Connect)];
+    script.extend((0..count).map(|i| (start_ms + i * interval_ms, UpstreamEvent::Message)));
+    script
+}
+
+/// Runs `scenario` twice on fresh paused runtimes, asserting that it played out identically, and
+/// returns the clients' traces.
+fn run_deterministically<F, Fut>(scenario: F) -> Vec<Vec<ClientEvent>>
+where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = Vec<Vec<ClientEvent>>>,
+{
+    let run = || {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .start_paused(true)
+            .build()
+            .unwrap()
+            .block_on(scenario())
+    };
+
+    let first = run();
+    assert_eq!(first, run(), "scenario is not deterministic");
+    first
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::load_shedding::{LoadShedConfig, LoadShedder, ShedLevel};
+    use crate::metrics::Metrics;
+    use crate::rate_limit::InMemoryRateLimit;
+    use crate::registry::LagStrategy;
+    use crate::server::{ReadinessConfig, Server};
+    use std::net::SocketAddr;
+
+    fn registry(buffer_size: usize) -> Registry {
+        Registry::new(buffer_size, 2, Arc::new(Metrics::default()))
+    }
+
+    fn server(registry: &Registry, upstreams: Vec<Arc<UpstreamStatus>>) -> Server {
+        Server::new(
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            registry.clone(),
+            Arc::new(Metrics::default()),
+            Arc::new(InMemoryRateLimit::new(100, 10)),
+            "X-Forwarded-For".to_string(),
+            upstreams,
+            ReadinessConfig {
+                max_upstream_age: Some(Duration::from_millis(100)),
+                max_capacity: None,
+            },
+        )
+    }
+
+    #[test]
+    fn test_stalled_client_skips_ahead() {
+        let traces = run_deterministically(|| async {
+            let mut sim = Simulation::new(registry(4));
+            let fast = sim.client(ClientBehavior::reader(1));
+            let stalled = sim.client(ClientBehavior::reader(1).stalls(2, 100));
+            sim.upstream("a", stream(10, 20, 10));
+
+            sim.run_until(300).await;
+            assert_eq!(sim.received(fast).len(), 20);
+            assert_eq!(sim.received(stalled).len(), 20 - 10);
+            sim.traces()
+        });
+
+        // The stalled client drops its queue along with the messages that didn't fit in it, then
+        // resumes with the next message published
+        let stalled = &traces[1];
+        assert_eq!(stalled[0], ClientEvent::Received(11, "a0".to_string()));
+        assert_eq!(stalled[1], ClientEvent::Received(21, "a1".to_string()));
+        assert_eq!(stalled[2], ClientEvent::Lagged(121, 10));
+        assert_eq!(stalled[3], ClientEvent::Received(131, "a12".to_string()));
+    }
+
+    #[test]
+    fn test_overwrite_keeps_newest_messages() {
+        let traces = run_deterministically(|| async {
+            let mut sim = Simulation::new(registry(4).with_lag_strategy(LagStrategy::Overwrite));
+            sim.client(ClientBehavior::reader(1).stalls(1, 100));
+            sim.upstream("a", stream(10, 15, 10));
+
+            sim.run_until(300).await;
+            sim.traces()
+        });
+
+        // The client catches up on the four newest messages when it resumes
+        assert_eq!(
+            traces[0],
+            vec![
+                ClientEvent::Received(11, "a0".to_string()),
+                ClientEvent::Overwritten(111, 6),
+                ClientEvent::Received(112, "a7".to_string()),
+                ClientEvent::Received(113, "a8".to_string()),
+                ClientEvent::Received(114, "a9".to_string()),
+                ClientEvent::Received(115, "a10".to_string()),
+                ClientEvent::Received(121, "a11".to_string()),
+                ClientEvent::Received(131, "a12".to_string()),
+                ClientEvent::Received(141, "a13".to_string()),
+                ClientEvent::Received(151, "a14".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_load_shedding_drops_slowest_client() {
+        let traces = run_deterministically(|| async {
+            let registry = registry(10);
+            let mut sim = Simulation::new(registry.clone());
+            sim.client(ClientBehavior::reader(1));
+            sim.client(ClientBehavior::reader(1).stalls(1, 1_000));
+            sim.upstream("a", stream(10, 50, 10));
+
+            let shedder = Arc::new(LoadShedder::new(LoadShedConfig {
+                max_lag: None,
+                max_queue_fraction: Some(0.5),
+                sustained_checks: 2,
+                lag_drop_fraction: 0.5,
+            }));
+            let task_shedder = shedder.clone();
+            let token = sim.token.clone();
+            tokio::spawn(async move {
+                task_shedder
+                    .run(
+                        registry,
+                        Arc::new(Metrics::default()),
+                        Duration::from_millis(20),
+                        token,
+                    )
+                    .await
+            });
+
+            sim.run_until(200).await;
+            assert_eq!(shedder.level(), ShedLevel::LagDrop);
+
+            sim.run_until(1_200).await;
+            assert_eq!(shedder.level(), ShedLevel::None);
+            sim.traces()
+        });
+
+        // The healthy client is unaffected, while the stalled client's backlog was dropped
+        assert_eq!(traces[0].len(), 50);
+        assert!(traces[1]
+            .iter()
+            .any(|event| matches!(event, ClientEvent::Lagged(..))));
+    }
+
+    #[test]
+    fn test_upstream_failover() {
+        let traces = run_deterministically(|| async {
+            let registry = registry(4);
+            let mut sim = Simulation::new(registry.clone());
+            let client = sim.client(ClientBehavior::reader(1));
+            let mut primary = stream(10, 100, 20);
+            primary.push((200, UpstreamEvent::Disconnect));
+            let primary = sim.upstream("a", primary);
+            let mut standby = stream(20, 100, 20);
+            standby.push((410, UpstreamEvent::Disconnect));
+            let standby = sim.upstream("b", standby);
+            let server = server(&registry, vec![primary, standby]);
+
+            // Still ready on the standby once the primary is gone
+            sim.run_until(300).await;
+            assert!(server.readiness_failures().is_empty());
+
+            // Not ready once neither upstream is connected
+            sim.run_until(400).await;
+            assert!(server.readiness_failures().is_empty());
+            sim.run_until(420).await;
+            assert_eq!(
+                server.readiness_failures(),
+                vec!["no upstream has delivered a recent message"]
+            );
+
+            let received = sim.received(client);
+            assert_eq!(received.iter().filter(|m| m.starts_with('a')).count(), 10);
+            assert_eq!(received.iter().filter(|m| m.starts_with('b')).count(), 20);
+            sim.traces()
+        });
+
+        assert!(!traces[0]
+            .iter()
+            .any(|event| matches!(event, ClientEvent::Lagged(..))));
+    }
+
+    #[test]
+    fn test_shutdown_ordering() {
+        run_deterministically(|| async {
+            let registry = registry(4);
+            let mut sim = Simulation::new(registry.clone());
+            let client = sim.client(ClientBehavior::reader(1));
+            let leaving = sim.client(ClientBehavior::reader(1).leaves_after(3));
+            let upstream = sim.upstream("a", stream(10, 100, 10));
+            let notice = CancellationToken::new();
+            let server = server(&registry, vec![upstream]).with_shutdown_notice(notice.clone());
+
+            sim.run_until(100).await;
+            assert!(server.readiness_failures().is_empty());
+            assert_eq!(registry.client_count(), 1);
+            assert_eq!(sim.trace(leaving).last(), Some(&ClientEvent::Left(31)));
+
+            // The shutdown notice makes the proxy unready while it keeps serving
+            notice.cancel();
+            assert_eq!(server.readiness_failures(), vec!["shutting down"]);
+            let before_delay = sim.received(client).len();
+            sim.run_until(205).await;
+            assert_eq!(sim.received(client).len(), before_delay + 11);
+
+            // Once the delay is over the upstreams stop, and clients have every message
+            // published before then
+            sim.token.cancel();
+            let published = sim.received(client).len();
+            sim.run_until(300).await;
+            assert_eq!(sim.received(client).len(), published);
+            assert_eq!(sim.received(client).last().unwrap(), "a19");
+
+            sim.traces()
+        });
+    }
+}
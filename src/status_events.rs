@@ -0,0 +1,123 @@
+//! Control messages announcing to clients that the proxy has lost or regained its upstreams, so
+//! that applications can show that the feed is degraded rather than silently receiving nothing.
+
+use crate::registry::Registry;
+use crate::subscriber::UpstreamStatus;
+use bytes::Bytes;
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+/// Whether the proxy is connected to any of its upstreams.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpstreamHealth {
+    Healthy,
+    Degraded,
+}
+
+impl UpstreamHealth {
+    pub fn of(upstreams: &[Arc<UpstreamStatus>]) -> Self {
+        if upstreams.iter().any(|upstream| upstream.is_connected()) {
+            UpstreamHealth::Healthy
+        } else {
+            UpstreamHealth::Degraded
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UpstreamHealth::Healthy => "healthy",
+            UpstreamHealth::Degraded => "degraded",
+        }
+    }
+
+    /// The control message sent to clients, e.g. `{"status":"degraded","type":"proxy_status"}`.
+    pub fn message(&self) -> Bytes {
+        Bytes::from(json!({"type": "proxy_status", "status": self.as_str()}).to_string())
+    }
+}
+
+/// Checks the upstreams every `interval` and publishes a control message to the registry's
+/// clients whenever their health changes. The upstreams are assumed healthy to begin with, so
+/// nothing is sent until they are first found to be disconnected.
+pub async fn announce(
+    registry: Registry,
+    upstreams: Vec<Arc<UpstreamStatus>>,
+    interval: Duration,
+    token: CancellationToken,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    let mut announced = UpstreamHealth::Healthy;
+
+    loop {
+        tokio::select! {
+            _ = token.cancelled() => return,
+            _ = ticker.tick() => {}
+        }
+
+        let health = UpstreamHealth::of(&upstreams);
+        if health == announced {
+            continue;
+        }
+
+        let clients = registry.publish(health.message());
+        info!(
+            message = "announced upstream health to clients",
+            status = health.as_str(),
+            clients = clients
+        );
+        announced = health;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Metrics;
+    use crate::registry::Delivery;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_announce() {
+        let registry = Registry::new(4, 1, Arc::new(Metrics::default()));
+        let mut subscription = registry.register();
+        let upstreams = vec![
+            Arc::new(UpstreamStatus::new("ws://a.invalid".parse().unwrap())),
+            Arc::new(UpstreamStatus::new("ws://b.invalid".parse().unwrap())),
+        ];
+        upstreams[0].set_connected(true);
+
+        let token = CancellationToken::new();
+        tokio::spawn(announce(
+            registry.clone(),
+            upstreams.clone(),
+            Duration::from_secs(1),
+            token.clone(),
+        ));
+
+        // Losing one of two upstreams isn't announced
+        upstreams[0].set_connected(false);
+        upstreams[1].set_connected(true);
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        upstreams[1].set_connected(false);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        upstreams[0].set_connected(true);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        token.cancel();
+
+        let mut batch = Vec::new();
+        assert!(matches!(
+            subscription.recv_many(&mut batch, 4).await,
+            Delivery::Messages(2)
+        ));
+        assert_eq!(
+            batch[0].frame.clone().into_data(),
+            r#"{"status":"degraded","type":"proxy_status"}"#
+        );
+        assert_eq!(
+            batch[1].frame.clone().into_data(),
+            r#"{"status":"healthy","type":"proxy_status"}"#
+        );
+    }
+}
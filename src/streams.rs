@@ -0,0 +1,279 @@
+//! Named streams, served at `/ws/{stream}` alongside the default stream at `/ws`, so that one
+//! deployment can proxy several upstream feeds, e.g. flashblocks for more than one chain. Each
+//! stream has its own registry, and so its own client queues, buffer size and connection limit.
+
+use crate::projection::Projection;
+use crate::rate_limit::RateLimitError;
+use crate::registry::Registry;
+use crate::subscriber::UpstreamStatus;
+use axum::http::Uri;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// A stream as configured with `--stream`, a comma separated list of `key=value` pairs:
+/// `name=base,upstream=wss://a.example/ws,upstream=wss://b.example/ws,buffer=50,max-connections=1000`.
+/// `name` and either at least one `upstream` or a `source` are required.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StreamConfig {
+    pub name: String,
+    pub upstreams: Vec<Uri>,
+    /// Stream to republish instead of subscribing to upstreams: `default` or an earlier stream's
+    /// name. See [`crate::tiers`].
+    pub source: Option<String>,
+    /// How long to hold back the source's messages.
+    pub delay: Option<Duration>,
+    /// Applied to the source's messages, from `project=<projection>` (repeatable).
+    pub projections: Vec<Projection>,
+    /// Overrides `--message-buffer-size` for this stream.
+    pub buffer_size: Option<usize>,
+    /// Most clients that may be connected to this stream at once.
+    pub max_connections: Option<usize>,
+    /// Name of the transform to run on every message published to the stream, from
+    /// `transform=<name>`. See [`crate::transform`].
+    pub transform: Option<String>,
+}
+
+impl FromStr for StreamConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut name = None;
+        let mut upstreams = Vec::new();
+        let mut buffer_size = None;
+        let mut max_connections = None;
+        let mut source = None;
+        let mut delay = None;
+        let mut projections = Vec::new();
+        let mut transform = None;
+
+        for pair in s.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("missing '=' in {pair}"))?;
+
+            match key.trim() {
+                "name" => name = Some(value.trim().to_string()),
+                "upstream" => upstreams.push(
+                    value
+                        .trim()
+                        .parse()
+                        .map_err(|e| format!("invalid upstream {value}: {e}"))?,
+                ),
+                "buffer" => {
+                    buffer_size = Some(
+                        value
+                            .trim()
+                            .parse()
+                            .map_err(|e| format!("invalid buffer {value}: {e}"))?,
+                    )
+                }
+                "max-connections" => {
+                    max_connections = Some(
+                        value
+                            .trim()
+                            .parse()
+                            .map_err(|e| format!("invalid max-connections {value}: {e}"))?,
+                    )
+                }
+                "source" => source = Some(value.trim().to_string()),
+                "delay-ms" => {
+                    delay = Some(Duration::from_millis(
+                        value
+                            .trim()
+                            .parse()
+                            .map_err(|e| format!("invalid delay-ms {value}: {e}"))?,
+                    ))
+                }
+                "project" => projections.push(value.trim().parse()?),
+                "transform" => transform = Some(value.trim().to_string()),
+                key => return Err(format!("unknown stream setting {key}")),
+            }
+        }
+
+        let name = name.ok_or("missing stream name")?;
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(format!(
+                "stream name {name:?} must be letters, digits, '-' and '_'"
+            ));
+        }
+        match (&source, upstreams.is_empty()) {
+            (None, true) => return Err(format!("stream {name} has no upstream or source")),
+            (Some(_), false) => {
+                return Err(format!(
+                    "stream {name} can't have both upstreams and a source"
+                ))
+            }
+            _ => {}
+        }
+        if (delay.is_some() || !projections.is_empty()) && source.is_none() {
+            return Err(format!(
+                "stream {name} can only be delayed or projected with a source"
+            ));
+        }
+
+        Ok(Self {
+            name,
+            upstreams,
+            buffer_size,
+            max_connections,
+            source,
+            delay,
+            projections,
+            transform,
+        })
+    }
+}
+
+/// A named stream's registry, limits and upstreams, as served by [`crate::server::Server`].
+#[derive(Clone)]
+pub struct Stream {
+    registry: Registry,
+    max_connections: Option<usize>,
+    slots: Option<Arc<Semaphore>>,
+    upstreams: Vec<Arc<UpstreamStatus>>,
+}
+
+impl Stream {
+    pub fn new(registry: Registry) -> Self {
+        Self {
+            registry,
+            max_connections: None,
+            slots: None,
+            upstreams: Vec::new(),
+        }
+    }
+
+    /// Refuse new clients while this many are connected to the stream.
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = Some(max_connections);
+        self.slots = Some(Arc::new(Semaphore::new(max_connections)));
+        self
+    }
+
+    /// Report these upstreams as the stream's on `/status`.
+    pub fn with_upstreams(mut self, upstreams: Vec<Arc<UpstreamStatus>>) -> Self {
+        self.upstreams = upstreams;
+        self
+    }
+
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    pub fn max_connections(&self) -> Option<usize> {
+        self.max_connections
+    }
+
+    pub fn upstreams(&self) -> &[Arc<UpstreamStatus>] {
+        &self.upstreams
+    }
+
+    /// Takes one of the stream's connection slots, held from the handshake until the client
+    /// disconnects, or `None` if the stream has no connection limit.
+    pub fn reserve(&self) -> Result<Option<OwnedSemaphorePermit>, RateLimitError> {
+        let Some(slots) = &self.slots else {
+            return Ok(None);
+        };
+        match slots.clone().try_acquire_owned() {
+            Ok(permit) => Ok(Some(permit)),
+            Err(_) => Err(RateLimitError::Limit {
+                reason: "stream is at its connection limit".to_string(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Metrics;
+
+    #[test]
+    fn test_parse_stream_config() {
+        let config: StreamConfig =
+            "name=base, upstream=wss://a.example/ws, upstream=wss://b.example/ws, buffer=50, max-connections=1000"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            config,
+            StreamConfig {
+                name: "base".to_string(),
+                upstreams: vec![
+                    "wss://a.example/ws".parse().unwrap(),
+                    "wss://b.example/ws".parse().unwrap(),
+                ],
+                buffer_size: Some(50),
+                max_connections: Some(1000),
+                source: None,
+                delay: None,
+                projections: Vec::new(),
+                transform: None,
+            }
+        );
+
+        let config: StreamConfig =
+            "name=free,source=default,delay-ms=500,project=hash:/diff/transactions,project=omit:/diff/withdrawals"
+                .parse()
+                .unwrap();
+        assert!(config.upstreams.is_empty());
+        assert_eq!(config.source.as_deref(), Some("default"));
+        assert_eq!(config.delay, Some(Duration::from_millis(500)));
+        assert_eq!(
+            config.projections,
+            [
+                Projection::Hash("/diff/transactions".to_string()),
+                Projection::Omit("/diff/withdrawals".to_string()),
+            ]
+        );
+
+        let config: StreamConfig = "name=raw,upstream=ws://localhost:8546".parse().unwrap();
+        assert_eq!(config.buffer_size, None);
+        assert_eq!(config.max_connections, None);
+        assert_eq!(config.transform, None);
+
+        let config: StreamConfig = "name=redacted,source=default,transform=redact"
+            .parse()
+            .unwrap();
+        assert_eq!(config.transform.as_deref(), Some("redact"));
+
+        for invalid in [
+            "upstream=ws://localhost:8546",
+            "name=raw",
+            "name=,upstream=ws://localhost:8546",
+            "name=a/b,upstream=ws://localhost:8546",
+            "name=raw,upstream=ws://localhost:8546,buffer=lots",
+            "name=raw,upstream=ws://localhost:8546,colour=blue",
+            "name=raw,upstream",
+            "name=free,source=default,upstream=ws://localhost:8546",
+            "name=free,upstream=ws://localhost:8546,delay-ms=500",
+            "name=free,source=default,delay-ms=soon",
+            "name=free,source=default,project=strip:/diff",
+            "name=free,upstream=ws://localhost:8546,project=count:/diff/transactions",
+        ] {
+            assert!(invalid.parse::<StreamConfig>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_stream_reserve() {
+        let registry = Registry::new(4, 1, Arc::new(Metrics::default()));
+        let stream = Stream::new(registry.clone()).with_max_connections(2);
+
+        let first = stream.reserve().unwrap();
+        assert!(first.is_some());
+        let _second = stream.reserve().unwrap();
+        assert!(stream.reserve().is_err());
+
+        // Slots are freed when the permit is dropped, however many clients are registered
+        drop(first);
+        let _subscription = registry.register();
+        assert!(stream.reserve().unwrap().is_some());
+        assert!(Stream::new(registry).reserve().unwrap().is_none());
+    }
+}
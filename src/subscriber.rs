@@ -1,27 +1,90 @@
-use crate::metrics::Metrics;
+#[cfg(feature = "chaos")]
+use crate::chaos::Chaos;
+use crate::error_reporting;
+use crate::filter::{self, MessageFilter};
+use crate::log_sampling::{self, EventClass};
+use crate::metrics::{Cadence, Metrics};
+use crate::panic_hook;
+use crate::relay::{self, SequenceTracker};
 use axum::http::Uri;
 use backoff::{backoff::Backoff, ExponentialBackoff};
-use futures::StreamExt;
-use std::sync::Arc;
+use bytes::Bytes;
+use futures::{FutureExt, StreamExt};
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::select;
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::header::AUTHORIZATION;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
 use tokio_tungstenite::{connect_async, tungstenite::Error};
 use tokio_util::sync::CancellationToken;
 use tracing::{error, info, trace, warn};
 
+/// Connection state of a single upstream, shared with the server for status reporting.
+#[derive(Debug)]
+pub struct UpstreamStatus {
+    uri: Uri,
+    connected: AtomicBool,
+    last_message_at: Mutex<Option<Instant>>,
+}
+
+impl UpstreamStatus {
+    pub fn new(uri: Uri) -> Self {
+        Self {
+            uri,
+            connected: AtomicBool::new(false),
+            last_message_at: Mutex::new(None),
+        }
+    }
+
+    pub fn uri(&self) -> &Uri {
+        &self.uri
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    /// Time since the last message was received from this upstream, if any has been received.
+    pub fn last_message_age(&self) -> Option<Duration> {
+        self.last_message_at
+            .lock()
+            .unwrap()
+            .map(|received_at| received_at.elapsed())
+    }
+
+    pub(crate) fn set_connected(&self, connected: bool) {
+        self.connected.store(connected, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_message(&self) {
+        *self.last_message_at.lock().unwrap() = Some(Instant::now());
+    }
+}
+
 pub struct WebsocketSubscriber<F>
 where
-    F: Fn(String) + Send + Sync + 'static,
+    F: Fn(Bytes) + Send + Sync + 'static,
 {
     uri: Uri,
     handler: F,
     backoff: ExponentialBackoff,
     metrics: Arc<Metrics>,
+    status: Arc<UpstreamStatus>,
+    consecutive_failures: u32,
+    relay_token: Option<String>,
+    sequence: SequenceTracker,
+    filters: Vec<MessageFilter>,
+    #[cfg(feature = "chaos")]
+    chaos: Option<Chaos>,
 }
 
 impl<F> WebsocketSubscriber<F>
 where
-    F: Fn(String) + Send + Sync + 'static,
+    F: Fn(Bytes) + Send + Sync + 'static,
 {
     pub fn new(uri: Uri, handler: F, max_interval: u64, metrics: Arc<Metrics>) -> Self {
         let backoff = ExponentialBackoff {
@@ -32,6 +95,13 @@ where
         };
 
         Self {
+            status: Arc::new(UpstreamStatus::new(uri.clone())),
+            consecutive_failures: 0,
+            relay_token: None,
+            sequence: SequenceTracker::default(),
+            filters: Vec::new(),
+            #[cfg(feature = "chaos")]
+            chaos: None,
             uri,
             handler,
             backoff,
@@ -39,6 +109,31 @@ where
         }
     }
 
+    /// Treat the upstream as another instance of this proxy, connecting to it with `token` as a
+    /// bearer token and reading messages in the relay envelope. See [`crate::relay`].
+    pub fn with_relay_token(mut self, token: String) -> Self {
+        self.relay_token = Some(token);
+        self
+    }
+
+    /// Drop messages matching any of `filters` instead of passing them to the handler. See
+    /// [`crate::filter`].
+    pub fn with_filters(mut self, filters: Vec<MessageFilter>) -> Self {
+        self.filters = filters;
+        self
+    }
+
+    /// Inject faults into the messages received from the upstream. See [`crate::chaos`].
+    #[cfg(feature = "chaos")]
+    pub fn with_chaos(mut self, chaos: Chaos) -> Self {
+        self.chaos = Some(chaos);
+        self
+    }
+
+    pub fn status(&self) -> Arc<UpstreamStatus> {
+        self.status.clone()
+    }
+
     pub async fn run(&mut self, token: CancellationToken) {
         info!(
             message = "starting upstream subscription",
@@ -51,9 +146,11 @@ where
                         message = "cancelled upstream subscription",
                         uri = self.uri.to_string()
                     );
+                    self.status.set_connected(false);
                     return;
                 }
                 result = self.connect_and_listen() => {
+                    self.status.set_connected(false);
                     match result {
                         Ok(()) => {
                             info!(
@@ -62,21 +159,33 @@ where
                             );
                         }
                         Err(e) => {
-                            error!(
-                                message = "upstream websocket error",
-                                uri = self.uri.to_string(),
-                                error = e.to_string()
-                            );
+                            let sampled = log_sampling::sample(EventClass::Reconnects);
+                            if let Some(suppressed) = sampled {
+                                error!(
+                                    message = "upstream websocket error",
+                                    uri = self.uri.to_string(),
+                                    error = e.to_string(),
+                                    suppressed = suppressed
+                                );
+                            }
                             self.metrics.upstream_errors.increment(1);
+                            self.consecutive_failures += 1;
+                            error_reporting::upstream_failed(
+                                &self.uri.to_string(),
+                                self.consecutive_failures,
+                                &e.to_string(),
+                            );
                             // Decrement the active connections count when connection fails
                             self.metrics.upstream_connections.decrement(1);
 
                             if let Some(duration) = self.backoff.next_backoff() {
-                                warn!(
-                                    message = "reconnecting",
-                                    uri = self.uri.to_string(),
-                                    seconds = duration.as_secs()
-                                );
+                                if sampled.is_some() {
+                                    warn!(
+                                        message = "reconnecting",
+                                        uri = self.uri.to_string(),
+                                        seconds = duration.as_secs()
+                                    );
+                                }
                                 select! {
                                     _ = token.cancelled() => {
                                         info!(
@@ -95,6 +204,37 @@ where
         }
     }
 
+    /// Runs the subscriber like [`Self::run`], but restarts it with the reconnect backoff if it
+    /// panics, e.g. in the handler, instead of leaving the upstream unsubscribed.
+    pub async fn run_supervised(&mut self, token: CancellationToken) {
+        loop {
+            let Err(panic) = AssertUnwindSafe(self.run(token.clone()))
+                .catch_unwind()
+                .await
+            else {
+                return;
+            };
+
+            if self.status.is_connected() {
+                self.status.set_connected(false);
+                self.metrics.upstream_connections.decrement(1);
+            }
+            self.metrics.upstream_subscriber_panics.increment(1);
+            let duration = self.backoff.next_backoff().unwrap_or_default();
+            error!(
+                message = "upstream subscriber panicked, restarting",
+                uri = self.uri.to_string(),
+                panic = panic_hook::panic_message(&*panic),
+                seconds = duration.as_secs()
+            );
+
+            select! {
+                _ = token.cancelled() => return,
+                _ = tokio::time::sleep(duration) => {}
+            }
+        }
+    }
+
     async fn connect_and_listen(&mut self) -> Result<(), Error> {
         info!(
             message = "connecting to websocket",
@@ -105,7 +245,14 @@ where
         self.metrics.upstream_connection_attempts.increment(1);
 
         // Modified connection with success/failure metrics tracking
-        let (ws_stream, _) = match connect_async(&self.uri).await {
+        let mut request = self.uri.to_string().into_client_request()?;
+        if let Some(token) = &self.relay_token {
+            let value = HeaderValue::from_str(&format!("Bearer {token}"))
+                .map_err(|e| Error::HttpFormat(e.into()))?;
+            request.headers_mut().insert(AUTHORIZATION, value);
+        }
+
+        let (ws_stream, _) = match connect_async(request).await {
             Ok(connection) => {
                 // Track successful connections
                 self.metrics.upstream_connection_successes.increment(1);
@@ -125,22 +272,55 @@ where
 
         // Increment active connections counter
         self.metrics.upstream_connections.increment(1);
+        self.status.set_connected(true);
         // Reset backoff timer on successful connection
         self.backoff.reset();
+        self.consecutive_failures = 0;
+        self.sequence.reset();
 
         let (_, mut read) = ws_stream.split();
+        // Measured per connection, so that time spent reconnecting isn't counted as an interval
+        let mut cadence = Cadence::default();
 
         while let Some(message) = read.next().await {
             match message {
+                Ok(msg) if self.relay_token.is_some() => {
+                    if !msg.is_binary() {
+                        continue;
+                    }
+                    let Some((sequence, payload)) = relay::open_envelope(msg.into_data()) else {
+                        warn!(
+                            message = "malformed relay message",
+                            uri = self.uri.to_string()
+                        );
+                        continue;
+                    };
+
+                    let missed = self.sequence.observe(sequence);
+                    if missed > 0 {
+                        self.metrics
+                            .upstream_relay_missed_messages
+                            .increment(missed);
+                    }
+                    self.metrics.upstream_messages.increment(1);
+                    self.record_interval(&mut cadence);
+                    self.status.record_message();
+                    self.deliver(payload).await?;
+                }
                 Ok(msg) => {
                     let text = msg.to_text()?;
-                    trace!(
-                        message = "received message",
-                        uri = self.uri.to_string(),
-                        payload = text
-                    );
+                    if let Some(suppressed) = log_sampling::sample(EventClass::Messages) {
+                        trace!(
+                            message = "received message",
+                            uri = self.uri.to_string(),
+                            payload = text,
+                            suppressed = suppressed
+                        );
+                    }
                     self.metrics.upstream_messages.increment(1);
-                    (self.handler)(text.into());
+                    self.record_interval(&mut cadence);
+                    self.status.record_message();
+                    self.deliver(msg.into_data()).await?;
                 }
                 Err(e) => {
                     error!(
@@ -155,6 +335,40 @@ where
 
         Ok(())
     }
+
+    fn record_interval(&self, cadence: &mut Cadence) {
+        if let Some(interval) = cadence.tick() {
+            self.metrics
+                .upstream_message_interval
+                .record(interval.as_secs_f64());
+        }
+    }
+
+    /// Passes an upstream message to the handler, through the fault injector if there is one,
+    /// unless it is filtered out.
+    async fn deliver(&self, payload: Bytes) -> Result<(), Error> {
+        if filter::matches_any(&self.filters, &payload) {
+            self.metrics.upstream_filtered_messages.increment(1);
+            return Ok(());
+        }
+
+        #[cfg(feature = "chaos")]
+        if let Some(chaos) = &self.chaos {
+            let injected = chaos.inject(payload).await;
+            for message in injected.messages {
+                (self.handler)(message);
+            }
+            if injected.disconnect {
+                return Err(Error::Io(std::io::Error::other(
+                    "injected upstream disconnect",
+                )));
+            }
+            return Ok(());
+        }
+
+        (self.handler)(payload);
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -285,9 +499,9 @@ mod tests {
         let received_clone = received_messages.clone();
 
         // Create a listener function that will be shared by both subscribers
-        let listener = move |data: String| {
+        let listener = move |data: Bytes| {
             if let Ok(mut messages) = received_clone.lock() {
-                messages.push(data);
+                messages.push(String::from_utf8(data.to_vec()).unwrap());
             }
         };
 
@@ -366,6 +580,103 @@ mod tests {
         assert!(messages.contains(&"Another message from server 1".to_string()));
         assert!(messages.contains(&"Another message from server 2".to_string()));
 
-        assert!(messages.len() > 0);
+        assert!(!messages.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_filters() {
+        let server = MockServer::new().await;
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let received_clone = received.clone();
+        let listener = move |data: Bytes| {
+            received_clone
+                .lock()
+                .unwrap()
+                .push(String::from_utf8(data.to_vec()).unwrap());
+        };
+
+        let filters = vec![
+            "prefix:ping".parse().unwrap(),
+            "pointer:/type=keepalive".parse().unwrap(),
+        ];
+        let mut subscriber =
+            WebsocketSubscriber::new(server.uri(), listener, 5, Arc::new(Metrics::default()))
+                .with_filters(filters);
+        let status = subscriber.status();
+        let token = CancellationToken::new();
+        let task = tokio::spawn({
+            let token = token.clone();
+            async move { subscriber.run(token).await }
+        });
+
+        while !status.is_connected() {
+            sleep(Duration::from_millis(10)).await;
+        }
+        sleep(Duration::from_millis(100)).await;
+        for message in ["ping", r#"{"type":"keepalive"}"#, r#"{"index":0}"#, "ping"] {
+            let _ = server.send_message(message).await;
+        }
+        sleep(Duration::from_millis(200)).await;
+
+        token.cancel();
+        let _ = timeout(Duration::from_secs(1), task).await;
+        server.shutdown().await;
+
+        assert_eq!(*received.lock().unwrap(), vec![r#"{"index":0}"#]);
+        // Filtered messages still show that the upstream is alive
+        assert!(status.last_message_age().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_restart_after_panic() {
+        let server = MockServer::new().await;
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let received_clone = received.clone();
+        let listener = move |data: Bytes| {
+            if data.as_ref() == b"panic" {
+                panic!("handler failed");
+            }
+            received_clone
+                .lock()
+                .unwrap()
+                .push(String::from_utf8(data.to_vec()).unwrap());
+        };
+
+        let metrics = Arc::new(Metrics::default());
+        let mut subscriber = WebsocketSubscriber::new(server.uri(), listener, 5, metrics);
+        let status = subscriber.status();
+        let token = CancellationToken::new();
+        let task = tokio::spawn({
+            let token = token.clone();
+            async move { subscriber.run_supervised(token).await }
+        });
+
+        while !status.is_connected() {
+            sleep(Duration::from_millis(10)).await;
+        }
+        let _ = server.send_message("panic").await;
+        sleep(Duration::from_millis(100)).await;
+        assert!(!status.is_connected());
+
+        // Restarted after the backoff, and reconnected
+        timeout(Duration::from_secs(5), async {
+            while !status.is_connected() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        sleep(Duration::from_millis(100)).await;
+        let _ = server.send_message("after").await;
+        sleep(Duration::from_millis(100)).await;
+
+        token.cancel();
+        timeout(Duration::from_secs(1), task)
+            .await
+            .unwrap()
+            .unwrap();
+        server.shutdown().await;
+
+        assert_eq!(*received.lock().unwrap(), vec!["after"]);
     }
 }
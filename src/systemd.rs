@@ -0,0 +1,94 @@
+use crate::healthcheck;
+use crate::subscriber::UpstreamStatus;
+use sd_notify::NotifyState;
+use std::net::SocketAddr;
+use std::os::fd::RawFd;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Whether the proxy was started by systemd with a notification socket (`Type=notify`).
+pub fn enabled() -> bool {
+    std::env::var_os("NOTIFY_SOCKET").is_some()
+}
+
+/// The first socket passed by systemd socket activation (`LISTEN_FDS`), if the proxy was started
+/// that way. systemd keeps the socket open across restarts, queueing connections while no process
+/// accepts them.
+pub fn listen_fd() -> Option<RawFd> {
+    match sd_notify::listen_fds() {
+        Ok(mut fds) => fds.next(),
+        Err(e) => {
+            warn!(
+                message = "ignoring invalid LISTEN_FDS",
+                error = e.to_string()
+            );
+            None
+        }
+    }
+}
+
+/// Tells systemd the proxy is ready once it accepts connections on `listen_addr` and at least one
+/// upstream is connected.
+pub async fn notify_ready(
+    listen_addr: SocketAddr,
+    upstreams: Vec<Arc<UpstreamStatus>>,
+    token: CancellationToken,
+) {
+    let probe_addr = healthcheck::local_addr(listen_addr);
+    let mut ticker = tokio::time::interval(READY_POLL_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = token.cancelled() => return,
+            _ = ticker.tick() => {}
+        }
+
+        if upstreams.iter().any(|upstream| upstream.is_connected())
+            && TcpStream::connect(probe_addr).await.is_ok()
+        {
+            break;
+        }
+    }
+
+    info!(message = "notifying systemd that the proxy is ready");
+    notify(&[NotifyState::Ready]);
+}
+
+/// Pings the systemd watchdog at half the interval configured by `WatchdogSec`, so that systemd
+/// restarts the proxy if its runtime stops making progress. Returns immediately if the watchdog
+/// isn't enabled.
+pub async fn run_watchdog(token: CancellationToken) {
+    let mut timeout_usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut timeout_usec) {
+        return;
+    }
+
+    let mut ticker = tokio::time::interval(Duration::from_micros(timeout_usec) / 2);
+    info!(
+        message = "pinging systemd watchdog",
+        interval_ms = ticker.period().as_millis() as u64
+    );
+
+    loop {
+        tokio::select! {
+            _ = token.cancelled() => return,
+            _ = ticker.tick() => notify(&[NotifyState::Watchdog]),
+        }
+    }
+}
+
+/// Tells systemd the proxy is shutting down.
+pub fn notify_stopping() {
+    notify(&[NotifyState::Stopping]);
+}
+
+fn notify(state: &[NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, state) {
+        warn!(message = "failed to notify systemd", error = e.to_string());
+    }
+}
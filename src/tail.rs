@@ -0,0 +1,37 @@
+use axum::http::Uri;
+use futures::StreamExt;
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::{Error, Message};
+
+#[derive(clap::Args, Debug)]
+pub struct TailArgs {
+    /// Websocket URL to read messages from, e.g. ws://localhost:8545/ws
+    #[arg(long, default_value = "ws://localhost:8545/ws")]
+    pub target: Uri,
+
+    /// Exit after printing this many messages
+    #[arg(long)]
+    pub count: Option<u64>,
+}
+
+/// Connects to the target and prints every message it sends to stdout, one per line.
+pub async fn run(args: TailArgs) -> Result<(), Error> {
+    let (mut ws_stream, _) = connect_async(&args.target).await?;
+    let mut printed = 0;
+
+    while args.count.is_none_or(|count| printed < count) {
+        let Some(msg) = ws_stream.next().await else {
+            break;
+        };
+
+        match msg? {
+            Message::Text(text) => println!("{text}"),
+            Message::Binary(data) => println!("{}", String::from_utf8_lossy(&data)),
+            Message::Close(_) => break,
+            _ => continue,
+        }
+        printed += 1;
+    }
+
+    Ok(())
+}
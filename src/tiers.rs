@@ -0,0 +1,179 @@
+//! Streams fed from another stream's messages rather than their own upstreams, configured with
+//! `--stream name=free,source=default,...`, so that several tiers of service can share one ingest.
+//! A tier can hold messages back for a fixed delay, so that free access can be offered without
+//! giving away the latency paid clients get, and project them to a different view of the data, e.g.
+//! transactions reduced to their hashes. Projections are applied once per message for the whole
+//! tier rather than for each client.
+
+use crate::metrics::DropCause;
+use crate::projection::{self, Projection};
+use crate::registry::{Delivery, Registry};
+use axum::extract::ws::Message;
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+/// How a tier changes its source's messages.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Tier {
+    /// How long after the source received a message it's published to the tier.
+    pub delay: Option<Duration>,
+    /// Applied to each message, in order.
+    pub projections: Vec<Projection>,
+}
+
+/// Messages waiting for their delay to pass, oldest first.
+#[derive(Debug, Default)]
+struct DelayBuffer {
+    messages: VecDeque<(Instant, Bytes, Option<u16>)>,
+}
+
+impl DelayBuffer {
+    fn push(&mut self, due: Instant, payload: Bytes, upstream: Option<u16>) {
+        self.messages.push_back((due, payload, upstream));
+    }
+
+    /// When the oldest message is due, if any are waiting.
+    fn deadline(&self) -> Option<Instant> {
+        self.messages.front().map(|(due, _, _)| *due)
+    }
+
+    /// Removes and returns the messages that are due.
+    fn release(&mut self, now: Instant) -> Vec<(Bytes, Option<u16>)> {
+        let due = self.messages.partition_point(|(due, _, _)| *due <= now);
+        self.messages
+            .drain(..due)
+            .map(|(_, payload, upstream)| (payload, upstream))
+            .collect()
+    }
+}
+
+/// Publishes every message in `source` to `target`, as changed by `tier`, until `token` is
+/// cancelled. The tier is subscribed to `source` as an internal subscriber, so it isn't counted as
+/// one of its clients or lag-dropped by load shedding, and messages it falls behind on are dropped
+/// and counted against `target`.
+pub async fn mirror(source: Registry, target: Registry, tier: Tier, token: CancellationToken) {
+    let mut subscription = source.register_internal();
+    let mut delayed = DelayBuffer::default();
+    let mut batch = Vec::new();
+
+    loop {
+        let delivery = tokio::select! {
+            _ = token.cancelled() => return,
+            _ = sleep_until(delayed.deadline()) => {
+                for (payload, upstream) in delayed.release(Instant::now()) {
+                    target.publish_from(payload, upstream);
+                }
+                continue;
+            }
+            delivery = subscription.recv_many(&mut batch, 64) => delivery,
+        };
+
+        match delivery {
+            Delivery::Messages(_) => {}
+            Delivery::Lagged(dropped) => {
+                target
+                    .metrics()
+                    .dropped_messages
+                    .increment(DropCause::Lagged, dropped);
+                continue;
+            }
+            Delivery::Overwritten(dropped) => {
+                target
+                    .metrics()
+                    .dropped_messages
+                    .increment(DropCause::Overwritten, dropped);
+                continue;
+            }
+        }
+
+        for msg in batch.drain(..) {
+            let payload = match msg.frame {
+                Message::Binary(payload) => payload,
+                Message::Text(text) => Bytes::from(text.as_str().to_owned()),
+                _ => continue,
+            };
+            let payload = projection::project(&tier.projections, payload);
+            match tier.delay {
+                Some(delay) => delayed.push(msg.received_at + delay, payload, msg.upstream),
+                None => {
+                    target.publish_from(payload, msg.upstream);
+                }
+            }
+        }
+    }
+}
+
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Metrics;
+    use std::sync::Arc;
+
+    fn registry() -> Registry {
+        Registry::new(16, 1, Arc::new(Metrics::default()))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_delayed_mirror() {
+        let source = registry();
+        let target = registry();
+        let token = CancellationToken::new();
+        tokio::spawn(mirror(
+            source.clone(),
+            target.clone(),
+            Tier {
+                delay: Some(Duration::from_millis(500)),
+                projections: vec!["count:/transactions".parse().unwrap()],
+            },
+            token.clone(),
+        ));
+        let mut client = target.register();
+        tokio::task::yield_now().await;
+
+        source.publish_from(Bytes::from(r#"{"transactions":["0x01","0x02"]}"#), Some(1));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        source.publish(Bytes::from("two"));
+
+        let mut batch = Vec::new();
+        let started = Instant::now();
+        client.recv_many(&mut batch, 16).await;
+        assert_eq!(started.elapsed(), Duration::from_millis(300));
+        assert_eq!(
+            batch[0].frame,
+            Message::Binary(Bytes::from(r#"{"transactions":2}"#))
+        );
+        assert_eq!(batch[0].upstream, Some(1));
+
+        batch.clear();
+        client.recv_many(&mut batch, 16).await;
+        assert_eq!(started.elapsed(), Duration::from_millis(500));
+        assert_eq!(batch[0].frame, Message::Binary(Bytes::from("two")));
+
+        token.cancel();
+    }
+
+    #[test]
+    fn test_delay_buffer() {
+        let now = Instant::now();
+        let mut buffer = DelayBuffer::default();
+        assert_eq!(buffer.deadline(), None);
+
+        buffer.push(now, Bytes::from("one"), None);
+        buffer.push(now + Duration::from_secs(1), Bytes::from("two"), None);
+        assert_eq!(buffer.deadline(), Some(now));
+
+        assert_eq!(buffer.release(now), [(Bytes::from("one"), None)]);
+        assert_eq!(buffer.deadline(), Some(now + Duration::from_secs(1)));
+        assert!(buffer.release(now).is_empty());
+    }
+}
@@ -0,0 +1,194 @@
+//! Transforms that inspect, rewrite or drop messages, loaded by operators at startup so that custom
+//! payload transformations can be deployed without recompiling the proxy. A transform runs either
+//! on a stream's ingest, on every message published to it, or on one client's egress, on the
+//! messages sent to that client. Transforms are loaded from WASM modules with the `wasm` feature,
+//! see [`crate::wasm`], or from rhai scripts with the `scripting` feature, see
+//! [`crate::scripting`].
+//!
+//! Each transform is named, and instantiated separately for each stream or client that uses it, so
+//! that clients never wait on one another's transforms. A message a transform fails on is dropped,
+//! so that a broken redaction never lets through what it was meant to remove.
+
+use crate::log_sampling::{self, EventClass};
+use crate::metrics::Metrics;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use thiserror::Error;
+use tracing::warn;
+
+/// What a transform made of a message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// Send the message unchanged.
+    Keep,
+    /// Send this instead.
+    Replace(Bytes),
+    Drop,
+}
+
+#[derive(Debug, Error)]
+#[error("transform {name} failed: {reason}")]
+pub struct TransformError {
+    pub name: String,
+    pub reason: String,
+}
+
+impl TransformError {
+    pub fn new(name: &str, reason: impl ToString) -> Self {
+        Self {
+            name: name.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+/// A transform as configured with `name=path`, e.g. `--wasm-module redact=/etc/proxy/redact.wasm`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransformFile {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+impl FromStr for TransformFile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, path) = s
+            .split_once('=')
+            .ok_or_else(|| format!("transform {s} must be name=path"))?;
+        let name = name.trim();
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(format!(
+                "transform name {name:?} must be letters, digits, '-' and '_'"
+            ));
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            path: PathBuf::from(path.trim()),
+        })
+    }
+}
+
+/// An instance of a transform, with its own state.
+pub trait Transform: Send {
+    fn apply(&mut self, payload: &Bytes) -> Result<Verdict, TransformError>;
+}
+
+/// A loaded transform, from which each stream or client that uses it gets its own instance.
+pub trait Plugin: Send + Sync {
+    fn instantiate(&self) -> Result<Box<dyn Transform>, TransformError>;
+}
+
+/// The transforms loaded at startup, by name.
+#[derive(Clone, Default)]
+pub struct Plugins {
+    plugins: Arc<HashMap<String, Arc<dyn Plugin>>>,
+}
+
+impl Plugins {
+    pub fn with_plugin(mut self, name: impl Into<String>, plugin: Arc<dyn Plugin>) -> Self {
+        Arc::make_mut(&mut self.plugins).insert(name.into(), plugin);
+        self
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.plugins.contains_key(name)
+    }
+
+    /// A new instance of the transform called `name`.
+    pub fn instantiate(&self, name: &str) -> Result<Box<dyn Transform>, TransformError> {
+        self.plugins
+            .get(name)
+            .ok_or_else(|| TransformError::new(name, "no transform with that name is loaded"))?
+            .instantiate()
+    }
+}
+
+/// Runs `transform` on `payload`, returning what to publish or send in its place, or `None` if it
+/// was dropped. Failures are logged, and counted in `transform_errors`.
+pub fn apply(transform: &mut dyn Transform, payload: Bytes, metrics: &Metrics) -> Option<Bytes> {
+    let verdict = transform.apply(&payload).unwrap_or_else(|e| {
+        if let Some(suppressed) = log_sampling::sample(EventClass::Messages) {
+            warn!(
+                message = "dropping message the transform failed on",
+                error = e.to_string(),
+                suppressed = suppressed
+            );
+        }
+        metrics.transform_errors.increment(1);
+        Verdict::Drop
+    });
+
+    match verdict {
+        Verdict::Keep => Some(payload),
+        Verdict::Replace(payload) => Some(payload),
+        Verdict::Drop => {
+            metrics.transform_dropped_messages.increment(1);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Drops messages starting with `-`, and upper cases the rest.
+    struct Shout;
+
+    impl Transform for Shout {
+        fn apply(&mut self, payload: &Bytes) -> Result<Verdict, TransformError> {
+            match payload.first() {
+                Some(b'-') => Ok(Verdict::Drop),
+                Some(b'!') => Err(TransformError::new("shout", "no")),
+                _ if payload.iter().all(u8::is_ascii_uppercase) => Ok(Verdict::Keep),
+                _ => Ok(Verdict::Replace(payload.to_ascii_uppercase().into())),
+            }
+        }
+    }
+
+    impl Plugin for Shout {
+        fn instantiate(&self) -> Result<Box<dyn Transform>, TransformError> {
+            Ok(Box::new(Shout))
+        }
+    }
+
+    #[test]
+    fn test_parse_transform_file() {
+        assert_eq!(
+            "redact=/etc/proxy/redact.wasm".parse::<TransformFile>(),
+            Ok(TransformFile {
+                name: "redact".to_string(),
+                path: PathBuf::from("/etc/proxy/redact.wasm"),
+            })
+        );
+        assert!("/etc/proxy/redact.wasm".parse::<TransformFile>().is_err());
+        assert!("re dact=/etc/proxy/redact.wasm"
+            .parse::<TransformFile>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_apply() {
+        let metrics = Metrics::default();
+        let plugins = Plugins::default().with_plugin("shout", Arc::new(Shout));
+        assert!(plugins.contains("shout"));
+        assert!(plugins.instantiate("whisper").is_err());
+
+        let mut transform = plugins.instantiate("shout").unwrap();
+        let mut run =
+            |payload: &'static str| apply(transform.as_mut(), Bytes::from(payload), &metrics);
+        assert_eq!(run("hello"), Some(Bytes::from("HELLO")));
+        assert_eq!(run("HELLO"), Some(Bytes::from("HELLO")));
+        assert_eq!(run("-hello"), None);
+        assert_eq!(run("!hello"), None);
+    }
+}
@@ -0,0 +1,163 @@
+//! A queue for websocket handshakes that arrive while the proxy is at its global connection limit,
+//! so that the reconnect stampede after a deploy is admitted as connections free up instead of
+//! being refused and retrying. Handshakes wait in the order they arrived, and are refused once the
+//! queue is full or they've waited for too long.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Notify, Semaphore};
+use tokio::time::Instant;
+
+/// How often the handshake at the front of the queue tries again without being told that a
+/// connection was released, e.g. for connections counted by other instances of the proxy.
+const RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WaitError {
+    /// The queue was already full.
+    Full,
+    /// No connection was released within the longest wait.
+    Expired,
+}
+
+#[derive(Debug)]
+pub struct WaitingRoom {
+    capacity: usize,
+    max_wait: Duration,
+    waiting: AtomicUsize,
+    /// Held by the handshake at the front of the queue. Tokio's semaphore is fair, so handshakes
+    /// get it in the order they asked.
+    front: Semaphore,
+    released: Arc<Notify>,
+}
+
+impl WaitingRoom {
+    pub fn new(capacity: usize, max_wait: Duration) -> Self {
+        Self {
+            capacity,
+            max_wait,
+            waiting: AtomicUsize::new(0),
+            front: Semaphore::new(1),
+            released: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Handshakes currently waiting.
+    pub fn waiting(&self) -> usize {
+        self.waiting.load(Ordering::Relaxed)
+    }
+
+    /// To be notified when a connection is released, so that the handshake at the front of the
+    /// queue can try again.
+    pub fn released(&self) -> Arc<Notify> {
+        self.released.clone()
+    }
+
+    /// Waits in the queue until `acquire` succeeds, trying again whenever a connection is
+    /// released.
+    pub async fn wait<T>(&self, mut acquire: impl FnMut() -> Option<T>) -> Result<T, WaitError> {
+        self.waiting
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |waiting| {
+                (waiting < self.capacity).then_some(waiting + 1)
+            })
+            .map_err(|_| WaitError::Full)?;
+        let _place = Place(&self.waiting);
+
+        let deadline = Instant::now() + self.max_wait;
+        tokio::time::timeout_at(deadline, async {
+            let _front = self.front.acquire().await.unwrap();
+            loop {
+                // Registered before trying, so that a release in between isn't missed
+                let released = self.released.notified();
+                if let Some(acquired) = acquire() {
+                    return acquired;
+                }
+                tokio::select! {
+                    _ = released => {}
+                    _ = tokio::time::sleep(RETRY_INTERVAL) => {}
+                }
+            }
+        })
+        .await
+        .map_err(|_| WaitError::Expired)
+    }
+}
+
+/// A handshake's place in the queue, given up when dropped.
+struct Place<'a>(&'a AtomicUsize);
+
+impl Drop for Place<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_waiting_room() {
+        let room = Arc::new(WaitingRoom::new(2, Duration::from_secs(5)));
+        let free = Arc::new(AtomicUsize::new(0));
+        let acquire = |free: Arc<AtomicUsize>| {
+            move || {
+                free.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |free| {
+                    free.checked_sub(1)
+                })
+                .ok()
+            }
+        };
+
+        let first = tokio::spawn({
+            let room = room.clone();
+            let acquire = acquire(free.clone());
+            async move { room.wait(acquire).await }
+        });
+        tokio::task::yield_now().await;
+        let second = tokio::spawn({
+            let room = room.clone();
+            let acquire = acquire(free.clone());
+            async move { (room.wait(acquire).await, Instant::now()) }
+        });
+        tokio::task::yield_now().await;
+        assert_eq!(room.waiting(), 2);
+
+        // The queue is full
+        assert_eq!(room.wait(acquire(free.clone())).await, Err(WaitError::Full));
+
+        // Released connections go to the handshakes in the order they arrived
+        let started = Instant::now();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        free.fetch_add(1, Ordering::Relaxed);
+        room.released().notify_one();
+        assert_eq!(first.await.unwrap(), Ok(1));
+        assert_eq!(room.waiting(), 1);
+
+        // Until the wait expires
+        let (result, finished) = second.await.unwrap();
+        assert_eq!(result, Err(WaitError::Expired));
+        assert_eq!(finished - started, Duration::from_secs(5));
+        assert_eq!(room.waiting(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retry_without_release() {
+        let room = WaitingRoom::new(1, Duration::from_secs(5));
+        let free = Arc::new(AtomicBool::new(false));
+
+        let setter = free.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            setter.store(true, Ordering::Relaxed);
+        });
+        let started = Instant::now();
+        let acquired = room
+            .wait(|| free.load(Ordering::Relaxed).then_some(()))
+            .await;
+        assert_eq!(acquired, Ok(()));
+        assert!(started.elapsed() <= Duration::from_secs(1) + RETRY_INTERVAL);
+    }
+}
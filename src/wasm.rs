@@ -0,0 +1,292 @@
+//! Transforms loaded from WASM modules and run with the wasmi interpreter, so that operators can
+//! deploy custom payload transformations without recompiling the proxy. Only built with the
+//! `wasm` feature. See [`crate::transform`] for where they run.
+//!
+//! A module exports its linear memory as `memory`, and two functions:
+//!
+//! - `alloc(len: i32) -> i32` returns the address of `len` bytes for the proxy to write a message
+//!   into. The proxy never frees them, so a module should reuse the space, e.g. by resetting a
+//!   bump allocator on each call.
+//! - `transform(ptr: i32, len: i32) -> i64` is called with the message, and returns `-1` to drop
+//!   it, or else the address and length of the message to send in its place, packed as
+//!   `(ptr << 32) | len`. Returning the message's own address and length sends it unchanged.
+//!
+//! Modules can't import anything, so they have no access to the host beyond the messages they're
+//! given. Each message may use up to `--wasm-fuel` units of fuel, about one per instruction, and
+//! each instance's memory is capped at `--wasm-max-memory-bytes`. A message the module runs out of
+//! fuel on, or otherwise traps on, is dropped, and the instance is replaced with a fresh one for
+//! the next message, since its memory may have been left in any state. Modules in the text format
+//! are accepted as well as binaries.
+
+use crate::transform::{Plugin, Plugins, Transform, TransformError, TransformFile, Verdict};
+use bytes::Bytes;
+use std::path::Path;
+use std::sync::Arc;
+use wasmi::{
+    Config, Engine, Instance, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder,
+    TypedFunc,
+};
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct WasmArgs {
+    /// WASM module to load as a named transform, as name=path, e.g.
+    /// redact=/etc/proxy/redact.wasm (repeatable, ';' separated in the environment)
+    #[arg(long = "wasm-module", env = "WASM_MODULES", value_delimiter = ';')]
+    pub wasm_modules: Vec<TransformFile>,
+
+    /// Fuel a WASM transform may use on each message, about one unit per instruction
+    #[arg(long, env, default_value = "10000000")]
+    pub wasm_fuel: u64,
+
+    /// Most linear memory each instance of a WASM transform may have, in bytes
+    #[arg(long, env, default_value = "16777216")]
+    pub wasm_max_memory_bytes: usize,
+}
+
+impl WasmArgs {
+    /// Adds the modules to `plugins`.
+    pub fn load(&self, plugins: Plugins) -> Result<Plugins, TransformError> {
+        let limits = WasmLimits {
+            fuel: self.wasm_fuel,
+            max_memory_bytes: self.wasm_max_memory_bytes,
+        };
+
+        self.wasm_modules
+            .iter()
+            .try_fold(plugins, |plugins, module| {
+                if plugins.contains(&module.name) {
+                    return Err(TransformError::new(
+                        &module.name,
+                        "another transform has the same name",
+                    ));
+                }
+                let plugin = WasmPlugin::load(&module.name, &module.path, limits)?;
+                Ok(plugins.with_plugin(module.name.clone(), Arc::new(plugin)))
+            })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct WasmLimits {
+    /// Fuel for each message.
+    pub fuel: u64,
+    /// Most linear memory for each instance.
+    pub max_memory_bytes: usize,
+}
+
+/// A compiled module, instantiated for each stream or client that uses it.
+#[derive(Clone)]
+pub struct WasmPlugin {
+    name: String,
+    engine: Engine,
+    module: Module,
+    limits: WasmLimits,
+}
+
+impl WasmPlugin {
+    /// Compiles `wasm`, checking that the module can be instantiated within `limits` and has the
+    /// exports a transform needs.
+    pub fn new(name: &str, wasm: &[u8], limits: WasmLimits) -> Result<Self, TransformError> {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, wasm).map_err(|e| TransformError::new(name, e))?;
+
+        let plugin = Self {
+            name: name.to_string(),
+            engine,
+            module,
+            limits,
+        };
+        plugin.instance()?;
+        Ok(plugin)
+    }
+
+    /// Reads and compiles the module at `path`.
+    pub fn load(name: &str, path: &Path, limits: WasmLimits) -> Result<Self, TransformError> {
+        let wasm = std::fs::read(path)
+            .map_err(|e| TransformError::new(name, format!("{}: {e}", path.display())))?;
+        Self::new(name, &wasm, limits)
+    }
+
+    fn instance(&self) -> Result<WasmInstance, TransformError> {
+        let error = |e: wasmi::Error| TransformError::new(&self.name, e);
+
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(self.limits.max_memory_bytes)
+            .instances(1)
+            .memories(1)
+            .tables(1)
+            .build();
+        let mut store = Store::new(&self.engine, limits);
+        store.limiter(|limits| limits);
+        // The start function, if any, runs on the same allowance as a message
+        store.set_fuel(self.limits.fuel).map_err(error)?;
+
+        let instance: Instance = Linker::new(&self.engine)
+            .instantiate(&mut store, &self.module)
+            .and_then(|instance| instance.start(&mut store))
+            .map_err(error)?;
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or_else(|| TransformError::new(&self.name, "module doesn't export memory"))?;
+
+        Ok(WasmInstance {
+            alloc: instance.get_typed_func(&store, "alloc").map_err(error)?,
+            transform: instance
+                .get_typed_func(&store, "transform")
+                .map_err(error)?,
+            store,
+            memory,
+        })
+    }
+}
+
+impl Plugin for WasmPlugin {
+    fn instantiate(&self) -> Result<Box<dyn Transform>, TransformError> {
+        Ok(Box::new(WasmTransform {
+            instance: Some(self.instance()?),
+            plugin: self.clone(),
+        }))
+    }
+}
+
+struct WasmInstance {
+    store: Store<StoreLimits>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    transform: TypedFunc<(i32, i32), i64>,
+}
+
+impl WasmInstance {
+    fn call(&mut self, payload: &Bytes, fuel: u64) -> Result<Verdict, String> {
+        self.store.set_fuel(fuel).map_err(|e| e.to_string())?;
+
+        let len = i32::try_from(payload.len()).map_err(|_| "message is too large")?;
+        let ptr = self
+            .alloc
+            .call(&mut self.store, len)
+            .map_err(|e| e.to_string())?;
+        self.memory
+            .write(&mut self.store, ptr as u32 as usize, payload)
+            .map_err(|e| format!("alloc returned {ptr}: {e}"))?;
+
+        let packed = self
+            .transform
+            .call(&mut self.store, (ptr, len))
+            .map_err(|e| e.to_string())?;
+        if packed < 0 {
+            return Ok(Verdict::Drop);
+        }
+
+        let start = (packed >> 32) as usize;
+        let end = start + (packed as u32) as usize;
+        let output = self
+            .memory
+            .data(&self.store)
+            .get(start..end)
+            .ok_or_else(|| format!("transform returned {start}..{end}, outside its memory"))?;
+        match output == payload.as_ref() {
+            true => Ok(Verdict::Keep),
+            false => Ok(Verdict::Replace(Bytes::copy_from_slice(output))),
+        }
+    }
+}
+
+/// An instance of a [`WasmPlugin`], replaced after a message it fails on.
+struct WasmTransform {
+    plugin: WasmPlugin,
+    instance: Option<WasmInstance>,
+}
+
+impl Transform for WasmTransform {
+    fn apply(&mut self, payload: &Bytes) -> Result<Verdict, TransformError> {
+        let instance = match &mut self.instance {
+            Some(instance) => instance,
+            None => self.instance.insert(self.plugin.instance()?),
+        };
+
+        let verdict = instance.call(payload, self.plugin.limits.fuel);
+        if verdict.is_err() {
+            self.instance = None;
+        }
+        verdict.map_err(|e| TransformError::new(&self.plugin.name, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Drops messages starting with `-`, replaces those starting with `{` with `{}`, spins on
+    /// those starting with `~` and tries to grow its memory by 64MiB on those starting with `+`.
+    const MODULE: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (data (i32.const 0) "{}")
+          (func (export "alloc") (param $len i32) (result i32)
+            (i32.const 1024))
+          (func (export "transform") (param $ptr i32) (param $len i32) (result i64)
+            (local $byte i32)
+            (local.set $byte (i32.load8_u (local.get $ptr)))
+            (if (i32.eq (local.get $byte) (i32.const 45))
+              (then (return (i64.const -1))))
+            (if (i32.eq (local.get $byte) (i32.const 123))
+              (then (return (i64.const 2))))
+            (if (i32.eq (local.get $byte) (i32.const 126))
+              (then (loop $spin (br $spin))))
+            (if (i32.eq (local.get $byte) (i32.const 43))
+              (then (if (i32.eq (memory.grow (i32.const 1024)) (i32.const -1))
+                (then (return (i64.const -1))))))
+            (i64.or
+              (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32))
+              (i64.extend_i32_u (local.get $len)))))
+    "#;
+
+    const LIMITS: WasmLimits = WasmLimits {
+        fuel: 100_000,
+        max_memory_bytes: 1 << 20,
+    };
+
+    #[test]
+    fn test_transform() {
+        let plugin = WasmPlugin::new("test", MODULE.as_bytes(), LIMITS).unwrap();
+        let mut transform = plugin.instantiate().unwrap();
+        let mut apply = |payload: &'static str| transform.apply(&Bytes::from(payload));
+
+        assert_eq!(apply("hello").unwrap(), Verdict::Keep);
+        assert_eq!(apply("-hello").unwrap(), Verdict::Drop);
+        assert_eq!(
+            apply(r#"{"secret":1}"#).unwrap(),
+            Verdict::Replace(Bytes::from("{}"))
+        );
+
+        // Out of memory and fuel
+        assert_eq!(apply("+hello").unwrap(), Verdict::Drop);
+        let error = apply("~hello").unwrap_err();
+        assert_eq!(error.name, "test");
+        assert!(error.reason.contains("fuel"), "{}", error.reason);
+
+        // The instance is replaced after failing
+        assert_eq!(apply("hello").unwrap(), Verdict::Keep);
+    }
+
+    #[test]
+    fn test_invalid_modules() {
+        // Needs more memory than it's allowed
+        let limits = WasmLimits {
+            max_memory_bytes: 1024,
+            ..LIMITS
+        };
+        assert!(WasmPlugin::new("test", MODULE.as_bytes(), limits).is_err());
+
+        // Missing exports, imports and garbage
+        for module in [
+            r#"(module (memory (export "memory") 1))"#,
+            r#"(module (import "env" "log" (func)) (memory (export "memory") 1))"#,
+            "not wasm",
+        ] {
+            assert!(WasmPlugin::new("test", module.as_bytes(), LIMITS).is_err());
+        }
+    }
+}
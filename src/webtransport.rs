@@ -0,0 +1,216 @@
+//! Experimental WebTransport listener, serving the same streams as the websocket listener over
+//! HTTP/3 on QUIC, for consumers on lossy networks where TCP's head-of-line blocking holds up
+//! every message behind a lost packet. Only built with the `webtransport` feature.
+//!
+//! A session is opened on `/ws` for the default stream or `/ws/{stream}` for a named one. Each
+//! message is sent on its own unidirectional stream, so that a message that is still being
+//! retransmitted doesn't delay those after it. The stream carries the bare payload and is
+//! finished after it, so a client reads each to its end.
+
+use crate::metrics::{DropCause, Metrics};
+use crate::rate_limit::Ticket;
+use crate::registry::{Delivery, Subscription};
+use axum::extract::ws::Message;
+use axum::http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use wtransport::endpoint::endpoint_side;
+use wtransport::endpoint::SessionRequest;
+use wtransport::tls::error::PemLoadError;
+use wtransport::{Connection, Endpoint, Identity, ServerConfig};
+
+/// How often sessions are kept alive while no messages are flowing.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(3);
+
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct WebTransportArgs {
+    /// Address to serve WebTransport on, over QUIC on UDP, e.g. 0.0.0.0:4433
+    #[arg(long, env)]
+    pub webtransport_addr: Option<SocketAddr>,
+
+    /// PEM certificate chain for the WebTransport listener, which always serves TLS
+    #[arg(long, env)]
+    pub webtransport_cert: Option<PathBuf>,
+
+    /// PEM private key for --webtransport-cert
+    #[arg(long, env)]
+    pub webtransport_key: Option<PathBuf>,
+}
+
+impl WebTransportArgs {
+    /// Problems with the flags, for validation.
+    pub fn problems(&self) -> Vec<String> {
+        match (
+            self.webtransport_addr,
+            &self.webtransport_cert,
+            &self.webtransport_key,
+        ) {
+            (None, None, None) | (Some(_), Some(_), Some(_)) => Vec::new(),
+            (None, _, _) => {
+                vec![
+                    "--webtransport-cert and --webtransport-key require --webtransport-addr"
+                        .to_string(),
+                ]
+            }
+            (Some(_), _, _) => {
+                vec![
+                    "--webtransport-addr requires --webtransport-cert and --webtransport-key"
+                        .to_string(),
+                ]
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct WebTransportConfig {
+    pub listen_addr: SocketAddr,
+    pub identity: Arc<Identity>,
+}
+
+impl WebTransportConfig {
+    /// Serves WebTransport on `listen_addr` with the certificate chain and key in the PEM files
+    /// `cert` and `key`.
+    pub async fn load(
+        listen_addr: SocketAddr,
+        cert: &Path,
+        key: &Path,
+    ) -> Result<Self, PemLoadError> {
+        Ok(Self {
+            listen_addr,
+            identity: Arc::new(Identity::load_pemfiles(cert, key).await?),
+        })
+    }
+
+    /// Binds the listener's UDP socket.
+    pub fn endpoint(&self) -> std::io::Result<Endpoint<endpoint_side::Server>> {
+        let identity = Identity::new(
+            self.identity.certificate_chain().clone(),
+            self.identity.private_key().clone_key(),
+        );
+        let config = ServerConfig::builder()
+            .with_bind_address(self.listen_addr)
+            .with_identity(identity)
+            .keep_alive_interval(Some(KEEP_ALIVE_INTERVAL))
+            .build();
+
+        Endpoint::server(config)
+    }
+}
+
+/// The stream a session asked for from its path: `Some(None)` for the default stream or
+/// `Some(name)` for a named one, or `None` if the path isn't one of ours.
+pub fn requested_stream(path: &str) -> Option<Option<&str>> {
+    let path = path.split('?').next().unwrap_or_default();
+    match path.strip_prefix("/ws")? {
+        "" => Some(None),
+        name => match name.strip_prefix('/') {
+            Some(name) if !name.is_empty() && !name.contains('/') => Some(Some(name)),
+            _ => None,
+        },
+    }
+}
+
+/// The session request's headers, less `ip_addr_http_header`: nothing sits between the listener
+/// and the client to set it, so it can't be trusted.
+pub fn headers(request: &SessionRequest, ip_addr_http_header: &str) -> HeaderMap {
+    request
+        .headers()
+        .iter()
+        .filter(|(name, _)| !name.starts_with(':'))
+        .filter(|(name, _)| !name.eq_ignore_ascii_case(ip_addr_http_header))
+        .filter_map(|(name, value)| {
+            Some((
+                HeaderName::from_bytes(name.as_bytes()).ok()?,
+                HeaderValue::from_str(value).ok()?,
+            ))
+        })
+        .collect()
+}
+
+/// Refuses the session with the closest status to `status` that WebTransport can answer with.
+pub async fn refuse(request: SessionRequest, status: StatusCode) {
+    match status {
+        StatusCode::NOT_FOUND => request.not_found().await,
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => {
+            request.too_many_requests().await
+        }
+        _ => request.forbidden().await,
+    }
+}
+
+/// Sends `subscription`'s messages on `connection` until the client goes away, holding `ticket`
+/// for as long. Messages that were dropped because the client lagged are skipped, as for
+/// websockets.
+pub async fn subscribe(
+    connection: Connection,
+    mut subscription: Subscription,
+    _ticket: Ticket,
+    metrics: Arc<Metrics>,
+) {
+    metrics.webtransport_sessions.increment(1);
+
+    let mut batch = Vec::new();
+    loop {
+        batch.clear();
+        let delivery = tokio::select! {
+            delivery = subscription.recv_many(&mut batch, 64) => delivery,
+            _ = connection.closed() => return,
+        };
+        match delivery {
+            Delivery::Messages(_) => {}
+            Delivery::Lagged(dropped) => {
+                metrics.lag_events.increment(1);
+                metrics
+                    .dropped_messages
+                    .increment(DropCause::Lagged, dropped);
+                continue;
+            }
+            Delivery::Overwritten(dropped) => {
+                metrics
+                    .dropped_messages
+                    .increment(DropCause::Overwritten, dropped);
+                continue;
+            }
+        }
+
+        for msg in &batch {
+            let Message::Binary(payload) = &msg.frame else {
+                unreachable!("broadcast messages are binary frames");
+            };
+            let sent = async {
+                let mut stream = connection.open_uni().await?.await?;
+                stream.write_all(payload).await?;
+                // Finishing waits for the client to acknowledge the message, which mustn't hold
+                // up the next one
+                tokio::spawn(async move { stream.finish().await });
+                Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
+            };
+            if sent.await.is_err() {
+                return;
+            }
+
+            metrics.sent_message_size.record(msg.size as f64);
+            let elapsed = subscription.record_delivered(msg);
+            metrics.fan_out_latency.record(elapsed.as_secs_f64());
+        }
+        metrics.sent_messages.increment(batch.len() as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_requested_stream() {
+        assert_eq!(requested_stream("/ws"), Some(None));
+        assert_eq!(requested_stream("/ws?token=abc"), Some(None));
+        assert_eq!(requested_stream("/ws/raw"), Some(Some("raw")));
+        for invalid in ["/", "/relay", "/wss", "/ws/", "/ws/raw/more"] {
+            assert_eq!(requested_stream(invalid), None, "{invalid}");
+        }
+    }
+}